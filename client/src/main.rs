@@ -1,5 +1,5 @@
 // Copyright 2021 Oxide Computer Company
-use std::net::SocketAddrV4;
+use std::net::{SocketAddr, SocketAddrV4};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
@@ -84,6 +84,58 @@ pub struct Opt {
      */
     #[structopt(long, parse(from_os_str), name = "FILE")]
     verify_out: Option<PathBuf>,
+
+    /*
+     * Start the upstairs control server on this address.
+     */
+    #[structopt(long)]
+    control: Option<SocketAddr>,
+
+    /*
+     * How many downstairs must finish a write or flush before it is
+     * acked.  The default is a majority of the targets.
+     */
+    #[structopt(long)]
+    quorum: Option<usize>,
+
+    /*
+     * The most guest IOs, and bytes of guest IO, the upstairs will hold
+     * before new IO has to wait for earlier IO to finish.
+     */
+    #[structopt(long, default_value = "1024")]
+    queue_jobs: usize,
+
+    #[structopt(long, default_value = "1073741824")]
+    queue_bytes: usize,
+
+    /*
+     * Attach without writing.  Workloads that write will fail.
+     */
+    #[structopt(long)]
+    read_only: bool,
+
+    /*
+     * Ack writes before they are done, up to this many bytes.
+     */
+    #[structopt(long)]
+    write_back: Option<usize>,
+
+    /*
+     * Seconds a downstairs has to answer a job, and how many checks in a
+     * row can find it late before it is faulted.
+     */
+    #[structopt(long, default_value = "30")]
+    job_timeout: u64,
+
+    #[structopt(long, default_value = "3")]
+    job_misses: u32,
+
+    /*
+     * What to do when a downstairs answers with UuidMismatch: fault,
+     * renegotiate or reactivate.
+     */
+    #[structopt(long, default_value = "fault")]
+    uuid_mismatch: UuidMismatchPolicy,
 }
 
 pub fn opts() -> Result<Opt> {
@@ -208,10 +260,27 @@ fn main() -> Result<()> {
         bail!("Verify requires verify_in file");
     }
 
+    let policy = match opt.quorum {
+        Some(quorum) => {
+            ReplicationPolicy::new(opt.target.len(), quorum, quorum)?
+        }
+        None => ReplicationPolicy::majority(opt.target.len())?,
+    };
+    let limits = QueueLimits::new(opt.queue_jobs, opt.queue_bytes)?;
+    let job_timeout = JobTimeout::new(
+        std::time::Duration::from_secs(opt.job_timeout),
+        opt.job_misses,
+    )?;
     let crucible_opts = CrucibleOpts {
         target: opt.target,
         lossy: opt.lossy,
         key: opt.key,
+        legacy_xts: false,
+        control: opt.control,
+        policy: Some(policy),
+        read_only: opt.read_only,
+        job_timeout: Some(job_timeout),
+        uuid_mismatch: Some(opt.uuid_mismatch),
     };
 
     /*
@@ -232,7 +301,8 @@ fn main() -> Result<()> {
      * We create this here instead of inside up_main() so we can use
      * the methods provided by guest to interact with Crucible.
      */
-    let guest = Arc::new(Guest::new());
+    let guest = Arc::new(Guest::with_limits(limits));
+    guest.set_write_back(opt.write_back);
 
     runtime.spawn(up_main(crucible_opts, guest.clone()));
     println!("Crucible runtime is spawned");
@@ -11,7 +11,8 @@ use tempfile::NamedTempFile;
 
 mod region;
 pub use region::{
-    Block, RegionDefinition, RegionOptions, MAX_BLOCK_SIZE, MIN_BLOCK_SIZE,
+    block_shift, Block, RegionDefinition, RegionOptions, MAX_BLOCK_SIZE,
+    MIN_BLOCK_SIZE,
 };
 
 #[derive(thiserror::Error, Debug, PartialEq, Clone, Serialize, Deserialize)]
@@ -54,6 +55,18 @@ pub enum CrucibleError {
 
     #[error("Saw a UUID that wasn't ours!")]
     UuidMismatch,
+
+    #[error("Attempting to modify a read only region!")]
+    ModifyingReadOnlyRegion,
+
+    #[error("Snapshot failed: {0}")]
+    SnapshotFailed(String),
+
+    #[error("Decryption failed: {0}")]
+    DecryptionError(String),
+
+    #[error("Invalid block size: {0}")]
+    InvalidBlockSize(String),
 }
 
 impl From<std::io::Error> for CrucibleError {
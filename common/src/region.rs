@@ -3,6 +3,8 @@ use anyhow::{bail, Result};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::{crucible_bail, CrucibleError};
+
 /*
  * Where the unit is blocks, not bytes, make sure to reflect that in the
  * types used.
@@ -31,6 +33,28 @@ pub const MAX_SHIFT: u32 = 15;
 pub const MIN_BLOCK_SIZE: usize = (1 << MIN_SHIFT) as usize;
 pub const MAX_BLOCK_SIZE: usize = (1 << MAX_SHIFT) as usize;
 
+/*
+ * The shift for a block size, if it is one we can use: a power of two
+ * from MIN_BLOCK_SIZE to MAX_BLOCK_SIZE.  512 and 4096 are the ones
+ * normally used.
+ */
+pub fn block_shift(block_size: u64) -> Result<u32, CrucibleError> {
+    if !block_size.is_power_of_two()
+        || block_size < MIN_BLOCK_SIZE as u64
+        || block_size > MAX_BLOCK_SIZE as u64
+    {
+        crucible_bail!(
+            InvalidBlockSize,
+            "{} is not a power of two from {} to {}",
+            block_size,
+            MIN_BLOCK_SIZE,
+            MAX_BLOCK_SIZE
+        );
+    }
+
+    Ok(block_size.trailing_zeros())
+}
+
 impl Block {
     pub fn new(value: u64, shift: u32) -> Block {
         // are you sure you need blocks that small?
@@ -68,6 +92,62 @@ impl Block {
         bytelen % (ddef.block_size() as usize) == 0
     }
 
+    /*
+     * The block a byte offset falls on, for blocks of block_size.  The
+     * offset has to be on a block boundary.
+     */
+    pub fn from_byte_offset(
+        offset: u64,
+        block_size: u64,
+    ) -> Result<Block, CrucibleError> {
+        let shift = block_shift(block_size)?;
+        if offset % block_size != 0 {
+            crucible_bail!(OffsetUnaligned);
+        }
+
+        Ok(Block::new(offset >> shift, shift))
+    }
+
+    /*
+     * How many blocks of block_size a buffer of len bytes holds.  It has
+     * to hold a whole number of them.
+     */
+    pub fn from_byte_len(
+        len: usize,
+        block_size: u64,
+    ) -> Result<Block, CrucibleError> {
+        let shift = block_shift(block_size)?;
+        if len as u64 % block_size != 0 {
+            crucible_bail!(DataLenUnaligned);
+        }
+
+        Ok(Block::new(len as u64 >> shift, shift))
+    }
+
+    /*
+     * Check that IO of len bytes at this offset fits blocks of
+     * block_size: the offset is in blocks of that size, and the length
+     * is a whole number of them.
+     */
+    pub fn check_io(
+        &self,
+        len: usize,
+        block_size: u64,
+    ) -> Result<(), CrucibleError> {
+        if len as u64 % block_size != 0 {
+            crucible_bail!(DataLenUnaligned);
+        }
+        /*
+         * The shift may have come from the other side of a connection,
+         * so don't trust it to be small enough to shift by.
+         */
+        if 1u64.checked_shl(self.shift) != Some(block_size) {
+            crucible_bail!(BlockSizeMismatch);
+        }
+
+        Ok(())
+    }
+
     pub fn block_size_in_bytes(&self) -> u32 {
         1 << self.shift
     }
@@ -129,14 +209,63 @@ impl RegionDefinition {
         })
     }
 
+    /*
+     * Check a definition we did not make ourselves, such as one read
+     * from disk or sent by a downstairs: the block size is one we can
+     * use, and the extent size is in blocks of that size.
+     */
+    pub fn validate(&self) -> Result<(), CrucibleError> {
+        let shift = block_shift(self.block_size)?;
+        if self.extent_size.shift != shift {
+            crucible_bail!(BlockSizeMismatch);
+        }
+        if self.extent_size.value < 1 {
+            crucible_bail!(
+                InvalidNumberOfBlocks,
+                "extent size must be at least 1 block"
+            );
+        }
+
+        Ok(())
+    }
+
     pub fn block_size(&self) -> u64 {
         self.block_size
     }
 
+    /*
+     * The block size as a power of 2, for making a Block.
+     */
+    pub fn block_shift(&self) -> u32 {
+        self.block_size.trailing_zeros()
+    }
+
     pub fn set_block_size(&mut self, bs: u64) {
         self.block_size = bs;
     }
 
+    /*
+     * Check that IO of len bytes at offset is in blocks of our size, and
+     * does not go past the end of the region.
+     */
+    pub fn validate_io(
+        &self,
+        offset: Block,
+        len: usize,
+    ) -> Result<(), CrucibleError> {
+        offset.check_io(len, self.block_size)?;
+
+        let end = offset
+            .byte_value()
+            .checked_add(len as u64)
+            .ok_or(CrucibleError::OffsetInvalid)?;
+        if end > self.total_size() {
+            crucible_bail!(OffsetInvalid);
+        }
+
+        Ok(())
+    }
+
     pub fn extent_size(&self) -> Block {
         self.extent_size
     }
@@ -201,14 +330,12 @@ pub struct RegionOptions {
 
 impl RegionOptions {
     pub fn validate(&self) -> Result<()> {
-        if !self.block_size.is_power_of_two() {
-            bail!("block size must be a power of two, not {}", self.block_size);
-        }
+        let shift = block_shift(self.block_size)?;
 
-        if self.block_size < (MIN_BLOCK_SIZE as u64) {
+        if self.extent_size.shift != shift {
             bail!(
-                "minimum block size is {} bytes, not {}",
-                MIN_BLOCK_SIZE,
+                "extent size {:?} is not in blocks of {} bytes",
+                self.extent_size,
                 self.block_size
             );
         }
@@ -258,3 +385,107 @@ impl Default for RegionOptions {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn region(block_size: u64) -> RegionDefinition {
+        let mut opts = RegionOptions::default();
+        opts.set_block_size(block_size);
+        opts.set_extent_size(Block::new(10, block_size.trailing_zeros()));
+        let mut def = RegionDefinition::from_options(&opts).unwrap();
+        def.set_extent_count(4);
+        def
+    }
+
+    #[test]
+    fn block_sizes() {
+        assert_eq!(block_shift(512).unwrap(), 9);
+        assert_eq!(block_shift(4096).unwrap(), 12);
+        for bad in [0, 256, 1000, 4097, 1 << 16] {
+            assert!(matches!(
+                block_shift(bad),
+                Err(CrucibleError::InvalidBlockSize(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn byte_conversions() {
+        let b = Block::from_byte_offset(8192, 4096).unwrap();
+        assert_eq!(b, Block::new(2, 12));
+        assert_eq!(b.byte_value(), 8192);
+        assert_eq!(
+            Block::from_byte_offset(512, 4096),
+            Err(CrucibleError::OffsetUnaligned)
+        );
+
+        assert_eq!(Block::from_byte_len(1024, 512).unwrap(), Block::new(2, 9));
+        assert_eq!(
+            Block::from_byte_len(100, 512),
+            Err(CrucibleError::DataLenUnaligned)
+        );
+    }
+
+    #[test]
+    fn check_io() {
+        let b = Block::new(1, 12);
+        assert!(b.check_io(8192, 4096).is_ok());
+        assert_eq!(b.check_io(512, 4096), Err(CrucibleError::DataLenUnaligned));
+        assert_eq!(
+            b.check_io(4096, 512),
+            Err(CrucibleError::BlockSizeMismatch)
+        );
+
+        let hostile = Block {
+            value: 0,
+            shift: 200,
+        };
+        assert_eq!(
+            hostile.check_io(512, 512),
+            Err(CrucibleError::BlockSizeMismatch)
+        );
+    }
+
+    #[test]
+    fn validate_io_4k() {
+        let def = region(4096);
+        assert!(def.validate().is_ok());
+        assert_eq!(def.block_shift(), 12);
+        assert_eq!(def.total_size(), 4096 * 40);
+
+        assert!(def.validate_io(Block::new(39, 12), 4096).is_ok());
+        assert_eq!(
+            def.validate_io(Block::new(39, 12), 8192),
+            Err(CrucibleError::OffsetInvalid)
+        );
+        assert_eq!(
+            def.validate_io(Block::new(0, 9), 4096),
+            Err(CrucibleError::BlockSizeMismatch)
+        );
+        assert_eq!(
+            def.validate_io(Block::new(u64::MAX >> 12, 12), 4096),
+            Err(CrucibleError::OffsetInvalid)
+        );
+    }
+
+    #[test]
+    fn validate_definition() {
+        let mut def = region(512);
+        assert!(def.validate().is_ok());
+
+        // An extent size in the wrong block size.
+        def.set_block_size(4096);
+        assert_eq!(def.validate(), Err(CrucibleError::BlockSizeMismatch));
+
+        def.set_block_size(3000);
+        assert!(def.validate().is_err());
+
+        let mut opts = RegionOptions::default();
+        opts.set_block_size(4096);
+        assert!(opts.validate().is_err());
+        opts.set_extent_size(Block::new(100, 12));
+        assert!(opts.validate().is_ok());
+    }
+}
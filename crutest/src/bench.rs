@@ -0,0 +1,185 @@
+// Copyright 2021 Oxide Computer Company
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use rand::{Rng, RngCore};
+use rand_chacha::ChaCha8Rng;
+use tokio::task::JoinHandle;
+
+use crucible::*;
+
+use crate::workload::{Disk, Pending};
+
+/*
+ * How much IO each phase of the benchmark does, how big, and how much
+ * of it to keep outstanding.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct BenchOpts {
+    pub count: u64,
+    pub io_blocks: u64,
+    pub depth: usize,
+}
+
+/*
+ * What one phase did, and how long its IO took.  Complete is the time
+ * from sending an IO to seeing it done, as the guest sees it.  For a
+ * single upstairs we also have where that time went inside it.
+ */
+struct PhaseResult {
+    name: &'static str,
+    ios: u64,
+    bytes: u64,
+    elapsed: Duration,
+    complete: LatencyHistogram,
+    upstairs: Option<PhaseLatency>,
+}
+
+impl PhaseResult {
+    fn show(&self) {
+        let secs = self.elapsed.as_secs_f64();
+        println!(
+            "{}: {} IOs in {:.3}s, {:.0} IOPS, {:.2} MiB/s",
+            self.name,
+            self.ios,
+            secs,
+            self.ios as f64 / secs,
+            self.bytes as f64 / secs / (1024.0 * 1024.0)
+        );
+        println!(
+            "  {:<12}{:>10}{:>10}{:>10}{:>10}{:>10}  (usec)",
+            "", "mean", "p50", "p95", "p99", "max"
+        );
+        if let Some(up) = &self.upstairs {
+            show_histogram("submit", &up.submit);
+            show_histogram("downstairs", &up.downstairs);
+        }
+        show_histogram("complete", &self.complete);
+    }
+}
+
+fn show_histogram(name: &str, h: &LatencyHistogram) {
+    println!(
+        "  {:<12}{:>10}{:>10}{:>10}{:>10}{:>10}",
+        name,
+        h.mean().as_micros(),
+        h.percentile(50.0).as_micros(),
+        h.percentile(95.0).as_micros(),
+        h.percentile(99.0).as_micros(),
+        h.max().as_micros()
+    );
+}
+
+type Waiting = JoinHandle<(Result<(), CrucibleError>, Duration)>;
+
+/*
+ * Wait for an IO on its own task, so the time it took is when it was
+ * done, not when we got around to looking.
+ */
+fn wait_on(pending: Pending, issued: Instant) -> Waiting {
+    tokio::spawn(async move {
+        let result = pending.wait().await;
+        (result, issued.elapsed())
+    })
+}
+
+async fn finish(io: Waiting, complete: &mut LatencyHistogram) -> Result<()> {
+    let (result, latency) = io.await?;
+    result?;
+    complete.record(latency);
+    Ok(())
+}
+
+async fn phase(
+    disk: &Disk,
+    name: &'static str,
+    write: bool,
+    block_size: u64,
+    blocks: u64,
+    opts: &BenchOpts,
+    rng: &mut ChaCha8Rng,
+) -> Result<PhaseResult> {
+    let shift = block_size.trailing_zeros();
+    let len = (opts.io_blocks * block_size) as usize;
+    let mut data = vec![0u8; len];
+    rng.fill_bytes(&mut data);
+    let data = Bytes::from(data);
+
+    if let Disk::Guest(guest) = disk {
+        guest.reset_io_latency();
+    }
+
+    let mut complete = LatencyHistogram::default();
+    let mut in_flight: VecDeque<Waiting> = VecDeque::new();
+    let start = Instant::now();
+
+    for _ in 0..opts.count {
+        if in_flight.len() >= opts.depth {
+            finish(in_flight.pop_front().unwrap(), &mut complete).await?;
+        }
+
+        let offset =
+            Block::new(rng.gen_range(0..=blocks - opts.io_blocks), shift);
+        let issued = Instant::now();
+        let pending = if write {
+            disk.write(offset, data.clone()).await?
+        } else {
+            disk.read(offset, Buffer::new(len)).await?
+        };
+        in_flight.push_back(wait_on(pending, issued));
+    }
+    while let Some(io) = in_flight.pop_front() {
+        finish(io, &mut complete).await?;
+    }
+    let elapsed = start.elapsed();
+
+    let upstairs = match disk {
+        Disk::Guest(guest) => {
+            let latency = guest.io_latency();
+            Some(if write { latency.write } else { latency.read })
+        }
+        Disk::Volume(_) => None,
+    };
+
+    Ok(PhaseResult {
+        name,
+        ios: opts.count,
+        bytes: opts.count * len as u64,
+        elapsed,
+        complete,
+        upstairs,
+    })
+}
+
+/*
+ * Random writes, then random reads, all of io_blocks blocks, and report
+ * the IOPS, throughput and latency of each.  Nothing read is checked, and
+ * whatever was on the volume is written over.
+ */
+pub async fn bench(
+    disk: &Disk,
+    block_size: u64,
+    blocks: u64,
+    opts: &BenchOpts,
+    rng: &mut ChaCha8Rng,
+) -> Result<()> {
+    if opts.io_blocks > blocks {
+        bail!(
+            "an IO of {} blocks is bigger than the volume of {}",
+            opts.io_blocks,
+            blocks
+        );
+    }
+
+    let write =
+        phase(disk, "write", true, block_size, blocks, opts, rng).await?;
+    disk.flush().await?.wait().await?;
+    write.show();
+
+    let read =
+        phase(disk, "read", false, block_size, blocks, opts, rng).await?;
+    read.show();
+
+    Ok(())
+}
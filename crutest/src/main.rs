@@ -0,0 +1,267 @@
+// Copyright 2021 Oxide Computer Company
+use std::net::SocketAddrV4;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use rand::Rng;
+use rand_chacha::rand_core::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use structopt::StructOpt;
+use tokio::runtime::Builder;
+
+use crucible::*;
+use crucible_opts::Config;
+
+mod bench;
+mod pattern;
+mod workload;
+
+use bench::{bench, BenchOpts};
+use pattern::VolumeState;
+use workload::{run, Disk, Runner, Workload, WorkloadOpts};
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "crucible workload generator and verifier")]
+pub struct Opt {
+    /*
+     * The downstairs to attach a single upstairs to.
+     */
+    #[structopt(short, long, required_unless_one = &["volume", "config"])]
+    target: Vec<SocketAddrV4>,
+
+    /*
+     * Or a volume construction request (JSON) to build a volume from.
+     */
+    #[structopt(long, parse(from_os_str), conflicts_with = "target")]
+    volume: Option<PathBuf>,
+
+    /*
+     * Or a crucible-opts config file (TOML), with either upstairs targets
+     * or a volume in it.  CRUCIBLE_* environment variables override it.
+     */
+    #[structopt(
+        long,
+        parse(from_os_str),
+        conflicts_with_all = &["target", "volume"]
+    )]
+    config: Option<PathBuf>,
+
+    #[structopt(short, long)]
+    key: Option<String>,
+
+    #[structopt(short, long, default_value = "0")]
+    gen: u64,
+
+    #[structopt(
+        short,
+        long,
+        possible_values = &Workload::variants(),
+        default_value = "Mixed",
+        case_insensitive = true
+    )]
+    workload: Workload,
+
+    /*
+     * IOs to send, not counting flushes.  For Bench, IOs in each of its
+     * write and read phases.
+     */
+    #[structopt(long, default_value = "1000")]
+    count: u64,
+
+    /*
+     * The largest IO in blocks.  Bench sends IOs of just this size.
+     */
+    #[structopt(long, default_value = "16")]
+    io_blocks: u64,
+
+    /*
+     * Of every hundred IOs the Mixed workload sends, how many are reads.
+     */
+    #[structopt(long, default_value = "50")]
+    read_pct: u32,
+
+    /*
+     * Flush after this many IOs, or only at the end if 0.
+     */
+    #[structopt(long, default_value = "100")]
+    flush_every: u64,
+
+    /*
+     * How many IOs to keep outstanding at once.
+     */
+    #[structopt(long, default_value = "8")]
+    depth: usize,
+
+    /*
+     * Where what was written is kept between runs.  If it exists, the
+     * volume is checked against it before the workload starts, and it is
+     * updated at every flush and when the workload is done.
+     */
+    #[structopt(long, parse(from_os_str))]
+    state: Option<PathBuf>,
+
+    /*
+     * Seed for which IOs are sent, so a run can be repeated.
+     */
+    #[structopt(long)]
+    seed: Option<u64>,
+}
+
+pub fn opts() -> Result<Opt> {
+    let opt: Opt = Opt::from_args();
+    println!("raw options: {:?}", opt);
+
+    if opt.volume.is_none() && opt.config.is_none() && opt.target.is_empty() {
+        bail!("must specify at least one --target, a --volume or a --config");
+    }
+    if opt.io_blocks == 0 {
+        bail!("--io-blocks must be at least 1");
+    }
+    if opt.read_pct > 100 {
+        bail!("--read-pct must be at most 100");
+    }
+    if opt.depth == 0 {
+        bail!("--depth must be at least 1");
+    }
+    if opt.workload == Workload::Bench && opt.state.is_some() {
+        bail!("bench writes over the volume, so can't be used with --state");
+    }
+
+    Ok(opt)
+}
+
+fn main() -> Result<()> {
+    /*
+     * If any of our async tasks in our runtime panic, then we should
+     * exit the program right away.
+     */
+    let default_panic = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default_panic(info);
+        std::process::exit(1);
+    }));
+
+    let opt = opts()?;
+
+    let runtime = Builder::new_multi_thread()
+        .worker_threads(10)
+        .thread_name("crucible-tokio")
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let config = match &opt.config {
+        Some(path) => Some(Config::load(Some(path))?),
+        None => None,
+    };
+    let request: Option<VolumeConstructionRequest> = match &opt.volume {
+        Some(path) => Some(read_json(path)?),
+        None => config.as_ref().and_then(|c| c.volume.clone()),
+    };
+
+    let (disk, block_size, blocks) = match request {
+        Some(request) => {
+            let volume = Volume::construct(&request, runtime.handle())?;
+            let block_size = volume.block_size();
+            let blocks = volume.total_blocks();
+            (Disk::Volume(Arc::new(volume)), block_size, blocks)
+        }
+        None => {
+            let (crucible_opts, gen) = match &config {
+                Some(config) => {
+                    (config.upstairs.crucible_opts()?, config.upstairs.gen)
+                }
+                None => (
+                    CrucibleOpts {
+                        target: opt.target.clone(),
+                        lossy: false,
+                        key: opt.key.clone(),
+                        legacy_xts: false,
+                        control: None,
+                        policy: Some(ReplicationPolicy::majority(
+                            opt.target.len(),
+                        )?),
+                        read_only: false,
+                        job_timeout: None,
+                        uuid_mismatch: None,
+                    },
+                    opt.gen,
+                ),
+            };
+
+            let guest = Arc::new(Guest::new());
+            runtime.spawn(up_main(crucible_opts, guest.clone()));
+            guest.activate(gen)?;
+
+            let block_size = guest.query_block_size()?;
+            let blocks = guest.query_total_size()? / block_size;
+            (Disk::Guest(guest), block_size, blocks)
+        }
+    };
+    println!("Attached to {} blocks of {}", blocks, block_size);
+
+    if opt.workload == Workload::Bench {
+        let bopts = BenchOpts {
+            count: opt.count,
+            io_blocks: opt.io_blocks,
+            depth: opt.depth,
+        };
+        let mut rng = ChaCha8Rng::seed_from_u64(
+            opt.seed.unwrap_or_else(|| rand::thread_rng().gen()),
+        );
+        return runtime
+            .block_on(bench(&disk, block_size, blocks, &bopts, &mut rng));
+    }
+
+    let saved = match &opt.state {
+        Some(path) => VolumeState::load(path, block_size, blocks)?,
+        None => None,
+    };
+    let checked = saved.is_some();
+    let state = match saved {
+        Some(state) => state,
+        None => {
+            let seed = opt.seed.unwrap_or_else(|| rand::thread_rng().gen());
+            VolumeState::new(seed, block_size, blocks)
+        }
+    };
+
+    let io_seed = opt.seed.unwrap_or_else(|| rand::thread_rng().gen());
+    println!("Data seed {}, IO seed {}", state.seed, io_seed);
+    let mut rng = ChaCha8Rng::seed_from_u64(io_seed);
+
+    let wopts = WorkloadOpts {
+        count: opt.count,
+        io_blocks: opt.io_blocks,
+        read_pct: opt.read_pct,
+        flush_every: opt.flush_every,
+    };
+    let mut runner = Runner::new(disk, state, opt.state.clone(), opt.depth);
+
+    runtime.block_on(async {
+        if checked {
+            println!("Check the volume against the saved state");
+            runner.verify(wopts.io_blocks).await?;
+        }
+
+        println!("Run {:?}", opt.workload);
+        run(&mut runner, &opt.workload, &wopts, &mut rng).await
+    })?;
+
+    if let Some(path) = &opt.state {
+        runner.state.save(path)?;
+    }
+
+    let stats = &runner.stats;
+    println!(
+        "Done: {} writes ({} blocks), {} reads ({} blocks), {} flushes",
+        stats.writes,
+        stats.blocks_written,
+        stats.reads,
+        stats.blocks_read,
+        stats.flushes
+    );
+
+    Ok(())
+}
@@ -0,0 +1,359 @@
+// Copyright 2021 Oxide Computer Company
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use rand::RngCore;
+use rand_chacha::rand_core::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+
+use crucible::{read_json_maybe, write_json};
+
+/*
+ * Every block written starts with the block number, the generation and
+ * the seed, so a block that reads back wrong can say what it holds: an
+ * older write to the same block, or a write meant for another block.
+ */
+const HEADER_LEN: usize = 20;
+
+/*
+ * What we know about a volume: the seed its data is made from, how many
+ * times each block has been written (its generation), and the generation
+ * each block had at the last flush that finished.  What is in a block
+ * follows from the seed, the block number and the generation, so any
+ * block can be checked without keeping its data.
+ *
+ * This is saved at every flush and after a run, and the next run checks
+ * the whole volume against it before it does anything else.  If a run
+ * was cut short, a block written since the last flush may hold any
+ * generation from the flushed one to the last one written.  A write sent
+ * after the last save is not in the state, and shows up as a block
+ * holding something newer than it should.
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VolumeState {
+    pub seed: u64,
+    pub block_size: u64,
+    pub generation: Vec<u32>,
+    #[serde(default)]
+    pub flushed: Vec<u32>,
+    /*
+     * The oldest generation a read of each block can return.  That is
+     * the last one written, except for blocks not written again since
+     * the state was loaded.
+     */
+    #[serde(skip)]
+    oldest: Vec<u32>,
+}
+
+impl VolumeState {
+    pub fn new(seed: u64, block_size: u64, blocks: u64) -> VolumeState {
+        VolumeState {
+            seed,
+            block_size,
+            generation: vec![0; blocks as usize],
+            flushed: vec![0; blocks as usize],
+            oldest: vec![0; blocks as usize],
+        }
+    }
+
+    /*
+     * The state saved in path, if there is one.  It has to be for a
+     * volume the same shape as this one.
+     */
+    pub fn load(
+        path: &Path,
+        block_size: u64,
+        blocks: u64,
+    ) -> Result<Option<VolumeState>> {
+        let mut state: VolumeState = match read_json_maybe(path)? {
+            Some(state) => state,
+            None => return Ok(None),
+        };
+
+        if state.block_size != block_size || state.blocks() != blocks {
+            bail!(
+                "{:?} is for {} blocks of {}, the volume has {} blocks of {}",
+                path,
+                state.blocks(),
+                state.block_size,
+                blocks,
+                block_size
+            );
+        }
+
+        state.restarted();
+        Ok(Some(state))
+    }
+
+    /*
+     * After a restart, only what was flushed is sure to be there.  A
+     * state saved before the flushed generations were kept was saved
+     * once everything was flushed.
+     */
+    fn restarted(&mut self) {
+        if self.flushed.len() != self.generation.len() {
+            self.flushed = self.generation.clone();
+        }
+        self.oldest = self.flushed.clone();
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        write_json(path, self, true)
+    }
+
+    pub fn blocks(&self) -> u64 {
+        self.generation.len() as u64
+    }
+
+    /*
+     * Note a write of count blocks from block, and return the data to
+     * write.
+     */
+    pub fn write(&mut self, block: u64, count: u64) -> Vec<u8> {
+        let bs = self.block_size as usize;
+        let mut data = vec![0u8; count as usize * bs];
+
+        for (i, buf) in data.chunks_mut(bs).enumerate() {
+            let b = block as usize + i;
+            self.generation[b] += 1;
+            self.oldest[b] = self.generation[b];
+            fill_block(buf, self.seed, b as u64, self.generation[b]);
+        }
+
+        data
+    }
+
+    /*
+     * Note that a flush sent when the blocks had generations gens has
+     * finished.
+     */
+    pub fn flushed(&mut self, gens: Vec<u32>) {
+        assert_eq!(gens.len(), self.generation.len());
+        self.flushed = gens;
+    }
+
+    /*
+     * The oldest and newest generations count blocks from block can
+     * have, for checking a read once it is done.
+     */
+    pub fn expect(&self, block: u64, count: u64) -> Vec<(u32, u32)> {
+        let range = block as usize..(block + count) as usize;
+        self.oldest[range.clone()]
+            .iter()
+            .copied()
+            .zip(self.generation[range].iter().copied())
+            .collect()
+    }
+}
+
+/*
+ * Fill buf with what block holds at generation gen.
+ */
+fn fill_block(buf: &mut [u8], seed: u64, block: u64, gen: u32) {
+    buf[0..8].copy_from_slice(&block.to_le_bytes());
+    buf[8..12].copy_from_slice(&gen.to_le_bytes());
+    buf[12..20].copy_from_slice(&seed.to_le_bytes());
+
+    let mut key = [0u8; 32];
+    key[..HEADER_LEN].copy_from_slice(&buf[..HEADER_LEN]);
+    ChaCha8Rng::from_seed(key).fill_bytes(&mut buf[HEADER_LEN..]);
+}
+
+/*
+ * The block and generation a block says it holds, or None if it isn't
+ * something we wrote.  We never write generation 0, so a block of zeros
+ * is not ours whatever the seed.
+ */
+fn header(buf: &[u8], seed: u64) -> Option<(u64, u32)> {
+    let mut block = [0u8; 8];
+    let mut gen = [0u8; 4];
+    let mut buf_seed = [0u8; 8];
+    block.copy_from_slice(&buf[0..8]);
+    gen.copy_from_slice(&buf[8..12]);
+    buf_seed.copy_from_slice(&buf[12..20]);
+
+    let gen = u32::from_le_bytes(gen);
+    if u64::from_le_bytes(buf_seed) != seed || gen == 0 {
+        return None;
+    }
+
+    Some((u64::from_le_bytes(block), gen))
+}
+
+/*
+ * Say what a block that doesn't match holds instead.
+ */
+fn describe(buf: &[u8], seed: u64) -> String {
+    match header(buf, seed) {
+        Some((block, gen)) => format!("block {} generation {}", block, gen),
+        None => "something we didn't write".to_string(),
+    }
+}
+
+/*
+ * Check data read from block onward against the oldest and newest
+ * generations each block was expected to have.  A block at generation 0
+ * has never been written by us, so we don't know what is in it, but it
+ * can't hold our data.  The error names every bad block.
+ */
+pub fn check(
+    seed: u64,
+    block_size: u64,
+    block: u64,
+    data: &[u8],
+    gens: &[(u32, u32)],
+) -> Result<()> {
+    let bs = block_size as usize;
+    assert_eq!(data.len(), gens.len() * bs);
+
+    let mut expected = vec![0u8; bs];
+    let mut bad = Vec::new();
+    for (i, (buf, (oldest, newest))) in
+        data.chunks(bs).zip(gens.iter().copied()).enumerate()
+    {
+        let b = block + i as u64;
+
+        let gen = match header(buf, seed) {
+            Some((hb, gen)) if hb == b && oldest <= gen && gen <= newest => {
+                Some(gen)
+            }
+            _ => None,
+        };
+        match gen {
+            Some(gen) => {
+                fill_block(&mut expected, seed, b, gen);
+                if buf == &expected[..] {
+                    continue;
+                }
+            }
+            None if oldest == 0 && header(buf, seed).is_none() => continue,
+            None => {}
+        }
+
+        let should = if newest == 0 {
+            "was never written".to_string()
+        } else if oldest == newest {
+            format!("should be generation {}", newest)
+        } else {
+            format!("should be generation {} to {}", oldest, newest)
+        };
+        bad.push(format!(
+            "block {} {}, but holds {}",
+            b,
+            should,
+            describe(buf, seed)
+        ));
+    }
+
+    if !bad.is_empty() {
+        for b in bad.iter() {
+            println!("{}", b);
+        }
+        bail!("{} blocks from {} are wrong: {}", bad.len(), block, bad[0]);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn written_blocks_check() {
+        let mut state = VolumeState::new(7, 512, 10);
+        let data = state.write(2, 3);
+        assert_eq!(
+            state.expect(0, 6),
+            vec![(0, 0), (0, 0), (1, 1), (1, 1), (1, 1), (0, 0)]
+        );
+        check(7, 512, 2, &data, &state.expect(2, 3)).unwrap();
+
+        // Blocks are all different from each other.
+        assert_ne!(data[..512], data[512..1024]);
+    }
+
+    #[test]
+    fn stale_block_fails() {
+        let mut state = VolumeState::new(7, 512, 10);
+        let old = state.write(4, 1);
+        state.write(4, 1);
+
+        let e = check(7, 512, 4, &old, &state.expect(4, 1)).unwrap_err();
+        assert!(e.to_string().contains(
+            "should be generation 2, but holds block 4 generation 1"
+        ));
+    }
+
+    #[test]
+    fn misplaced_block_fails() {
+        let mut state = VolumeState::new(7, 512, 10);
+        let data = state.write(4, 1);
+        state.write(5, 1);
+
+        // Block 4's data where block 5 should be.
+        let e = check(7, 512, 5, &data, &state.expect(5, 1)).unwrap_err();
+        assert!(e.to_string().contains("holds block 4 generation 1"));
+
+        // Or where nothing was written.
+        let e = check(7, 512, 6, &data, &state.expect(6, 1)).unwrap_err();
+        assert!(e.to_string().contains("block 6 was never written"));
+    }
+
+    #[test]
+    fn changed_byte_fails() {
+        let mut state = VolumeState::new(7, 512, 10);
+        let mut data = state.write(0, 2);
+        data[700] ^= 1;
+
+        let e = check(7, 512, 0, &data, &state.expect(0, 2)).unwrap_err();
+        assert!(e.to_string().contains("block 1 should be generation 1"));
+    }
+
+    #[test]
+    fn unwritten_blocks_pass() {
+        let state = VolumeState::new(7, 512, 10);
+        check(7, 512, 0, &[0u8; 1024], &state.expect(0, 2)).unwrap();
+
+        // Zeros aren't mistaken for block 0 when the seed is 0.
+        let state = VolumeState::new(0, 512, 10);
+        check(0, 512, 0, &[0u8; 1024], &state.expect(0, 2)).unwrap();
+
+        // Someone else's data is not ours.
+        let mut other = VolumeState::new(8, 512, 10);
+        let data = other.write(0, 2);
+        check(7, 512, 0, &data, &state.expect(0, 2)).unwrap();
+    }
+    #[test]
+    fn unflushed_blocks_after_restart() {
+        let mut state = VolumeState::new(7, 512, 10);
+        let first = state.write(3, 2);
+        state.flushed(state.generation.clone());
+        let second = state.write(3, 1);
+        let third = state.write(3, 1);
+        let never_flushed = state.write(6, 1);
+
+        state.restarted();
+        assert_eq!(state.expect(3, 1), vec![(1, 3)]);
+
+        // Any generation written since the flush is fine.
+        for data in [&first[..512], &second[..], &third[..]] {
+            check(7, 512, 3, data, &state.expect(3, 1)).unwrap();
+        }
+        check(7, 512, 6, &never_flushed, &state.expect(6, 1)).unwrap();
+        check(7, 512, 6, &[0u8; 512], &state.expect(6, 1)).unwrap();
+
+        // A flushed block is still exact.
+        let e = check(7, 512, 4, &[0u8; 512], &state.expect(4, 1)).unwrap_err();
+        assert!(e.to_string().contains("block 4 should be generation 1"));
+
+        // And once a block is written again, only that will do.
+        let fourth = state.write(3, 1);
+        check(7, 512, 3, &fourth, &state.expect(3, 1)).unwrap();
+        let e = check(7, 512, 3, &third, &state.expect(3, 1)).unwrap_err();
+        assert!(e.to_string().contains(
+            "should be generation 4, but holds block 3 generation 3"
+        ));
+    }
+}
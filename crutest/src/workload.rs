@@ -0,0 +1,380 @@
+// Copyright 2021 Oxide Computer Company
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::Result;
+use rand::Rng;
+use rand_chacha::ChaCha8Rng;
+use structopt::clap::arg_enum;
+use structopt::StructOpt;
+
+use crucible::*;
+
+use crate::pattern::{check, VolumeState};
+
+arg_enum! {
+    #[derive(Debug, PartialEq, StructOpt)]
+    pub enum Workload {
+        Bench,
+        Fill,
+        Mixed,
+        Rand,
+        Seq,
+        Verify,
+    }
+}
+
+/*
+ * What the workload runs against: a single upstairs, or a volume built
+ * from a construction request.
+ */
+pub enum Disk {
+    Guest(Arc<Guest>),
+    Volume(Arc<Volume>),
+}
+
+/*
+ * An IO that has been sent.  A Guest hands back a waiter and keeps going,
+ * so any number of IOs can be outstanding.  A Volume IO is done by the
+ * time it returns, as there is nothing that keeps IOs to it in order if
+ * they were run at the same time.
+ */
+pub enum Pending {
+    Waiter(BlockReqWaiter),
+    Done(Result<(), CrucibleError>),
+}
+
+impl Pending {
+    pub async fn wait(self) -> Result<(), CrucibleError> {
+        match self {
+            Pending::Waiter(waiter) => waiter.wait().await,
+            Pending::Done(result) => result,
+        }
+    }
+}
+
+impl Disk {
+    pub async fn read(
+        &self,
+        offset: Block,
+        data: Buffer,
+    ) -> Result<Pending, CrucibleError> {
+        Ok(match self {
+            Disk::Guest(guest) => {
+                Pending::Waiter(guest.read_async(offset, data).await?)
+            }
+            Disk::Volume(volume) => {
+                Pending::Done(volume.read(offset, data).await)
+            }
+        })
+    }
+
+    pub async fn write(
+        &self,
+        offset: Block,
+        data: Bytes,
+    ) -> Result<Pending, CrucibleError> {
+        Ok(match self {
+            Disk::Guest(guest) => {
+                Pending::Waiter(guest.write_async(offset, data).await?)
+            }
+            Disk::Volume(volume) => {
+                Pending::Done(volume.write(offset, data).await)
+            }
+        })
+    }
+
+    pub async fn flush(&self) -> Result<Pending, CrucibleError> {
+        Ok(match self {
+            Disk::Guest(guest) => Pending::Waiter(guest.flush_async().await?),
+            Disk::Volume(volume) => Pending::Done(volume.flush().await),
+        })
+    }
+}
+
+/*
+ * A read also keeps its buffer, where it started, and the generations
+ * its blocks could have when it was sent.  Those are what it has to hold
+ * once it is done, whatever was sent after it.  A flush keeps the
+ * generations every block had when it was sent, which are the flushed
+ * ones once it is done.
+ */
+enum Check {
+    Read(u64, Buffer, Vec<(u32, u32)>),
+    Flush(Vec<u32>),
+}
+
+struct InFlight {
+    pending: Pending,
+    check: Option<Check>,
+}
+
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub reads: u64,
+    pub writes: u64,
+    pub flushes: u64,
+    pub blocks_read: u64,
+    pub blocks_written: u64,
+}
+
+/*
+ * How much work a workload does, and what kind.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct WorkloadOpts {
+    /*
+     * IOs to send, not counting flushes.
+     */
+    pub count: u64,
+    /*
+     * The largest IO in blocks.
+     */
+    pub io_blocks: u64,
+    /*
+     * Of every hundred IOs Mixed sends, how many are reads.
+     */
+    pub read_pct: u32,
+    /*
+     * Flush after this many IOs, or never if 0.
+     */
+    pub flush_every: u64,
+}
+
+/*
+ * Sends IO to a disk, keeping up to depth IOs outstanding, and checks
+ * every read against the state as it completes.  If there is somewhere
+ * to keep the state, it is saved each time a flush finishes.
+ */
+pub struct Runner {
+    disk: Disk,
+    pub state: VolumeState,
+    state_path: Option<PathBuf>,
+    shift: u32,
+    depth: usize,
+    in_flight: VecDeque<InFlight>,
+    pub stats: Stats,
+}
+
+impl Runner {
+    pub fn new(
+        disk: Disk,
+        state: VolumeState,
+        state_path: Option<PathBuf>,
+        depth: usize,
+    ) -> Runner {
+        assert!(depth > 0);
+        let shift = state.block_size.trailing_zeros();
+
+        Runner {
+            disk,
+            state,
+            state_path,
+            shift,
+            depth,
+            in_flight: VecDeque::new(),
+            stats: Stats::default(),
+        }
+    }
+
+    async fn submit(
+        &mut self,
+        pending: Pending,
+        check: Option<Check>,
+    ) -> Result<()> {
+        self.in_flight.push_back(InFlight { pending, check });
+
+        while self.in_flight.len() >= self.depth {
+            self.complete_one().await?;
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Wait for the oldest outstanding IO, and check it if it was a read,
+     * or save the state if it was a flush.
+     */
+    async fn complete_one(&mut self) -> Result<()> {
+        let io = match self.in_flight.pop_front() {
+            Some(io) => io,
+            None => return Ok(()),
+        };
+
+        io.pending.wait().await?;
+
+        match io.check {
+            Some(Check::Read(block, data, gens)) => {
+                check(
+                    self.state.seed,
+                    self.state.block_size,
+                    block,
+                    &data.as_vec(),
+                    &gens,
+                )?;
+            }
+            Some(Check::Flush(gens)) => {
+                self.state.flushed(gens);
+                if let Some(path) = &self.state_path {
+                    self.state.save(path)?;
+                }
+            }
+            None => {}
+        }
+
+        Ok(())
+    }
+
+    pub async fn drain(&mut self) -> Result<()> {
+        while !self.in_flight.is_empty() {
+            self.complete_one().await?;
+        }
+        Ok(())
+    }
+
+    pub async fn write(&mut self, block: u64, count: u64) -> Result<()> {
+        let data = Bytes::from(self.state.write(block, count));
+        let pending =
+            self.disk.write(Block::new(block, self.shift), data).await?;
+
+        self.stats.writes += 1;
+        self.stats.blocks_written += count;
+        self.submit(pending, None).await
+    }
+
+    pub async fn read(&mut self, block: u64, count: u64) -> Result<()> {
+        let gens = self.state.expect(block, count);
+        let data = Buffer::new((count * self.state.block_size) as usize);
+        let pending = self
+            .disk
+            .read(Block::new(block, self.shift), data.clone())
+            .await?;
+
+        self.stats.reads += 1;
+        self.stats.blocks_read += count;
+        self.submit(pending, Some(Check::Read(block, data, gens)))
+            .await
+    }
+
+    pub async fn flush(&mut self) -> Result<()> {
+        let gens = self.state.generation.clone();
+        let pending = self.disk.flush().await?;
+
+        self.stats.flushes += 1;
+        self.submit(pending, Some(Check::Flush(gens))).await
+    }
+
+    /*
+     * Read back the whole volume, io_blocks at a time.
+     */
+    pub async fn verify(&mut self, io_blocks: u64) -> Result<()> {
+        let blocks = self.state.blocks();
+        let mut block = 0;
+        while block < blocks {
+            let count = io_blocks.min(blocks - block);
+            self.read(block, count).await?;
+            block += count;
+        }
+
+        self.drain().await
+    }
+}
+
+/*
+ * A random IO somewhere on the volume, no larger than io_blocks.
+ */
+fn random_io(rng: &mut ChaCha8Rng, blocks: u64, io_blocks: u64) -> (u64, u64) {
+    let block = rng.gen_range(0..blocks);
+    let count = rng.gen_range(1..=io_blocks.min(blocks - block));
+    (block, count)
+}
+
+/*
+ * Run a workload, then flush and read back the whole volume.
+ */
+pub async fn run(
+    runner: &mut Runner,
+    workload: &Workload,
+    opts: &WorkloadOpts,
+    rng: &mut ChaCha8Rng,
+) -> Result<()> {
+    let blocks = runner.state.blocks();
+
+    match workload {
+        Workload::Fill => {
+            let mut block = 0;
+            while block < blocks {
+                let count = opts.io_blocks.min(blocks - block);
+                runner.write(block, count).await?;
+                block += count;
+            }
+        }
+        Workload::Seq => {
+            /*
+             * Write each IO and read it straight back, walking the volume
+             * from the start and wrapping around at the end.
+             */
+            let mut block = 0;
+            for i in 0..opts.count {
+                let count = opts.io_blocks.min(blocks - block);
+                if i % 2 == 0 {
+                    runner.write(block, count).await?;
+                } else {
+                    runner.read(block, count).await?;
+                    block = (block + count) % blocks;
+                }
+                if opts.flush_every > 0 && (i + 1) % opts.flush_every == 0 {
+                    runner.flush().await?;
+                }
+            }
+        }
+        Workload::Rand | Workload::Mixed => {
+            let read_pct = match workload {
+                Workload::Rand => 0,
+                _ => opts.read_pct,
+            };
+            for i in 0..opts.count {
+                let (block, count) = random_io(rng, blocks, opts.io_blocks);
+                if rng.gen_range(0..100) < read_pct {
+                    runner.read(block, count).await?;
+                } else {
+                    runner.write(block, count).await?;
+                }
+                if opts.flush_every > 0 && (i + 1) % opts.flush_every == 0 {
+                    runner.flush().await?;
+                }
+            }
+        }
+        Workload::Verify => {}
+        Workload::Bench => unreachable!("bench is not a verified workload"),
+    }
+
+    if *workload != Workload::Verify {
+        runner.flush().await?;
+    }
+    runner.drain().await?;
+    runner.verify(opts.io_blocks).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand_chacha::rand_core::SeedableRng;
+
+    #[test]
+    fn random_io_stays_on_the_volume() {
+        let mut rng = ChaCha8Rng::seed_from_u64(1);
+        for _ in 0..1000 {
+            let (block, count) = random_io(&mut rng, 100, 16);
+            assert!(count >= 1 && count <= 16);
+            assert!(block + count <= 100);
+        }
+
+        // A volume smaller than an IO.
+        for _ in 0..100 {
+            let (block, count) = random_io(&mut rng, 3, 16);
+            assert!(block + count <= 3);
+        }
+    }
+}
@@ -0,0 +1,547 @@
+// Copyright 2021 Oxide Computer Company
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::panic::{self, AssertUnwindSafe};
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Result};
+use bytes::{BufMut, BytesMut};
+
+/*
+ * Reads and writes to an extent file go through one of these, so how
+ * the IO is done can be picked when the region is opened.  All IO is at
+ * an offset, so nothing depends on where a file's cursor was left.
+ */
+pub trait ExtentFile: fmt::Debug + Send {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+    /*
+     * Read len bytes at offset onto the end of buf, straight into its
+     * spare capacity without filling that first.  buf only grows once
+     * all of it has been read.
+     */
+    fn read_append_at(
+        &self,
+        buf: &mut BytesMut,
+        len: usize,
+        offset: u64,
+    ) -> io::Result<()>;
+    fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()>;
+    /*
+     * Make everything written so far durable.
+     */
+    fn sync(&self) -> io::Result<()>;
+}
+
+extern "C" {
+    fn fsync(fildes: i32) -> i32;
+    fn pread(fildes: i32, buf: *mut u8, nbyte: usize, offset: i64) -> isize;
+}
+
+fn fsync_file(file: &File) -> io::Result<()> {
+    if unsafe { fsync(file.as_raw_fd()) } == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/*
+ * Read exactly len bytes at offset to dst.  std's read_exact_at wants a
+ * &mut [u8], which memory that has never been written is not allowed to
+ * be, so this calls pread itself.
+ *
+ * Safety: dst must be valid for writes of len bytes.
+ */
+unsafe fn pread_exact(
+    file: &File,
+    dst: *mut u8,
+    len: usize,
+    offset: u64,
+) -> io::Result<()> {
+    let mut done = 0;
+    while done < len {
+        let n = pread(
+            file.as_raw_fd(),
+            dst.add(done),
+            len - done,
+            (offset + done as u64) as i64,
+        );
+        if n == -1 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "failed to fill whole buffer",
+            ));
+        }
+        done += n as usize;
+    }
+
+    Ok(())
+}
+
+/*
+ * Make room for len more bytes in buf, have read fill them in, then count
+ * them as part of buf.
+ */
+fn append_with<F>(buf: &mut BytesMut, len: usize, read: F) -> io::Result<()>
+where
+    F: FnOnce(*mut u8) -> io::Result<()>,
+{
+    buf.reserve(len);
+    let dst = buf.chunk_mut();
+    assert!(dst.len() >= len);
+    read(dst.as_mut_ptr())?;
+
+    /*
+     * Safety: read has written all len bytes.
+     */
+    unsafe { buf.advance_mut(len) };
+    Ok(())
+}
+
+/*
+ * Which backend to open extent files with.
+ *
+ * Sync does each IO as one pread or pwrite on the thread running the job.
+ *
+ * Pool splits an IO larger than POOL_CHUNK into pieces and has a pool of
+ * worker threads do the pieces at the same time, which keeps more of the
+ * device busy for large reads and writes.  The pool is shared by every
+ * extent in the region.
+ *
+ * There is no io_uring backend.  It is Linux only, and the downstairs
+ * has to run on illumos.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IoBackend {
+    Sync,
+    Pool(usize),
+}
+
+impl Default for IoBackend {
+    fn default() -> IoBackend {
+        IoBackend::Sync
+    }
+}
+
+/*
+ * The number of workers when "pool" is given without one.
+ */
+const POOL_THREADS: usize = 8;
+
+impl FromStr for IoBackend {
+    type Err = anyhow::Error;
+
+    /*
+     * "sync", "pool", or "pool:<threads>"
+     */
+    fn from_str(s: &str) -> Result<IoBackend> {
+        let mut parts = s.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some("sync"), None) => Ok(IoBackend::Sync),
+            (Some("pool"), None) => Ok(IoBackend::Pool(POOL_THREADS)),
+            (Some("pool"), Some(n)) => match n.parse::<usize>() {
+                Ok(n) if n > 0 => Ok(IoBackend::Pool(n)),
+                _ => bail!("pool needs a thread count above 0, not {}", n),
+            },
+            _ => bail!("unknown IO backend {}, try sync or pool", s),
+        }
+    }
+}
+
+impl fmt::Display for IoBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IoBackend::Sync => write!(f, "sync"),
+            IoBackend::Pool(n) => write!(f, "pool:{}", n),
+        }
+    }
+}
+
+/*
+ * What a region uses to turn the files it opens into ExtentFiles.
+ */
+#[derive(Debug, Clone)]
+pub struct IoEngine {
+    backend: IoBackend,
+    pool: Option<Arc<IoPool>>,
+}
+
+impl IoEngine {
+    pub fn new(backend: IoBackend) -> IoEngine {
+        let pool = match backend {
+            IoBackend::Sync => None,
+            IoBackend::Pool(threads) => Some(Arc::new(IoPool::new(threads))),
+        };
+
+        IoEngine { backend, pool }
+    }
+
+    pub fn backend(&self) -> IoBackend {
+        self.backend
+    }
+
+    pub fn file(&self, file: File) -> Box<dyn ExtentFile> {
+        match &self.pool {
+            None => Box::new(SyncFile { file }),
+            Some(pool) => Box::new(PoolFile {
+                file: Arc::new(file),
+                pool: pool.clone(),
+            }),
+        }
+    }
+}
+
+impl Default for IoEngine {
+    fn default() -> IoEngine {
+        IoEngine::new(IoBackend::Sync)
+    }
+}
+
+#[derive(Debug)]
+pub struct SyncFile {
+    file: File,
+}
+
+impl SyncFile {
+    pub fn new(file: File) -> SyncFile {
+        SyncFile { file }
+    }
+}
+
+impl ExtentFile for SyncFile {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.file.read_exact_at(buf, offset)
+    }
+
+    fn read_append_at(
+        &self,
+        buf: &mut BytesMut,
+        len: usize,
+        offset: u64,
+    ) -> io::Result<()> {
+        append_with(buf, len, |dst| unsafe {
+            pread_exact(&self.file, dst, len, offset)
+        })
+    }
+
+    fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.file.write_all_at(buf, offset)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        fsync_file(&self.file)
+    }
+}
+
+/*
+ * IOs up to this size are not worth splitting up, and are done on the
+ * calling thread.
+ */
+const POOL_CHUNK: usize = 128 * 1024;
+
+type PoolJob = Box<dyn FnOnce() + Send>;
+
+/*
+ * One piece of a split up IO, run by a pool worker.
+ */
+type PieceJob = Box<dyn FnOnce() -> io::Result<()> + Send>;
+
+/*
+ * Where in a caller's buffer a pool worker reads or writes its piece.
+ * The caller waits for every piece before it returns, so the buffer
+ * outlives the workers' use of it.
+ */
+struct PieceDst(*mut u8);
+
+unsafe impl Send for PieceDst {}
+
+struct PieceSrc(*const u8);
+
+unsafe impl Send for PieceSrc {}
+
+/*
+ * A fixed set of threads taking jobs from a shared queue.  They exit
+ * once the pool is dropped and the queue is empty.  A job that panics
+ * doesn't take its worker with it.
+ */
+#[derive(Debug)]
+pub struct IoPool {
+    tx: Mutex<mpsc::Sender<PoolJob>>,
+}
+
+impl IoPool {
+    fn new(threads: usize) -> IoPool {
+        let (tx, rx) = mpsc::channel::<PoolJob>();
+        let rx = Arc::new(Mutex::new(rx));
+
+        for i in 0..threads {
+            let rx = rx.clone();
+            std::thread::Builder::new()
+                .name(format!("extent-io-{}", i))
+                .spawn(move || loop {
+                    let job = rx.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => {
+                            let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                        }
+                        Err(_) => break,
+                    }
+                })
+                .unwrap();
+        }
+
+        IoPool { tx: Mutex::new(tx) }
+    }
+
+    fn submit(&self, job: PoolJob) {
+        self.tx.lock().unwrap().send(job).unwrap();
+    }
+
+    /*
+     * Run every piece on the pool and wait for all of them, even after
+     * one fails: they may all be using the caller's buffer, and a write
+     * still in flight when we return could land after a later write to
+     * the same blocks.
+     *
+     * Our own sender is dropped before we wait, so a piece that panics
+     * closes its end without answering and we see that, rather than
+     * waiting forever.
+     */
+    fn run(&self, pieces: Vec<PieceJob>) -> io::Result<()> {
+        let count = pieces.len();
+        let (tx, rx) = mpsc::channel();
+        for piece in pieces {
+            let tx = tx.clone();
+            self.submit(Box::new(move || {
+                let _ = tx.send(piece());
+            }));
+        }
+        drop(tx);
+
+        let mut answered = 0;
+        let mut error = None;
+        for result in rx.iter() {
+            answered += 1;
+            if let Err(e) = result {
+                error = Some(e);
+            }
+        }
+
+        if answered < count {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "{} of {} IO pieces failed to finish",
+                    count - answered,
+                    count
+                ),
+            ));
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+/*
+ * The start and length of each POOL_CHUNK piece of len bytes.
+ */
+fn pieces(len: usize) -> impl Iterator<Item = (usize, usize)> {
+    (0..len)
+        .step_by(POOL_CHUNK)
+        .map(move |start| (start, std::cmp::min(POOL_CHUNK, len - start)))
+}
+
+#[derive(Debug)]
+pub struct PoolFile {
+    file: Arc<File>,
+    pool: Arc<IoPool>,
+}
+
+impl PoolFile {
+    /*
+     * Have the pool read len bytes at offset to dst, each worker reading
+     * its piece straight to where it goes.
+     *
+     * Safety: dst must be valid for writes of len bytes.
+     */
+    unsafe fn read_pieces(
+        &self,
+        dst: *mut u8,
+        len: usize,
+        offset: u64,
+    ) -> io::Result<()> {
+        let jobs = pieces(len)
+            .map(|(start, piece_len)| {
+                let file = self.file.clone();
+                let piece = PieceDst(dst.add(start));
+                let at = offset + start as u64;
+                Box::new(move || pread_exact(&file, piece.0, piece_len, at))
+                    as PieceJob
+            })
+            .collect();
+
+        self.pool.run(jobs)
+    }
+}
+
+impl ExtentFile for PoolFile {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        if buf.len() <= POOL_CHUNK {
+            return self.file.read_exact_at(buf, offset);
+        }
+
+        unsafe { self.read_pieces(buf.as_mut_ptr(), buf.len(), offset) }
+    }
+
+    fn read_append_at(
+        &self,
+        buf: &mut BytesMut,
+        len: usize,
+        offset: u64,
+    ) -> io::Result<()> {
+        if len <= POOL_CHUNK {
+            return append_with(buf, len, |dst| unsafe {
+                pread_exact(&self.file, dst, len, offset)
+            });
+        }
+
+        append_with(buf, len, |dst| unsafe {
+            self.read_pieces(dst, len, offset)
+        })
+    }
+
+    /*
+     * Each worker writes its piece straight from buf.
+     */
+    fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        if buf.len() <= POOL_CHUNK {
+            return self.file.write_all_at(buf, offset);
+        }
+
+        let jobs = pieces(buf.len())
+            .map(|(start, piece_len)| {
+                let file = self.file.clone();
+                let piece = PieceSrc(unsafe { buf.as_ptr().add(start) });
+                let at = offset + start as u64;
+                Box::new(move || {
+                    let data = unsafe {
+                        std::slice::from_raw_parts(piece.0, piece_len)
+                    };
+                    file.write_all_at(data, at)
+                }) as PieceJob
+            })
+            .collect();
+
+        self.pool.run(jobs)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        fsync_file(&self.file)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_io_backend() {
+        assert_eq!("sync".parse::<IoBackend>().unwrap(), IoBackend::Sync);
+        assert_eq!(
+            "pool".parse::<IoBackend>().unwrap(),
+            IoBackend::Pool(POOL_THREADS)
+        );
+        assert_eq!("pool:3".parse::<IoBackend>().unwrap(), IoBackend::Pool(3));
+        assert!("pool:0".parse::<IoBackend>().is_err());
+        assert!("uring".parse::<IoBackend>().is_err());
+
+        for b in [IoBackend::Sync, IoBackend::Pool(5)].iter() {
+            assert_eq!(b.to_string().parse::<IoBackend>().unwrap(), *b);
+        }
+    }
+
+    #[test]
+    fn pool_survives_a_panic() {
+        let pool = IoPool::new(2);
+
+        let jobs: Vec<PieceJob> = vec![
+            Box::new(|| Ok(())),
+            Box::new(|| panic!("piece panicked")),
+            Box::new(|| Ok(())),
+        ];
+        assert!(pool.run(jobs).is_err());
+
+        // The workers are all still there.
+        let jobs: Vec<PieceJob> =
+            (0..4).map(|_| Box::new(|| Ok(())) as PieceJob).collect();
+        assert!(pool.run(jobs).is_ok());
+    }
+
+    #[test]
+    fn pool_write_and_read() {
+        let len = POOL_CHUNK * 2 + 512;
+        let data: Vec<u8> = (0..len).map(|i| (i % 253) as u8).collect();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("extent");
+        std::fs::write(&path, vec![0u8; len + 512]).unwrap();
+
+        let engine = IoEngine::new(IoBackend::Pool(3));
+        let file = engine.file(
+            std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&path)
+                .unwrap(),
+        );
+        file.write_all_at(&data, 512).unwrap();
+
+        let mut buf = vec![0u8; len];
+        file.read_exact_at(&mut buf, 512).unwrap();
+        assert_eq!(buf, data);
+    }
+
+    #[test]
+    fn read_append() {
+        let len = POOL_CHUNK * 3 + 512;
+        let data: Vec<u8> = (0..len + 512).map(|i| (i % 251) as u8).collect();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("extent");
+        std::fs::write(&path, &data).unwrap();
+
+        for backend in [IoBackend::Sync, IoBackend::Pool(3)].iter() {
+            let engine = IoEngine::new(*backend);
+            let file = engine.file(File::open(&path).unwrap());
+
+            /*
+             * Small and large reads, after what the buffer already holds.
+             */
+            let mut buf = BytesMut::from(&b"head"[..]);
+            file.read_append_at(&mut buf, 512, 512).unwrap();
+            file.read_append_at(&mut buf, len, 512).unwrap();
+            assert_eq!(&buf[..4], b"head");
+            assert_eq!(&buf[4..516], &data[512..1024]);
+            assert_eq!(&buf[516..], &data[512..512 + len]);
+
+            /*
+             * A read past the end of the file fails and adds nothing.
+             */
+            let mut buf = BytesMut::new();
+            assert!(file.read_append_at(&mut buf, len, 1024).is_err());
+            assert!(buf.is_empty());
+        }
+    }
+}
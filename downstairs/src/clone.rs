@@ -0,0 +1,118 @@
+// Copyright 2021 Oxide Computer Company
+use super::*;
+use crucible_common::RegionOptions;
+
+/*
+ * How many extents we ask the source for before waiting on the answer to
+ * the first one.  Each answer is a whole extent, so keep this small.
+ */
+const CLONE_WINDOW: u64 = 4;
+
+async fn next_message(
+    fr: &mut FramedRead<OwnedReadHalf, CrucibleDecoder>,
+) -> Result<Message> {
+    match fr.next().await.transpose()? {
+        Some(m) => Ok(m),
+        None => bail!("source downstairs disconnected"),
+    }
+}
+
+/*
+ * Create a new region in `data` that is a copy of the region served by
+ * the downstairs at `source`: the same geometry, and every extent's data,
+ * encryption contexts, and flush and generation numbers.
+ *
+ * We attach to the source as a read only upstairs, so whatever upstairs
+ * is active there keeps going while we copy.  Each extent is copied as
+ * a whole, but the region as a whole is not a point in time copy if the
+ * source is taking writes.  That's fine for a replacement downstairs, as
+ * the upstairs will compare extent versions and repair any that moved on
+ * when the clone joins the volume.
+ */
+pub async fn clone_region(
+    source: SocketAddrV4,
+    data: &Path,
+    uuid: Uuid,
+) -> Result<Region> {
+    let sock = TcpStream::connect(source).await?;
+    let (read, write) = sock.into_split();
+    let mut fr = FramedRead::new(read, CrucibleDecoder::new());
+    let mut fw = FramedWrite::new(write, CrucibleEncoder::new());
+
+    let clone_uuid = Uuid::new_v4();
+    fw.send(Message::HereIAm(CRUCIBLE_MESSAGE_VERSION, clone_uuid))
+        .await?;
+    match next_message(&mut fr).await? {
+        Message::YesItsMe(v) if v == CRUCIBLE_MESSAGE_VERSION => {}
+        m => bail!("source did not negotiate: {:?}", m),
+    }
+    fw.send(Message::Attach(true)).await?;
+
+    fw.send(Message::PromoteToActive(clone_uuid, 0)).await?;
+    match next_message(&mut fr).await? {
+        Message::YouAreNowActive(u) if u == clone_uuid => {}
+        m => bail!("source did not attach us: {:?}", m),
+    }
+
+    fw.send(Message::RegionInfoPlease).await?;
+    let def = match next_message(&mut fr).await? {
+        Message::RegionInfo(def) => def,
+        m => bail!("expected RegionInfo, got {:?}", m),
+    };
+
+    fw.send(Message::ExtentVersionsPlease).await?;
+    match next_message(&mut fr).await? {
+        Message::ExtentVersions(_, _, _) => {}
+        m => bail!("expected ExtentVersions, got {:?}", m),
+    }
+
+    println!(
+        "Cloning {} extents of {} blocks from {}",
+        def.extent_count(),
+        def.extent_size().value,
+        source,
+    );
+
+    let mut region_options: RegionOptions = Default::default();
+    region_options.set_block_size(def.block_size());
+    region_options.set_extent_size(def.extent_size());
+    region_options.set_uuid(uuid);
+
+    let mut region = Region::create(data, region_options)?;
+    region.extend(def.extent_count())?;
+
+    /*
+     * Keep a few extent reads in flight so the source isn't waiting on us
+     * to write the last one out.  The source answers in the order we ask.
+     */
+    let extent_count = def.extent_count() as u64;
+    let mut next_eid = 0;
+    for eid in 0..extent_count {
+        while next_eid < extent_count && next_eid < eid + CLONE_WINDOW {
+            fw.send(Message::ExtentRepairRead(
+                clone_uuid,
+                next_eid,
+                vec![],
+                next_eid,
+            ))
+            .await?;
+            next_eid += 1;
+        }
+
+        match next_message(&mut fr).await? {
+            Message::ExtentRepairData(_, job_id, Ok(extent))
+                if job_id == eid && extent.eid == eid =>
+            {
+                region.extent_repair_write(&extent)?;
+            }
+            Message::ExtentRepairData(_, job_id, Err(e)) if job_id == eid => {
+                bail!("source failed to read extent {}: {:?}", eid, e);
+            }
+            m => bail!("expected extent {}, got {:?}", eid, m),
+        }
+    }
+
+    println!("Cloned {} extents from {}", extent_count, source);
+
+    Ok(region)
+}
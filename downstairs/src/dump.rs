@@ -1,10 +1,52 @@
 // Copyright 2021 Oxide Computer Company
 use super::*;
-use crate::region::ExtentMeta;
+use crate::region::{BlockInfo, ExtentMeta};
 
 #[derive(Debug, Default)]
 struct ExtInfo {
     ei_hm: HashMap<u32, ExtentMeta>,
+    /*
+     * How many blocks don't match their checksum, by directory index.
+     */
+    bad_hm: HashMap<u32, usize>,
+}
+
+impl ExtInfo {
+    /*
+     * True if the regions don't agree about this extent, or any of them
+     * has blocks that don't match their checksums.
+     */
+    fn differs(&self) -> bool {
+        let mut metas = self.ei_hm.values();
+        let first = metas.next();
+        let meta_differs = metas.any(|em| {
+            let first = first.unwrap();
+            em.gen_number != first.gen_number
+                || em.flush_number != first.flush_number
+                || em.dirty != first.dirty
+        });
+
+        meta_differs || self.bad_hm.values().any(|bad| *bad > 0)
+    }
+}
+
+/*
+ * Give each value a letter.  Values that are the same share a letter,
+ * which is the letter for the position of the first of them: A for the
+ * first region, B for the second, and C for the third.  Also return true
+ * if they aren't all the same.
+ */
+fn status_letters<T: PartialEq>(values: &[T]) -> (Vec<char>, bool) {
+    let letters: Vec<char> = values
+        .iter()
+        .map(|value| {
+            let first = values.iter().position(|v| v == value).unwrap();
+            (b'A' + first as u8) as char
+        })
+        .collect();
+
+    let diff = letters.iter().any(|l| *l != 'A');
+    (letters, diff)
 }
 
 /*
@@ -30,7 +72,7 @@ pub fn dump_region(
     let mut total_extents = 0;
 
     for (index, dir) in region_dir.iter().enumerate() {
-        let region = Region::open(&dir, Default::default(), false)?;
+        let region = Region::open(&dir, Default::default(), false, true)?;
 
         blocks_per_extent = region.def().extent_size().value;
         total_extents = region.def().extent_count();
@@ -71,8 +113,13 @@ pub fn dump_region(
              * index.  If we don't the create the hashmap for this index,
              * then add the extent_info to it.
              */
+            drop(inner);
+
+            let bad_blocks = e.check_blocks()?.len();
+
             let ei = all_extents.entry(en).or_default();
             ei.ei_hm.insert(index as u32, extent_info);
+            ei.bad_hm.insert(index as u32, bad_blocks);
         }
     }
 
@@ -107,7 +154,7 @@ pub fn dump_region(
 
     print!("EXT");
     for _ in 0..dir_count {
-        print!("      GEN FLUSH_ID D");
+        print!("      GEN FLUSH_ID D  BAD");
     }
     println!();
 
@@ -122,14 +169,18 @@ pub fn dump_region(
                     } else {
                         dirty = " ".to_string();
                     }
+                    let bad = ei.bad_hm.get(&(dir_index as u32)).unwrap();
                     print!(
-                        "{:8} {:8} {} ",
-                        em.gen_number, em.flush_number, dirty
+                        "{:8} {:8} {} {:4} ",
+                        em.gen_number, em.flush_number, dirty, bad
                     );
                 } else {
                     print!("-");
                 }
             }
+            if ei.differs() {
+                print!("<-------");
+            }
         } else {
             println!("No data for {}", en);
         }
@@ -205,9 +256,17 @@ fn show_extent(
     for (index, _) in region_dir.iter().enumerate() {
         print!(" {0:^11}", format!("Tag {}", index));
     }
+    for (index, _) in region_dir.iter().enumerate() {
+        print!(" {0:^11}", format!("Sum {}", index));
+    }
     print!(" {0:^7}", "DIFF");
     println!();
 
+    let regions = region_dir
+        .iter()
+        .map(|dir| Region::open(&dir, Default::default(), false, true))
+        .collect::<Result<Vec<_>>>()?;
+
     /*
      * Compare the data from each block.
      * Print a letter representing the data for each block.
@@ -216,30 +275,17 @@ fn show_extent(
         print!("Block {:4}", block);
 
         /*
-         * Build a Vector to hold our responses, one for each
-         * region we are comparing.
-         */
-        let mut dvec: Vec<ReadResponse> = Vec::with_capacity(dir_count);
-
-        /*
-         * Read the requested block in from the extent.  Store it
-         * in the Vec based on index.
+         * Read everything for the requested block from the extent in each
+         * region, without checking it, as the point may be to look at
+         * blocks that fail their checksum.
          */
-        for (index, dir) in region_dir.iter().enumerate() {
-            let region = Region::open(&dir, Default::default(), false)?;
-
-            dvec.insert(
-                index,
-                region.single_block_region_read(ReadRequest {
-                    eid: cmp_extent as u64,
-                    offset: Block::new_with_ddef(block, &region.def()),
-                    num_blocks: 1,
-                })?,
-            );
-        }
+        let blocks = regions
+            .iter()
+            .map(|region| region.extents[cmp_extent as usize].block_info(block))
+            .collect::<Result<Vec<BlockInfo>>>()?;
 
         /*
-         * Compare all the responses to each other.
+         * Compare all the blocks to each other.
          *
          * A,B,C all represent unique values in a block. If blocks match,
          * they will share the same letter.
@@ -247,118 +293,46 @@ fn show_extent(
          * Each row is a new block and the values are unrelated to the
          * previous block.
          */
-
         let mut diff_found = false;
 
-        // first compare data
-        let mut status_letters = vec![String::new(); 3];
-
-        if dvec[0].data == dvec[1].data {
-            status_letters[0] += "A";
-            status_letters[1] += "A";
-
-            if dir_count > 2 {
-                if dvec[0].data == dvec[2].data {
-                    status_letters[2] += "A";
-                } else {
-                    status_letters[2] += "C";
-                    diff_found = true;
-                }
-            }
-        } else {
-            diff_found = true;
-            status_letters[0] += "A";
-            status_letters[1] += "B";
-
-            if dir_count > 2 {
-                if dvec[0].data == dvec[2].data {
-                    status_letters[2] += "A";
-                } else if dvec[1].data == dvec[2].data {
-                    status_letters[2] += "B";
-                } else {
-                    status_letters[2] += "C";
-                }
+        let data: Vec<&Vec<u8>> = blocks.iter().map(|b| &b.data).collect();
+        let nonces: Vec<&Option<Vec<u8>>> =
+            blocks.iter().map(|b| &b.nonce).collect();
+        let tags: Vec<&Option<Vec<u8>>> =
+            blocks.iter().map(|b| &b.tag).collect();
+
+        for (letters, diff) in [
+            status_letters(&data),
+            status_letters(&nonces),
+            status_letters(&tags),
+        ]
+        .iter()
+        {
+            for letter in letters {
+                print!(" {0:^11}", letter);
             }
+            diff_found |= diff;
         }
 
-        print!("{0:^11} {1:^11} ", status_letters[0], status_letters[1],);
-        if dir_count > 2 {
-            print!("{0:^11} ", status_letters[2]);
-        }
-
-        // then, compare nonces
-        let mut status_letters = vec![String::new(); 3];
-
-        if dvec[0].nonce == dvec[1].nonce {
-            status_letters[0] += "A";
-            status_letters[1] += "A";
-
-            if dir_count > 2 {
-                if dvec[0].nonce == dvec[2].nonce {
-                    status_letters[2] += "A";
-                } else {
-                    status_letters[2] += "C";
-                    diff_found = true;
-                }
-            }
-        } else {
-            diff_found = true;
-            status_letters[0] += "A";
-            status_letters[1] += "B";
-
-            if dir_count > 2 {
-                if dvec[0].nonce == dvec[2].nonce {
-                    status_letters[2] += "A";
-                } else if dvec[1].nonce == dvec[2].nonce {
-                    status_letters[2] += "B";
-                } else {
-                    status_letters[2] += "C";
+        /*
+         * Print the stored checksum, and flag it if the data on disk
+         * doesn't match it.
+         */
+        for b in &blocks {
+            let sum = match b.checksum {
+                Some(checksum) if b.checksum_ok() => {
+                    format!("{:08x}", checksum)
                 }
-            }
-        }
-
-        print!("{0:^11} {1:^11} ", status_letters[0], status_letters[1],);
-        if dir_count > 2 {
-            print!("{0:^11} ", status_letters[2]);
-        }
-
-        // then, compare tags
-        let mut status_letters = vec![String::new(); 3];
-
-        if dvec[0].tag == dvec[1].tag {
-            status_letters[0] += "A";
-            status_letters[1] += "A";
-
-            if dir_count > 2 {
-                if dvec[0].tag == dvec[2].tag {
-                    status_letters[2] += "A";
-                } else {
-                    status_letters[2] += "C";
+                Some(checksum) => {
                     diff_found = true;
+                    format!("{:08x}!", checksum)
                 }
-            }
-        } else {
-            diff_found = true;
-            status_letters[0] += "A";
-            status_letters[1] += "B";
-
-            if dir_count > 2 {
-                if dvec[0].tag == dvec[2].tag {
-                    status_letters[2] += "A";
-                } else if dvec[1].tag == dvec[2].tag {
-                    status_letters[2] += "B";
-                } else {
-                    status_letters[2] += "C";
-                }
-            }
-        }
-
-        print!("{0:^11} {1:^11} ", status_letters[0], status_letters[1],);
-        if dir_count > 2 {
-            print!("{0:^11} ", status_letters[2]);
+                None => "-".to_string(),
+            };
+            print!(" {0:^11}", sum);
         }
 
-        print!("{0:^7}", if diff_found { "<-------" } else { "" });
+        print!(" {0:^7}", if diff_found { "<-------" } else { "" });
 
         println!();
     }
@@ -0,0 +1,172 @@
+// Copyright 2021 Oxide Computer Company
+use super::*;
+
+use dropshot::endpoint;
+use dropshot::ApiDescription;
+use dropshot::ConfigDropshot;
+use dropshot::ConfigLogging;
+use dropshot::ConfigLoggingLevel;
+use dropshot::HttpError;
+use dropshot::HttpResponseOk;
+use dropshot::HttpResponseUpdatedNoContent;
+use dropshot::HttpServerStarter;
+use dropshot::RequestContext;
+use dropshot::TypedBody;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+
+/*
+ * Ways this downstairs can be told to misbehave, for testing how the
+ * upstairs copes.  Percentages are the chance that any one job is hit.
+ */
+#[derive(
+    Debug, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema,
+)]
+pub struct Faults {
+    /*
+     * Hold back the ack for a job by up to delay_max_ms.
+     */
+    pub delay_pct: u8,
+    pub delay_max_ms: u64,
+    /*
+     * Answer a read, write, or flush with an error instead of doing it.
+     */
+    pub error_pct: u8,
+    /*
+     * Drop the connection to the upstairs instead of sending an ack.
+     */
+    pub disconnect_pct: u8,
+    /*
+     * Ack flushes without making anything durable.
+     */
+    pub skip_flush: bool,
+}
+
+impl Faults {
+    pub fn validate(&self) -> Result<()> {
+        for (name, pct) in [
+            ("delay", self.delay_pct),
+            ("error", self.error_pct),
+            ("disconnect", self.disconnect_pct),
+        ]
+        .iter()
+        {
+            if *pct > 100 {
+                bail!("{} percent must be 0 to 100, not {}", name, pct);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn roll(pct: u8) -> bool {
+        pct > 0 && thread_rng().gen_range(0..100) < pct
+    }
+
+    pub fn error(&self) -> bool {
+        Faults::roll(self.error_pct)
+    }
+
+    pub fn disconnect(&self) -> bool {
+        Faults::roll(self.disconnect_pct)
+    }
+
+    pub fn delay(&self) -> Option<Duration> {
+        if self.delay_max_ms > 0 && Faults::roll(self.delay_pct) {
+            let ms = thread_rng().gen_range(0..=self.delay_max_ms);
+            Some(Duration::from_millis(ms))
+        } else {
+            None
+        }
+    }
+}
+
+/*
+ * What the control server endpoints get.  The faults and stats are
+ * shared with the Downstairs, so reading or changing them doesn't wait
+ * behind jobs holding the Downstairs lock.
+ */
+pub(crate) struct ControlContext {
+    pub ds: Arc<Mutex<Downstairs>>,
+    pub faults: Arc<std::sync::Mutex<Faults>>,
+    pub stats: Arc<DsStats>,
+    pub region: Arc<Region>,
+}
+
+/*
+ * A small HTTP server to look at and change the faults while the
+ * downstairs runs, and to get metrics from.
+ */
+pub async fn start(ds: Arc<Mutex<Downstairs>>, addr: SocketAddr) -> Result<()> {
+    let context = {
+        let d = ds.lock().await;
+        ControlContext {
+            faults: d.faults.clone(),
+            stats: d.stats.clone(),
+            region: d.region.clone(),
+            ds: ds.clone(),
+        }
+    };
+
+    let config_dropshot = ConfigDropshot {
+        bind_address: addr,
+        request_body_max_bytes: 1024,
+        ..Default::default()
+    };
+
+    let log = ConfigLogging::StderrTerminal {
+        level: ConfigLoggingLevel::Info,
+    }
+    .to_logger("downstairs-control")
+    .map_err(|e| anyhow::anyhow!("failed to create logger: {}", e))?;
+
+    let mut api = ApiDescription::new();
+    api.register(faults_get).unwrap();
+    api.register(faults_set).unwrap();
+    api.register(stats::metrics).unwrap();
+
+    let server = HttpServerStarter::new(&config_dropshot, api, context, &log)
+        .map_err(|e| anyhow::anyhow!("failed to create control server: {}", e))?
+        .start();
+    println!("Control server listening on {}", addr);
+
+    server
+        .await
+        .map_err(|e| anyhow::anyhow!("control server failed: {}", e))
+}
+
+#[endpoint {
+    method = GET,
+    path = "/faults",
+}]
+async fn faults_get(
+    rqctx: Arc<RequestContext<ControlContext>>,
+) -> Result<HttpResponseOk<Faults>, HttpError> {
+    let faults = rqctx.context().faults.lock().unwrap().clone();
+
+    Ok(HttpResponseOk(faults))
+}
+
+/*
+ * Replace the faults.  Jobs already running keep the faults they
+ * started with.
+ */
+#[endpoint {
+    method = PUT,
+    path = "/faults",
+}]
+async fn faults_set(
+    rqctx: Arc<RequestContext<ControlContext>>,
+    body: TypedBody<Faults>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    let new_faults = body.into_inner();
+    new_faults
+        .validate()
+        .map_err(|e| HttpError::for_bad_request(None, e.to_string()))?;
+
+    println!("Faults now {:?}", new_faults);
+    *rqctx.context().faults.lock().unwrap() = new_faults;
+
+    Ok(HttpResponseUpdatedNoContent())
+}
@@ -0,0 +1,147 @@
+// Copyright 2021 Oxide Computer Company
+use super::*;
+use crate::backend::{IoBackend, IoEngine};
+use crucible_common::RegionOptions;
+
+fn mib_per_sec(bytes: u64, elapsed: Duration) -> f64 {
+    bytes as f64 / (1024.0 * 1024.0) / elapsed.as_secs_f64()
+}
+
+/*
+ * For each backend, time writing the whole region in `data` followed by
+ * a flush, then reading the whole region back, in IOs of `io_blocks`
+ * blocks (or an extent, if that is smaller).  Then time reading the
+ * extent files directly, into buffers that are filled first and into
+ * uninitialized ones.  The region is created if
+ * it isn't there, and is left behind so a later run can reuse it.
+ */
+pub fn io_bench(
+    data: &Path,
+    backends: &[IoBackend],
+    block_size: u64,
+    extent_size: u64,
+    extent_count: u32,
+    io_blocks: u64,
+) -> Result<()> {
+    if io_blocks == 0 {
+        bail!("IO size must be at least one block");
+    }
+
+    if !region::config_path(data).exists() {
+        let mut region_options: RegionOptions = Default::default();
+        region_options.set_block_size(block_size);
+        region_options.set_extent_size(Block::new(
+            extent_size,
+            block_size.trailing_zeros(),
+        ));
+        region_options.set_uuid(Uuid::new_v4());
+
+        let mut region = Region::create(data, region_options)?;
+        region.extend(extent_count)?;
+    }
+
+    for (pass, backend) in backends.iter().enumerate() {
+        let region = Region::open_with_backend(
+            data,
+            Default::default(),
+            false,
+            false,
+            *backend,
+        )?;
+        let def = region.def();
+        let shift = def.block_size().trailing_zeros();
+        let blocks = def.extent_size().value;
+        let total = def.total_size();
+
+        let io_len =
+            (std::cmp::min(io_blocks, blocks) * def.block_size()) as usize;
+        let mut data_buf = BytesMut::with_capacity(io_len);
+        data_buf.resize(io_len, 0);
+        thread_rng().fill_bytes(&mut data_buf);
+        let data_buf = data_buf.freeze();
+
+        /*
+         * Every IO in the region, as (extent, first block, block count).
+         */
+        let mut ios = Vec::new();
+        for eid in 0..def.extent_count() as u64 {
+            let mut offset = 0;
+            while offset < blocks {
+                let count = std::cmp::min(io_blocks, blocks - offset);
+                ios.push((eid, offset, count));
+                offset += count;
+            }
+        }
+
+        let start = std::time::Instant::now();
+        for (eid, offset, count) in ios.iter() {
+            let len = (count * def.block_size()) as usize;
+            region.region_write(&[crucible_protocol::Write {
+                eid: *eid,
+                offset: Block::new(*offset, shift),
+                data: data_buf.slice(..len),
+                nonce: None,
+                tag: None,
+            }])?;
+        }
+        region.region_flush(pass as u64 + 1, 1)?;
+        let write_time = start.elapsed();
+
+        let start = std::time::Instant::now();
+        for (eid, offset, count) in ios.iter() {
+            region.region_read(&[ReadRequest {
+                eid: *eid,
+                offset: Block::new(*offset, shift),
+                num_blocks: *count,
+            }])?;
+        }
+        let read_time = start.elapsed();
+
+        println!(
+            "{:>8}  write {:8.1} MiB/s  read {:8.1} MiB/s",
+            backend.to_string(),
+            mib_per_sec(total, write_time),
+            mib_per_sec(total, read_time),
+        );
+
+        /*
+         * Reads used to fill their buffer before reading into it, and now
+         * read into uninitialized memory.  Read the extent files both
+         * ways, to see what the fill cost.
+         */
+        let engine = IoEngine::new(*backend);
+        let files = (0..def.extent_count())
+            .map(|eid| {
+                Ok(engine.file(File::open(region::extent_path(data, eid))?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let bs = def.block_size();
+
+        let start = std::time::Instant::now();
+        for (eid, offset, count) in ios.iter() {
+            let len = (count * bs) as usize;
+            let mut buf = BytesMut::with_capacity(len);
+            buf.resize(len, 1);
+            files[*eid as usize].read_exact_at(&mut buf, offset * bs)?;
+        }
+        let filled_time = start.elapsed();
+
+        let start = std::time::Instant::now();
+        for (eid, offset, count) in ios.iter() {
+            let len = (count * bs) as usize;
+            let mut buf = BytesMut::with_capacity(len);
+            files[*eid as usize].read_append_at(&mut buf, len, offset * bs)?;
+        }
+        let uninit_time = start.elapsed();
+
+        println!(
+            "{:>8}  extent files: filled buffer {:8.1} MiB/s  \
+            uninitialized {:8.1} MiB/s",
+            "",
+            mib_per_sec(total, filled_time),
+            mib_per_sec(total, uninit_time),
+        );
+    }
+
+    Ok(())
+}
@@ -15,6 +15,7 @@ use crucible_protocol::*;
 
 use anyhow::{bail, Result};
 use bytes::BytesMut;
+use futures::stream::FuturesUnordered;
 use futures::{SinkExt, StreamExt};
 use rand::prelude::*;
 use structopt::StructOpt;
@@ -22,16 +23,36 @@ use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::task::JoinHandle;
 use tokio::time::{sleep_until, Instant};
 use tokio_util::codec::{FramedRead, FramedWrite};
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use uuid::Uuid;
 
+mod backend;
+mod clone;
 mod dump;
+mod faults;
+mod iobench;
+mod qos;
 mod region;
+mod snapshot;
+mod stats;
+use backend::IoBackend;
+use clone::clone_region;
 use dump::dump_region;
+use faults::{ControlContext, Faults};
+use iobench::io_bench;
+use qos::{ConnectionQos, JobCost, QosLimits};
 use region::Region;
+use snapshot::{SnapshotKind, SnapshotProvider};
+use stats::DsStats;
+
+/*
+ * How long the scrub waits between extents.
+ */
+const SCRUB_PAUSE_MS: u64 = 10;
 
 #[derive(Debug, StructOpt)]
 #[structopt(about = "disk-side storage component")]
@@ -56,11 +77,31 @@ enum Args {
         #[structopt(short, long, name = "UUID", parse(try_from_str))]
         uuid: Uuid,
     },
+    /*
+     * Create a new region that is a copy of the one served by another
+     * downstairs, for replacing a failed one.  The new region has its
+     * own UUID.
+     */
+    Clone {
+        #[structopt(short, long, parse(from_os_str), name = "DIRECTORY")]
+        data: PathBuf,
+
+        /*
+         * The downstairs to copy the region from.
+         */
+        #[structopt(short, long)]
+        source: SocketAddrV4,
+
+        #[structopt(short, long, name = "UUID", parse(try_from_str))]
+        uuid: Uuid,
+    },
     /*
      * Dump region information.
-     * Multiple directories can be passed (up to 3)
+     * Multiple directories can be passed (up to 3), and extents where
+     * they differ, or that have blocks failing their checksums, are
+     * pointed out.
      * With -e, you can dump just a single extent which will include
-     * a block by block comparison.
+     * a block by block comparison and each block's stored checksum.
      */
     Dump {
         /*
@@ -91,12 +132,64 @@ enum Args {
         #[structopt(short, long, default_value = "0", name = "SKIP")]
         skip: u64,
     },
-    Run {
-        #[structopt(short, long, default_value = "0.0.0.0")]
-        address: Ipv4Addr,
-
+    /*
+     * Compare the IO backends by timing reads and writes of a whole
+     * region.  The region is created in DIRECTORY if it isn't there.
+     */
+    IoBench {
         #[structopt(short, long, parse(from_os_str), name = "DIRECTORY")]
         data: PathBuf,
+
+        /*
+         * Backends to time, in order: sync, pool, or pool:<threads>
+         */
+        #[structopt(long, default_value = "sync,pool", use_delimiter = true)]
+        backend: Vec<IoBackend>,
+
+        #[structopt(long, default_value = "512")]
+        block_size: u64,
+
+        #[structopt(long, default_value = "16384")]
+        extent_size: u64,
+
+        #[structopt(long, default_value = "16")]
+        extent_count: u32,
+
+        /*
+         * Blocks in each read and write.
+         */
+        #[structopt(long, default_value = "2048")]
+        io_blocks: u64,
+    },
+    Run {
+        /*
+         * Address, port, and region directory can also come from the
+         * [downstairs] table of a crucible-opts config file, and the
+         * CRUCIBLE_DS_* environment variables that override it.  Those
+         * given here win.  By default, listen on 0.0.0.0:9000.
+         */
+        #[structopt(long, parse(from_os_str))]
+        config: Option<PathBuf>,
+
+        #[structopt(short, long)]
+        address: Option<Ipv4Addr>,
+
+        #[structopt(
+            short,
+            long,
+            parse(from_os_str),
+            name = "DIRECTORY",
+            required_unless = "config"
+        )]
+        data: Option<PathBuf>,
+
+        /*
+         * How IO to the extent files is done: sync, pool, or
+         * pool:<threads>.  See IoBackend.
+         */
+        #[structopt(long, default_value = "sync")]
+        io_backend: IoBackend,
+
         /*
          * Test option, makes the search for new work sleep and sometimes
          * skip doing work.  XXX Note that the flow control between upstairs
@@ -106,17 +199,131 @@ enum Args {
         #[structopt(long)]
         lossy: bool,
 
-        #[structopt(short, long, default_value = "9000")]
-        port: u16,
+        /*
+         * Let a flush that is ready to run also answer for the flushes
+         * queued right behind it, with a single pass over the region.
+         */
+        #[structopt(long)]
+        coalesce_flushes: bool,
+
+        #[structopt(short, long)]
+        port: Option<u16>,
+
+        /*
+         * Open the region without write access, for serving a snapshot.
+         * Any number of upstairs can read from it at once, and writes and
+         * flushes are refused.
+         */
+        #[structopt(long)]
+        read_only: bool,
+
+        /*
+         * How to take a snapshot when an upstairs asks for one with a
+         * flush: zfs, copy, none, or auto to use zfs if the region
+         * directory is a ZFS dataset and copy if not.
+         */
+        #[structopt(long, default_value = "auto")]
+        snapshot_provider: SnapshotKind,
 
+        /*
+         * Answer a quarter of reads, writes, and flushes with an error.
+         * The same as --error-pct 25.
+         */
         #[structopt(long)]
         return_errors: bool,
 
+        /*
+         * Fault injection, for testing the upstairs.  See Faults.  These
+         * can be changed while running through the control server.
+         */
+        #[structopt(long, default_value = "0")]
+        delay_pct: u8,
+
+        #[structopt(long, default_value = "0")]
+        delay_max_ms: u64,
+
+        #[structopt(long, default_value = "0")]
+        error_pct: u8,
+
+        #[structopt(long, default_value = "0")]
+        disconnect_pct: u8,
+
+        #[structopt(long)]
+        skip_flush: bool,
+
+        /*
+         * Address for the control server, which can change the faults
+         * above while the downstairs runs, and serves Prometheus metrics
+         * at /metrics.
+         */
+        #[structopt(long)]
+        control: Option<std::net::SocketAddr>,
+
+        /*
+         * Limits on each upstairs connection: jobs started per second,
+         * bytes read and written per second, and extent repair jobs
+         * running at once.  Guest IO goes ahead of repair IO.
+         */
+        #[structopt(long)]
+        iops_limit: Option<u64>,
+
+        #[structopt(long)]
+        bandwidth_limit: Option<u64>,
+
+        #[structopt(long)]
+        max_repair_jobs: Option<usize>,
+
+        /*
+         * Seconds between passes of the background scrub, which checks
+         * every block against its checksum.  No scrub if not given.
+         */
+        #[structopt(long)]
+        scrub_interval: Option<u64>,
+
         #[structopt(short, long)]
         trace_endpoint: Option<String>,
     },
 }
 
+/*
+ * Walk every extent, checking each block against its checksum, then wait
+ * for the interval and do it again.  Extents with bad blocks are marked
+ * as needing repair by the region.  This pauses between extents, so real
+ * IO doesn't wait long on the scrub for an extent's lock.
+ */
+async fn scrub_task(region: Arc<Region>, interval: Duration) {
+    loop {
+        let extent_count = region.def().extent_count() as usize;
+        let mut needs_repair = 0;
+
+        for eid in 0..extent_count {
+            let r = region.clone();
+            match tokio::task::spawn_blocking(move || r.scrub_extent(eid)).await
+            {
+                Ok(Ok(bad)) => {
+                    if !bad.is_empty() {
+                        needs_repair += 1;
+                    }
+                }
+                Ok(Err(e)) => {
+                    println!("scrub of extent {} failed: {:?}", eid, e);
+                }
+                Err(e) => {
+                    println!("scrub of extent {} panicked: {:?}", eid, e);
+                }
+            }
+
+            tokio::time::sleep(Duration::from_millis(SCRUB_PAUSE_MS)).await;
+        }
+
+        println!(
+            "Scrubbed {} extents, {} need repair",
+            extent_count, needs_repair
+        );
+        tokio::time::sleep(interval).await;
+    }
+}
+
 fn deadline_secs(secs: u64) -> Instant {
     Instant::now()
         .checked_add(Duration::from_secs(secs))
@@ -334,6 +541,9 @@ fn downstairs_import<P: AsRef<Path> + std::fmt::Debug>(
  */
 async fn _show_work(ds: &Downstairs) {
     println!("Active Upstairs UUID: {:?}", ds.active_upstairs());
+    for (uuid, c) in ds.connections.iter() {
+        println!("  {:?} connection {} {:?}", uuid, c.id, c.role);
+    }
     let work = ds.work.lock().await;
 
     let mut kvec: Vec<u64> = work.active.keys().cloned().collect::<Vec<u64>>();
@@ -366,10 +576,27 @@ async fn _show_work(ds: &Downstairs) {
                     dependencies,
                     flush_number: _flush_number,
                     gen_number: _gen_number,
+                    snapshot_details: _,
+                    extent_limit: _,
                 } => {
                     dsw_type = "Flush".to_string();
                     dep_list = dependencies.to_vec();
                 }
+                IOop::ExtentRepairRead {
+                    dependencies,
+                    eid: _,
+                } => {
+                    dsw_type = "RepRd".to_string();
+                    dep_list = dependencies.to_vec();
+                }
+                IOop::ExtentRepairWrite {
+                    dependencies,
+                    eid: _,
+                    extent: _,
+                } => {
+                    dsw_type = "RepWr".to_string();
+                    dep_list = dependencies.to_vec();
+                }
             };
             println!(
                 "DSW:[{:04}] {} {:?} deps:{:?}",
@@ -390,17 +617,39 @@ async fn _show_work(ds: &Downstairs) {
  */
 async fn proc_frame(
     upstairs_uuid: Uuid,
+    read_only: bool,
     ad: &mut Arc<Mutex<Downstairs>>,
     m: &Message,
     fw: &mut Arc<Mutex<FramedWrite<OwnedWriteHalf, CrucibleEncoder>>>,
     job_channel_tx: &Arc<Mutex<Sender<u64>>>,
 ) -> Result<()> {
+    if read_only {
+        return proc_read_only_frame(upstairs_uuid, ad, m, fw).await;
+    }
+
+    /*
+     * Only the active upstairs can put work on the queue.  One that was
+     * just taken over can have IO on the way before it hears about it,
+     * so answer that IO with an error rather than drop the connection.
+     */
+    if !ad.lock().await.is_active(upstairs_uuid) {
+        if let Some(reply) = refuse(m, CrucibleError::UpstairsInactive) {
+            let mut fw = fw.lock().await;
+            fw.send(reply).await?;
+            return Ok(());
+        }
+    }
+
     let mut new_ds_id = None;
     match m {
         Message::Ruok => {
             let mut fw = fw.lock().await;
             fw.send(Message::Imok).await?;
         }
+        Message::RuokSeq(seq, ts) => {
+            let mut fw = fw.lock().await;
+            fw.send(Message::ImokSeq(*seq, *ts)).await?;
+        }
         // Regular work path
         Message::Write(uuid, ds_id, dependencies, writes) => {
             if upstairs_uuid != *uuid {
@@ -418,7 +667,15 @@ async fn proc_frame(
             d.add_work(*uuid, *ds_id, new_write).await?;
             new_ds_id = Some(*ds_id);
         }
-        Message::Flush(uuid, ds_id, dependencies, flush_number, gen_number) => {
+        Message::Flush(
+            uuid,
+            ds_id,
+            dependencies,
+            flush_number,
+            gen_number,
+            snapshot_details,
+            extent_limit,
+        ) => {
             if upstairs_uuid != *uuid {
                 let mut fw = fw.lock().await;
                 fw.send(Message::UuidMismatch(upstairs_uuid)).await?;
@@ -429,6 +686,8 @@ async fn proc_frame(
                 dependencies: dependencies.to_vec(),
                 flush_number: *flush_number,
                 gen_number: *gen_number,
+                snapshot_details: snapshot_details.clone(),
+                extent_limit: *extent_limit,
             };
 
             let d = ad.lock().await;
@@ -451,6 +710,39 @@ async fn proc_frame(
             d.add_work(*uuid, *ds_id, new_read).await?;
             new_ds_id = Some(*ds_id);
         }
+        Message::ExtentRepairRead(uuid, ds_id, dependencies, eid) => {
+            if upstairs_uuid != *uuid {
+                let mut fw = fw.lock().await;
+                fw.send(Message::UuidMismatch(upstairs_uuid)).await?;
+                return Ok(());
+            }
+
+            let new_repair = IOop::ExtentRepairRead {
+                dependencies: dependencies.to_vec(),
+                eid: *eid,
+            };
+
+            let d = ad.lock().await;
+            d.add_work(*uuid, *ds_id, new_repair).await?;
+            new_ds_id = Some(*ds_id);
+        }
+        Message::ExtentRepairWrite(uuid, ds_id, dependencies, extent) => {
+            if upstairs_uuid != *uuid {
+                let mut fw = fw.lock().await;
+                fw.send(Message::UuidMismatch(upstairs_uuid)).await?;
+                return Ok(());
+            }
+
+            let new_repair = IOop::ExtentRepairWrite {
+                dependencies: dependencies.to_vec(),
+                eid: extent.eid,
+                extent: Some(extent.clone()),
+            };
+
+            let d = ad.lock().await;
+            d.add_work(*uuid, *ds_id, new_repair).await?;
+            new_ds_id = Some(*ds_id);
+        }
         x => bail!("unexpected frame {:?}", x),
     }
 
@@ -464,80 +756,301 @@ async fn proc_frame(
     Ok(())
 }
 
-async fn do_work_task(
-    ads: &mut Arc<Mutex<Downstairs>>,
-    mut job_channel_rx: Receiver<u64>,
+/*
+ * A read only upstairs never becomes the active upstairs, so it has no
+ * place on the work queue.  It only ever sends reads, which don't depend
+ * on anything it could have changed, so those are done right away.
+ * Anything that would change the region is refused.
+ *
+ * This is also how every upstairs of a read only downstairs is served,
+ * so any number of them can read from it at once.
+ */
+async fn proc_read_only_frame(
+    upstairs_uuid: Uuid,
+    ad: &mut Arc<Mutex<Downstairs>>,
+    m: &Message,
     fw: &mut Arc<Mutex<FramedWrite<OwnedWriteHalf, CrucibleEncoder>>>,
 ) -> Result<()> {
-    /*
-     * job_channel_rx is a notification that we should look for new work.
-     */
-    while job_channel_rx.recv().await.is_some() {
-        // Add a little time to completion for this operation.
-        if ads.lock().await.lossy && random() && random() {
-            tokio::time::sleep(Duration::from_secs(1)).await;
-        }
-
-        let upstairs_uuid = {
-            if let Some(upstairs_uuid) = ads.lock().await.active_upstairs() {
-                upstairs_uuid
+    let reply = match m {
+        Message::Ruok => Message::Imok,
+        Message::RuokSeq(seq, ts) => Message::ImokSeq(*seq, *ts),
+        Message::ReadRequest(uuid, ds_id, _dependencies, requests) => {
+            if upstairs_uuid != *uuid {
+                Message::UuidMismatch(upstairs_uuid)
             } else {
-                // We are not an active downstairs, wait until we are
-                continue;
+                /*
+                 * Don't hold the Downstairs lock or this task while the
+                 * read is done.
+                 */
+                let region = ad.lock().await.region.clone();
+                let requests = requests.clone();
+                let responses = tokio::task::spawn_blocking(move || {
+                    region.region_read(&requests)
+                })
+                .await?;
+                Message::ReadResponse(*uuid, *ds_id, responses)
             }
-        };
-
+        }
         /*
-         * Build ourselves a list of all the jobs on the work hashmap that
-         * are New or DepWait.
+         * Reading a whole extent is how a new downstairs clones this
+         * region, see clone_region.
          */
-        let mut new_work = {
-            if let Ok(new_work) = ads.lock().await.new_work(upstairs_uuid).await
-            {
-                new_work
+        Message::ExtentRepairRead(uuid, ds_id, _dependencies, eid) => {
+            if upstairs_uuid != *uuid {
+                Message::UuidMismatch(upstairs_uuid)
             } else {
-                // This means we couldn't unblock jobs for this UUID
-                continue;
+                let region = ad.lock().await.region.clone();
+                let eid = *eid;
+                let extent = tokio::task::spawn_blocking(move || {
+                    region.extent_repair_read(eid)
+                })
+                .await?;
+                Message::ExtentRepairData(*uuid, *ds_id, extent)
             }
-        };
+        }
+        x => match refuse(x, CrucibleError::ModifyingReadOnlyRegion) {
+            Some(reply) => reply,
+            None => bail!("unexpected frame from read only upstairs {:?}", x),
+        },
+    };
 
-        /*
-         * We don't have to do jobs in order, but the dependencies are, at
-         * least for now, always going to be in order of job id.  So,
-         * to best move things forward it is going to be fewer laps
-         * through the list if we take the lowest job id first.
-         */
-        new_work.sort_unstable();
+    let mut fw = fw.lock().await;
+    fw.send(reply).await?;
 
-        for new_id in new_work.iter() {
-            if ads.lock().await.lossy && random() && random() {
-                // Skip a job that needs to be done. Sometimes
-                continue;
-            }
+    Ok(())
+}
+
+/*
+ * The answer to a work message that won't be done, because of e.
+ */
+fn refuse(m: &Message, e: CrucibleError) -> Option<Message> {
+    match m {
+        Message::Write(uuid, ds_id, _, _) => {
+            Some(Message::WriteAck(*uuid, *ds_id, Err(e)))
+        }
+        Message::Flush(uuid, ds_id, _, _, _, _, _) => {
+            Some(Message::FlushAck(*uuid, *ds_id, Err(e)))
+        }
+        Message::ReadRequest(uuid, ds_id, _, _) => {
+            Some(Message::ReadResponse(*uuid, *ds_id, Err(e)))
+        }
+        Message::ExtentRepairRead(uuid, ds_id, _, _) => {
+            Some(Message::ExtentRepairData(*uuid, *ds_id, Err(e)))
+        }
+        Message::ExtentRepairWrite(uuid, ds_id, _, _) => {
+            Some(Message::ExtentRepairAck(*uuid, *ds_id, Err(e)))
+        }
+        _ => None,
+    }
+}
+
+async fn do_work_task(
+    ads: &mut Arc<Mutex<Downstairs>>,
+    mut job_channel_rx: Receiver<u64>,
+    fw: &mut Arc<Mutex<FramedWrite<OwnedWriteHalf, CrucibleEncoder>>>,
+) -> Result<()> {
+    let mut running: FuturesUnordered<JoinHandle<Vec<(u64, Uuid, Message)>>> =
+        FuturesUnordered::new();
+    let limits = ads.lock().await.qos;
+    let mut qos = ConnectionQos::new(limits, Instant::now());
+    /*
+     * When the limits will let us start the next job, if they are what
+     * is holding it back.
+     */
+    let mut retry: Option<Instant> = None;
 
+    loop {
+        tokio::select! {
             /*
-             * If this job is still new, take it and go to work. The
-             * in_progress method will only return a job if all
-             * dependencies are met.
+             * job_channel_rx is a notification that we should look for
+             * new work.
              */
-            let job_id = ads.lock().await.in_progress(*new_id).await;
-            if let Some(job_id) = job_id {
-                let m = ads.lock().await.do_work(job_id).await?;
+            kick = job_channel_rx.recv() => {
+                if kick.is_none() {
+                    // None means the channel is closed
+                    return Ok(());
+                }
+            }
+            _ = sleep_until(retry.unwrap_or_else(Instant::now)),
+                if retry.is_some() => {}
+            done = running.next(), if !running.is_empty() => {
+                let faults = ads.lock().await.faults.lock().unwrap().clone();
+                if faults.disconnect() {
+                    bail!("fault injection: dropping the upstairs connection");
+                }
+
+                for (job_id, upstairs_uuid, m) in done.unwrap()? {
+                    qos.finish(&m);
 
-                if let Some(m) = m {
                     // Notify the upstairs before completing work
                     let mut fw = fw.lock().await;
                     fw.send(&m).await?;
                     drop(fw);
 
-                    ads.lock().await.complete_work(job_id, m).await?;
+                    ads.lock()
+                        .await
+                        .complete_work(job_id, upstairs_uuid, m)
+                        .await?;
                 }
             }
         }
+
+        /*
+         * Either there is new work, or a job finished and something that
+         * was waiting on it may be able to go.
+         */
+        retry = start_ready_jobs(ads, &mut running, &mut qos).await;
+    }
+}
+
+/*
+ * Start every job whose dependencies are all met, as far as the limits
+ * for this connection allow.  Each one runs on the blocking pool, so jobs
+ * that don't depend on each other (IO to different extents, say) run at
+ * the same time, and are acked in the order they finish rather than the
+ * order they arrived.
+ *
+ * If the rate limits stop us, return when to try again.
+ */
+async fn start_ready_jobs(
+    ads: &mut Arc<Mutex<Downstairs>>,
+    running: &mut FuturesUnordered<JoinHandle<Vec<(u64, Uuid, Message)>>>,
+    qos: &mut ConnectionQos,
+) -> Option<Instant> {
+    // Add a little time to completion for this operation.
+    if ads.lock().await.lossy && random() && random() {
+        tokio::time::sleep(Duration::from_secs(1)).await;
     }
 
-    // None means the channel is closed
-    Ok(())
+    let upstairs_uuid = {
+        if let Some(upstairs_uuid) = ads.lock().await.active_upstairs() {
+            upstairs_uuid
+        } else {
+            // We are not an active downstairs, wait until we are
+            return None;
+        }
+    };
+
+    /*
+     * Build ourselves a list of all the jobs on the work hashmap that
+     * are New or DepWait.
+     */
+    let new_work = {
+        if let Ok(new_work) = ads.lock().await.new_work(upstairs_uuid).await {
+            new_work
+        } else {
+            // This means we couldn't unblock jobs for this UUID
+            return None;
+        }
+    };
+
+    /*
+     * The dependencies are, at least for now, always going to be in order
+     * of job id.  So, to best move things forward it is going to be fewer
+     * laps through the list if we take the lowest job id first.  Guest IO
+     * comes before any repair IO, so it gets what the limits allow first.
+     */
+    let mut new_work = ads.lock().await.job_costs(&new_work).await;
+    new_work.sort_unstable_by_key(|(id, cost)| (cost.repair, *id));
+
+    for (new_id, cost) in new_work.iter() {
+        let ds = ads.lock().await;
+        if ds.lossy && random() && random() {
+            // Skip a job that needs to be done. Sometimes
+            continue;
+        }
+
+        if cost.repair && !qos.repair_allowed() {
+            continue;
+        }
+        if let Some(at) = qos.ready_at(Instant::now()) {
+            return Some(at);
+        }
+
+        /*
+         * If this job is still new, take it and go to work. The
+         * in_progress method will only return a job if all
+         * dependencies are met.
+         */
+        if let Some(job) = ds.in_progress(*new_id).await {
+            qos.start(*cost);
+
+            let jobs = if ds.coalesce_flushes
+                && matches!(
+                    job.work,
+                    IOop::Flush {
+                        snapshot_details: None,
+                        extent_limit: None,
+                        ..
+                    }
+                ) {
+                ds.coalesce_flushes(job).await
+            } else {
+                vec![job]
+            };
+            let region = ds.region.clone();
+            /*
+             * Hold this until the IO is done, so a new upstairs can't
+             * take over while IO from the old one is still landing.
+             * Anything that changes it holds the Downstairs lock, which
+             * we have, so this doesn't wait.
+             */
+            let active = ds.active_io.clone().read_owned().await;
+            let faults = ds.faults.lock().unwrap().clone();
+            let snapshots = ds.snapshots.clone();
+            let stats = ds.stats.clone();
+
+            running.push(tokio::task::spawn_blocking(move || {
+                let last = jobs.last().unwrap();
+                let is_active = *active == Some(last.upstairs_uuid);
+                let start = std::time::Instant::now();
+                let m = execute(
+                    &region,
+                    last,
+                    &faults,
+                    snapshots.as_deref(),
+                    is_active,
+                );
+                drop(active);
+                stats.record(&last.work, &m, start.elapsed());
+
+                if let Some(delay) = faults.delay() {
+                    std::thread::sleep(delay);
+                }
+
+                group_acks(&jobs, m)
+            }));
+        }
+    }
+
+    None
+}
+
+/*
+ * Every job in a group of coalesced flushes gets the result of the one
+ * pass that was done for the last of them.  They are returned in job
+ * order, which is the order they must be acked in.
+ */
+fn group_acks(
+    jobs: &[DownstairsWork],
+    m: Message,
+) -> Vec<(u64, Uuid, Message)> {
+    let mut acks = Vec::with_capacity(jobs.len());
+
+    if let Message::FlushAck(_, _, result) = &m {
+        for job in &jobs[..jobs.len() - 1] {
+            acks.push((
+                job.ds_id,
+                job.upstairs_uuid,
+                Message::FlushAck(job.upstairs_uuid, job.ds_id, result.clone()),
+            ));
+        }
+    }
+
+    let last = jobs.last().unwrap();
+    acks.push((last.ds_id, last.upstairs_uuid, m));
+
+    acks
 }
 
 /*
@@ -547,6 +1060,35 @@ async fn do_work_task(
  * taking IOs from the upstairs.
  */
 async fn proc(ads: &mut Arc<Mutex<Downstairs>>, sock: TcpStream) -> Result<()> {
+    let mut connection = None;
+    let result = serve_upstairs(ads, sock, &mut connection).await;
+
+    /*
+     * However the connection ended, it no longer holds any role.
+     */
+    if let Some((upstairs_uuid, id)) = connection {
+        let mut ds = ads.lock().await;
+        println!(
+            "upstairs {:?} disconnected, {} jobs left",
+            upstairs_uuid,
+            ds.jobs().await,
+        );
+        ds.disconnect(upstairs_uuid, id).await;
+    }
+
+    result
+}
+
+/*
+ * Negotiate with an upstairs, then answer its requests.  Once it has
+ * attached, connection is set to its UUID and the ID it was
+ * registered with.
+ */
+async fn serve_upstairs(
+    ads: &mut Arc<Mutex<Downstairs>>,
+    sock: TcpStream,
+    connection: &mut Option<(Uuid, u64)>,
+) -> Result<()> {
     let (read, write) = sock.into_split();
     let mut fr = FramedRead::new(read, CrucibleDecoder::new());
     let fw =
@@ -554,10 +1096,12 @@ async fn proc(ads: &mut Arc<Mutex<Downstairs>>, sock: TcpStream) -> Result<()> {
 
     let mut negotiated = 0;
     let mut upstairs_uuid = None;
+    let mut read_only = false;
 
     let (_another_upstairs_active_tx, mut another_upstairs_active_rx) =
         channel(1);
-    let another_upstairs_active_tx = Arc::new(_another_upstairs_active_tx);
+    let mut another_upstairs_active_tx =
+        Some(Arc::new(_another_upstairs_active_tx));
 
     /*
      * See the comment in the proc() function on the upstairs side that
@@ -580,28 +1124,22 @@ async fn proc(ads: &mut Arc<Mutex<Downstairs>>, sock: TcpStream) -> Result<()> {
             }
             /*
              * This Upstairs' thread will receive this signal when another
-             * Upstairs promotes itself to active. The only way this path is
-             * reached is if this Upstairs promoted itself to active, storing
-             * another_upstairs_active_tx in the Downstairs active_upstairs
-             * tuple.
-             *
-             * The two unwraps here should be safe: this thread negotiated and
-             * activated, and then another did (in order to send this thread
-             * this signal).
+             * Upstairs promotes itself to active, or when this Upstairs
+             * has connected again and this connection is stale.  The
+             * sender is kept with this connection in the Downstairs
+             * connections map, so the signal only reaches us once we have
+             * said who we are.
              */
-            _ = another_upstairs_active_rx.recv() => {
+            Some((active_upstairs, active_gen)) =
+                another_upstairs_active_rx.recv() =>
+            {
                 let upstairs_uuid = upstairs_uuid.unwrap();
-                println!("Another upstairs promoted to active, \
-                    shutting down connection for {:?}", upstairs_uuid);
-
-                let active_upstairs = {
-                    let ds = ads.lock().await;
-                    ds.active_upstairs().unwrap()
-                };
-                let mut fw = fw.lock().await;
-                fw.send(Message::YouAreNoLongerActive(active_upstairs)).await?;
-
-                return Ok(());
+                return took_over(
+                    &fw,
+                    upstairs_uuid,
+                    active_upstairs,
+                    active_gen,
+                ).await;
             }
             new_read = fr.next() => {
                 /*
@@ -609,23 +1147,10 @@ async fn proc(ads: &mut Arc<Mutex<Downstairs>>, sock: TcpStream) -> Result<()> {
                  */
                 match new_read.transpose()? {
                     None => {
-                        let mut ds = ads.lock().await;
-
-                        if let Some(upstairs_uuid) = upstairs_uuid {
-                            println!(
-                                "upstairs {:?} disconnected, {} jobs left",
-                                upstairs_uuid, ds.jobs().await,
-                            );
-
-                            if ds.is_active(upstairs_uuid) {
-                                println!("upstairs {:?} was previously \
-                                    active, clearing", upstairs_uuid);
-                                ds.clear_active().await;
-                            }
-                        } else {
+                        if upstairs_uuid.is_none() {
                             println!(
                                 "upstairs disconnected, {} jobs left",
-                                ds.jobs().await,
+                                ads.lock().await.jobs().await,
                             );
                         }
 
@@ -635,23 +1160,57 @@ async fn proc(ads: &mut Arc<Mutex<Downstairs>>, sock: TcpStream) -> Result<()> {
                         let mut fw = fw.lock().await;
                         fw.send(Message::Imok).await?;
                     }
+                    Some(Message::RuokSeq(seq, ts)) => {
+                        let mut fw = fw.lock().await;
+                        fw.send(Message::ImokSeq(seq, ts)).await?;
+                    }
                     Some(Message::HereIAm(version, uuid)) => {
                         if negotiated != 0 {
                             bail!("Received connect out of order {}",
                                 negotiated);
                         }
-                        if version != 1 {
-                            bail!("expected version 1, got {}", version);
+                        if version != CRUCIBLE_MESSAGE_VERSION {
+                            let mut fw = fw.lock().await;
+                            fw.send(Message::VersionMismatch(
+                                CRUCIBLE_MESSAGE_VERSION
+                            )).await?;
+                            bail!(
+                                "expected version {}, got {}",
+                                CRUCIBLE_MESSAGE_VERSION,
+                                version
+                            );
                         }
                         negotiated = 1;
                         upstairs_uuid = Some(uuid);
-                        println!("upstairs {:?} connected",
-                            upstairs_uuid.unwrap());
+
                         let mut fw = fw.lock().await;
-                        fw.send(Message::YesItsMe(1)).await?;
+                        fw.send(Message::YesItsMe(CRUCIBLE_MESSAGE_VERSION))
+                            .await?;
+                    }
+                    Some(Message::Attach(ro)) => {
+                        if negotiated != 1 || connection.is_some() {
+                            bail!("Received attach out of order {}",
+                                negotiated);
+                        }
+                        /*
+                         * Every upstairs of a read only downstairs is a
+                         * reader, whatever it asked for.
+                         */
+                        let uuid = upstairs_uuid.unwrap();
+                        let mut ds = ads.lock().await;
+                        read_only = ro || ds.region.read_only();
+                        println!("upstairs {:?} connected, read_only:{}",
+                            uuid, read_only);
+                        let id = ds.connect(
+                            uuid,
+                            read_only,
+                            another_upstairs_active_tx.take().unwrap(),
+                        ).await;
+                        drop(ds);
+                        *connection = Some((uuid, id));
                     }
-                    Some(Message::PromoteToActive(uuid)) => {
-                        if negotiated != 1 {
+                    Some(Message::PromoteToActive(uuid, gen)) => {
+                        if negotiated != 1 || connection.is_none() {
                             bail!("Received activate out of order {}",
                                 negotiated);
                         }
@@ -666,12 +1225,31 @@ async fn proc(ads: &mut Arc<Mutex<Downstairs>>, sock: TcpStream) -> Result<()> {
                              * XXX
                              */
                         } else {
-                            {
+                            /*
+                             * Any number of read only upstairs can be
+                             * attached next to the one active upstairs,
+                             * so a read only upstairs doesn't take over.
+                             */
+                            if read_only {
+                                println!("{:?} attached read only", uuid);
+                            } else {
                                 let mut ds = ads.lock().await;
-                                ds.promote_to_active(
-                                    uuid,
-                                    another_upstairs_active_tx.clone()
-                                ).await;
+                                if !ds.promote_allowed(uuid, gen) {
+                                    let (active_uuid, active_gen) =
+                                        ds.generation.unwrap();
+                                    drop(ds);
+                                    println!(
+                                        "{:?} gen {} refused, {:?} has gen {}",
+                                        uuid, gen, active_uuid, active_gen
+                                    );
+                                    let mut fw = fw.lock().await;
+                                    fw.send(Message::YouAreNoLongerActive(
+                                        active_uuid,
+                                        active_gen,
+                                    )).await?;
+                                    return Ok(());
+                                }
+                                ds.promote_to_active(uuid, gen).await?;
                             }
                             negotiated = 2;
 
@@ -699,7 +1277,7 @@ async fn proc(ads: &mut Arc<Mutex<Downstairs>>, sock: TcpStream) -> Result<()> {
                                 negotiated);
                         }
                         negotiated = 4;
-                        {
+                        if !read_only {
                             let ds = ads.lock().await;
                             let mut work = ds.work_lock(
                                 upstairs_uuid.unwrap()
@@ -752,7 +1330,37 @@ async fn proc(ads: &mut Arc<Mutex<Downstairs>>, sock: TcpStream) -> Result<()> {
     assert!(upstairs_uuid.is_some());
     let u_uuid = upstairs_uuid.unwrap();
 
-    resp_loop(ads, fr, fw, another_upstairs_active_rx, u_uuid).await
+    resp_loop(ads, fr, fw, another_upstairs_active_rx, u_uuid, read_only).await
+}
+
+/*
+ * Tell an upstairs which upstairs has taken over from it.  If that is
+ * the same upstairs, it has a newer connection and this one is just
+ * closed.
+ */
+async fn took_over(
+    fw: &Arc<Mutex<FramedWrite<OwnedWriteHalf, CrucibleEncoder>>>,
+    upstairs_uuid: Uuid,
+    active_upstairs: Uuid,
+    active_gen: u64,
+) -> Result<()> {
+    if active_upstairs == upstairs_uuid {
+        println!(
+            "upstairs {:?} has a newer connection, closing this one",
+            upstairs_uuid
+        );
+        return Ok(());
+    }
+
+    println!(
+        "{:?} gen {} promoted to active, shutting down connection for {:?}",
+        active_upstairs, active_gen, upstairs_uuid
+    );
+    let mut fw = fw.lock().await;
+    fw.send(Message::YouAreNoLongerActive(active_upstairs, active_gen))
+        .await?;
+
+    Ok(())
 }
 
 /*
@@ -764,8 +1372,9 @@ async fn resp_loop(
     ads: &mut Arc<Mutex<Downstairs>>,
     mut fr: FramedRead<OwnedReadHalf, CrucibleDecoder>,
     fw: Arc<Mutex<FramedWrite<OwnedWriteHalf, CrucibleEncoder>>>,
-    mut another_upstairs_active_rx: mpsc::Receiver<u64>,
+    mut another_upstairs_active_rx: mpsc::Receiver<(Uuid, u64)>,
     upstairs_uuid: Uuid,
+    read_only: bool,
 ) -> Result<()> {
     let mut lossy_interval = deadline_secs(5);
 
@@ -803,8 +1412,15 @@ async fn resp_loop(
         let mut fwc = fw.clone();
         tokio::spawn(async move {
             while let Some(m) = message_channel_rx.recv().await {
-                if let Err(e) =
-                    proc_frame(upstairs_uuid, &mut adc, &m, &mut fwc, &tx).await
+                if let Err(e) = proc_frame(
+                    upstairs_uuid,
+                    read_only,
+                    &mut adc,
+                    &m,
+                    &mut fwc,
+                    &tx,
+                )
+                .await
                 {
                     bail!("Proc frame returns error: {}", e);
                 }
@@ -835,7 +1451,11 @@ async fn resp_loop(
                     //_show_work(&ds).await;
                     ds.lossy
                 };
-                if lossy {
+                /*
+                 * A read only upstairs has nothing on the work queue, so
+                 * don't send it looking for the active upstairs' work.
+                 */
+                if lossy && !read_only {
                     job_channel_tx.lock().await.send(0).await?;
                 }
                 lossy_interval = deadline_secs(5);
@@ -849,46 +1469,22 @@ async fn resp_loop(
                 bail!("inactivity timeout");
             }
             /*
-             * This Upstairs' thread will receive this signal when another
-             * Upstairs promotes itself to active. The only way this path is
-             * reached is if this Upstairs promoted itself to active, storing
-             * another_upstairs_active_tx in the Downstairs active_upstairs
-             * tuple.
-             *
-             * The two unwraps here should be safe: this thread negotiated and
-             * activated, and then another did (in order to send this thread
-             * this signal).
+             * Another Upstairs has promoted itself to active, or this
+             * Upstairs has connected again, see serve_upstairs.
              */
-            _ = another_upstairs_active_rx.recv() => {
-                println!("Another upstairs promoted to active, \
-                    shutting down connection for {:?}", upstairs_uuid);
-
-                let active_upstairs = {
-                    let ds = ads.lock().await;
-                    ds.active_upstairs().unwrap()
-                };
-
-                let mut fw = fw.lock().await;
-                fw.send(Message::YouAreNoLongerActive(active_upstairs)).await?;
-
-                return Ok(());
+            Some((active_upstairs, active_gen)) =
+                another_upstairs_active_rx.recv() =>
+            {
+                return took_over(
+                    &fw,
+                    upstairs_uuid,
+                    active_upstairs,
+                    active_gen,
+                ).await;
             }
             new_read = fr.next() => {
                 match new_read.transpose()? {
                     None => {
-                        let mut ds = ads.lock().await;
-
-                        println!(
-                            "upstairs {:?} disconnected, {} jobs left",
-                            upstairs_uuid, ds.jobs().await,
-                        );
-
-                        if ds.is_active(upstairs_uuid) {
-                            println!("upstairs {:?} was previously \
-                                active, clearing", upstairs_uuid);
-                            ds.clear_active().await;
-                        }
-
                         return Ok(());
                     }
                     Some(msg) => {
@@ -900,6 +1496,35 @@ async fn resp_loop(
     }
 }
 
+/*
+ * What an upstairs connected to this downstairs may do.  At most one is
+ * active, and only it can put work on the queue.  A standby upstairs can
+ * write but has not promoted itself, or has been taken over.  A read only
+ * upstairs is only ever served reads.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum UpstairsRole {
+    Active,
+    Standby,
+    ReadOnly,
+}
+
+/*
+ * An upstairs connected to this downstairs.  An upstairs that connects
+ * again replaces its old connection, and the id tells the two apart so
+ * the old one going away can't undo the new one.
+ */
+#[derive(Debug)]
+struct UpstairsConnection {
+    id: u64,
+    role: UpstairsRole,
+    /*
+     * Tells the task serving this connection the UUID and generation of
+     * the upstairs that took over from it.
+     */
+    takeover: Arc<Sender<(Uuid, u64)>>,
+}
+
 /*
  * Overall structure for things the downstairs is tracking.
  * This includes the extents and their status as well as the
@@ -907,21 +1532,71 @@ async fn resp_loop(
  */
 #[derive(Debug)]
 struct Downstairs {
-    region: Region,
+    region: Arc<Region>,
     work: Mutex<Work>,
-    lossy: bool,         // Test flag, enables pauses and skipped jobs
-    return_errors: bool, // Test flag
-    active_upstairs: Option<(Uuid, Arc<Sender<u64>>)>,
+    lossy: bool, // Test flag, enables pauses and skipped jobs
+    /*
+     * Test faults to inject, which the control server can change.
+     */
+    faults: Arc<std::sync::Mutex<Faults>>,
+    coalesce_flushes: bool,
+    /*
+     * What takes a snapshot when a flush asks for one.
+     */
+    snapshots: Option<Arc<dyn SnapshotProvider>>,
+    /*
+     * Limits applied to each upstairs connection.
+     */
+    qos: QosLimits,
+    /*
+     * Counts of the work done, for the metrics endpoint.
+     */
+    stats: Arc<DsStats>,
+    /*
+     * Every upstairs connected to us, by UUID.
+     */
+    connections: HashMap<Uuid, UpstairsConnection>,
+    next_connection_id: u64,
+    active_upstairs: Option<Uuid>,
+    /*
+     * The upstairs whose jobs may do IO to the region.  A running job
+     * holds a read lock on this for as long as its IO takes, so changing
+     * it waits for IO from the old upstairs to finish.
+     */
+    active_io: Arc<tokio::sync::RwLock<Option<Uuid>>>,
+    /*
+     * The UUID and generation number of the last upstairs promoted to
+     * active.  This is kept after that upstairs goes away, and saved
+     * with the region, so an upstairs with an older generation can't
+     * come back and take over, even after we restart.
+     */
+    generation: Option<(Uuid, u64)>,
 }
 
 impl Downstairs {
-    fn new(region: Region, lossy: bool, return_errors: bool) -> Self {
+    fn new(
+        region: Region,
+        lossy: bool,
+        faults: Faults,
+        coalesce_flushes: bool,
+        snapshots: Option<Arc<dyn SnapshotProvider>>,
+        qos: QosLimits,
+    ) -> Self {
+        let generation = region.promoted();
         Downstairs {
-            region,
+            region: Arc::new(region),
             work: Mutex::new(Work::default()),
             lossy,
-            return_errors,
+            faults: Arc::new(std::sync::Mutex::new(faults)),
+            coalesce_flushes,
+            snapshots,
+            qos,
+            stats: Arc::new(DsStats::default()),
+            connections: HashMap::new(),
+            next_connection_id: 0,
             active_upstairs: None,
+            active_io: Arc::new(tokio::sync::RwLock::new(None)),
+            generation,
         }
     }
 
@@ -948,7 +1623,7 @@ impl Downstairs {
      * Let's say `new_work` and `promote_to_active` are racing. If `new_work`
      * wins, then it will return and run those jobs in `do_work_task`.
      * However, `promote_to_active` will grab the lock and change the
-     * UUID, causing `do_work` to return UpstairsInactive for the jobs
+     * UUID, causing `execute` to return UpstairsInactive for the jobs
      * that were just returned. If `promote_to_active` wins, it will
      * clear out the jobs of the old UUID.
      *
@@ -960,8 +1635,7 @@ impl Downstairs {
         &self,
         upstairs_uuid: Uuid,
     ) -> Result<MutexGuard<'_, Work>> {
-        if let Some(active_upstairs) = &self.active_upstairs {
-            let active_uuid = active_upstairs.0;
+        if let Some(active_uuid) = self.active_upstairs {
             if active_uuid != upstairs_uuid {
                 println!(
                     "{:?} cannot grab lock, {:?} is active!",
@@ -1005,7 +1679,7 @@ impl Downstairs {
         Ok(())
     }
 
-    async fn in_progress(&self, ds_id: u64) -> Option<u64> {
+    async fn in_progress(&self, ds_id: u64) -> Option<DownstairsWork> {
         let mut work = self.work.lock().await;
         if let Some((job_id, upstairs_uuid)) = work.in_progress(ds_id) {
             if !self.is_active(upstairs_uuid) {
@@ -1015,16 +1689,45 @@ impl Downstairs {
                 panic!("Don't return a job with the wrong uuid!");
             }
 
-            Some(job_id)
+            work.active.get(&job_id).cloned()
         } else {
             None
         }
     }
 
-    /// Given a job ID, do the work for that IO.
-    async fn do_work(&self, job_id: u64) -> Result<Option<Message>> {
+    /*
+     * What each of these jobs will cost against the connection limits.
+     */
+    async fn job_costs(&self, ids: &[u64]) -> Vec<(u64, JobCost)> {
+        let block_size = self.region.def().block_size();
+        let extent_blocks = self.region.def().extent_size().value;
+        let work = self.work.lock().await;
+
+        ids.iter()
+            .filter_map(|id| {
+                work.active.get(id).map(|job| {
+                    (*id, JobCost::of(&job.work, block_size, extent_blocks))
+                })
+            })
+            .collect()
+    }
+
+    /*
+     * Given a flush that was just made InProgress, take any flushes that
+     * can run along with it, and return all of them in job order.
+     */
+    async fn coalesce_flushes(
+        &self,
+        job: DownstairsWork,
+    ) -> Vec<DownstairsWork> {
         let mut work = self.work.lock().await;
-        work.do_work(self, job_id).await
+        let mut jobs = vec![job];
+
+        for ds_id in work.coalesce_flushes(jobs[0].ds_id) {
+            jobs.push(work.active.get(&ds_id).unwrap().clone());
+        }
+
+        jobs
     }
 
     /*
@@ -1035,55 +1738,150 @@ impl Downstairs {
      * - removing the response
      * - putting the id on the completed list.
      */
-    async fn complete_work(&mut self, ds_id: u64, m: Message) -> Result<()> {
+    async fn complete_work(
+        &mut self,
+        ds_id: u64,
+        upstairs_uuid: Uuid,
+        m: Message,
+    ) -> Result<()> {
         let mut work = self.work.lock().await;
 
+        /*
+         * The job is gone if promote_to_active ran and cleared out active
+         * while it was running.  The new upstairs can reuse the same job
+         * ID, so make sure it is still ours.
+         */
+        match work.active.get(&ds_id) {
+            Some(job) if job.upstairs_uuid == upstairs_uuid => {}
+            _ => return Ok(()),
+        }
+
         // Complete the job
         let is_flush = matches!(m, Message::FlushAck(_, _, _));
+        work.complete(ds_id, is_flush);
 
-        // _ can be None if promote_to_active ran and cleared out active.
-        let _ = work.active.remove(&ds_id);
+        Ok(())
+    }
 
-        if is_flush {
-            work.last_flush = ds_id;
-            work.completed = Vec::with_capacity(32);
+    /*
+     * A higher generation always takes over.  The same generation is only
+     * allowed from the upstairs that holds it now, or from any upstairs
+     * once that one has gone away.
+     */
+    fn promote_allowed(&self, uuid: Uuid, gen: u64) -> bool {
+        match self.generation {
+            None => true,
+            Some((last_uuid, last_gen)) => {
+                gen > last_gen
+                    || (gen == last_gen
+                        && (last_uuid == uuid
+                            || self.active_upstairs.is_none()))
+            }
+        }
+    }
+
+    /*
+     * Note a new upstairs connection, and return its id.  It starts out
+     * standby (or read only), and has to promote itself to be active.
+     */
+    async fn connect(
+        &mut self,
+        uuid: Uuid,
+        read_only: bool,
+        takeover: Arc<Sender<(Uuid, u64)>>,
+    ) -> u64 {
+        let id = self.next_connection_id;
+        self.next_connection_id += 1;
+
+        /*
+         * An upstairs that connects again has given up on its old
+         * connection, though we may not have noticed it is gone yet.
+         * Close the old one, and make this one negotiate from the start.
+         */
+        if let Some(old) = self.connections.remove(&uuid) {
+            println!("{:?} connected again, closing old connection", uuid);
+            let gen = self.generation.map_or(0, |(_, gen)| gen);
+            let _ = old.takeover.try_send((uuid, gen));
+            if old.role == UpstairsRole::Active {
+                self.clear_active().await;
+            }
+        }
+
+        let role = if read_only {
+            UpstairsRole::ReadOnly
         } else {
-            work.completed.push(ds_id);
+            UpstairsRole::Standby
+        };
+        self.connections
+            .insert(uuid, UpstairsConnection { id, role, takeover });
+
+        id
+    }
+
+    /*
+     * Forget a connection that has closed, unless the upstairs has
+     * already replaced it with a newer one.
+     */
+    async fn disconnect(&mut self, uuid: Uuid, id: u64) {
+        match self.connections.get(&uuid) {
+            Some(c) if c.id == id => {}
+            _ => return,
         }
 
-        Ok(())
+        if self.connections.remove(&uuid).unwrap().role == UpstairsRole::Active
+        {
+            println!("upstairs {:?} was previously active, clearing", uuid);
+            self.clear_active().await;
+        }
+    }
+
+    fn role(&self, uuid: Uuid) -> Option<UpstairsRole> {
+        self.connections.get(&uuid).map(|c| c.role)
     }
 
-    async fn promote_to_active(&mut self, uuid: Uuid, tx: Arc<Sender<u64>>) {
+    async fn promote_to_active(&mut self, uuid: Uuid, gen: u64) -> Result<()> {
+        /*
+         * The generation is on disk before anything is done with it, so
+         * it is still there to check against if we restart.
+         */
+        let region = self.region.clone();
+        tokio::task::spawn_blocking(move || region.set_promoted(uuid, gen))
+            .await??;
+
         let mut work = self.work.lock().await;
 
-        println!("{:?} is now active", uuid);
+        println!("{:?} is now active with gen {}", uuid, gen);
 
         /*
-         * If there's an existing Upstairs connection, signal to terminate
-         * it. Do this while holding the work lock so the previously
+         * If another Upstairs is active, tell it who took over so its
+         * connection can pass that on and close.  It stays standby until
+         * then.  Do this while holding the work lock so the previously
          * active Upstairs isn't adding more work.
          */
-        if let Some(old_upstairs) = &self.active_upstairs {
-            println!("Signaling to {:?} thread", old_upstairs.0);
-            match futures::executor::block_on(old_upstairs.1.send(0)) {
-                Ok(_) => {}
-                Err(e) => {
+        if let Some(old_uuid) = self.active_upstairs.filter(|u| *u != uuid) {
+            if let Some(old) = self.connections.get_mut(&old_uuid) {
+                println!("Signaling to {:?} thread", old_uuid);
+                old.role = UpstairsRole::Standby;
+                if let Err(e) = old.takeover.try_send((uuid, gen)) {
                     /*
                      * It's possible the old thread died due to some
-                     * connection error. In that case the
-                     * receiver will have closed and
-                     * the above send will fail.
+                     * connection error. In that case the receiver will
+                     * have closed and the send will fail.
                      */
                     println!(
                         "Error while signaling to {:?} thread: {:?}",
-                        old_upstairs.0, e,
+                        old_uuid, e,
                     );
                 }
             }
         }
 
-        self.active_upstairs = Some((uuid, tx));
+        if let Some(c) = self.connections.get_mut(&uuid) {
+            c.role = UpstairsRole::Active;
+        }
+        self.active_upstairs = Some(uuid);
+        self.generation = Some((uuid, gen));
+        *self.active_io.write().await = Some(uuid);
 
         /*
          * Note: in the future, differentiate between new upstairs connecting
@@ -1113,23 +1911,27 @@ impl Downstairs {
 
         work.completed = Vec::with_capacity(32);
         work.last_flush = 0;
+
+        Ok(())
     }
 
     fn is_active(&self, uuid: Uuid) -> bool {
-        match self.active_upstairs.as_ref() {
-            None => false,
-            Some(tuple) => tuple.0 == uuid,
-        }
+        self.active_upstairs == Some(uuid)
     }
 
     fn active_upstairs(&self) -> Option<Uuid> {
-        self.active_upstairs.as_ref().map(|e| e.0)
+        self.active_upstairs
     }
 
     async fn clear_active(&mut self) {
         let mut work = self.work.lock().await;
 
-        self.active_upstairs = None;
+        if let Some(uuid) = self.active_upstairs.take() {
+            if let Some(c) = self.connections.get_mut(&uuid) {
+                c.role = UpstairsRole::Standby;
+            }
+        }
+        *self.active_io.write().await = None;
 
         work.active = HashMap::new();
         work.completed = Vec::with_capacity(32);
@@ -1194,6 +1996,72 @@ impl Work {
         self.active.insert(ds_id, dsw);
     }
 
+    /*
+     * A flush that is about to run can stand in for flushes queued right
+     * behind it: if every dependency of the next flush is either done or
+     * is a flush we are already doing, then syncing with the later flush
+     * number leaves the region just as doing both would.  Mark any such
+     * flushes InProgress and return their IDs in order.
+     */
+    fn coalesce_flushes(&mut self, first: u64) -> Vec<u64> {
+        let mut group = vec![first];
+
+        loop {
+            let last = *group.last().unwrap();
+            let next = self
+                .active
+                .values()
+                .filter(|job| {
+                    job.ds_id > last
+                        && (job.state == WorkState::New
+                            || job.state == WorkState::DepWait)
+                        && matches!(
+                            job.work,
+                            IOop::Flush {
+                                snapshot_details: None,
+                                extent_limit: None,
+                                ..
+                            }
+                        )
+                        && job.work.deps().iter().all(|dep| {
+                            *dep <= self.last_flush
+                                || self.completed.contains(dep)
+                                || group.contains(dep)
+                        })
+                })
+                .map(|job| job.ds_id)
+                .min();
+
+            match next {
+                Some(ds_id) => {
+                    self.active.get_mut(&ds_id).unwrap().state =
+                        WorkState::InProgress;
+                    group.push(ds_id);
+                }
+                None => break,
+            }
+        }
+
+        group.remove(0);
+        group
+    }
+
+    fn complete(&mut self, ds_id: u64, is_flush: bool) {
+        let _ = self.active.remove(&ds_id);
+
+        if is_flush {
+            self.last_flush = ds_id;
+            /*
+             * Jobs after the flush that don't depend on it can finish
+             * before it does.  Those are still needed to meet the
+             * dependencies of later jobs.
+             */
+            self.completed.retain(|id| *id > ds_id);
+        } else {
+            self.completed.push(ds_id);
+        }
+    }
+
     /**
      * If the requested job is still new, and the dependencies are all met,
      * return the DownstairsWork struct and let the caller take action
@@ -1261,11 +2129,22 @@ impl Work {
                                     dependencies: _,
                                     flush_number: _flush_number,
                                     gen_number: _gen_number,
+                                    snapshot_details: _,
+                                    extent_limit: _,
                                 } => "Flush",
                                 IOop::Read {
                                     dependencies: _,
                                     requests: _,
                                 } => "Read",
+                                IOop::ExtentRepairRead {
+                                    dependencies: _,
+                                    eid: _,
+                                } => "RepairRead",
+                                IOop::ExtentRepairWrite {
+                                    dependencies: _,
+                                    eid: _,
+                                    extent: _,
+                                } => "RepairWrite",
                             },
                             job.upstairs_uuid,
                             deps_outstanding.len(),
@@ -1310,113 +2189,123 @@ impl Work {
             None
         }
     }
+}
 
-    /*
-     * This method calls into the Downstair's region and performs the read /
-     * write / flush action. A reference to Downstairs is required to do this
-     * so that the job can continue to be owned by Work.
-     *
-     * If by the time this job_id is processed here the job is no longer on
-     * the active work queue, return None. If this happens no response
-     * will have been put onto the response queue.
-     */
-    async fn do_work(
-        &mut self,
-        ds: &Downstairs,
-        job_id: u64,
-    ) -> Result<Option<Message>> {
-        let job = match self.active.get(&job_id) {
-            Some(job) => job,
-            None => {
-                /*
-                 * This branch occurs when another Upstairs has promoted
-                 * itself to active, causing active work to
-                 * be cleared (in promote_to_active).
-                 *
-                 * If this has happened, work.completed and work.last_flush
-                 * have also been reset. Do nothing here,
-                 * especially since the Upstairs has already
-                 * been notified.
-                 */
-                return Ok(None);
-            }
-        };
-
-        assert_eq!(job.state, WorkState::InProgress);
-        assert_eq!(job_id, job.ds_id);
+/*
+ * Call into the region and perform the read / write / flush action for a
+ * job, returning the message to send back to the upstairs.  This runs on
+ * the blocking pool, without any of the Downstairs locks held, so that
+ * jobs that don't depend on each other can run at the same time.
+ *
+ * If the upstairs that sent this job is no longer active, the IO is not
+ * done and the upstairs is told so instead.
+ */
+fn execute(
+    region: &Region,
+    job: &DownstairsWork,
+    faults: &Faults,
+    snapshots: Option<&dyn SnapshotProvider>,
+    active: bool,
+) -> Message {
+    match &job.work {
+        IOop::Read {
+            dependencies: _dependencies,
+            requests,
+        } => {
+            /*
+             * Any error from an IO should be intercepted here and passed
+             * back to the upstairs.
+             */
+            let responses = if faults.error() {
+                println!("returning error on read!");
+                Err(CrucibleError::GenericError("test error".to_string()))
+            } else if !active {
+                Err(CrucibleError::UpstairsInactive)
+            } else {
+                region.region_read(requests)
+            };
 
-        // validate that deps are done
-        let dep_list = job.work.deps();
-        for dep in dep_list {
-            let last_flush_satisfied = dep <= &self.last_flush;
-            let complete_satisfied = self.completed.contains(dep);
+            Message::ReadResponse(job.upstairs_uuid, job.ds_id, responses)
+        }
+        IOop::Write {
+            dependencies: _dependencies,
+            writes,
+        } => {
+            let result = if faults.error() {
+                println!("returning error on write!");
+                Err(CrucibleError::GenericError("test error".to_string()))
+            } else if !active {
+                Err(CrucibleError::UpstairsInactive)
+            } else {
+                region.region_write(writes)
+            };
 
-            assert!(last_flush_satisfied || complete_satisfied);
+            Message::WriteAck(job.upstairs_uuid, job.ds_id, result)
         }
+        IOop::Flush {
+            dependencies: _dependencies,
+            flush_number,
+            gen_number,
+            snapshot_details,
+            extent_limit,
+        } => {
+            let result = if faults.error() {
+                println!("returning error on flush!");
+                Err(CrucibleError::GenericError("test error".to_string()))
+            } else if !active {
+                Err(CrucibleError::UpstairsInactive)
+            } else if faults.skip_flush {
+                Ok(())
+            } else {
+                region.region_flush_limited(
+                    *flush_number,
+                    *gen_number,
+                    *extent_limit,
+                )
+            };
 
-        match &job.work {
-            IOop::Read {
-                dependencies: _dependencies,
-                requests,
-            } => {
-                /*
-                 * Any error from an IO should be intercepted here and passed
-                 * back to the upstairs.
-                 */
-                let responses = if ds.return_errors && random() && random() {
-                    println!("returning error on read!");
-                    Err(CrucibleError::GenericError("test error".to_string()))
-                } else if !ds.is_active(job.upstairs_uuid) {
-                    Err(CrucibleError::UpstairsInactive)
-                } else {
-                    ds.region.region_read(requests)
-                };
+            /*
+             * Nothing that depends on this flush has started yet, so the
+             * snapshot holds exactly what was written before it.
+             */
+            let result = match (result, snapshot_details) {
+                (Ok(()), Some(details)) => {
+                    snapshot::take(snapshots, region.dir(), details)
+                }
+                (result, _) => result,
+            };
 
-                Ok(Some(Message::ReadResponse(
-                    job.upstairs_uuid,
-                    job.ds_id,
-                    responses,
-                )))
-            }
-            IOop::Write {
-                dependencies: _dependencies,
-                writes,
-            } => {
-                let result = if ds.return_errors && random() && random() {
-                    println!("returning error on write!");
-                    Err(CrucibleError::GenericError("test error".to_string()))
-                } else if !ds.is_active(job.upstairs_uuid) {
-                    Err(CrucibleError::UpstairsInactive)
-                } else {
-                    ds.region.region_write(writes)
-                };
+            Message::FlushAck(job.upstairs_uuid, job.ds_id, result)
+        }
+        IOop::ExtentRepairRead {
+            dependencies: _dependencies,
+            eid,
+        } => {
+            let result = if !active {
+                Err(CrucibleError::UpstairsInactive)
+            } else {
+                region.extent_repair_read(*eid)
+            };
 
-                Ok(Some(Message::WriteAck(
-                    job.upstairs_uuid,
-                    job.ds_id,
-                    result,
-                )))
-            }
-            IOop::Flush {
-                dependencies: _dependencies,
-                flush_number,
-                gen_number,
-            } => {
-                let result = if ds.return_errors && random() && random() {
-                    println!("returning error on flush!");
-                    Err(CrucibleError::GenericError("test error".to_string()))
-                } else if !ds.is_active(job.upstairs_uuid) {
-                    Err(CrucibleError::UpstairsInactive)
-                } else {
-                    ds.region.region_flush(*flush_number, *gen_number)
-                };
+            Message::ExtentRepairData(job.upstairs_uuid, job.ds_id, result)
+        }
+        IOop::ExtentRepairWrite {
+            dependencies: _dependencies,
+            eid: _,
+            extent,
+        } => {
+            /*
+             * The upstairs only sends a repair write once it has the
+             * data to put in it.
+             */
+            let extent = extent.as_ref().unwrap();
+            let result = if !active {
+                Err(CrucibleError::UpstairsInactive)
+            } else {
+                region.extent_repair_write(extent)
+            };
 
-                Ok(Some(Message::FlushAck(
-                    job.upstairs_uuid,
-                    job.ds_id,
-                    result,
-                )))
-            }
+            Message::ExtentRepairAck(job.upstairs_uuid, job.ds_id, result)
         }
     }
 }
@@ -1506,6 +2395,12 @@ async fn main() -> Result<()> {
             );
             Ok(())
         }
+        Args::Clone { data, source, uuid } => {
+            region = clone_region(source, &data, uuid).await?;
+
+            println!("UUID: {:?}", region.def().uuid());
+            Ok(())
+        }
         Args::Dump { data, extent } => {
             dump_region(data, extent)?;
             Ok(())
@@ -1516,20 +2411,97 @@ async fn main() -> Result<()> {
             export_path,
             skip,
         } => {
-            region = Region::open(&data, Default::default(), true)?;
+            region = Region::open(&data, Default::default(), true, true)?;
 
             downstairs_export(&mut region, export_path, skip, count).unwrap();
             Ok(())
         }
+        Args::IoBench {
+            data,
+            backend,
+            block_size,
+            extent_size,
+            extent_count,
+            io_blocks,
+        } => io_bench(
+            &data,
+            &backend,
+            block_size,
+            extent_size,
+            extent_count,
+            io_blocks,
+        ),
         Args::Run {
+            config,
             address,
             data,
+            io_backend,
             lossy,
+            coalesce_flushes,
             port,
+            read_only,
+            snapshot_provider,
             return_errors,
+            delay_pct,
+            delay_max_ms,
+            error_pct,
+            disconnect_pct,
+            skip_flush,
+            control,
+            iops_limit,
+            bandwidth_limit,
+            max_repair_jobs,
+            scrub_interval,
             trace_endpoint,
         } => {
-            region = Region::open(&data, Default::default(), true)?;
+            let faults = Faults {
+                delay_pct,
+                delay_max_ms,
+                error_pct: if return_errors { 25 } else { error_pct },
+                disconnect_pct,
+                skip_flush,
+            };
+            faults.validate()?;
+
+            let ds = match &config {
+                Some(path) => {
+                    crucible_opts::Config::load(Some(path))?.downstairs
+                }
+                None => Default::default(),
+            };
+            let address =
+                address.or(ds.address).unwrap_or(Ipv4Addr::UNSPECIFIED);
+            let port = port.or(ds.port).unwrap_or(9000);
+            let data = match data.or(ds.data) {
+                Some(data) => data,
+                None => bail!("no region directory in --data or the config"),
+            };
+
+            region = Region::open_with_backend(
+                &data,
+                Default::default(),
+                true,
+                read_only,
+                io_backend,
+            )?;
+            println!("Extent IO backend: {}", io_backend);
+
+            let snapshots = snapshot::provider(snapshot_provider, &data)?;
+            println!("Snapshot provider: {:?}", snapshots);
+            if read_only {
+                println!("Serving region read only");
+            }
+
+            if let Some(uuid) = ds.uuid {
+                if region.def().uuid() != uuid {
+                    bail!(
+                        "region {:?} has UUID {}, not {} as configured",
+                        data,
+                        region.def().uuid(),
+                        uuid
+                    );
+                }
+            }
 
             println!("UUID: {:?}", region.def().uuid());
             println!(
@@ -1541,9 +2513,30 @@ async fn main() -> Result<()> {
             let d = Arc::new(Mutex::new(Downstairs::new(
                 region,
                 lossy,
-                return_errors,
+                faults,
+                coalesce_flushes,
+                snapshots.map(Arc::from),
+                QosLimits {
+                    iops: iops_limit,
+                    bandwidth: bandwidth_limit,
+                    max_repair_jobs,
+                },
             )));
 
+            if let Some(addr) = control {
+                let d = d.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = faults::start(d, addr).await {
+                        println!("control server exited: {:?}", e);
+                    }
+                });
+            }
+
+            if let Some(secs) = scrub_interval {
+                let region = d.lock().await.region.clone();
+                tokio::spawn(scrub_task(region, Duration::from_secs(secs)));
+            }
+
             /*
              * If any of our async tasks in our runtime panic, then we should
              * exit the program right away.
@@ -1625,6 +2618,8 @@ mod test {
                         dependencies: deps,
                         flush_number: 10,
                         gen_number: 0,
+                        snapshot_details: None,
+                        extent_limit: None,
                     }
                 } else {
                     IOop::Read {
@@ -1660,18 +2655,13 @@ mod test {
                     dependencies: _,
                     flush_number: _,
                     gen_number: _,
+                    snapshot_details: _,
+                    extent_limit: _,
                 }
             )
         };
 
-        let _ = work.active.remove(&ds_id);
-
-        if is_flush {
-            work.last_flush = ds_id;
-            work.completed = Vec::with_capacity(32);
-        } else {
-            work.completed.push(ds_id);
-        }
+        work.complete(ds_id, is_flush);
     }
 
     fn test_push_next_jobs(work: &mut Work, uuid: Uuid) -> Vec<u64> {
@@ -2128,6 +3118,254 @@ mod test {
         assert_eq!(work.completed, vec![1000, 1001, 1002, 1003]);
     }
 
+    #[test]
+    fn independent_job_completes_before_flush() {
+        /*
+         * Test that a job which finishes before an earlier flush it does
+         * not depend on is still counted once the flush completes.
+         */
+        let mut work = Work::default();
+        let uuid = Uuid::new_v4();
+
+        add_work(&mut work, uuid, 1000, vec![], false);
+        add_work(&mut work, uuid, 1001, vec![1000], true);
+        add_work(&mut work, uuid, 1002, vec![], false);
+        add_work(&mut work, uuid, 1003, vec![1001, 1002], false);
+
+        // 1000 and 1002 don't depend on anything, so both can run.
+        let next_jobs = test_push_next_jobs(&mut work, uuid);
+        assert_eq!(next_jobs, vec![1000, 1002]);
+
+        // 1002 finishes first.
+        test_do_work(&mut work, vec![1002, 1000]);
+
+        let next_jobs = test_push_next_jobs(&mut work, uuid);
+        assert_eq!(next_jobs, vec![1001]);
+
+        // The flush must not forget that 1002 is done.
+        test_do_work(&mut work, next_jobs);
+        assert_eq!(work.last_flush, 1001);
+        assert_eq!(work.completed, vec![1002]);
+
+        let next_jobs = test_push_next_jobs(&mut work, uuid);
+        assert_eq!(next_jobs, vec![1003]);
+        test_do_work(&mut work, next_jobs);
+
+        assert_eq!(work.completed, vec![1002, 1003]);
+        assert!(work.active.is_empty());
+    }
+
+    #[test]
+    fn coalesce_queued_flushes() {
+        /*
+         * Test that flushes queued behind a flush are taken along with
+         * it, up to the first job that still has to wait.
+         */
+        let mut work = Work::default();
+        let uuid = Uuid::new_v4();
+
+        add_work(&mut work, uuid, 1000, vec![], true);
+        add_work(&mut work, uuid, 1001, vec![1000], true);
+        add_work(&mut work, uuid, 1002, vec![1000, 1001], true);
+        add_work(&mut work, uuid, 1003, vec![1002], false);
+        add_work(&mut work, uuid, 1004, vec![1003], true);
+
+        let next_jobs = test_push_next_jobs(&mut work, uuid);
+        assert_eq!(next_jobs, vec![1000]);
+
+        assert_eq!(work.coalesce_flushes(1000), vec![1001, 1002]);
+        assert!(test_push_next_jobs(&mut work, uuid).is_empty());
+
+        test_do_work(&mut work, vec![1000, 1001, 1002]);
+        assert_eq!(work.last_flush, 1002);
+
+        let next_jobs = test_push_next_jobs(&mut work, uuid);
+        assert_eq!(next_jobs, vec![1003]);
+
+        // The read in the way means there is nothing to take along.
+        test_do_work(&mut work, next_jobs);
+        let next_jobs = test_push_next_jobs(&mut work, uuid);
+        assert_eq!(next_jobs, vec![1004]);
+        assert!(work.coalesce_flushes(1004).is_empty());
+    }
+
+    fn flush_job(uuid: Uuid, ds_id: u64, flush_number: u64) -> DownstairsWork {
+        DownstairsWork {
+            upstairs_uuid: uuid,
+            ds_id,
+            work: IOop::Flush {
+                dependencies: vec![],
+                flush_number,
+                gen_number: 1,
+                snapshot_details: None,
+                extent_limit: None,
+            },
+            state: WorkState::New,
+        }
+    }
+
+    #[test]
+    fn faults_error_and_skip_flush() -> Result<()> {
+        let mut region_options: crucible_common::RegionOptions =
+            Default::default();
+        region_options.set_block_size(512);
+        region_options.set_extent_size(Block::new_512(10));
+        region_options.set_uuid(Uuid::new_v4());
+
+        let dir = tempdir()?;
+        mkdir_for_file(dir.path())?;
+        let mut region = Region::create(&dir, region_options)?;
+        region.extend(2)?;
+
+        let mut buffer = BytesMut::with_capacity(512);
+        buffer.resize(512, 9);
+        region.single_block_region_write(
+            1,
+            Block::new_512(0),
+            buffer.freeze(),
+            None,
+            None,
+        )?;
+
+        let uuid = Uuid::new_v4();
+        let faults = Faults {
+            error_pct: 100,
+            ..Default::default()
+        };
+        match execute(&region, &flush_job(uuid, 1000, 3), &faults, None, true) {
+            Message::FlushAck(_, 1000, Err(_)) => {}
+            m => panic!("expected a failed flush, got {:?}", m),
+        }
+
+        // Skipping a flush acks it but leaves the flush numbers alone.
+        let faults = Faults {
+            skip_flush: true,
+            ..Default::default()
+        };
+        match execute(&region, &flush_job(uuid, 1001, 4), &faults, None, true) {
+            Message::FlushAck(_, 1001, Ok(())) => {}
+            m => panic!("expected a flush ack, got {:?}", m),
+        }
+        assert_eq!(region.flush_numbers()?, vec![0, 0]);
+
+        let faults = Faults::default();
+        execute(&region, &flush_job(uuid, 1002, 5), &faults, None, true);
+        assert_eq!(region.flush_numbers()?, vec![0, 5]);
+
+        Ok(())
+    }
+
+    fn snapshot_job(uuid: Uuid, ds_id: u64, name: &str) -> DownstairsWork {
+        let mut job = flush_job(uuid, ds_id, 1);
+        if let IOop::Flush {
+            snapshot_details, ..
+        } = &mut job.work
+        {
+            *snapshot_details = Some(SnapshotDetails {
+                snapshot_name: name.to_string(),
+            });
+        }
+        job
+    }
+
+    #[test]
+    fn flush_takes_snapshot() -> Result<()> {
+        let mut region_options: crucible_common::RegionOptions =
+            Default::default();
+        region_options.set_block_size(512);
+        region_options.set_extent_size(Block::new_512(10));
+        region_options.set_uuid(Uuid::new_v4());
+
+        let dir = tempdir()?;
+        mkdir_for_file(dir.path())?;
+        let mut region = Region::create(&dir, region_options)?;
+        region.extend(2)?;
+
+        let mut buffer = BytesMut::with_capacity(512);
+        buffer.resize(512, 1);
+        region.single_block_region_write(
+            1,
+            Block::new_512(0),
+            buffer.freeze(),
+            None,
+            None,
+        )?;
+
+        let uuid = Uuid::new_v4();
+        let faults = Faults::default();
+        let copy = snapshot::CopySnapshot {};
+        let job = snapshot_job(uuid, 1000, "one");
+        match execute(&region, &job, &faults, Some(&copy), true) {
+            Message::FlushAck(_, 1000, Ok(())) => {}
+            m => panic!("expected a flush ack, got {:?}", m),
+        }
+
+        // A write after the flush is not in the snapshot.
+        let mut buffer = BytesMut::with_capacity(512);
+        buffer.resize(512, 2);
+        region.single_block_region_write(
+            1,
+            Block::new_512(0),
+            buffer.freeze(),
+            None,
+            None,
+        )?;
+
+        let snap_dir = dir.path().join(snapshot::SNAPSHOT_DIR).join("one");
+        let snap = Region::open(&snap_dir, Default::default(), false, true)?;
+        assert_eq!(snap.flush_numbers()?, vec![0, 1]);
+        let response = snap.single_block_region_read(ReadRequest {
+            eid: 1,
+            offset: Block::new_512(0),
+            num_blocks: 1,
+        })?;
+        assert_eq!(response.data.to_vec(), vec![1u8; 512]);
+
+        // The same name can't be used again, and a name must be safe.
+        let job = snapshot_job(uuid, 1001, "one");
+        match execute(&region, &job, &faults, Some(&copy), true) {
+            Message::FlushAck(_, 1001, Err(_)) => {}
+            m => panic!("expected a failed flush, got {:?}", m),
+        }
+        let job = snapshot_job(uuid, 1002, "../two");
+        match execute(&region, &job, &faults, Some(&copy), true) {
+            Message::FlushAck(_, 1002, Err(_)) => {}
+            m => panic!("expected a failed flush, got {:?}", m),
+        }
+
+        // Without a provider, asking for a snapshot fails the flush.
+        let job = snapshot_job(uuid, 1003, "three");
+        match execute(&region, &job, &faults, None, true) {
+            Message::FlushAck(
+                _,
+                1003,
+                Err(CrucibleError::SnapshotFailed(_)),
+            ) => {}
+            m => panic!("expected a failed flush, got {:?}", m),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn faults_validate() {
+        assert!(Faults::default().validate().is_ok());
+
+        let faults = Faults {
+            error_pct: 100,
+            delay_pct: 100,
+            disconnect_pct: 100,
+            ..Default::default()
+        };
+        assert!(faults.validate().is_ok());
+
+        let faults = Faults {
+            disconnect_pct: 101,
+            ..Default::default()
+        };
+        assert!(faults.validate().is_err());
+    }
+
     #[test]
     fn import_test_basic() -> Result<()> {
         /*
@@ -2197,6 +3435,255 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn promote_generation() -> Result<()> {
+        let mut region_options: crucible_common::RegionOptions =
+            Default::default();
+        region_options.set_block_size(512);
+        region_options.set_extent_size(Block::new_512(10));
+        region_options.set_uuid(Uuid::new_v4());
+
+        let dir = tempdir()?;
+        mkdir_for_file(dir.path())?;
+        let region = Region::create(&dir, region_options)?;
+        let mut ds = Downstairs::new(
+            region,
+            false,
+            Faults::default(),
+            false,
+            None,
+            QosLimits::default(),
+        );
+
+        let up1 = Uuid::new_v4();
+        let up2 = Uuid::new_v4();
+
+        assert!(ds.promote_allowed(up1, 0));
+        ds.promote_to_active(up1, 2).await?;
+
+        // The active upstairs can come back with the same generation.
+        assert!(ds.promote_allowed(up1, 2));
+
+        // Another upstairs needs a higher one.
+        assert!(!ds.promote_allowed(up2, 1));
+        assert!(!ds.promote_allowed(up2, 2));
+        assert!(ds.promote_allowed(up2, 3));
+
+        // Once the active one is gone, the same generation is enough.
+        ds.clear_active().await;
+        assert!(!ds.promote_allowed(up2, 1));
+        assert!(ds.promote_allowed(up2, 2));
+
+        // A downstairs started again on the region still knows it.
+        drop(ds);
+        let region = Region::open(&dir, Default::default(), false, false)?;
+        assert_eq!(region.promoted(), Some((up1, 2)));
+        let ds = Downstairs::new(
+            region,
+            false,
+            Faults::default(),
+            false,
+            None,
+            QosLimits::default(),
+        );
+        assert!(!ds.promote_allowed(up2, 1));
+        assert!(ds.promote_allowed(up2, 2));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn upstairs_roles_and_takeover() -> Result<()> {
+        let mut region_options: crucible_common::RegionOptions =
+            Default::default();
+        region_options.set_block_size(512);
+        region_options.set_extent_size(Block::new_512(10));
+        region_options.set_uuid(Uuid::new_v4());
+
+        let dir = tempdir()?;
+        mkdir_for_file(dir.path())?;
+        let region = Region::create(&dir, region_options)?;
+        let mut ds = Downstairs::new(
+            region,
+            false,
+            Faults::default(),
+            false,
+            None,
+            QosLimits::default(),
+        );
+
+        let up1 = Uuid::new_v4();
+        let up2 = Uuid::new_v4();
+        let reader = Uuid::new_v4();
+        let (tx1, mut rx1) = channel(1);
+        let (tx2, mut rx2) = channel(1);
+        let (tx3, mut rx3) = channel(1);
+
+        let id1 = ds.connect(up1, false, Arc::new(tx1)).await;
+        ds.connect(up2, false, Arc::new(tx2)).await;
+        ds.connect(reader, true, Arc::new(tx3)).await;
+        assert_eq!(ds.role(up1), Some(UpstairsRole::Standby));
+        assert_eq!(ds.role(reader), Some(UpstairsRole::ReadOnly));
+
+        ds.promote_to_active(up1, 1).await?;
+        assert_eq!(ds.role(up1), Some(UpstairsRole::Active));
+        assert_eq!(ds.role(up2), Some(UpstairsRole::Standby));
+
+        // Only the active upstairs can put work on the queue.
+        let flush = IOop::Flush {
+            dependencies: vec![],
+            flush_number: 1,
+            gen_number: 1,
+            snapshot_details: None,
+            extent_limit: None,
+        };
+        ds.add_work(up1, 1000, flush.clone()).await?;
+        assert!(ds.add_work(up2, 1000, flush.clone()).await.is_err());
+
+        // The old active upstairs hears who took over, nobody else does.
+        ds.promote_to_active(up2, 2).await?;
+        assert_eq!(rx1.try_recv().unwrap(), (up2, 2));
+        assert!(rx2.try_recv().is_err());
+        assert!(rx3.try_recv().is_err());
+        assert_eq!(ds.role(up1), Some(UpstairsRole::Standby));
+        assert_eq!(ds.role(up2), Some(UpstairsRole::Active));
+        assert_eq!(ds.jobs().await, 0);
+
+        // The old connection closing leaves the new active one alone.
+        ds.disconnect(up1, id1).await;
+        assert_eq!(ds.role(up1), None);
+        assert!(ds.is_active(up2));
+
+        // up2 connecting again closes its old connection.
+        let (tx4, _rx4) = channel(1);
+        let id4 = ds.connect(up2, false, Arc::new(tx4)).await;
+        assert_eq!(rx2.try_recv().unwrap(), (up2, 2));
+        assert_eq!(ds.active_upstairs(), None);
+        assert_eq!(ds.role(up2), Some(UpstairsRole::Standby));
+
+        // It can promote again with the same generation.
+        assert!(ds.promote_allowed(up2, 2));
+        ds.promote_to_active(up2, 2).await?;
+        assert_eq!(ds.role(up2), Some(UpstairsRole::Active));
+
+        ds.disconnect(up2, id4).await;
+        assert_eq!(ds.active_upstairs(), None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn clone_from_peer() -> Result<()> {
+        let mut region_options: crucible_common::RegionOptions =
+            Default::default();
+        region_options.set_block_size(512);
+        region_options.set_extent_size(Block::new_512(10));
+        region_options.set_uuid(Uuid::new_v4());
+
+        let dir = tempdir()?;
+        mkdir_for_file(dir.path())?;
+        let mut region = Region::create(&dir, region_options)?;
+        region.extend(3)?;
+
+        let mut buffer = BytesMut::with_capacity(512);
+        buffer.resize(512, 7);
+        region.single_block_region_write(
+            1,
+            Block::new_512(2),
+            buffer.freeze(),
+            Some(vec![1, 2]),
+            Some(vec![3, 4]),
+        )?;
+        region.region_flush(3, 2)?;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let source = match listener.local_addr()? {
+            std::net::SocketAddr::V4(addr) => addr,
+            addr => panic!("unexpected address {:?}", addr),
+        };
+
+        let mut ads = Arc::new(Mutex::new(Downstairs::new(
+            region,
+            false,
+            Faults::default(),
+            false,
+            None,
+            QosLimits::default(),
+        )));
+        tokio::spawn(async move {
+            let (sock, _) = listener.accept().await.unwrap();
+            let _ = proc(&mut ads, sock).await;
+        });
+
+        let dir2 = tempdir()?;
+        let uuid = Uuid::new_v4();
+        let clone = clone_region(source, dir2.path(), uuid).await?;
+
+        assert_eq!(clone.def().uuid(), uuid);
+        assert_eq!(clone.def().extent_count(), 3);
+        assert_eq!(clone.flush_numbers()?, vec![0, 3, 0]);
+        assert_eq!(clone.gen_numbers()?, vec![0, 2, 0]);
+        assert_eq!(clone.dirty()?, vec![false, false, false]);
+
+        let response =
+            clone.single_block_region_read(crucible_protocol::ReadRequest {
+                eid: 1,
+                offset: Block::new_512(2),
+                num_blocks: 1,
+            })?;
+        assert_eq!(response.data.to_vec(), vec![7u8; 512]);
+        assert_eq!(response.nonce, Some(vec![1, 2]));
+        assert_eq!(response.tag, Some(vec![3, 4]));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn old_upstairs_gets_version_mismatch() -> Result<()> {
+        let mut region_options: crucible_common::RegionOptions =
+            Default::default();
+        region_options.set_block_size(512);
+        region_options.set_extent_size(Block::new_512(10));
+        region_options.set_uuid(Uuid::new_v4());
+
+        let dir = tempdir()?;
+        mkdir_for_file(dir.path())?;
+        let mut region = Region::create(&dir, region_options)?;
+        region.extend(1)?;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let mut ads = Arc::new(Mutex::new(Downstairs::new(
+            region,
+            false,
+            Faults::default(),
+            false,
+            None,
+            QosLimits::default(),
+        )));
+        tokio::spawn(async move {
+            let (sock, _) = listener.accept().await.unwrap();
+            let _ = proc(&mut ads, sock).await;
+        });
+
+        /*
+         * An upstairs from before the read only flag moved out of
+         * HereIAm is told what we speak, not dropped with a decode error.
+         */
+        let (read, write) = TcpStream::connect(addr).await?.into_split();
+        let mut fr = FramedRead::new(read, CrucibleDecoder::new());
+        let mut fw = FramedWrite::new(write, CrucibleEncoder::new());
+        fw.send(Message::HereIAm(3, Uuid::new_v4())).await?;
+        match fr.next().await.transpose()? {
+            Some(Message::VersionMismatch(v)) => {
+                assert_eq!(v, CRUCIBLE_MESSAGE_VERSION)
+            }
+            m => panic!("expected VersionMismatch, got {:?}", m),
+        }
+
+        Ok(())
+    }
+
     #[test]
     fn import_test_too_small() -> Result<()> {
         /*
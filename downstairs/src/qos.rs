@@ -0,0 +1,248 @@
+// Copyright 2021 Oxide Computer Company
+use super::*;
+
+/*
+ * Limits on what one upstairs connection can ask of this downstairs, so
+ * that one busy upstairs (a big repair, say) can't take the whole disk
+ * from the other regions on it.
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct QosLimits {
+    /*
+     * Jobs started per second.
+     */
+    pub iops: Option<u64>,
+    /*
+     * Bytes read or written per second.
+     */
+    pub bandwidth: Option<u64>,
+    /*
+     * Extent repair jobs that can run at once.  Guest IO that is ready
+     * always goes ahead of repair IO.
+     */
+    pub max_repair_jobs: Option<usize>,
+}
+
+/*
+ * A token bucket, refilled at rate per second and holding up to a
+ * second's worth.  A job can take more than is there, leaving the bucket
+ * in debt, so a job bigger than the rate still runs.  Nothing else runs
+ * until the debt is paid off.
+ */
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64, now: Instant) -> Bucket {
+        Bucket {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last);
+        self.tokens =
+            (self.tokens + self.rate * elapsed.as_secs_f64()).min(self.rate);
+        self.last = now;
+    }
+
+    /*
+     * When the bucket will be out of debt, or None if it is now.
+     */
+    fn ready_at(&mut self, now: Instant) -> Option<Instant> {
+        self.refill(now);
+        if self.tokens >= 0.0 {
+            None
+        } else {
+            Some(now + Duration::from_secs_f64(-self.tokens / self.rate))
+        }
+    }
+
+    fn take(&mut self, amount: u64) {
+        self.tokens -= amount as f64;
+    }
+}
+
+/*
+ * What a job costs against the limits.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct JobCost {
+    pub repair: bool,
+    pub bytes: u64,
+}
+
+impl JobCost {
+    pub fn of(work: &IOop, block_size: u64, extent_blocks: u64) -> JobCost {
+        match work {
+            IOop::Read { requests, .. } => JobCost {
+                repair: false,
+                bytes: requests.iter().map(|r| r.num_blocks).sum::<u64>()
+                    * block_size,
+            },
+            IOop::Write { writes, .. } => JobCost {
+                repair: false,
+                bytes: writes.iter().map(|w| w.data.len() as u64).sum(),
+            },
+            IOop::Flush { .. } => JobCost {
+                repair: false,
+                bytes: 0,
+            },
+            IOop::ExtentRepairRead { .. } | IOop::ExtentRepairWrite { .. } => {
+                JobCost {
+                    repair: true,
+                    bytes: extent_blocks * block_size,
+                }
+            }
+        }
+    }
+}
+
+/*
+ * The limits as they apply to one upstairs connection.
+ */
+#[derive(Debug)]
+pub struct ConnectionQos {
+    iops: Option<Bucket>,
+    bandwidth: Option<Bucket>,
+    max_repair_jobs: Option<usize>,
+    repair_jobs: usize,
+}
+
+impl ConnectionQos {
+    pub fn new(limits: QosLimits, now: Instant) -> ConnectionQos {
+        ConnectionQos {
+            iops: limits.iops.map(|rate| Bucket::new(rate, now)),
+            bandwidth: limits.bandwidth.map(|rate| Bucket::new(rate, now)),
+            max_repair_jobs: limits.max_repair_jobs,
+            repair_jobs: 0,
+        }
+    }
+
+    /*
+     * When the rate limits will let another job start, or None if one
+     * can start now.
+     */
+    pub fn ready_at(&mut self, now: Instant) -> Option<Instant> {
+        let iops = self.iops.as_mut().and_then(|b| b.ready_at(now));
+        let bandwidth = self.bandwidth.as_mut().and_then(|b| b.ready_at(now));
+        iops.max(bandwidth)
+    }
+
+    /*
+     * If another repair job can be started now.
+     */
+    pub fn repair_allowed(&self) -> bool {
+        match self.max_repair_jobs {
+            Some(max) => self.repair_jobs < max,
+            None => true,
+        }
+    }
+
+    pub fn start(&mut self, cost: JobCost) {
+        if let Some(b) = self.iops.as_mut() {
+            b.take(1);
+        }
+        if let Some(b) = self.bandwidth.as_mut() {
+            b.take(cost.bytes);
+        }
+        if cost.repair {
+            self.repair_jobs += 1;
+        }
+    }
+
+    /*
+     * Note a job is done, from the message it answered with.
+     */
+    pub fn finish(&mut self, m: &Message) {
+        if matches!(
+            m,
+            Message::ExtentRepairData(..) | Message::ExtentRepairAck(..)
+        ) {
+            self.repair_jobs = self.repair_jobs.saturating_sub(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn iops_limit_waits_for_refill() {
+        let now = Instant::now();
+        let limits = QosLimits {
+            iops: Some(10),
+            ..Default::default()
+        };
+        let mut qos = ConnectionQos::new(limits, now);
+        let cost = JobCost {
+            repair: false,
+            bytes: 512,
+        };
+
+        // A second's worth can go at once.
+        for _ in 0..10 {
+            assert_eq!(qos.ready_at(now), None);
+            qos.start(cost);
+        }
+        assert_eq!(qos.ready_at(now), None);
+        qos.start(cost);
+
+        // Now one job in debt, which takes a tenth of a second to pay.
+        let ready = qos.ready_at(now).unwrap();
+        assert!(ready > now + Duration::from_millis(90));
+        assert!(ready <= now + Duration::from_millis(100));
+        assert_eq!(qos.ready_at(now + Duration::from_millis(100)), None);
+    }
+
+    #[test]
+    fn bandwidth_limit_lets_a_big_job_through() {
+        let now = Instant::now();
+        let limits = QosLimits {
+            bandwidth: Some(1000),
+            ..Default::default()
+        };
+        let mut qos = ConnectionQos::new(limits, now);
+
+        qos.start(JobCost {
+            repair: false,
+            bytes: 3000,
+        });
+
+        // Two seconds of debt.
+        let ready = qos.ready_at(now).unwrap();
+        assert!(ready > now + Duration::from_millis(1900));
+        assert_eq!(qos.ready_at(now + Duration::from_secs(2)), None);
+    }
+
+    #[test]
+    fn repair_jobs_are_capped() {
+        let limits = QosLimits {
+            max_repair_jobs: Some(1),
+            ..Default::default()
+        };
+        let mut qos = ConnectionQos::new(limits, Instant::now());
+        let repair = JobCost {
+            repair: true,
+            bytes: 0,
+        };
+
+        assert!(qos.repair_allowed());
+        qos.start(repair);
+        assert!(!qos.repair_allowed());
+
+        // Guest IO finishing doesn't free a repair slot.
+        qos.finish(&Message::FlushAck(Uuid::new_v4(), 1, Ok(())));
+        assert!(!qos.repair_allowed());
+
+        qos.finish(&Message::ExtentRepairAck(Uuid::new_v4(), 2, Ok(())));
+        assert!(qos.repair_allowed());
+    }
+}
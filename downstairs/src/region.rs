@@ -1,30 +1,98 @@
 // Copyright 2021 Oxide Computer Company
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::os::unix::io::AsRawFd;
+use std::cell::Cell;
+use std::collections::BTreeSet;
+use std::fs::OpenOptions;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Mutex, MutexGuard};
 
 use anyhow::{bail, Result};
 use crucible_common::*;
-use rusqlite::{params, Connection};
+use rusqlite::{params, Connection, OpenFlags};
 use serde::{Deserialize, Serialize};
 use tracing::instrument;
+use uuid::Uuid;
+
+use crate::backend::{ExtentFile, IoBackend, IoEngine};
 
 #[derive(Debug)]
 pub struct Extent {
     number: u32,
     block_size: u64,
     extent_size: Block,
+    read_only: bool,
+    /*
+     * Set when a block is found that doesn't match its checksum.
+     */
+    needs_repair: AtomicBool,
     inner: Mutex<Inner>,
 }
 
 #[derive(Debug)]
 pub struct Inner {
-    file: File,
+    file: Box<dyn ExtentFile>,
     metadb: Connection,
+    /*
+     * False only for an extent from before block checksums that was
+     * opened read only, so the table for them could not be added.
+     */
+    checksums: bool,
+    /*
+     * True once the dirty bit is known to be set in metadb, so writes
+     * after the first since a flush don't each commit it again.
+     */
+    dirty_on_disk: Cell<bool>,
 }
 
+/*
+ * Each block written has a CRC-32C of its data stored in the metadata
+ * db, so reads and the scrubber can tell if what is on disk now is what
+ * was written.
+ */
+const CRC32C_TABLE: [u32; 256] = crc32c_table();
+
+const fn crc32c_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+pub fn block_checksum(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc = CRC32C_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+const CREATE_BLOCK_CHECKSUM: &str =
+    "CREATE TABLE IF NOT EXISTS block_checksum (
+    block INTEGER PRIMARY KEY,
+    checksum INTEGER NOT NULL
+)";
+
+/*
+ * The blocks found not to match their checksum, kept until the extent
+ * is repaired.
+ */
+const CREATE_NEEDS_REPAIR: &str = "CREATE TABLE IF NOT EXISTS needs_repair (
+    block INTEGER PRIMARY KEY
+)";
+
 impl Inner {
     pub fn gen_number(&self) -> Result<u64> {
         let mut stmt = self
@@ -59,9 +127,21 @@ impl Inner {
     }
 
     /*
-     * The flush and generation numbers will be updated at the same time.
+     * The flush and generation numbers will be updated at the same time,
+     * in one transaction, so a crash can't leave one without the other.
      */
     fn set_flush_number(&self, new_flush: u64, new_gen: u64) -> Result<()> {
+        let tx = self.metadb.unchecked_transaction()?;
+        self.write_flush_number(new_flush, new_gen)?;
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    /*
+     * The caller must be in a transaction.
+     */
+    fn write_flush_number(&self, new_flush: u64, new_gen: u64) -> Result<()> {
         let mut stmt = self.metadb.prepare(
             "UPDATE metadata SET value=?1 WHERE name='flush_number'",
         )?;
@@ -76,8 +156,10 @@ impl Inner {
 
         /*
          * When we write out the new flush number, the dirty bit should be
-         * set back to false.
+         * set back to false.  Forget it was set first, so if this doesn't
+         * commit, the next write just sets it again.
          */
+        self.dirty_on_disk.set(false);
         let _rows_affected = self
             .metadb
             .execute("UPDATE metadata SET value=0 WHERE name='dirty'", [])?;
@@ -85,6 +167,100 @@ impl Inner {
         Ok(())
     }
 
+    /*
+     * Read all the metadata with one query, so the values all come from
+     * the same committed transaction.
+     */
+    pub fn meta(&self) -> Result<ExtentMeta> {
+        let mut stmt =
+            self.metadb.prepare("SELECT name, value FROM metadata")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+        })?;
+
+        let mut ext_version = None;
+        let mut gen_number = None;
+        let mut flush_number = None;
+        let mut dirty = None;
+        for row in rows {
+            let (name, value) = row?;
+            if value < 0 {
+                bail!("metadata {} has negative value {}", name, value);
+            }
+            let slot = match name.as_str() {
+                "ext_version" => &mut ext_version,
+                "gen_number" => &mut gen_number,
+                "flush_number" => &mut flush_number,
+                "dirty" => &mut dirty,
+                _ => bail!("unexpected metadata {}", name),
+            };
+            *slot = Some(value as u64);
+        }
+
+        match (ext_version, gen_number, flush_number, dirty) {
+            (
+                Some(ext_version),
+                Some(gen_number),
+                Some(flush_number),
+                Some(dirty),
+            ) if dirty <= 1 && ext_version <= u32::MAX as u64 => {
+                Ok(ExtentMeta {
+                    ext_version: ext_version as u32,
+                    gen_number,
+                    flush_number,
+                    dirty: dirty == 1,
+                })
+            }
+            _ => bail!(
+                "bad metadata: ext_version {:?} gen {:?} flush {:?} dirty {:?}",
+                ext_version,
+                gen_number,
+                flush_number,
+                dirty
+            ),
+        }
+    }
+
+    /*
+     * Check the metadata db is intact and makes sense for an extent with
+     * this many blocks.
+     */
+    fn validate(&self, blocks: u64) -> Result<()> {
+        let check: String =
+            self.metadb
+                .query_row("PRAGMA quick_check", [], |row| row.get(0))?;
+        if check != "ok" {
+            bail!("metadata db check failed: {}", check);
+        }
+
+        let meta = self.meta()?;
+        if meta.ext_version != ExtentMeta::default().ext_version {
+            bail!("unknown extent version {}", meta.ext_version);
+        }
+
+        let past_end: u64 = self.metadb.query_row(
+            "SELECT COUNT(*) FROM encryption_context WHERE block >= ?1",
+            params![blocks],
+            |row| row.get(0),
+        )?;
+        if past_end != 0 {
+            bail!("{} encryption contexts past the last block", past_end);
+        }
+
+        if self.checksums {
+            let past_end: u64 = self.metadb.query_row(
+                "SELECT COUNT(*) FROM block_checksum WHERE block >= ?1",
+                params![blocks],
+                |row| row.get(0),
+            )?;
+            if past_end != 0 {
+                bail!("{} block checksums past the last block", past_end);
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn dirty(&self) -> Result<bool> {
         let mut stmt = self
             .metadb
@@ -101,13 +277,69 @@ impl Inner {
         Ok(dirty_values[0])
     }
 
+    /*
+     * Set the dirty bit, unless it already is.  It is committed on its
+     * own, before the data it covers is written.
+     */
     fn set_dirty(&self) -> Result<()> {
+        if self.dirty_on_disk.get() {
+            return Ok(());
+        }
+        self.write_dirty()?;
+        self.dirty_on_disk.set(true);
+        Ok(())
+    }
+
+    fn write_dirty(&self) -> Result<()> {
         let _rows_affected = self
             .metadb
             .execute("UPDATE metadata SET value=1 WHERE name='dirty'", [])?;
         Ok(())
     }
 
+    /*
+     * Note blocks that failed their checksum, and set the dirty bit so
+     * the upstairs looks at this extent.
+     */
+    fn set_needs_repair(&self, blocks: &[u64]) -> Result<()> {
+        let tx = self.metadb.unchecked_transaction()?;
+        let mut stmt = self.metadb.prepare(
+            "INSERT OR IGNORE INTO needs_repair (block) VALUES (?1)",
+        )?;
+        for block in blocks {
+            let _rows_affected = stmt.execute(params![block])?;
+        }
+        self.write_dirty()?;
+        tx.commit()?;
+        self.dirty_on_disk.set(true);
+
+        Ok(())
+    }
+
+    /*
+     * True if blocks were noted as bad and the extent has not been
+     * repaired since.  An extent from before this was kept, opened read
+     * only, has no table for it and nothing noted.
+     */
+    fn needs_repair_noted(&self) -> Result<bool> {
+        let table: u64 = self.metadb.query_row(
+            "SELECT COUNT(*) FROM sqlite_master \
+            WHERE type='table' AND name='needs_repair'",
+            [],
+            |row| row.get(0),
+        )?;
+        if table == 0 {
+            return Ok(false);
+        }
+
+        let noted: u64 = self.metadb.query_row(
+            "SELECT COUNT(*) FROM needs_repair",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(noted > 0)
+    }
+
     fn get_encryption_context(
         &self,
         block: u64,
@@ -158,13 +390,140 @@ impl Inner {
 
         Ok(())
     }
+
+    /*
+     * Forget the contexts of count blocks from first, which have been
+     * written without one.
+     */
+    fn clear_encryption_contexts(&self, first: u64, count: u64) -> Result<()> {
+        let _rows_affected = self.metadb.execute(
+            "DELETE FROM encryption_context WHERE block >= ?1 AND block < ?2",
+            params![first, first + count],
+        )?;
+
+        Ok(())
+    }
+
+    /*
+     * Store the checksum of each block in data, starting at block first.
+     */
+    fn set_checksums(
+        &self,
+        first: u64,
+        data: &[u8],
+        block_size: u64,
+    ) -> Result<()> {
+        let mut stmt = self.metadb.prepare(
+            "INSERT OR REPLACE INTO block_checksum (block, checksum) \
+            values (?1, ?2)",
+        )?;
+
+        for (i, block) in data.chunks(block_size as usize).enumerate() {
+            let _rows_affected =
+                stmt.execute(params![first + i as u64, block_checksum(block)])?;
+        }
+
+        Ok(())
+    }
+
+    fn get_checksums(
+        &self,
+        first: u64,
+        count: u64,
+    ) -> Result<Vec<Option<u32>>> {
+        let mut checksums = vec![None; count as usize];
+        if !self.checksums {
+            return Ok(checksums);
+        }
+
+        let mut stmt = self.metadb.prepare(
+            "SELECT block, checksum FROM block_checksum \
+            WHERE block >= ?1 AND block < ?2",
+        )?;
+        let rows = stmt.query_map(params![first, first + count], |row| {
+            Ok((row.get::<_, u64>(0)?, row.get::<_, u32>(1)?))
+        })?;
+
+        for row in rows {
+            let (block, checksum) = row?;
+            checksums[(block - first) as usize] = Some(checksum);
+        }
+
+        Ok(checksums)
+    }
+
+    /*
+     * Compare blocks read from the extent file, starting at block first,
+     * against the checksums stored when they were written, and return the
+     * blocks that don't match.  A block without a checksum has not been
+     * written since checksums were added, and can't be checked.
+     */
+    fn bad_blocks(
+        &self,
+        first: u64,
+        data: &[u8],
+        block_size: u64,
+    ) -> Result<Vec<u64>> {
+        let count = data.len() as u64 / block_size;
+        let checksums = self.get_checksums(first, count)?;
+
+        Ok(data
+            .chunks(block_size as usize)
+            .zip(checksums)
+            .enumerate()
+            .filter(|(_, (block, checksum))| {
+                matches!(checksum, Some(c) if *c != block_checksum(block))
+            })
+            .map(|(i, _)| first + i as u64)
+            .collect())
+    }
+
+    /*
+     * Throw away the stored checksums and compute them again from what is
+     * in the extent file now.
+     */
+    fn rehash(&self, block_size: u64, blocks: u64) -> Result<()> {
+        let mut data = vec![0u8; (block_size * blocks) as usize];
+        self.file.read_exact_at(&mut data, 0)?;
+
+        let tx = self.metadb.unchecked_transaction()?;
+        let _rows_affected =
+            self.metadb.execute("DELETE FROM block_checksum", [])?;
+        self.set_checksums(0, &data, block_size)?;
+        tx.commit()?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct BlockInfo {
+    pub data: Vec<u8>,
+    pub nonce: Option<Vec<u8>>,
+    pub tag: Option<Vec<u8>>,
+    /*
+     * The checksum stored when the block was written, if there is one.
+     */
+    pub checksum: Option<u32>,
+}
+
+impl BlockInfo {
+    /*
+     * False if the data on disk doesn't match the checksum stored for it.
+     */
+    pub fn checksum_ok(&self) -> bool {
+        match self.checksum {
+            Some(checksum) => checksum == block_checksum(&self.data),
+            None => true,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ExtentMeta {
     /**
      * Version information regarding the extent structure.
-     * Not currently connected to anything XXX
+     * Checked when the extent is opened.
      */
     pub ext_version: u32,
     /**
@@ -201,7 +560,7 @@ impl Default for ExtentMeta {
  * Produce a PathBuf that refers to the backing file for extent "number",
  * anchored under "dir".
  */
-fn extent_path<P: AsRef<Path>>(dir: P, number: u32) -> PathBuf {
+pub fn extent_path<P: AsRef<Path>>(dir: P, number: u32) -> PathBuf {
     let mut out = dir.as_ref().to_path_buf();
     out.push(format!("{:02X}", (number >> 24) & 0xFF));
     out.push(format!("{:03X}", (number >> 12) & 0xFFF));
@@ -209,12 +568,51 @@ fn extent_path<P: AsRef<Path>>(dir: P, number: u32) -> PathBuf {
     out
 }
 
-fn config_path<P: AsRef<Path>>(dir: P) -> PathBuf {
+/*
+ * Open the metadata db next to an extent file.  Every update to it is
+ * on disk before the call that made it returns.
+ */
+fn open_metadb(path: &Path) -> Result<Connection> {
+    let metadb = Connection::open(path)?;
+    assert!(metadb.is_autocommit());
+    metadb.pragma_update(None, "journal_mode", &"WAL")?;
+    metadb.pragma_update(None, "synchronous", &"FULL")?;
+    Ok(metadb)
+}
+
+/*
+ * A read only region may be on a read only filesystem (a snapshot, say),
+ * so don't ask for anything that would need to write there.
+ */
+fn open_metadb_read_only(path: &Path) -> Result<Connection> {
+    let metadb =
+        Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    Ok(metadb)
+}
+
+pub fn config_path<P: AsRef<Path>>(dir: P) -> PathBuf {
     let mut out = dir.as_ref().to_path_buf();
     out.push("region.json");
     out
 }
 
+pub fn promoted_path<P: AsRef<Path>>(dir: P) -> PathBuf {
+    let mut out = dir.as_ref().to_path_buf();
+    out.push("promoted.json");
+    out
+}
+
+/*
+ * The last upstairs promoted to active on a region, and the generation
+ * it came with.  This is saved so a downstairs that restarts still
+ * refuses an upstairs with an older generation.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct Promoted {
+    upstairs: Uuid,
+    gen: u64,
+}
+
 impl Extent {
     /**
      * Open an existing extent file at the location requested.
@@ -224,6 +622,8 @@ impl Extent {
         dir: P,
         def: &RegionDefinition,
         number: u32,
+        read_only: bool,
+        io: &IoEngine,
     ) -> Result<Extent> {
         /*
          * Store extent data in files within a directory hierarchy so that
@@ -237,38 +637,81 @@ impl Extent {
         /*
          * Open the extent file and verify the size is as we expect.
          */
-        let file = match OpenOptions::new().read(true).write(true).open(&path) {
-            Err(e) => {
-                bail!("Error: e {} No extent file found for {:?}", e, path);
-            }
-            Ok(f) => {
-                let cur_size = f.metadata().unwrap().len();
-                if size != cur_size {
-                    bail!(
-                        "File size {:?} does not match expected {:?}",
-                        size,
-                        cur_size
-                    );
+        let file =
+            match OpenOptions::new().read(true).write(!read_only).open(&path) {
+                Err(e) => {
+                    bail!("Error: e {} No extent file found for {:?}", e, path);
                 }
-                f
-            }
-        };
+                Ok(f) => {
+                    let cur_size = f.metadata().unwrap().len();
+                    if size != cur_size {
+                        bail!(
+                            "File size {:?} does not match expected {:?}",
+                            size,
+                            cur_size
+                        );
+                    }
+                    f
+                }
+            };
 
         /*
-         * Open a connection to the metadata db
+         * Open a connection to the metadata db, and make sure what we find
+         * there is something we can trust before taking any IO.
          */
         path.set_extension("db");
-        let metadb = Connection::open(&path)?;
-        assert!(metadb.is_autocommit());
-        metadb.pragma_update(None, "journal_mode", &"WAL")?;
+        let metadb = if read_only {
+            open_metadb_read_only(&path)?
+        } else {
+            open_metadb(&path)?
+        };
+
+        /*
+         * Extents made before block checksums need the table added.  We
+         * can't do that to a read only one, so it goes without.
+         */
+        let checksums = if read_only {
+            metadb.query_row(
+                "SELECT COUNT(*) FROM sqlite_master \
+                WHERE type='table' AND name='block_checksum'",
+                [],
+                |row| row.get::<_, u64>(0),
+            )? == 1
+        } else {
+            metadb.execute(CREATE_BLOCK_CHECKSUM, [])?;
+            metadb.execute(CREATE_NEEDS_REPAIR, [])?;
+            true
+        };
 
-        // XXX: schema updates?
+        let inner = Inner {
+            file: io.file(file),
+            metadb,
+            checksums,
+            dirty_on_disk: Cell::new(false),
+        };
+        if let Err(e) = inner.validate(bcount) {
+            bail!("extent {} metadata {:?}: {}", number, path, e);
+        }
+
+        /*
+         * A crash can leave the data and checksums of a dirty extent out
+         * of step with each other.  The upstairs compares and repairs
+         * dirty extents before it uses them, so take what is there now.
+         * An extent that is dirty because a block failed its checksum
+         * keeps the checksums that caught it until it is repaired.
+         */
+        let needs_repair = inner.needs_repair_noted()?;
+        if !read_only && inner.dirty()? && !needs_repair {
+            inner.rehash(def.block_size(), bcount)?;
+        }
 
         Ok(Extent {
             number,
             block_size: def.block_size(),
             extent_size: def.extent_size(),
-            inner: Mutex::new(Inner { file, metadb }),
+            read_only,
+            needs_repair: AtomicBool::new(needs_repair),
+            inner: Mutex::new(inner),
         })
     }
 
@@ -281,6 +724,7 @@ impl Extent {
         dir: P,
         def: &RegionDefinition,
         number: u32,
+        io: &IoEngine,
     ) -> Result<Extent> {
         /*
          * Store extent data in files within a directory hierarchy so that
@@ -299,26 +743,26 @@ impl Extent {
         let size = def.block_size().checked_mul(bcount).unwrap();
 
         mkdir_for_file(&path)?;
-        let mut file = OpenOptions::new()
+        let file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
             .open(&path)?;
 
         file.set_len(size)?;
-        file.seek(SeekFrom::Start(0))?;
 
         /*
          * Create the metadata db
          */
         path.set_extension("db");
-        let metadb = Connection::open(&path)?;
-        assert!(metadb.is_autocommit());
-        metadb.pragma_update(None, "journal_mode", &"WAL")?;
+        let metadb = open_metadb(&path)?;
 
         /*
-         * Create tables and insert base data
+         * Create tables and insert base data.  This is one transaction, a
+         * crash part way through leaves no tables and the extent fails to
+         * open.
          */
+        let tx = metadb.unchecked_transaction()?;
         metadb.execute(
             "CREATE TABLE metadata (
                 name TEXT PRIMARY KEY,
@@ -356,6 +800,9 @@ impl Extent {
             )",
             [],
         )?;
+        metadb.execute(CREATE_BLOCK_CHECKSUM, [])?;
+        metadb.execute(CREATE_NEEDS_REPAIR, [])?;
+        tx.commit()?;
 
         /*
          * Complete the construction of our new extent
@@ -364,7 +811,14 @@ impl Extent {
             number,
             block_size: def.block_size(),
             extent_size: def.extent_size(),
-            inner: Mutex::new(Inner { file, metadb }),
+            read_only: false,
+            needs_repair: AtomicBool::new(false),
+            inner: Mutex::new(Inner {
+                file: io.file(file),
+                metadb,
+                checksums: true,
+                dirty_on_disk: Cell::new(false),
+            }),
         })
     }
 
@@ -376,29 +830,138 @@ impl Extent {
         self.number
     }
 
+    pub fn needs_repair(&self) -> bool {
+        self.needs_repair.load(Ordering::SeqCst)
+    }
+
+    /*
+     * Remember that this extent has bad data.  It is reported as dirty,
+     * so the upstairs replaces it with a good copy the next time it
+     * compares extents, and the blocks are noted in the metadata db so
+     * that is kept across a restart.
+     */
+    fn mark_needs_repair(&self, inner: &Inner, bad: &[u64]) {
+        println!(
+            "extent {}: checksum mismatch in blocks {:?}, needs repair",
+            self.number, bad
+        );
+        self.needs_repair.store(true, Ordering::SeqCst);
+
+        if !self.read_only {
+            if let Err(e) = inner.set_needs_repair(bad) {
+                println!(
+                    "extent {}: failed to note bad blocks: {:?}",
+                    self.number, e
+                );
+            }
+        }
+    }
+
+    /*
+     * Read the whole extent and check every block against its checksum,
+     * returning any that don't match.
+     */
+    pub fn check_blocks(&self) -> Result<Vec<u64>> {
+        let inner = self.inner.lock().unwrap();
+        self.check_blocks_locked(&inner)
+    }
+
+    fn check_blocks_locked(&self, inner: &Inner) -> Result<Vec<u64>> {
+        let mut data =
+            vec![0u8; (self.block_size * self.extent_size.value) as usize];
+        inner.file.read_exact_at(&mut data, 0)?;
+
+        inner.bad_blocks(0, &data, self.block_size)
+    }
+
+    /*
+     * Check every block, and mark the extent as needing repair if any
+     * of them are bad.
+     */
+    pub fn scrub(&self) -> Result<Vec<u64>> {
+        let inner = self.inner.lock().unwrap();
+
+        let bad = self.check_blocks_locked(&inner)?;
+        if !bad.is_empty() {
+            self.mark_needs_repair(&inner, &bad);
+        }
+
+        Ok(bad)
+    }
+
+    /*
+     * Everything stored for one block, read as is without checking it
+     * against its checksum.  This is for looking at a damaged extent.
+     */
+    pub fn block_info(&self, block: u64) -> Result<BlockInfo> {
+        if block >= self.extent_size.value {
+            bail!("block {} past the end of extent {}", block, self.number);
+        }
+
+        let inner = self.inner.lock().unwrap();
+
+        let mut data = vec![0u8; self.block_size as usize];
+        inner
+            .file
+            .read_exact_at(&mut data, block * self.block_size)?;
+
+        let (nonce, tag) = match inner.get_encryption_context(block)? {
+            Some((nonce, tag)) => (Some(nonce), Some(tag)),
+            None => (None, None),
+        };
+        let checksum = inner.get_checksums(block, 1)?.pop().unwrap();
+
+        Ok(BlockInfo {
+            data,
+            nonce,
+            tag,
+            checksum,
+        })
+    }
+
     #[instrument]
     pub fn read(
         &self,
         request: &crucible_protocol::ReadRequest,
     ) -> Result<crucible_protocol::ReadResponse, CrucibleError> {
+        /*
+         * Don't make a buffer for more than the extent holds just because
+         * we were asked to.
+         */
+        if request.num_blocks > self.extent_size.value {
+            crucible_bail!(OffsetInvalid);
+        }
+
         let mut response = crucible_protocol::ReadResponse::from_request(
             request,
             self.block_size as usize,
         );
+        let len = request.num_blocks as usize * self.block_size as usize;
 
-        self.check_input(request.offset, &response.data)?;
+        self.check_range(request.offset, len)?;
 
         let byte_offset = request.offset.value * self.block_size;
 
-        let mut inner = self.inner.lock().unwrap();
-        inner.file.seek(SeekFrom::Start(byte_offset))?;
+        let inner = self.inner.lock().unwrap();
 
-        /*
-         * XXX This read_exact only works because we have filled our buffer
-         * with data ahead of time.  If we want to use an uninitialized
-         * buffer, then we need a different read or type for the destination
-         */
-        inner.file.read_exact(&mut response.data)?;
+        inner
+            .file
+            .read_append_at(&mut response.data, len, byte_offset)?;
+
+        let bad = inner.bad_blocks(
+            request.offset.value,
+            &response.data,
+            self.block_size,
+        )?;
+        if !bad.is_empty() {
+            self.mark_needs_repair(&inner, &bad);
+            crucible_bail!(
+                IoError,
+                "extent {}: checksum mismatch in blocks {:?}",
+                self.number,
+                bad
+            );
+        }
 
         let ctx = inner.get_encryption_context(request.offset.value)?;
         if let Some((nonce, tag)) = ctx {
@@ -421,23 +984,25 @@ impl Extent {
         offset: Block,
         data: &[u8],
     ) -> Result<(), CrucibleError> {
-        if (data.len() % self.block_size as usize) != 0 {
-            crucible_bail!(DataLenUnaligned);
-        }
-
-        if offset.block_size_in_bytes() != self.block_size as u32 {
-            crucible_bail!(BlockSizeMismatch);
-        }
+        self.check_range(offset, data.len())
+    }
 
-        if offset.shift != self.extent_size.shift {
-            crucible_bail!(BlockSizeMismatch);
-        }
+    fn check_range(
+        &self,
+        offset: Block,
+        len: usize,
+    ) -> Result<(), CrucibleError> {
+        offset.check_io(len, self.block_size)?;
 
         let total_size = self.block_size * self.extent_size.value;
-        let byte_offset = offset.value * self.block_size;
-
-        if (byte_offset + data.len() as u64) > total_size {
-            crucible_bail!(OffsetInvalid);
+        let end = offset
+            .value
+            .checked_mul(self.block_size)
+            .and_then(|byte_offset| byte_offset.checked_add(len as u64));
+
+        match end {
+            Some(end) if end <= total_size => {}
+            _ => crucible_bail!(OffsetInvalid),
         }
 
         Ok(())
@@ -448,24 +1013,60 @@ impl Extent {
         &self,
         write: &crucible_protocol::Write,
     ) -> Result<(), CrucibleError> {
-        let mut inner = self.inner.lock().unwrap();
+        let inner = self.inner.lock().unwrap();
 
         self.check_input(write.offset, &write.data)?;
 
+        /*
+         * A nonce and tag belong to one block, so a write that has them
+         * must be one block.
+         */
+        if write.nonce.is_some() != write.tag.is_some() {
+            crucible_bail!(
+                GenericError,
+                "write to extent {} has only one of nonce and tag",
+                self.number
+            );
+        }
+        if write.nonce.is_some() && write.data.len() as u64 != self.block_size {
+            crucible_bail!(
+                InvalidNumberOfBlocks,
+                "an encrypted write must be one block"
+            );
+        }
+
         inner.set_dirty()?;
 
         let byte_offset = write.offset.value * self.block_size;
 
-        inner.file.seek(SeekFrom::Start(byte_offset))?;
-        inner.file.write_all(&write.data)?;
+        inner.file.write_all_at(&write.data, byte_offset)?;
 
-        if write.nonce.is_some() && write.tag.is_some() {
-            inner.set_encryption_context(
-                write.offset.value,
-                write.nonce.as_ref().unwrap(),
-                write.tag.as_ref().unwrap(),
-            )?;
+        /*
+         * The checksums go in after the data is written, along with the
+         * encryption context.
+         */
+        let tx = inner
+            .metadb
+            .unchecked_transaction()
+            .map_err(anyhow::Error::new)?;
+        inner.set_checksums(
+            write.offset.value,
+            &write.data,
+            self.block_size,
+        )?;
+
+        match (&write.nonce, &write.tag) {
+            (Some(nonce), Some(tag)) => {
+                inner.set_encryption_context(write.offset.value, nonce, tag)?;
+            }
+            _ => {
+                inner.clear_encryption_contexts(
+                    write.offset.value,
+                    write.data.len() as u64 / self.block_size,
+                )?;
+            }
         }
+        tx.commit().map_err(anyhow::Error::new)?;
 
         Ok(())
     }
@@ -476,7 +1077,7 @@ impl Extent {
         new_flush: u64,
         new_gen: u64,
     ) -> Result<(), CrucibleError> {
-        let mut inner = self.inner.lock().unwrap();
+        let inner = self.inner.lock().unwrap();
 
         if !inner.dirty()? {
             /*
@@ -490,8 +1091,7 @@ impl Extent {
          * We must first fsync to get any outstanding data written to disk.
          * This must be done before we update the flush number.
          */
-        if unsafe { fsync(inner.file.as_raw_fd()) } == -1 {
-            let e = std::io::Error::last_os_error();
+        if let Err(e) = inner.file.sync() {
             /*
              * XXX Retry?  Mark extent as broken?
              */
@@ -503,18 +1103,149 @@ impl Extent {
             );
         }
 
-        inner.file.seek(SeekFrom::Start(0))?;
-
-        inner.set_flush_number(new_flush, new_gen)?;
+        inner.set_flush_number(new_flush, new_gen)?;
+
+        Ok(())
+    }
+
+    /**
+     * Read everything about this extent, for sending to a downstairs that
+     * is being repaired.  An extent with a block that doesn't match its
+     * checksum is not a good copy, so the read fails.
+     */
+    pub fn repair_read(
+        &self,
+    ) -> Result<crucible_protocol::ExtentData, CrucibleError> {
+        let inner = self.inner.lock().unwrap();
+
+        let mut data =
+            vec![0u8; (self.block_size * self.extent_size.value) as usize];
+        inner.file.read_exact_at(&mut data, 0)?;
+
+        let bad = inner.bad_blocks(0, &data, self.block_size)?;
+        if !bad.is_empty() {
+            self.mark_needs_repair(&inner, &bad);
+            crucible_bail!(
+                IoError,
+                "extent {}: checksum mismatch in blocks {:?}",
+                self.number,
+                bad
+            );
+        }
+        let checksums = inner.get_checksums(0, self.extent_size.value)?;
+
+        let mut contexts = Vec::with_capacity(self.extent_size.value as usize);
+        for block in 0..self.extent_size.value {
+            contexts.push(inner.get_encryption_context(block)?);
+        }
+
+        Ok(crucible_protocol::ExtentData {
+            eid: self.number as u64,
+            data: bytes::Bytes::from(data),
+            contexts,
+            checksums,
+            gen_number: inner.gen_number()?,
+            flush_number: inner.flush_number()?,
+        })
+    }
+
+    /**
+     * Replace the contents of this extent with what was read from another
+     * downstairs.  The data is on disk before the flush and generation
+     * numbers are updated, so a crash part way through leaves the extent
+     * looking out of date rather than repaired.
+     */
+    pub fn repair_write(
+        &self,
+        extent: &crucible_protocol::ExtentData,
+    ) -> Result<(), CrucibleError> {
+        let inner = self.inner.lock().unwrap();
+
+        if extent.data.len() as u64 != self.block_size * self.extent_size.value
+            || extent.contexts.len() as u64 != self.extent_size.value
+            || extent.checksums.len() as u64 != self.extent_size.value
+        {
+            crucible_bail!(
+                InvalidNumberOfBlocks,
+                "extent {}: repair data has {} bytes, {} contexts and {} \
+                checksums",
+                self.number,
+                extent.data.len(),
+                extent.contexts.len(),
+                extent.checksums.len()
+            );
+        }
+
+        /*
+         * Don't take a copy that was damaged on the way here.
+         */
+        let bad: Vec<usize> = extent
+            .data
+            .chunks(self.block_size as usize)
+            .zip(extent.checksums.iter())
+            .enumerate()
+            .filter(|(_, (block, checksum))| {
+                matches!(checksum, Some(c) if *c != block_checksum(block))
+            })
+            .map(|(i, _)| i)
+            .collect();
+        if !bad.is_empty() {
+            crucible_bail!(
+                IoError,
+                "extent {}: repair data fails checksum in blocks {:?}",
+                self.number,
+                bad
+            );
+        }
+
+        inner.set_dirty()?;
+
+        inner.file.write_all_at(&extent.data, 0)?;
+
+        if let Err(e) = inner.file.sync() {
+            crucible_bail!(
+                IoError,
+                "extent {}: repair fsync failure: {:?}",
+                self.number,
+                e
+            );
+        }
+
+        /*
+         * The new contexts go in with the new flush number, so the
+         * metadata never describes a mix of the old and new data.
+         */
+        let tx = inner
+            .metadb
+            .unchecked_transaction()
+            .map_err(anyhow::Error::new)?;
+        let _rows_affected = inner
+            .metadb
+            .execute("DELETE FROM encryption_context", [])
+            .map_err(anyhow::Error::new)?;
+        let _rows_affected = inner
+            .metadb
+            .execute("DELETE FROM block_checksum", [])
+            .map_err(anyhow::Error::new)?;
+        let _rows_affected = inner
+            .metadb
+            .execute("DELETE FROM needs_repair", [])
+            .map_err(anyhow::Error::new)?;
+        inner.set_checksums(0, &extent.data, self.block_size)?;
+        for (block, ctx) in extent.contexts.iter().enumerate() {
+            if let Some((nonce, tag)) = ctx {
+                inner.set_encryption_context(block as u64, nonce, tag)?;
+            }
+        }
+
+        inner.write_flush_number(extent.flush_number, extent.gen_number)?;
+        tx.commit().map_err(anyhow::Error::new)?;
+        self.needs_repair.store(false, Ordering::SeqCst);
 
         Ok(())
     }
 }
 
-extern "C" {
-    fn fsync(fildes: i32) -> i32;
-}
-
 /**
  * The main structure describing a region.
  */
@@ -523,6 +1254,25 @@ pub struct Region {
     dir: PathBuf,
     def: RegionDefinition,
     pub extents: Vec<Extent>,
+    /*
+     * A read only region is opened without write access to any of its
+     * files, and refuses anything that would change it.
+     */
+    read_only: bool,
+    /*
+     * Extents that may have been written since they were last flushed.
+     * A flush only needs to visit these, rather than asking every extent
+     * in the region if it is dirty.
+     */
+    dirty_extents: Mutex<BTreeSet<usize>>,
+    /*
+     * What is saved in promoted.json.
+     */
+    promoted: Mutex<Option<Promoted>>,
+    /*
+     * How IO to the extent files is done.
+     */
+    io: IoEngine,
 }
 
 impl Region {
@@ -556,6 +1306,10 @@ impl Region {
             dir: dir.as_ref().to_path_buf(),
             def,
             extents: Vec::new(),
+            read_only: false,
+            dirty_extents: Mutex::new(BTreeSet::new()),
+            promoted: Mutex::new(None),
+            io: IoEngine::default(),
         };
 
         region.open_extents(true)?;
@@ -570,6 +1324,27 @@ impl Region {
         dir: P,
         options: RegionOptions,
         verbose: bool,
+        read_only: bool,
+    ) -> Result<Region> {
+        Region::open_with_backend(
+            dir,
+            options,
+            verbose,
+            read_only,
+            IoBackend::default(),
+        )
+    }
+
+    /**
+     * Open an existing region file, doing IO to its extents with the
+     * given backend.
+     */
+    pub fn open_with_backend<P: AsRef<Path>>(
+        dir: P,
+        options: RegionOptions,
+        verbose: bool,
+        read_only: bool,
+        backend: IoBackend,
     ) -> Result<Region> {
         options.validate()?;
 
@@ -578,10 +1353,13 @@ impl Region {
          * We are expecting to find a region config file and extent files.
          * If we do not, then report error and exit.
          */
-        let def = match read_json(&cp) {
+        let def: RegionDefinition = match read_json(&cp) {
             Ok(def) => def,
             Err(e) => bail!("Error {:?} opening region config {:?}", e, cp),
         };
+        if let Err(e) = def.validate() {
+            bail!("Region config {:?} is not valid: {}", cp, e);
+        }
 
         if verbose {
             println!("Opened existing region file {:?}", cp);
@@ -593,10 +1371,28 @@ impl Region {
             dir: dir.as_ref().to_path_buf(),
             def,
             extents: Vec::new(),
+            read_only,
+            dirty_extents: Mutex::new(BTreeSet::new()),
+            promoted: Mutex::new(read_json_maybe(promoted_path(dir.as_ref()))?),
+            io: IoEngine::new(backend),
         };
 
         region.open_extents(false)?;
 
+        /*
+         * Anything written but not flushed before we last stopped still
+         * needs to go out with the next flush.
+         */
+        let dirty = region.dirty()?;
+        region.dirty_extents = Mutex::new(
+            dirty
+                .iter()
+                .enumerate()
+                .filter(|(_, dirty)| **dirty)
+                .map(|(eid, _)| eid)
+                .collect(),
+        );
+
         Ok(region)
     }
 
@@ -616,9 +1412,16 @@ impl Region {
         for eid in next_eid..self.def.extent_count() {
             let new_extent: Extent;
             if create {
-                new_extent = Extent::create(&self.dir, &self.def, eid)?;
+                new_extent =
+                    Extent::create(&self.dir, &self.def, eid, &self.io)?;
             } else {
-                new_extent = Extent::open(&self.dir, &self.def, eid)?;
+                new_extent = Extent::open(
+                    &self.dir,
+                    &self.def,
+                    eid,
+                    self.read_only,
+                    &self.io,
+                )?;
             }
             self.extents.push(new_extent);
             assert_eq!(self.extents[eid as usize].number, eid);
@@ -632,6 +1435,10 @@ impl Region {
      * and what is requested, go out and create the new extent files.
      */
     pub fn extend(&mut self, newsize: u32) -> Result<()> {
+        if self.read_only {
+            bail!("will not extend a read only region");
+        }
+
         if newsize < self.def.extent_count() {
             bail!(
                 "will not truncate {} -> {} for now",
@@ -659,6 +1466,44 @@ impl Region {
         self.def
     }
 
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /**
+     * The UUID and generation of the last upstairs promoted to active
+     * here, as saved by set_promoted.
+     */
+    pub fn promoted(&self) -> Option<(Uuid, u64)> {
+        self.promoted.lock().unwrap().map(|p| (p.upstairs, p.gen))
+    }
+
+    /**
+     * Save the upstairs being promoted to active, and its generation.
+     * This is on disk before it returns.
+     */
+    pub fn set_promoted(&self, upstairs: Uuid, gen: u64) -> Result<()> {
+        if self.read_only {
+            bail!("will not promote an upstairs on a read only region");
+        }
+
+        let mut promoted = self.promoted.lock().unwrap();
+        let new = Promoted { upstairs, gen };
+        let path = promoted_path(&self.dir);
+        write_json(&path, &new, true)?;
+        OpenOptions::new().read(true).open(&path)?.sync_all()?;
+        *promoted = Some(new);
+        Ok(())
+    }
+
+    pub fn io_backend(&self) -> IoBackend {
+        self.io.backend()
+    }
+
     pub fn flush_numbers(&self) -> Result<Vec<u64>> {
         let mut ver = self
             .extents
@@ -684,13 +1529,32 @@ impl Region {
             .collect::<Result<Vec<_>>>()
     }
 
+    /*
+     * An extent that failed a checksum is reported as dirty until it has
+     * been repaired, even if a flush has since cleared its dirty bit.
+     */
     pub fn dirty(&self) -> Result<Vec<bool>> {
         self.extents
             .iter()
-            .map(|e| e.inner().dirty())
+            .map(|e| Ok(e.inner().dirty()? || e.needs_repair()))
             .collect::<Result<Vec<_>>>()
     }
 
+    /*
+     * The extent an IO is for.  The extent ID comes from the other side,
+     * so it has to be checked before it is used.
+     */
+    fn extent(&self, eid: u64) -> Result<&Extent, CrucibleError> {
+        match self.extents.get(eid as usize) {
+            Some(extent) => Ok(extent),
+            None => crucible_bail!(OffsetInvalid),
+        }
+    }
+
+    pub fn scrub_extent(&self, eid: usize) -> Result<Vec<u64>> {
+        self.extents[eid].scrub()
+    }
+
     #[instrument]
     pub fn single_block_region_write(
         &self,
@@ -714,9 +1578,25 @@ impl Region {
         &self,
         writes: &[crucible_protocol::Write],
     ) -> Result<(), CrucibleError> {
+        if self.read_only {
+            crucible_bail!(ModifyingReadOnlyRegion);
+        }
+
         for write in writes {
-            let extent = &self.extents[write.eid as usize];
-            extent.write(write)?;
+            let extent = self.extent(write.eid)?;
+            let result = extent.write(write);
+
+            /*
+             * Only mark the extent once the write is done with it, so a
+             * flush that runs at the same time either sees the write or
+             * leaves the extent for the next flush.  A failed write may
+             * still have dirtied the extent.
+             */
+            self.dirty_extents
+                .lock()
+                .unwrap()
+                .insert(write.eid as usize);
+            result?;
         }
         Ok(())
     }
@@ -740,16 +1620,36 @@ impl Region {
         let mut responses = Vec::with_capacity(requests.len());
 
         for request in requests {
-            let extent = &self.extents[request.eid as usize];
-            responses.push(extent.read(request)?);
+            responses.push(self.extent(request.eid)?.read(request)?);
         }
 
         Ok(responses)
     }
 
+    #[instrument]
+    pub fn extent_repair_read(
+        &self,
+        eid: u64,
+    ) -> Result<crucible_protocol::ExtentData, CrucibleError> {
+        self.extent(eid)?.repair_read()
+    }
+
+    #[instrument]
+    pub fn extent_repair_write(
+        &self,
+        extent: &crucible_protocol::ExtentData,
+    ) -> Result<(), CrucibleError> {
+        if self.read_only {
+            crucible_bail!(ModifyingReadOnlyRegion);
+        }
+
+        self.extent(extent.eid)?.repair_write(extent)
+    }
+
     /*
-     * Send a flush to all extents. The provided flush number is
-     * what an extent should use if a flush is required.
+     * Send a flush to every extent written since the last flush. The
+     * provided flush number is what an extent should use if a flush is
+     * required.  Extents with no new writes keep their old flush number.
      */
     #[instrument]
     pub fn region_flush(
@@ -757,21 +1657,71 @@ impl Region {
         flush_number: u64,
         gen_number: u64,
     ) -> Result<(), CrucibleError> {
-        // XXX How to we convert between usize and u32 correctly?
-        for eid in 0..self.def.extent_count() {
-            let extent = &self.extents[eid as usize];
-            extent.flush_block(flush_number, gen_number)?;
+        self.region_flush_limited(flush_number, gen_number, None)
+    }
+
+    /*
+     * Flush, but only the extents below extent_limit.  Dirty extents at
+     * or above the limit stay dirty and keep their old flush number; the
+     * upstairs uses this while a live repair has not reached them yet,
+     * so an extent that still holds stale data is not stamped as current.
+     */
+    #[instrument]
+    pub fn region_flush_limited(
+        &self,
+        flush_number: u64,
+        gen_number: u64,
+        extent_limit: Option<u64>,
+    ) -> Result<(), CrucibleError> {
+        if self.read_only {
+            crucible_bail!(ModifyingReadOnlyRegion);
+        }
+
+        let dirty: Vec<usize> = {
+            let mut dirty_extents = self.dirty_extents.lock().unwrap();
+            let keep = match extent_limit {
+                Some(limit) => dirty_extents.split_off(&(limit as usize)),
+                None => BTreeSet::new(),
+            };
+            std::mem::replace(&mut *dirty_extents, keep)
+                .into_iter()
+                .collect()
+        };
+
+        for (i, eid) in dirty.iter().enumerate() {
+            let extent = &self.extents[*eid];
+            if let Err(e) = extent.flush_block(flush_number, gen_number) {
+                /*
+                 * Put back this extent and the ones we didn't get to, so
+                 * the next flush tries them again.
+                 */
+                self.dirty_extents
+                    .lock()
+                    .unwrap()
+                    .extend(dirty[i..].iter().copied());
+                return Err(e);
+            }
         }
         Ok(())
     }
+
+    /*
+     * How many extents the next flush will visit.
+     */
+    pub fn dirty_extent_count(&self) -> usize {
+        self.dirty_extents.lock().unwrap().len()
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::backend::SyncFile;
     use crate::dump::dump_region;
     use bytes::{BufMut, BytesMut};
     use rand::Rng;
+    use std::fs::File;
+    use std::io::{Seek, SeekFrom, Write};
     use std::path::PathBuf;
     use tempfile::tempdir;
     use uuid::Uuid;
@@ -784,8 +1734,10 @@ mod test {
         let ff = File::open("/dev/null").unwrap();
 
         let inn = Inner {
-            file: ff,
+            file: Box::new(SyncFile::new(ff)),
             metadb: Connection::open_in_memory().unwrap(),
+            checksums: false,
+            dirty_on_disk: Cell::new(false),
         };
 
         /*
@@ -796,6 +1748,8 @@ mod test {
             number: 0,
             block_size: 512,
             extent_size: Block::new_512(100),
+            read_only: false,
+            needs_repair: AtomicBool::new(false),
             inner: Mutex::new(inn),
         }
     }
@@ -828,7 +1782,7 @@ mod test {
     fn new_existing_region() -> Result<()> {
         let dir = tempdir()?;
         let _ = Region::create(&dir, new_region_options());
-        let _ = Region::open(&dir, new_region_options(), false);
+        let _ = Region::open(&dir, new_region_options(), false, false);
         Ok(())
     }
 
@@ -839,6 +1793,7 @@ mod test {
             &"/tmp/12345678-1111-2222-3333-123456789999/notadir",
             new_region_options(),
             false,
+            false,
         )
         .unwrap();
         ()
@@ -1091,4 +2046,543 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn flush_metadata_survives_reopen() -> Result<()> {
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, new_region_options())?;
+        region.extend(2)?;
+
+        let mut buffer = BytesMut::with_capacity(512);
+        buffer.put_slice(&[3u8; 512]);
+        region.single_block_region_write(
+            1,
+            Block::new_512(0),
+            buffer.freeze(),
+            None,
+            None,
+        )?;
+        assert!(region.extents[1].inner().meta()?.dirty);
+        region.region_flush(5, 3)?;
+        drop(region);
+
+        let region = Region::open(&dir, new_region_options(), false, false)?;
+        let meta = region.extents[1].inner().meta()?;
+        assert_eq!(meta.flush_number, 5);
+        assert_eq!(meta.gen_number, 3);
+        assert!(!meta.dirty);
+        assert_eq!(region.extents[0].inner().meta()?.flush_number, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn dirty_bit_written_once_per_flush() -> Result<()> {
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, new_region_options())?;
+        region.extend(1)?;
+
+        let write = |region: &Region, block: u64| {
+            let mut buffer = BytesMut::with_capacity(512);
+            buffer.put_slice(&[block as u8; 512]);
+            region.single_block_region_write(
+                0,
+                Block::new_512(block),
+                buffer.freeze(),
+                None,
+                None,
+            )
+        };
+
+        write(&region, 0)?;
+        assert!(region.extents[0].inner().dirty()?);
+
+        // Once it is set, writes leave it alone.
+        region.extents[0]
+            .inner()
+            .metadb
+            .execute("UPDATE metadata SET value=0 WHERE name='dirty'", [])?;
+        write(&region, 1)?;
+        assert!(!region.extents[0].inner().dirty()?);
+
+        // Until a flush clears it.
+        region.extents[0]
+            .inner()
+            .metadb
+            .execute("UPDATE metadata SET value=1 WHERE name='dirty'", [])?;
+        region.region_flush(1, 1)?;
+        assert!(!region.extents[0].inner().dirty()?);
+        write(&region, 2)?;
+        assert!(region.extents[0].inner().dirty()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn block_checksum_known_value() {
+        assert_eq!(block_checksum(b"123456789"), 0xe306_9283);
+    }
+
+    #[test]
+    fn checksum_catches_corruption() -> Result<()> {
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, new_region_options())?;
+        region.extend(2)?;
+
+        let mut buffer = BytesMut::with_capacity(512);
+        buffer.put_slice(&[8u8; 512]);
+        region.single_block_region_write(
+            1,
+            Block::new_512(3),
+            buffer.freeze(),
+            None,
+            None,
+        )?;
+        region.region_flush(2, 1)?;
+        assert!(region.scrub_extent(1)?.is_empty());
+
+        // Change the data behind the region's back.
+        let mut file =
+            OpenOptions::new().write(true).open(extent_path(&dir, 1))?;
+        file.seek(SeekFrom::Start(3 * 512))?;
+        file.write_all(&[9u8; 512])?;
+        drop(file);
+
+        assert!(region
+            .single_block_region_read(crucible_protocol::ReadRequest {
+                eid: 1,
+                offset: Block::new_512(3),
+                num_blocks: 1,
+            })
+            .is_err());
+        assert_eq!(region.scrub_extent(1)?, vec![3]);
+        assert_eq!(region.dirty()?, vec![false, true]);
+
+        let block = region.extents[1].block_info(3)?;
+        assert_eq!(block.data, vec![9u8; 512]);
+        assert!(!block.checksum_ok());
+        assert!(region.extents[1].block_info(2)?.checksum.is_none());
+
+        // It is not a good copy to repair another downstairs from.
+        assert!(region.extent_repair_read(1).is_err());
+
+        // Opening it again doesn't take the bad block as good.
+        drop(region);
+        let region = Region::open(&dir, new_region_options(), false, false)?;
+        assert_eq!(region.dirty()?, vec![false, true]);
+        assert!(region.extents[1].needs_repair());
+        assert_eq!(region.scrub_extent(1)?, vec![3]);
+
+        // A copy that was damaged on the way is refused.
+        let mut data = vec![0u8; 10 * 512];
+        data[3 * 512..4 * 512].copy_from_slice(&[8u8; 512]);
+        let mut checksums = vec![None; 10];
+        checksums[3] = Some(block_checksum(&[7u8; 512]));
+        let mut extent = crucible_protocol::ExtentData {
+            eid: 1,
+            data: bytes::Bytes::from(data),
+            contexts: vec![None; 10],
+            checksums,
+            gen_number: 1,
+            flush_number: 3,
+        };
+        assert!(region.extent_repair_write(&extent).is_err());
+        assert!(region.extents[1].needs_repair());
+
+        // A good copy of the extent clears it.
+        extent.checksums[3] = Some(block_checksum(&[8u8; 512]));
+        region.extent_repair_write(&extent)?;
+
+        assert!(region.scrub_extent(1)?.is_empty());
+        assert_eq!(region.dirty()?, vec![false, false]);
+        drop(region);
+        let region = Region::open(&dir, new_region_options(), false, false)?;
+        assert!(!region.extents[1].needs_repair());
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_only_region_refuses_changes() -> Result<()> {
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, new_region_options())?;
+        region.extend(2)?;
+
+        let mut buffer = BytesMut::with_capacity(512);
+        buffer.put_slice(&[6u8; 512]);
+        region.single_block_region_write(
+            1,
+            Block::new_512(0),
+            buffer.freeze(),
+            None,
+            None,
+        )?;
+        region.region_flush(2, 1)?;
+        drop(region);
+
+        let mut region = Region::open(&dir, new_region_options(), false, true)?;
+        assert!(region.read_only());
+
+        let response = region.single_block_region_read(
+            crucible_protocol::ReadRequest {
+                eid: 1,
+                offset: Block::new_512(0),
+                num_blocks: 1,
+            },
+        )?;
+        assert_eq!(response.data.to_vec(), vec![6u8; 512]);
+
+        let mut buffer = BytesMut::with_capacity(512);
+        buffer.put_slice(&[7u8; 512]);
+        assert_eq!(
+            region.single_block_region_write(
+                1,
+                Block::new_512(0),
+                buffer.freeze(),
+                None,
+                None,
+            ),
+            Err(CrucibleError::ModifyingReadOnlyRegion)
+        );
+        assert_eq!(
+            region.region_flush(3, 1),
+            Err(CrucibleError::ModifyingReadOnlyRegion)
+        );
+        assert!(region.extend(3).is_err());
+        assert_eq!(region.flush_numbers()?, vec![0, 2]);
+
+        Ok(())
+    }
+
+    /*
+     * Create a region with one extent, run some SQL against the metadata
+     * db for that extent, and try to open the region again.
+     */
+    fn open_after(sql: &str) -> Result<Region> {
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, new_region_options())?;
+        region.extend(1)?;
+        drop(region);
+
+        let mut path = extent_path(&dir, 0);
+        path.set_extension("db");
+        let metadb = Connection::open(&path)?;
+        metadb.execute(sql, [])?;
+        drop(metadb);
+
+        Region::open(&dir, new_region_options(), false, false)
+    }
+
+    #[test]
+    fn open_validates_metadata() {
+        assert!(open_after("UPDATE metadata SET value=2 WHERE name='dirty'")
+            .is_err());
+        assert!(
+            open_after("DELETE FROM metadata WHERE name='gen_number'").is_err()
+        );
+        assert!(open_after(
+            "UPDATE metadata SET value=7 WHERE name='ext_version'"
+        )
+        .is_err());
+        assert!(open_after(
+            "INSERT INTO encryption_context (block, nonce, tag) \
+            VALUES (10, x'01', x'02')"
+        )
+        .is_err());
+        assert!(open_after(
+            "UPDATE metadata SET value=4 WHERE name='flush_number'"
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn flush_only_dirty_extents() -> Result<()> {
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, new_region_options())?;
+        region.extend(3)?;
+        assert_eq!(region.dirty_extent_count(), 0);
+
+        let mut buffer = BytesMut::with_capacity(512);
+        buffer.put_slice(&[4u8; 512]);
+        region.single_block_region_write(
+            2,
+            Block::new_512(1),
+            buffer.freeze(),
+            None,
+            None,
+        )?;
+        assert_eq!(region.dirty_extent_count(), 1);
+
+        region.region_flush(2, 1)?;
+        assert_eq!(region.dirty_extent_count(), 0);
+        assert_eq!(region.flush_numbers()?, vec![0, 0, 2]);
+
+        // Nothing new was written, so nothing changes.
+        region.region_flush(3, 1)?;
+        assert_eq!(region.flush_numbers()?, vec![0, 0, 2]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn flush_with_extent_limit() -> Result<()> {
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, new_region_options())?;
+        region.extend(3)?;
+
+        for eid in 0..3 {
+            let mut buffer = BytesMut::with_capacity(512);
+            buffer.put_slice(&[6u8; 512]);
+            region.single_block_region_write(
+                eid,
+                Block::new_512(0),
+                buffer.freeze(),
+                None,
+                None,
+            )?;
+        }
+        assert_eq!(region.dirty_extent_count(), 3);
+
+        // Extents at or past the limit keep their old flush number.
+        region.region_flush_limited(5, 1, Some(1))?;
+        assert_eq!(region.flush_numbers()?, vec![5, 0, 0]);
+        assert_eq!(region.dirty_extent_count(), 2);
+
+        // A later flush without a limit picks them up.
+        region.region_flush(6, 1)?;
+        assert_eq!(region.flush_numbers()?, vec![5, 6, 6]);
+        assert_eq!(region.dirty_extent_count(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn dirty_extents_found_at_open() -> Result<()> {
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, new_region_options())?;
+        region.extend(2)?;
+
+        let mut buffer = BytesMut::with_capacity(512);
+        buffer.put_slice(&[5u8; 512]);
+        region.single_block_region_write(
+            0,
+            Block::new_512(0),
+            buffer.freeze(),
+            None,
+            None,
+        )?;
+        drop(region);
+
+        // Stop without a flush, then make sure the next flush finds it.
+        let region = Region::open(&dir, new_region_options(), false, false)?;
+        assert_eq!(region.dirty_extent_count(), 1);
+        region.region_flush(4, 1)?;
+        assert_eq!(region.flush_numbers()?, vec![4, 0]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn extent_repair_copy() -> Result<()> {
+        let dir = tempdir()?;
+        let dir2 = tempdir()?;
+        let mut r1 = Region::create(&dir, new_region_options())?;
+        let mut r2 = Region::create(&dir2, new_region_options())?;
+        r1.extend(2)?;
+        r2.extend(2)?;
+
+        let mut buffer = BytesMut::with_capacity(512);
+        buffer.put_slice(&[9u8; 512]);
+        r1.single_block_region_write(
+            1,
+            Block::new_512(3),
+            buffer.freeze(),
+            Some(vec![1, 2, 3]),
+            Some(vec![4, 5, 6]),
+        )?;
+        r1.region_flush(7, 2)?;
+
+        let extent = r1.extent_repair_read(1)?;
+        assert_eq!(extent.eid, 1);
+        assert_eq!(extent.flush_number, 7);
+        assert_eq!(extent.gen_number, 2);
+        assert_eq!(extent.contexts.len(), 10);
+        assert_eq!(extent.checksums[3], Some(block_checksum(&[9u8; 512])));
+
+        r2.extent_repair_write(&extent)?;
+
+        let inner = r2.extents[1].inner();
+        assert_eq!(inner.flush_number()?, 7);
+        assert_eq!(inner.gen_number()?, 2);
+        assert!(!inner.dirty()?);
+        drop(inner);
+
+        let response =
+            r2.single_block_region_read(crucible_protocol::ReadRequest {
+                eid: 1,
+                offset: Block::new_512(3),
+                num_blocks: 1,
+            })?;
+        assert_eq!(response.data.to_vec(), vec![9u8; 512]);
+        assert_eq!(response.nonce, Some(vec![1, 2, 3]));
+        assert_eq!(response.tag, Some(vec![4, 5, 6]));
+
+        // Extent 0 was not touched.
+        assert_eq!(r2.extent_repair_read(0)?.flush_number, 0);
+
+        // A mismatched extent is rejected.
+        let mut bad = extent;
+        bad.contexts.pop();
+        assert!(r2.extent_repair_write(&bad).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn encrypted_write_context() -> Result<()> {
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, new_region_options())?;
+        region.extend(1)?;
+
+        let write = |offset: u64, blocks: usize, ctx: bool| {
+            let (nonce, tag) = if ctx {
+                (Some(vec![1; 12]), Some(vec![2; 16]))
+            } else {
+                (None, None)
+            };
+            region.region_write(&[crucible_protocol::Write {
+                eid: 0,
+                offset: Block::new_512(offset),
+                data: bytes::Bytes::from(vec![3u8; blocks * 512]),
+                nonce,
+                tag,
+            }])
+        };
+        let context = |block: u64| {
+            region.extents[0]
+                .inner()
+                .get_encryption_context(block)
+                .unwrap()
+        };
+
+        // A nonce and tag only go with a single block.
+        assert!(write(0, 2, true).is_err());
+
+        write(0, 1, true)?;
+        write(1, 1, true)?;
+        assert_eq!(context(0), Some((vec![1; 12], vec![2; 16])));
+        assert!(context(1).is_some());
+
+        // Writing over a block without one leaves no stale context.
+        write(0, 1, false)?;
+        assert_eq!(context(0), None);
+        assert!(context(1).is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn hostile_io_is_refused() -> Result<()> {
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, new_region_options())?;
+        region.extend(1)?;
+
+        let read = |eid: u64, offset: Block, num_blocks: u64| {
+            region.region_read(&[crucible_protocol::ReadRequest {
+                eid,
+                offset,
+                num_blocks,
+            }])
+        };
+
+        // An extent that doesn't exist.
+        assert_eq!(
+            read(1, Block::new_512(0), 1).unwrap_err(),
+            CrucibleError::OffsetInvalid
+        );
+
+        // More blocks than the extent holds, without making a buffer
+        // for all of them.
+        assert_eq!(
+            read(0, Block::new_512(0), u64::MAX / 512).unwrap_err(),
+            CrucibleError::OffsetInvalid
+        );
+
+        // An offset that overflows once it is made into bytes.
+        assert_eq!(
+            read(0, Block::new_512(u64::MAX / 4), 1).unwrap_err(),
+            CrucibleError::OffsetInvalid
+        );
+
+        // A shift that can't be used.
+        let offset = Block {
+            value: 0,
+            shift: 200,
+        };
+        assert_eq!(
+            read(0, offset, 1).unwrap_err(),
+            CrucibleError::BlockSizeMismatch
+        );
+
+        let write = region.region_write(&[crucible_protocol::Write {
+            eid: 7,
+            offset: Block::new_512(0),
+            data: bytes::Bytes::from(vec![3u8; 512]),
+            nonce: None,
+            tag: None,
+        }]);
+        assert_eq!(write.unwrap_err(), CrucibleError::OffsetInvalid);
+
+        Ok(())
+    }
+
+    #[test]
+    fn pool_backend_round_trip() -> Result<()> {
+        /*
+         * IOs bigger than a pool chunk are split across the workers, and
+         * have to come back together in the right order.
+         */
+        let dir = tempdir()?;
+        let mut region_options = new_region_options();
+        region_options.set_extent_size(Block::new_512(1024));
+        let mut region = Region::create(&dir, region_options)?;
+        region.extend(2)?;
+        drop(region);
+
+        let region = Region::open_with_backend(
+            &dir,
+            new_region_options(),
+            false,
+            false,
+            IoBackend::Pool(3),
+        )?;
+        assert_eq!(region.io_backend(), IoBackend::Pool(3));
+
+        let mut data = vec![0u8; 600 * 512];
+        rand::thread_rng().fill(&mut data[..]);
+        region.region_write(&[crucible_protocol::Write {
+            eid: 1,
+            offset: Block::new_512(5),
+            data: bytes::Bytes::from(data.clone()),
+            nonce: None,
+            tag: None,
+        }])?;
+        region.region_flush(1, 1)?;
+
+        let request = crucible_protocol::ReadRequest {
+            eid: 1,
+            offset: Block::new_512(5),
+            num_blocks: 600,
+        };
+        let response = region.single_block_region_read(request.clone())?;
+        assert_eq!(response.data.to_vec(), data);
+        drop(region);
+
+        // The same data is there through the plain backend.
+        let region = Region::open(&dir, new_region_options(), false, false)?;
+        let response = region.single_block_region_read(request)?;
+        assert_eq!(response.data.to_vec(), data);
+
+        Ok(())
+    }
 }
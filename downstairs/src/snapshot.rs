@@ -0,0 +1,262 @@
+// Copyright 2021 Oxide Computer Company
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+use crucible_common::{crucible_bail, CrucibleError};
+use crucible_protocol::SnapshotDetails;
+use rusqlite::Connection;
+
+/*
+ * Something that can take a named, point in time copy of the directory
+ * holding a region.  This is called once a flush has made everything
+ * before it durable, and before any IO that depends on that flush runs.
+ */
+pub trait SnapshotProvider: fmt::Debug + Send + Sync {
+    fn snapshot(&self, dir: &Path, name: &str) -> Result<()>;
+}
+
+/*
+ * Which provider to use, as given on the command line.  Auto picks ZFS if
+ * the region is on a ZFS dataset, and copies the region otherwise.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SnapshotKind {
+    Auto,
+    Zfs,
+    Copy,
+    None,
+}
+
+impl FromStr for SnapshotKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<SnapshotKind> {
+        match s {
+            "auto" => Ok(SnapshotKind::Auto),
+            "zfs" => Ok(SnapshotKind::Zfs),
+            "copy" => Ok(SnapshotKind::Copy),
+            "none" => Ok(SnapshotKind::None),
+            _ => bail!("unknown snapshot provider {}", s),
+        }
+    }
+}
+
+/*
+ * Build the provider for a region in dir.
+ */
+pub fn provider(
+    kind: SnapshotKind,
+    dir: &Path,
+) -> Result<Option<Box<dyn SnapshotProvider>>> {
+    Ok(match kind {
+        SnapshotKind::None => None,
+        SnapshotKind::Copy => Some(Box::new(CopySnapshot {})),
+        SnapshotKind::Zfs => match ZfsSnapshot::for_dir(dir) {
+            Some(zfs) => Some(Box::new(zfs)),
+            None => bail!("{:?} is not on a ZFS dataset", dir),
+        },
+        SnapshotKind::Auto => match ZfsSnapshot::for_dir(dir) {
+            Some(zfs) => Some(Box::new(zfs)),
+            None => Some(Box::new(CopySnapshot {})),
+        },
+    })
+}
+
+/*
+ * Take the snapshot asked for with a flush, once the flush is done.
+ */
+pub fn take(
+    provider: Option<&dyn SnapshotProvider>,
+    dir: &Path,
+    details: &SnapshotDetails,
+) -> Result<(), CrucibleError> {
+    let provider = match provider {
+        Some(provider) => provider,
+        None => crucible_bail!(SnapshotFailed, "snapshots are turned off"),
+    };
+
+    if let Err(e) = validate_name(&details.snapshot_name) {
+        crucible_bail!(SnapshotFailed, "{}", e);
+    }
+
+    match provider.snapshot(dir, &details.snapshot_name) {
+        Ok(()) => {
+            println!("Took snapshot {}", details.snapshot_name);
+            Ok(())
+        }
+        Err(e) => {
+            crucible_bail!(SnapshotFailed, "{}: {:?}", details.snapshot_name, e)
+        }
+    }
+}
+
+/*
+ * The name ends up in a ZFS snapshot name or a directory name, so keep
+ * it to what is safe in both.
+ */
+pub fn validate_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.len() > 200 {
+        bail!("snapshot name must be 1 to 200 characters");
+    }
+    if name.starts_with('.') {
+        bail!("snapshot name {} can't start with a '.'", name);
+    }
+    if let Some(c) = name.chars().find(|c| {
+        !(c.is_ascii_alphanumeric() || *c == '-' || *c == '_' || *c == '.')
+    }) {
+        bail!("snapshot name {} can't have a {:?}", name, c);
+    }
+
+    Ok(())
+}
+
+/*
+ * Snapshot the ZFS dataset the region directory is the mountpoint of.
+ * The snapshot is atomic, and shows up under .zfs/snapshot in the region
+ * directory.
+ */
+#[derive(Debug)]
+pub struct ZfsSnapshot {
+    dataset: String,
+}
+
+impl ZfsSnapshot {
+    /*
+     * The dataset mounted at dir, if dir is a ZFS mountpoint.
+     */
+    fn for_dir(dir: &Path) -> Option<ZfsSnapshot> {
+        let output = Command::new("zfs")
+            .args(&["list", "-H", "-o", "name,mountpoint"])
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+
+        let dir = dir.canonicalize().ok()?;
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split('\t');
+                match (fields.next(), fields.next()) {
+                    (Some(name), Some(mountpoint)) => {
+                        Some((name.to_string(), PathBuf::from(mountpoint)))
+                    }
+                    _ => None,
+                }
+            })
+            .find(|(_, mountpoint)| *mountpoint == dir)
+            .map(|(dataset, _)| ZfsSnapshot { dataset })
+    }
+}
+
+impl SnapshotProvider for ZfsSnapshot {
+    fn snapshot(&self, _dir: &Path, name: &str) -> Result<()> {
+        let snapshot = format!("{}@{}", self.dataset, name);
+        let output = Command::new("zfs")
+            .args(&["snapshot", &snapshot])
+            .output()?;
+        if !output.status.success() {
+            bail!(
+                "zfs snapshot {} failed: {}",
+                snapshot,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/*
+ * Where CopySnapshot puts its snapshots, under the region directory.
+ */
+pub const SNAPSHOT_DIR: &str = ".snapshot";
+
+/*
+ * Copy the region into .snapshot/<name> under the region directory.  On
+ * a filesystem that supports it the copy may be a reflink, otherwise it
+ * takes time and space in proportion to the region.
+ *
+ * The copy is made under a temporary name and renamed into place, so a
+ * snapshot that is there is complete.
+ */
+#[derive(Debug)]
+pub struct CopySnapshot {}
+
+impl SnapshotProvider for CopySnapshot {
+    fn snapshot(&self, dir: &Path, name: &str) -> Result<()> {
+        let snapshots = dir.join(SNAPSHOT_DIR);
+        let dest = snapshots.join(name);
+        if dest.exists() {
+            bail!("snapshot {:?} already exists", dest);
+        }
+
+        let partial = snapshots.join(format!(".{}.partial", name));
+        if partial.exists() {
+            std::fs::remove_dir_all(&partial)?;
+        }
+
+        let mut dbs = Vec::new();
+        copy_region(dir, &partial, &snapshots, &mut dbs)?;
+
+        /*
+         * Fold the WAL back into each copied db, so the snapshot can be
+         * opened from read only storage.
+         */
+        for db in dbs {
+            let metadb = Connection::open(&db)?;
+            metadb.pragma_update(None, "journal_mode", &"DELETE")?;
+        }
+
+        std::fs::rename(&partial, &dest)?;
+
+        Ok(())
+    }
+}
+
+/*
+ * Copy every file under from to the same place under to, skipping the
+ * directory skip, and note the metadata dbs that were copied.
+ */
+fn copy_region(
+    from: &Path,
+    to: &Path,
+    skip: &Path,
+    dbs: &mut Vec<PathBuf>,
+) -> Result<()> {
+    std::fs::create_dir_all(to)?;
+
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let path = entry.path();
+        if path == skip {
+            continue;
+        }
+
+        let dest = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_region(&path, &dest, skip, dbs)?;
+            continue;
+        }
+
+        /*
+         * The metadata dbs are in WAL mode, and the WAL is copied along
+         * with each one.  The shared memory index is only good for the
+         * connections that made it, so leave it behind.
+         */
+        if path.to_string_lossy().ends_with("-shm") {
+            continue;
+        }
+        std::fs::copy(&path, &dest)?;
+
+        if path.extension().map_or(false, |e| e == "db") {
+            dbs.push(dest);
+        }
+    }
+
+    Ok(())
+}
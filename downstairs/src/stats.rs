@@ -0,0 +1,354 @@
+// Copyright 2021 Oxide Computer Company
+use super::*;
+
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use dropshot::endpoint;
+use dropshot::HttpError;
+use dropshot::RequestContext;
+use http::{header, Response, StatusCode};
+use hyper::Body;
+
+/*
+ * Running totals of the work this downstairs has done, for the metrics
+ * endpoint.  Jobs are counted when they finish, whether or not the IO
+ * worked; errors counts those that didn't.
+ */
+#[derive(Debug, Default)]
+pub struct DsStats {
+    read_ops: AtomicU64,
+    read_bytes: AtomicU64,
+    write_ops: AtomicU64,
+    write_bytes: AtomicU64,
+    flush_ops: AtomicU64,
+    repair_ops: AtomicU64,
+    errors: AtomicU64,
+    flush_latency: std::sync::Mutex<LatencyHistogram>,
+}
+
+/*
+ * What the metrics endpoint reports besides the counters, gathered when
+ * it is asked.
+ */
+#[derive(Debug)]
+pub struct RegionState {
+    pub uuid: Uuid,
+    pub bytes_used: u64,
+    pub connections: usize,
+    pub active: bool,
+}
+
+impl DsStats {
+    /*
+     * Count a job that has run, with the message that answers it.
+     */
+    pub fn record(&self, work: &IOop, m: &Message, took: Duration) {
+        let ok = match m {
+            Message::ReadResponse(_, _, Ok(responses)) => {
+                let bytes: usize = responses.iter().map(|r| r.data.len()).sum();
+                self.read_ops.fetch_add(1, Ordering::Relaxed);
+                self.read_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+                true
+            }
+            Message::WriteAck(_, _, Ok(())) => {
+                if let IOop::Write { writes, .. } = work {
+                    let bytes: usize =
+                        writes.iter().map(|w| w.data.len()).sum();
+                    self.write_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+                }
+                self.write_ops.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            Message::FlushAck(_, _, Ok(())) => {
+                self.flush_ops.fetch_add(1, Ordering::Relaxed);
+                self.flush_latency.lock().unwrap().record(took);
+                true
+            }
+            Message::ExtentRepairData(_, _, Ok(_))
+            | Message::ExtentRepairAck(_, _, Ok(())) => {
+                self.repair_ops.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            _ => false,
+        };
+
+        if !ok {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /*
+     * Everything in the Prometheus text format.  Metrics for the region
+     * are labeled with its UUID, so a scraper can tell the downstairs on
+     * one sled apart.
+     */
+    pub fn prometheus(&self, region: &RegionState) -> String {
+        let mut out = String::new();
+        let label = format!("region=\"{}\"", region.uuid);
+
+        let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+            writeln!(out, "# HELP crucible_downstairs_{} {}", name, help)
+                .unwrap();
+            writeln!(out, "# TYPE crucible_downstairs_{} {}", name, kind)
+                .unwrap();
+            writeln!(
+                out,
+                "crucible_downstairs_{}{{{}}} {}",
+                name, label, value
+            )
+            .unwrap();
+        };
+
+        let counter = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        metric("regions", "gauge", "Regions served.", 1);
+        metric(
+            "bytes_used",
+            "gauge",
+            "Bytes on disk for the region, not counting snapshots.",
+            region.bytes_used,
+        );
+        metric(
+            "connections",
+            "gauge",
+            "Upstairs connected.",
+            region.connections as u64,
+        );
+        metric(
+            "active",
+            "gauge",
+            "1 if an upstairs is active.",
+            region.active as u64,
+        );
+        metric(
+            "read_ops_total",
+            "counter",
+            "Read jobs done.",
+            counter(&self.read_ops),
+        );
+        metric(
+            "read_bytes_total",
+            "counter",
+            "Bytes read.",
+            counter(&self.read_bytes),
+        );
+        metric(
+            "write_ops_total",
+            "counter",
+            "Write jobs done.",
+            counter(&self.write_ops),
+        );
+        metric(
+            "write_bytes_total",
+            "counter",
+            "Bytes written.",
+            counter(&self.write_bytes),
+        );
+        metric(
+            "flush_ops_total",
+            "counter",
+            "Flushes done.",
+            counter(&self.flush_ops),
+        );
+        metric(
+            "repair_ops_total",
+            "counter",
+            "Extent repair jobs done.",
+            counter(&self.repair_ops),
+        );
+        metric(
+            "errors_total",
+            "counter",
+            "Jobs that returned an error.",
+            counter(&self.errors),
+        );
+
+        let flush = self.flush_latency.lock().unwrap().clone();
+        let name = "crucible_downstairs_flush_latency_seconds";
+        writeln!(out, "# HELP {} Time to do a flush.", name).unwrap();
+        writeln!(out, "# TYPE {} summary", name).unwrap();
+        for q in [0.5, 0.9, 0.99].iter() {
+            writeln!(
+                out,
+                "{}{{{},quantile=\"{}\"}} {}",
+                name,
+                label,
+                q,
+                flush.percentile(q * 100.0).as_secs_f64()
+            )
+            .unwrap();
+        }
+        writeln!(
+            out,
+            "{}_sum{{{}}} {}",
+            name,
+            label,
+            flush.sum().as_secs_f64()
+        )
+        .unwrap();
+        writeln!(out, "{}_count{{{}}} {}", name, label, flush.count()).unwrap();
+
+        out
+    }
+}
+
+/*
+ * Space taken by every file under dir, which is the extents and their
+ * metadata.  Snapshots are left out: they are copies of the region that
+ * can be as big as it is and slow to walk, and they aren't what the
+ * upstairs is using.
+ */
+pub fn bytes_used(dir: &Path) -> Result<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    let mut total = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_name() == snapshot::SNAPSHOT_DIR {
+            continue;
+        }
+        let md = entry.metadata()?;
+        if md.is_dir() {
+            total += bytes_used(&entry.path())?;
+        } else if md.is_file() {
+            total += md.blocks() * 512;
+        }
+    }
+
+    Ok(total)
+}
+
+/*
+ * The counters and region state in the Prometheus text format.
+ */
+#[endpoint {
+    method = GET,
+    path = "/metrics",
+}]
+pub(crate) async fn metrics(
+    rqctx: Arc<RequestContext<ControlContext>>,
+) -> Result<Response<Body>, HttpError> {
+    let context = rqctx.context();
+    let (connections, active) = {
+        let ds = context.ds.lock().await;
+        (ds.connections.len(), ds.active_upstairs.is_some())
+    };
+
+    /*
+     * Walking the region directory is file system IO, so it goes on the
+     * blocking pool.
+     */
+    let region = context.region.clone();
+    let bytes_used =
+        tokio::task::spawn_blocking(move || bytes_used(region.dir()))
+            .await
+            .map_err(|e| HttpError::for_internal_error(e.to_string()))?
+            .map_err(|e| HttpError::for_internal_error(e.to_string()))?;
+
+    let text = context.stats.prometheus(&RegionState {
+        uuid: context.region.def().uuid(),
+        bytes_used,
+        connections,
+        active,
+    });
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(text.into())
+        .map_err(|e| HttpError::for_internal_error(e.to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn flush() -> IOop {
+        IOop::Flush {
+            dependencies: vec![],
+            flush_number: 1,
+            gen_number: 1,
+            snapshot_details: None,
+            extent_limit: None,
+        }
+    }
+
+    #[test]
+    fn counts_jobs() {
+        let stats = DsStats::default();
+        let uuid = Uuid::new_v4();
+        let write = IOop::Write {
+            dependencies: vec![],
+            writes: vec![crucible_protocol::Write {
+                eid: 0,
+                offset: Block::new_512(0),
+                data: bytes::Bytes::from(vec![1; 1024]),
+                nonce: None,
+                tag: None,
+            }],
+        };
+
+        stats.record(
+            &write,
+            &Message::WriteAck(uuid, 1, Ok(())),
+            Duration::from_millis(1),
+        );
+        stats.record(
+            &flush(),
+            &Message::FlushAck(uuid, 2, Ok(())),
+            Duration::from_millis(3),
+        );
+        stats.record(
+            &flush(),
+            &Message::FlushAck(uuid, 3, Err(CrucibleError::UpstairsInactive)),
+            Duration::from_millis(3),
+        );
+
+        let text = stats.prometheus(&RegionState {
+            uuid,
+            bytes_used: 4096,
+            connections: 2,
+            active: true,
+        });
+        let label = format!("{{region=\"{}\"}}", uuid);
+        for line in [
+            format!("crucible_downstairs_write_ops_total{} 1", label),
+            format!("crucible_downstairs_write_bytes_total{} 1024", label),
+            format!("crucible_downstairs_flush_ops_total{} 1", label),
+            format!("crucible_downstairs_errors_total{} 1", label),
+            format!("crucible_downstairs_bytes_used{} 4096", label),
+            format!("crucible_downstairs_connections{} 2", label),
+            format!("crucible_downstairs_active{} 1", label),
+            format!(
+                "crucible_downstairs_flush_latency_seconds_count{} 1",
+                label
+            ),
+        ]
+        .iter()
+        {
+            assert!(
+                text.lines().any(|l| l == line),
+                "{} not in\n{}",
+                line,
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn bytes_used_counts_subdirectories() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::create_dir(dir.path().join("00"))?;
+        std::fs::write(dir.path().join("00").join("000"), vec![1u8; 8192])?;
+        std::fs::write(dir.path().join("region.json"), b"{}")?;
+
+        let used = bytes_used(dir.path())?;
+        assert!(used >= 8192);
+
+        let snapshot = dir.path().join(snapshot::SNAPSHOT_DIR).join("one");
+        std::fs::create_dir_all(&snapshot)?;
+        std::fs::write(snapshot.join("000"), vec![1u8; 65536])?;
+        assert_eq!(bytes_used(dir.path())?, used);
+        Ok(())
+    }
+}
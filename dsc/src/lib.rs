@@ -0,0 +1,405 @@
+// Copyright 2021 Oxide Computer Company
+use std::fs::{self, File};
+use std::net::{Ipv4Addr, SocketAddrV4, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+use uuid::Uuid;
+
+/*
+ * How a set of downstairs is laid out: which binary to run, where their
+ * regions go, and the ports they listen on.  Downstairs `i` listens on
+ * `first_port + i` and keeps its region in `<output_dir>/<port>`.
+ */
+#[derive(Clone, Debug)]
+pub struct DscOpts {
+    pub ds_bin: PathBuf,
+    pub output_dir: PathBuf,
+    pub count: usize,
+    pub first_port: u16,
+    pub block_size: u64,
+    pub extent_size: u64,
+    pub extent_count: u64,
+}
+
+impl DscOpts {
+    /*
+     * Three downstairs on 8810, 8811 and 8812 with the region size the
+     * downstairs `create` uses by default.
+     */
+    pub fn new<P: AsRef<Path>, Q: AsRef<Path>>(
+        ds_bin: P,
+        output_dir: Q,
+    ) -> DscOpts {
+        DscOpts {
+            ds_bin: ds_bin.as_ref().to_path_buf(),
+            output_dir: output_dir.as_ref().to_path_buf(),
+            count: 3,
+            first_port: 8810,
+            block_size: 512,
+            extent_size: 100,
+            extent_count: 15,
+        }
+    }
+}
+
+/*
+ * The crucible-downstairs binary built next to the one running now, which
+ * is where cargo puts it when the whole workspace is built.
+ */
+pub fn default_ds_bin() -> Result<PathBuf> {
+    let exe = std::env::current_exe()?;
+    let dir = exe
+        .parent()
+        .ok_or_else(|| anyhow!("no directory for {:?}", exe))?;
+    Ok(dir.join("crucible-downstairs"))
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DsState {
+    /*
+     * Never started, or stopped by us.
+     */
+    Stopped,
+    Running(u32),
+    /*
+     * Went away on its own.
+     */
+    Exited(ExitStatus),
+}
+
+#[derive(Debug)]
+struct Downstairs {
+    port: u16,
+    dir: PathBuf,
+    log: PathBuf,
+    child: Option<Child>,
+}
+
+/*
+ * A set of downstairs processes, each of which can be started, stopped
+ * and restarted on its own.  Any still running are stopped when this is
+ * dropped.
+ */
+#[derive(Debug)]
+pub struct DsCluster {
+    opts: DscOpts,
+    ds: Vec<Downstairs>,
+}
+
+impl DsCluster {
+    pub fn new(opts: DscOpts) -> Result<DsCluster> {
+        if opts.count == 0 {
+            bail!("a cluster needs at least one downstairs");
+        }
+        if opts.first_port as usize + opts.count - 1 > u16::MAX as usize {
+            bail!(
+                "{} downstairs from port {} runs out of ports",
+                opts.count,
+                opts.first_port
+            );
+        }
+
+        let ds = (0..opts.count)
+            .map(|i| {
+                let port = opts.first_port + i as u16;
+                Downstairs {
+                    port,
+                    dir: opts.output_dir.join(port.to_string()),
+                    log: opts
+                        .output_dir
+                        .join(format!("downstairs-{}.log", port)),
+                    child: None,
+                }
+            })
+            .collect();
+
+        Ok(DsCluster { opts, ds })
+    }
+
+    pub fn count(&self) -> usize {
+        self.ds.len()
+    }
+
+    /*
+     * The addresses an upstairs should be given to reach these downstairs.
+     */
+    pub fn targets(&self) -> Vec<SocketAddrV4> {
+        self.ds
+            .iter()
+            .map(|d| SocketAddrV4::new(Ipv4Addr::LOCALHOST, d.port))
+            .collect()
+    }
+
+    pub fn region_dir(&self, cid: usize) -> Result<&Path> {
+        Ok(&self.get(cid)?.dir)
+    }
+
+    fn get(&self, cid: usize) -> Result<&Downstairs> {
+        self.ds
+            .get(cid)
+            .ok_or_else(|| anyhow!("no downstairs {}", cid))
+    }
+
+    fn get_mut(&mut self, cid: usize) -> Result<&mut Downstairs> {
+        self.ds
+            .get_mut(cid)
+            .ok_or_else(|| anyhow!("no downstairs {}", cid))
+    }
+
+    /*
+     * Create a new region, with its own UUID, for every downstairs.  A
+     * region that is already there is an error, so old data is not
+     * served by mistake.
+     */
+    pub fn create_regions(&self) -> Result<()> {
+        fs::create_dir_all(&self.opts.output_dir)?;
+
+        for d in self.ds.iter() {
+            if d.dir.join("region.json").exists() {
+                bail!("region already exists in {:?}", d.dir);
+            }
+
+            let uuid = Uuid::new_v4();
+            let status = Command::new(&self.opts.ds_bin)
+                .arg("create")
+                .arg("-u")
+                .arg(uuid.to_string())
+                .arg("-d")
+                .arg(&d.dir)
+                .arg("--block-size")
+                .arg(self.opts.block_size.to_string())
+                .arg("--extent-size")
+                .arg(self.opts.extent_size.to_string())
+                .arg("--extent-count")
+                .arg(self.opts.extent_count.to_string())
+                .stdout(Stdio::null())
+                .status()
+                .with_context(|| format!("running {:?}", self.opts.ds_bin))?;
+            if !status.success() {
+                bail!("create of region in {:?} failed: {}", d.dir, status);
+            }
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Start downstairs `cid`, with its output going to its log file.
+     * Starting one that is already running is an error.
+     */
+    pub fn start(&mut self, cid: usize) -> Result<()> {
+        if let DsState::Running(pid) = self.state(cid)? {
+            bail!("downstairs {} is already running as pid {}", cid, pid);
+        }
+
+        let ds_bin = self.opts.ds_bin.clone();
+        let d = self.get_mut(cid)?;
+        let log = File::create(&d.log)?;
+        let child = Command::new(&ds_bin)
+            .arg("run")
+            .arg("-p")
+            .arg(d.port.to_string())
+            .arg("-d")
+            .arg(&d.dir)
+            .stdout(log.try_clone()?)
+            .stderr(log)
+            .spawn()
+            .with_context(|| format!("running {:?}", ds_bin))?;
+        d.child = Some(child);
+
+        Ok(())
+    }
+
+    /*
+     * Kill downstairs `cid`, the way a crash would, and wait for it to go.
+     * Stopping one that is not running does nothing.
+     */
+    pub fn stop(&mut self, cid: usize) -> Result<()> {
+        let d = self.get_mut(cid)?;
+        if let Some(mut child) = d.child.take() {
+            if child.try_wait()?.is_none() {
+                child.kill()?;
+            }
+            child.wait()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn restart(&mut self, cid: usize) -> Result<()> {
+        self.stop(cid)?;
+        self.start(cid)
+    }
+
+    pub fn start_all(&mut self) -> Result<()> {
+        for cid in 0..self.ds.len() {
+            self.start(cid)?;
+        }
+        Ok(())
+    }
+
+    pub fn stop_all(&mut self) -> Result<()> {
+        for cid in 0..self.ds.len() {
+            self.stop(cid)?;
+        }
+        Ok(())
+    }
+
+    pub fn state(&mut self, cid: usize) -> Result<DsState> {
+        let d = self.get_mut(cid)?;
+        match &mut d.child {
+            None => Ok(DsState::Stopped),
+            Some(child) => match child.try_wait()? {
+                None => Ok(DsState::Running(child.id())),
+                Some(status) => Ok(DsState::Exited(status)),
+            },
+        }
+    }
+
+    /*
+     * Wait until downstairs `cid` takes connections on its port.  If it
+     * exits first, or does not listen before the timeout, this fails.
+     */
+    pub fn wait_listening(
+        &mut self,
+        cid: usize,
+        timeout: Duration,
+    ) -> Result<()> {
+        let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, self.get(cid)?.port);
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            match self.state(cid)? {
+                DsState::Running(_) => {}
+                s => bail!("downstairs {} is not running: {:?}", cid, s),
+            }
+            if TcpStream::connect(addr).is_ok() {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                bail!("downstairs {} not listening on {}", cid, addr);
+            }
+            sleep(Duration::from_millis(100));
+        }
+    }
+}
+
+impl Drop for DsCluster {
+    fn drop(&mut self) {
+        let _ = self.stop_all();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    /*
+     * A stand in for the downstairs: `create` makes the region directory
+     * and `run` just waits around to be killed.
+     */
+    fn fake_ds(dir: &Path) -> PathBuf {
+        let bin = dir.join("fake-downstairs");
+        fs::write(
+            &bin,
+            "#!/bin/sh\n\
+             case $1 in\n\
+             create) mkdir -p $5 && echo {} > $5/region.json ;;\n\
+             run) exec sleep 60 ;;\n\
+             esac\n",
+        )
+        .unwrap();
+        fs::set_permissions(&bin, fs::Permissions::from_mode(0o755)).unwrap();
+        bin
+    }
+
+    fn cluster(dir: &Path) -> DsCluster {
+        let mut opts = DscOpts::new(fake_ds(dir), dir.join("regions"));
+        opts.first_port = 5000;
+        DsCluster::new(opts).unwrap()
+    }
+
+    #[test]
+    fn layout() {
+        let dir = tempfile::tempdir().unwrap();
+        let dsc = cluster(dir.path());
+
+        assert_eq!(dsc.count(), 3);
+        assert_eq!(
+            dsc.targets(),
+            vec![
+                "127.0.0.1:5000".parse::<SocketAddrV4>().unwrap(),
+                "127.0.0.1:5001".parse().unwrap(),
+                "127.0.0.1:5002".parse().unwrap(),
+            ]
+        );
+        assert_eq!(
+            dsc.region_dir(2).unwrap(),
+            dir.path().join("regions").join("5002")
+        );
+        assert!(dsc.region_dir(3).is_err());
+    }
+
+    #[test]
+    fn too_many_ports() {
+        let mut opts = DscOpts::new("ds", "out");
+        opts.first_port = u16::MAX - 1;
+        assert!(DsCluster::new(opts).is_err());
+    }
+
+    #[test]
+    fn create_refuses_existing() {
+        let dir = tempfile::tempdir().unwrap();
+        let dsc = cluster(dir.path());
+
+        dsc.create_regions().unwrap();
+        assert!(dsc.region_dir(0).unwrap().join("region.json").exists());
+        assert!(dsc.create_regions().is_err());
+    }
+
+    #[test]
+    fn start_stop_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut dsc = cluster(dir.path());
+        dsc.create_regions().unwrap();
+
+        assert_eq!(dsc.state(1).unwrap(), DsState::Stopped);
+        dsc.start_all().unwrap();
+        let pid = match dsc.state(1).unwrap() {
+            DsState::Running(pid) => pid,
+            s => panic!("not running: {:?}", s),
+        };
+        assert!(dsc.start(1).is_err());
+
+        dsc.stop(1).unwrap();
+        assert_eq!(dsc.state(1).unwrap(), DsState::Stopped);
+        assert!(matches!(dsc.state(0).unwrap(), DsState::Running(_)));
+
+        dsc.restart(1).unwrap();
+        match dsc.state(1).unwrap() {
+            DsState::Running(new_pid) => assert_ne!(pid, new_pid),
+            s => panic!("not running: {:?}", s),
+        }
+
+        dsc.stop_all().unwrap();
+        for cid in 0..3 {
+            assert_eq!(dsc.state(cid).unwrap(), DsState::Stopped);
+        }
+    }
+
+    #[test]
+    fn wait_times_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut dsc = cluster(dir.path());
+        dsc.create_regions().unwrap();
+
+        // The fake never listens.
+        dsc.start(0).unwrap();
+        assert!(dsc.wait_listening(0, Duration::from_millis(200)).is_err());
+    }
+}
@@ -0,0 +1,166 @@
+// Copyright 2021 Oxide Computer Company
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use structopt::StructOpt;
+
+use dsc::*;
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "downstairs cluster controller")]
+enum Args {
+    /*
+     * Create a new region for each downstairs, then exit.
+     */
+    Create {
+        #[structopt(flatten)]
+        cluster: ClusterArgs,
+
+        #[structopt(long, default_value = "512")]
+        block_size: u64,
+
+        #[structopt(long, default_value = "100")]
+        extent_size: u64,
+
+        #[structopt(long, default_value = "15")]
+        extent_count: u64,
+
+        /*
+         * Remove the output directory first, regions and all.
+         */
+        #[structopt(long)]
+        cleanup: bool,
+    },
+    /*
+     * Run a downstairs for each region, then take commands on stdin to
+     * stop, start or restart any of them.  All are stopped on exit.
+     */
+    Start {
+        #[structopt(flatten)]
+        cluster: ClusterArgs,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+struct ClusterArgs {
+    /*
+     * The crucible-downstairs binary.  By default the one next to dsc.
+     */
+    #[structopt(long, parse(from_os_str))]
+    ds_bin: Option<PathBuf>,
+
+    #[structopt(short, long, parse(from_os_str), default_value = "var/dsc")]
+    output_dir: PathBuf,
+
+    #[structopt(short, long, default_value = "3")]
+    count: usize,
+
+    /*
+     * Downstairs listen on this port and the ones after it.
+     */
+    #[structopt(short, long, default_value = "8810")]
+    port: u16,
+}
+
+impl ClusterArgs {
+    fn opts(&self) -> Result<DscOpts> {
+        let ds_bin = match &self.ds_bin {
+            Some(bin) => bin.clone(),
+            None => default_ds_bin()?,
+        };
+        let mut opts = DscOpts::new(ds_bin, &self.output_dir);
+        opts.count = self.count;
+        opts.first_port = self.port;
+        Ok(opts)
+    }
+}
+
+fn show(dsc: &mut DsCluster) -> Result<()> {
+    let targets = dsc.targets();
+    for (cid, target) in targets.iter().enumerate() {
+        println!("[{}] {} {:?}", cid, target, dsc.state(cid)?);
+    }
+    Ok(())
+}
+
+fn command(dsc: &mut DsCluster, line: &str) -> Result<bool> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let (cmd, cid) = match words.as_slice() {
+        [] => return Ok(true),
+        [cmd] => (*cmd, None),
+        [cmd, cid] => (*cmd, Some(cid.parse::<usize>()?)),
+        _ => bail!("too many arguments"),
+    };
+
+    match (cmd, cid) {
+        ("start", Some(cid)) => dsc.start(cid)?,
+        ("stop", Some(cid)) => dsc.stop(cid)?,
+        ("restart", Some(cid)) => dsc.restart(cid)?,
+        ("start", None) => dsc.start_all()?,
+        ("stop", None) => dsc.stop_all()?,
+        ("status", None) => {}
+        ("quit", None) => return Ok(false),
+        _ => bail!("commands: start|stop|restart [N], status, quit"),
+    }
+    show(dsc)?;
+
+    Ok(true)
+}
+
+fn main() -> Result<()> {
+    let args = Args::from_args();
+
+    match args {
+        Args::Create {
+            cluster,
+            block_size,
+            extent_size,
+            extent_count,
+            cleanup,
+        } => {
+            let mut opts = cluster.opts()?;
+            opts.block_size = block_size;
+            opts.extent_size = extent_size;
+            opts.extent_count = extent_count;
+
+            if cleanup && opts.output_dir.exists() {
+                std::fs::remove_dir_all(&opts.output_dir)?;
+            }
+
+            let dsc = DsCluster::new(opts)?;
+            dsc.create_regions()?;
+            for cid in 0..dsc.count() {
+                println!("Created region in {:?}", dsc.region_dir(cid)?);
+            }
+            Ok(())
+        }
+        Args::Start { cluster } => {
+            let mut dsc = DsCluster::new(cluster.opts()?)?;
+            dsc.start_all()?;
+            for cid in 0..dsc.count() {
+                dsc.wait_listening(cid, Duration::from_secs(30))?;
+            }
+            show(&mut dsc)?;
+
+            let stdin = io::stdin();
+            loop {
+                print!("dsc> ");
+                io::stdout().flush()?;
+
+                let mut line = String::new();
+                if stdin.lock().read_line(&mut line)? == 0 {
+                    break;
+                }
+                match command(&mut dsc, &line) {
+                    Ok(true) => {}
+                    Ok(false) => break,
+                    Err(e) => println!("{}", e),
+                }
+            }
+
+            dsc.stop_all()
+        }
+    }
+}
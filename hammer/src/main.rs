@@ -15,6 +15,9 @@ use std::io::{Read, Seek, SeekFrom, Write};
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 
+mod stress;
+use stress::{Stress, StressOpts};
+
 // https://stackoverflow.com/questions/29504514/whats-the-best-way-to-compare-2-vectors-or-strings-element-by-element
 fn do_vecs_match<T: PartialEq>(a: &[T], b: &[T]) -> bool {
     let matching = a.iter().zip(b.iter()).filter(|&(a, b)| a == b).count();
@@ -47,6 +50,33 @@ pub struct Opt {
      */
     #[structopt(short, long, default_value = "5")]
     num_upstairs: usize,
+
+    /*
+     * Instead of the handoff test, keep this many IOs outstanding at once
+     * against the first upstairs and check each of them.  See Stress.
+     */
+    #[structopt(long, default_value = "0")]
+    depth: usize,
+
+    /*
+     * For --depth: IOs to send, the blocks at the start of the volume
+     * they go to, the largest IO in blocks, and how many of every hundred
+     * IOs are reads and flushes.
+     */
+    #[structopt(long, default_value = "10000")]
+    ios: u64,
+
+    #[structopt(long, default_value = "128")]
+    span: u64,
+
+    #[structopt(long, default_value = "8")]
+    max_blocks: u64,
+
+    #[structopt(long, default_value = "40")]
+    read_pct: u32,
+
+    #[structopt(long, default_value = "5")]
+    flush_pct: u32,
 }
 
 pub fn opts() -> Result<Opt> {
@@ -56,6 +86,14 @@ pub fn opts() -> Result<Opt> {
     if opt.target.is_empty() {
         bail!("must specify at least one --target");
     }
+    if opt.depth > 0 {
+        if opt.max_blocks == 0 {
+            bail!("--max-blocks must be at least 1");
+        }
+        if opt.read_pct + opt.flush_pct > 100 {
+            bail!("--read-pct and --flush-pct add up to more than 100");
+        }
+    }
 
     Ok(opt)
 }
@@ -67,10 +105,17 @@ fn main() -> Result<()> {
         bail!("Must have non-zero number of upstairs");
     }
 
+    let policy = ReplicationPolicy::majority(opt.target.len())?;
     let crucible_opts = CrucibleOpts {
         target: opt.target,
         lossy: false,
         key: opt.key,
+        legacy_xts: false,
+        control: None,
+        policy: Some(policy),
+        read_only: false,
+        job_timeout: None,
+        uuid_mismatch: None,
     };
     let mut generation_number = opt.gen;
 
@@ -116,6 +161,7 @@ fn main() -> Result<()> {
     // Create N CruciblePseudoFiles to test activation handoff.
     let mut cpfs: Vec<crucible::CruciblePseudoFile> =
         Vec::with_capacity(opt.num_upstairs);
+    let mut guests: Vec<Arc<Guest>> = Vec::with_capacity(opt.num_upstairs);
 
     for _ in 0..opt.num_upstairs {
         /*
@@ -129,12 +175,38 @@ fn main() -> Result<()> {
         runtime.spawn(up_main(crucible_opts.clone(), guest.clone()));
         println!("Crucible runtime is spawned");
 
+        guests.push(guest.clone());
         cpfs.push(crucible::CruciblePseudoFile::from_guest(guest)?);
     }
 
     use rand::Rng;
     let mut rng = rand::thread_rng();
 
+    if opt.depth > 0 {
+        let guest = &guests[0];
+        guest.activate(generation_number)?;
+
+        let sopts = StressOpts {
+            ios: opt.ios,
+            depth: opt.depth,
+            span: opt.span,
+            max_blocks: opt.max_blocks,
+            read_pct: opt.read_pct,
+            flush_pct: opt.flush_pct,
+        };
+        println!("Stress with {:?}", sopts);
+
+        let mut stress = Stress::new(guest, sopts.span, &mut rng)?;
+        stress.run(&sopts, &mut rng)?;
+
+        let stats = &stress.stats;
+        println!(
+            "Done ok: {} writes, {} reads, {} flushes",
+            stats.writes, stats.reads, stats.flushes
+        );
+        return Ok(());
+    }
+
     let rounds = 1500;
     let handoff_amount = rounds / opt.num_upstairs;
     let mut cpf_idx = 0;
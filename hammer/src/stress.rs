@@ -0,0 +1,304 @@
+// Copyright 2021 Oxide Computer Company
+use anyhow::{bail, Result};
+use rand::Rng;
+
+use crucible::*;
+
+/*
+ * How the concurrent stress test sends IO.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct StressOpts {
+    /*
+     * IOs to send, including flushes.
+     */
+    pub ios: u64,
+    /*
+     * How many IOs to keep outstanding at once.
+     */
+    pub depth: usize,
+    /*
+     * IOs only go to the first span blocks of the volume, so that the
+     * ones outstanding at the same time overlap.
+     */
+    pub span: u64,
+    /*
+     * The largest IO in blocks.
+     */
+    pub max_blocks: u64,
+    /*
+     * Of every hundred IOs, how many are reads and how many are flushes.
+     * The rest are writes.
+     */
+    pub read_pct: u32,
+    pub flush_pct: u32,
+}
+
+#[derive(Debug, Default)]
+pub struct StressStats {
+    pub reads: u64,
+    pub writes: u64,
+    pub flushes: u64,
+}
+
+enum Op {
+    /*
+     * A read keeps its buffer, where it started, and what the volume
+     * held there when it was sent.
+     */
+    Read {
+        block: u64,
+        data: Buffer,
+        expect: Vec<u8>,
+    },
+    Write,
+    Flush,
+}
+
+struct InFlight {
+    seq: u64,
+    op: Op,
+    waiter: BlockReqWaiter,
+}
+
+/*
+ * Sends IO to a guest with many of them outstanding at once, and keeps
+ * what every block should hold.  The upstairs runs overlapping IO in the
+ * order it was sent, so:
+ *
+ * - a read returns what the writes sent before it put there, whatever
+ *   was sent after it and however the IOs complete.
+ * - a flush is acked only once everything sent before it has been.
+ *
+ * Outstanding IOs are waited on in random order, so completions are seen
+ * in any order the upstairs hands them back.
+ */
+pub struct Stress<'a> {
+    guest: &'a Guest,
+    block_size: u64,
+    shift: u32,
+    /*
+     * What the volume holds in the first span blocks, as of the last IO
+     * sent.
+     */
+    image: Vec<u8>,
+    in_flight: Vec<InFlight>,
+    next_seq: u64,
+    pub stats: StressStats,
+}
+
+impl<'a> Stress<'a> {
+    /*
+     * Write over the whole span, so we know what it holds before any IO
+     * is outstanding.
+     */
+    pub fn new<R: Rng>(
+        guest: &'a Guest,
+        span: u64,
+        rng: &mut R,
+    ) -> Result<Stress<'a>> {
+        let block_size = guest.query_block_size()?;
+        let blocks = guest.query_total_size()? / block_size;
+        if span == 0 || span > blocks {
+            bail!("span {} must be from 1 to {} blocks", span, blocks);
+        }
+
+        let mut image = vec![0u8; (span * block_size) as usize];
+        rng.fill(&mut image[..]);
+
+        let shift = block_size.trailing_zeros();
+        guest
+            .write(Block::new(0, shift), Bytes::from(image.clone()))?
+            .block_wait()?;
+        guest.flush()?.block_wait()?;
+
+        Ok(Stress {
+            guest,
+            block_size,
+            shift,
+            image,
+            in_flight: Vec::new(),
+            next_seq: 0,
+            stats: StressStats::default(),
+        })
+    }
+
+    fn range(&self, block: u64, count: u64) -> std::ops::Range<usize> {
+        let start = (block * self.block_size) as usize;
+        start..start + (count * self.block_size) as usize
+    }
+
+    fn submit(&mut self, op: Op, waiter: BlockReqWaiter) {
+        self.in_flight.push(InFlight {
+            seq: self.next_seq,
+            op,
+            waiter,
+        });
+        self.next_seq += 1;
+    }
+
+    pub fn read(&mut self, block: u64, count: u64) -> Result<()> {
+        let expect = self.image[self.range(block, count)].to_vec();
+        let data = Buffer::new(expect.len());
+        let waiter = self
+            .guest
+            .read(Block::new(block, self.shift), data.clone())?;
+
+        self.stats.reads += 1;
+        self.submit(
+            Op::Read {
+                block,
+                data,
+                expect,
+            },
+            waiter,
+        );
+        Ok(())
+    }
+
+    pub fn write<R: Rng>(
+        &mut self,
+        block: u64,
+        count: u64,
+        rng: &mut R,
+    ) -> Result<()> {
+        let range = self.range(block, count);
+        rng.fill(&mut self.image[range.clone()]);
+        let data = Bytes::from(self.image[range].to_vec());
+        let waiter = self.guest.write(Block::new(block, self.shift), data)?;
+
+        self.stats.writes += 1;
+        self.submit(Op::Write, waiter);
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        let waiter = self.guest.flush()?;
+
+        self.stats.flushes += 1;
+        self.submit(Op::Flush, waiter);
+        Ok(())
+    }
+
+    /*
+     * Check an IO that has completed.  For a flush, everything sent
+     * before it must be done too.
+     */
+    fn done(&mut self, io: InFlight) -> Result<()> {
+        match io.op {
+            Op::Read {
+                block,
+                data,
+                expect,
+            } => {
+                let data = data.as_vec();
+                if *data != expect {
+                    let bs = self.block_size as usize;
+                    let bad = data
+                        .chunks(bs)
+                        .zip(expect.chunks(bs))
+                        .position(|(a, b)| a != b)
+                        .unwrap();
+                    bail!(
+                        "IO {}: read of block {} does not hold what was \
+                        last written",
+                        io.seq,
+                        block + bad as u64
+                    );
+                }
+            }
+            Op::Write => {}
+            Op::Flush => {
+                let mut i = 0;
+                while i < self.in_flight.len() {
+                    if self.in_flight[i].seq > io.seq {
+                        i += 1;
+                        continue;
+                    }
+                    let mut earlier = self.in_flight.remove(i);
+                    match earlier.waiter.try_wait() {
+                        Some(result) => result?,
+                        None => bail!(
+                            "flush {} acked before IO {} sent ahead of it",
+                            io.seq,
+                            earlier.seq
+                        ),
+                    }
+                    self.done(earlier)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Wait for one outstanding IO, picked at random.
+     */
+    fn complete_one<R: Rng>(&mut self, rng: &mut R) -> Result<()> {
+        if self.in_flight.is_empty() {
+            return Ok(());
+        }
+
+        let idx = rng.gen_range(0..self.in_flight.len());
+        let mut io = self.in_flight.swap_remove(idx);
+        io.waiter.block_wait()?;
+        self.done(io)
+    }
+
+    pub fn drain<R: Rng>(&mut self, rng: &mut R) -> Result<()> {
+        while !self.in_flight.is_empty() {
+            self.complete_one(rng)?;
+        }
+        Ok(())
+    }
+
+    fn wait_for_room<R: Rng>(
+        &mut self,
+        depth: usize,
+        rng: &mut R,
+    ) -> Result<()> {
+        while self.in_flight.len() >= depth {
+            self.complete_one(rng)?;
+        }
+        Ok(())
+    }
+
+    pub fn run<R: Rng>(
+        &mut self,
+        opts: &StressOpts,
+        rng: &mut R,
+    ) -> Result<()> {
+        let span = (self.image.len() as u64) / self.block_size;
+
+        for _ in 0..opts.ios {
+            self.wait_for_room(opts.depth, rng)?;
+
+            let block = rng.gen_range(0..span);
+            let count = rng.gen_range(1..=opts.max_blocks.min(span - block));
+            let pick = rng.gen_range(0..100);
+            if pick < opts.read_pct {
+                self.read(block, count)?;
+            } else if pick < opts.read_pct + opts.flush_pct {
+                self.flush()?;
+            } else {
+                self.write(block, count, rng)?;
+            }
+        }
+
+        /*
+         * A last flush, then read back the whole span.
+         */
+        self.wait_for_room(opts.depth, rng)?;
+        self.flush()?;
+        let mut block = 0;
+        while block < span {
+            self.wait_for_room(opts.depth, rng)?;
+            let count = opts.max_blocks.min(span - block);
+            self.read(block, count)?;
+            block += count;
+        }
+
+        self.drain(rng)
+    }
+}
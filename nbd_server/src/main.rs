@@ -1,4 +1,5 @@
 // Copyright 2021 Oxide Computer Company
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::net::SocketAddrV4;
 use std::sync::Arc;
 
@@ -8,25 +9,209 @@ use tokio::runtime::Builder;
 
 use crucible::*;
 
-use nbd::server::{handshake, transmission, Export};
+use nbd::server::{handshake, Export};
 use std::net::{TcpListener, TcpStream as NetTcpStream};
 
+/*
+ * The transmission phase of the NBD protocol, with simple replies only.
+ * See https://github.com/NetworkBlockDevice/nbd/blob/master/doc/proto.md
+ */
+const NBD_REQUEST_MAGIC: u32 = 0x2560_9513;
+const NBD_SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+
+const NBD_CMD_READ: u16 = 0;
+const NBD_CMD_WRITE: u16 = 1;
+const NBD_CMD_DISC: u16 = 2;
+const NBD_CMD_FLUSH: u16 = 3;
+
+const NBD_CMD_FLAG_FUA: u16 = 1 << 0;
+
+const NBD_EPERM: u32 = 1;
+const NBD_EIO: u32 = 5;
+const NBD_EINVAL: u32 = 22;
+
+/*
+ * The largest read or write we will take from a client.  The kernel
+ * client sends at most 32 MiB.
+ */
+const NBD_MAX_LEN: u32 = 32 * 1024 * 1024;
+
+struct Request {
+    flags: u16,
+    cmd: u16,
+    handle: u64,
+    offset: u64,
+    len: u32,
+}
+
+fn read_request(stream: &mut NetTcpStream) -> Result<Request> {
+    let mut hdr = [0u8; 28];
+    stream.read_exact(&mut hdr)?;
+
+    let magic = u32::from_be_bytes([hdr[0], hdr[1], hdr[2], hdr[3]]);
+    if magic != NBD_REQUEST_MAGIC {
+        bail!("bad request magic {:#x}", magic);
+    }
+
+    let mut handle = [0u8; 8];
+    handle.copy_from_slice(&hdr[8..16]);
+    let mut offset = [0u8; 8];
+    offset.copy_from_slice(&hdr[16..24]);
+
+    Ok(Request {
+        flags: u16::from_be_bytes([hdr[4], hdr[5]]),
+        cmd: u16::from_be_bytes([hdr[6], hdr[7]]),
+        handle: u64::from_be_bytes(handle),
+        offset: u64::from_be_bytes(offset),
+        len: u32::from_be_bytes([hdr[24], hdr[25], hdr[26], hdr[27]]),
+    })
+}
+
+fn send_reply(
+    stream: &mut NetTcpStream,
+    handle: u64,
+    error: u32,
+    data: Option<&[u8]>,
+) -> Result<()> {
+    let mut reply = Vec::with_capacity(16 + data.map_or(0, |d| d.len()));
+    reply.extend_from_slice(&NBD_SIMPLE_REPLY_MAGIC.to_be_bytes());
+    reply.extend_from_slice(&error.to_be_bytes());
+    reply.extend_from_slice(&handle.to_be_bytes());
+    if let Some(data) = data {
+        reply.extend_from_slice(data);
+    }
+    stream.write_all(&reply)?;
+    Ok(())
+}
+
 /*
  * NBD server commands translate through the CruciblePseudoFile and turn
- * into Guest work ops.
+ * into Guest work ops.  Errors from Crucible go back to the client as EIO,
+ * errors in the protocol itself drop the connection.
+ *
+ * Reads and writes must be whole sectors of the size given with
+ * --sector-size, which can be smaller than the block size of the regions:
+ * 512 byte sectors work on regions with 4K blocks.  The pseudo file goes
+ * through the guest's read_bytes and write_bytes, which do a
+ * read-modify-write of partial blocks, ordered against all other IO on
+ * the guest.
  */
+fn transmission(
+    cpf: &mut crucible::CruciblePseudoFile,
+    stream: &mut NetTcpStream,
+    read_only: bool,
+) -> Result<()> {
+    loop {
+        let req = read_request(stream)?;
+
+        let valid = req
+            .offset
+            .checked_add(req.len as u64)
+            .map_or(false, |end| end <= cpf.sz())
+            && req.offset % cpf.block_size() == 0
+            && req.len as u64 % cpf.block_size() == 0;
+
+        match req.cmd {
+            NBD_CMD_READ => {
+                if !valid || req.len > NBD_MAX_LEN {
+                    send_reply(stream, req.handle, NBD_EINVAL, None)?;
+                    continue;
+                }
+
+                let mut data = vec![0u8; req.len as usize];
+                let result = cpf
+                    .seek(SeekFrom::Start(req.offset))
+                    .and_then(|_| cpf.read_exact(&mut data));
+                match result {
+                    Ok(()) => send_reply(stream, req.handle, 0, Some(&data))?,
+                    Err(e) => {
+                        eprintln!("nbd read at {}: {}", req.offset, e);
+                        send_reply(stream, req.handle, NBD_EIO, None)?;
+                    }
+                }
+            }
+            NBD_CMD_WRITE => {
+                /*
+                 * The data follows the request whether or not we take it,
+                 * and we can't stay in step with the client if we don't
+                 * read all of it.
+                 */
+                if req.len > NBD_MAX_LEN {
+                    bail!("write of {} bytes is too large", req.len);
+                }
+                let mut data = vec![0u8; req.len as usize];
+                stream.read_exact(&mut data)?;
+
+                if read_only {
+                    send_reply(stream, req.handle, NBD_EPERM, None)?;
+                    continue;
+                }
+                if !valid {
+                    send_reply(stream, req.handle, NBD_EINVAL, None)?;
+                    continue;
+                }
+
+                let mut result = cpf
+                    .seek(SeekFrom::Start(req.offset))
+                    .and_then(|_| cpf.write_all(&data));
+                if result.is_ok() && req.flags & NBD_CMD_FLAG_FUA != 0 {
+                    result = cpf.flush();
+                }
+                match result {
+                    Ok(()) => send_reply(stream, req.handle, 0, None)?,
+                    Err(e) => {
+                        eprintln!("nbd write at {}: {}", req.offset, e);
+                        send_reply(stream, req.handle, NBD_EIO, None)?;
+                    }
+                }
+            }
+            NBD_CMD_FLUSH => {
+                /*
+                 * A flush is acked once every write acked before it is
+                 * on disk, which is what a Crucible flush gives us.  On a
+                 * read only volume it is acked without doing anything.
+                 */
+                let result = cpf.flush();
+                match result {
+                    Ok(()) => send_reply(stream, req.handle, 0, None)?,
+                    Err(e) => {
+                        eprintln!("nbd flush: {}", e);
+                        send_reply(stream, req.handle, NBD_EIO, None)?;
+                    }
+                }
+            }
+            NBD_CMD_DISC => {
+                /*
+                 * The client waits for nothing, but make sure what it
+                 * already wrote is not lost when it goes away.
+                 */
+                cpf.flush()?;
+                return Ok(());
+            }
+            _ => {
+                /*
+                 * This includes trim, which we don't advertise: Crucible
+                 * has no way to give space back to the downstairs yet.
+                 */
+                send_reply(stream, req.handle, NBD_EINVAL, None)?;
+            }
+        }
+    }
+}
 
 fn handle_nbd_client(
     cpf: &mut crucible::CruciblePseudoFile,
     mut stream: NetTcpStream,
+    read_only: bool,
 ) -> Result<()> {
     let e = Export {
         size: cpf.sz(),
-        readonly: false,
+        readonly: read_only,
+        send_flush: true,
         ..Default::default()
     };
     handshake(&mut stream, &e)?;
-    transmission(&mut stream, cpf)?;
+    transmission(cpf, &mut stream, read_only)?;
     Ok(())
 }
 
@@ -41,6 +226,26 @@ pub struct Opt {
 
     #[structopt(short, long, default_value = "0")]
     gen: u64,
+
+    /*
+     * Where NBD clients connect.
+     */
+    #[structopt(long, default_value = "127.0.0.1:10809")]
+    listen: SocketAddrV4,
+
+    /*
+     * Export the volume read only.  Writes from the client
+     * fail with EPERM.
+     */
+    #[structopt(long)]
+    read_only: bool,
+
+    /*
+     * The sector size clients see.  It can be smaller than the block
+     * size of the regions, down to 512.
+     */
+    #[structopt(long, default_value = "512")]
+    sector_size: u64,
 }
 
 pub fn opts() -> Result<Opt> {
@@ -56,10 +261,17 @@ pub fn opts() -> Result<Opt> {
 
 fn main() -> Result<()> {
     let opt = opts()?;
+    let policy = ReplicationPolicy::majority(opt.target.len())?;
     let crucible_opts = CrucibleOpts {
         target: opt.target,
         lossy: false,
         key: opt.key,
+        legacy_xts: false,
+        control: None,
+        policy: Some(policy),
+        read_only: opt.read_only,
+        job_timeout: None,
+        uuid_mismatch: None,
     };
 
     /*
@@ -87,23 +299,32 @@ fn main() -> Result<()> {
 
     // NBD server
 
-    let listener = TcpListener::bind("127.0.0.1:10809").unwrap();
-    let mut cpf = crucible::CruciblePseudoFile::from_guest(guest)?;
+    let listener = TcpListener::bind(opt.listen)?;
+    let mut cpf =
+        crucible::CruciblePseudoFile::with_sector_size(guest, opt.sector_size)?;
 
     cpf.activate(opt.gen)?;
 
     // sent to NBD client during handshake through Export struct
     println!("NBD advertised size as {} bytes", cpf.sz());
+    println!(
+        "NBD sectors are {} bytes, region blocks are {}",
+        cpf.block_size(),
+        cpf.physical_block_size()
+    );
+    println!("NBD listening on {}", opt.listen);
 
     for stream in listener.incoming() {
         println!("waiting on nbd traffic");
         match stream {
-            Ok(stream) => match handle_nbd_client(&mut cpf, stream) {
-                Ok(_) => {}
-                Err(e) => {
-                    eprintln!("handle_nbd_client error: {}", e);
+            Ok(stream) => {
+                match handle_nbd_client(&mut cpf, stream, opt.read_only) {
+                    Ok(_) => {}
+                    Err(e) => {
+                        eprintln!("handle_nbd_client error: {}", e);
+                    }
                 }
-            },
+            }
             Err(_) => {
                 println!("Error");
             }
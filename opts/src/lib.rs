@@ -0,0 +1,472 @@
+// Copyright 2021 Oxide Computer Company
+use std::fs;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crucible::{
+    CrucibleOpts, JobTimeout, ReplicationPolicy, UuidMismatchPolicy,
+    VolumeConstructionRequest,
+};
+
+/*
+ * Everything needed to start an upstairs, a volume, or a downstairs, in
+ * one place, so whatever runs them can hand over a single TOML file
+ * instead of building a command line for each.  For example:
+ *
+ *     [upstairs]
+ *     target = ["10.0.0.1:3801", "10.0.0.2:3801", "10.0.0.3:3801"]
+ *     gen = 3
+ *     job_timeout_secs = 10
+ *     uuid_mismatch = "renegotiate"
+ *
+ *     [downstairs]
+ *     port = 3801
+ *     data = "/var/crucible/3801"
+ *
+ * A volume is given as a [volume] table laid out the same as a
+ * VolumeConstructionRequest, with a [[volume.sub_volumes]] table for each
+ * sub volume.  Use either [volume] or upstairs targets, not both.
+ *
+ * Anything in the file can then be replaced from the environment, see
+ * Config::apply_env().
+ */
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub upstairs: UpstairsConfig,
+    pub downstairs: DownstairsConfig,
+    pub volume: Option<VolumeConstructionRequest>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstairsConfig {
+    pub target: Vec<SocketAddrV4>,
+    /*
+     * 32 bytes in base64, to encrypt with AES-256-GCM.
+     */
+    pub key: Option<String>,
+    /*
+     * The key was used to write blocks with AES-XTS, before blocks had an
+     * encryption version.  See CrucibleOpts::legacy_xts.
+     */
+    pub legacy_xts: bool,
+    pub gen: u64,
+    pub control: Option<SocketAddr>,
+    pub lossy: bool,
+    pub read_only: bool,
+    /*
+     * How many downstairs must finish a write or a flush before it is
+     * acked.  By default a majority of the targets.
+     */
+    pub write_quorum: Option<usize>,
+    pub flush_quorum: Option<usize>,
+    /*
+     * See JobTimeout.  Either can be left out to use its default.
+     */
+    pub job_timeout_secs: Option<u64>,
+    pub job_max_misses: Option<u32>,
+    /*
+     * fault, renegotiate or reactivate, see UuidMismatchPolicy.
+     */
+    pub uuid_mismatch: Option<UuidMismatchPolicy>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DownstairsConfig {
+    pub address: Option<Ipv4Addr>,
+    pub port: Option<u16>,
+    pub data: Option<PathBuf>,
+    /*
+     * If set, the region in data must have this UUID.
+     */
+    pub uuid: Option<Uuid>,
+}
+
+fn parse_env<T>(name: &str, value: &str) -> Result<T>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    value
+        .trim()
+        .parse()
+        .map_err(|e| anyhow!("{}={:?}: {}", name, value, e))
+}
+
+impl Config {
+    pub fn from_toml(s: &str) -> Result<Config> {
+        Ok(toml::from_str(s)?)
+    }
+
+    /*
+     * Read the file if there is one, then apply any overrides from the
+     * environment, then check the result.
+     */
+    pub fn load(path: Option<&Path>) -> Result<Config> {
+        let mut config = match path {
+            Some(path) => {
+                let s = fs::read_to_string(path)
+                    .with_context(|| format!("read config {:?}", path))?;
+                Config::from_toml(&s)
+                    .with_context(|| format!("parse config {:?}", path))?
+            }
+            None => Config::default(),
+        };
+
+        config.apply_env(|name| std::env::var(name).ok())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /*
+     * Replace settings with any of these that are set:
+     *
+     *     CRUCIBLE_TARGET       targets, separated by commas
+     *     CRUCIBLE_KEY
+     *     CRUCIBLE_GEN          for the upstairs and for the volume
+     *     CRUCIBLE_CONTROL
+     *     CRUCIBLE_READ_ONLY    true or false
+     *     CRUCIBLE_VOLUME       a whole volume construction request, JSON
+     *     CRUCIBLE_DS_ADDRESS
+     *     CRUCIBLE_DS_PORT
+     *     CRUCIBLE_DS_DATA
+     *     CRUCIBLE_DS_UUID
+     *
+     * var looks one up by name, so tests don't have to change the real
+     * environment.
+     */
+    pub fn apply_env<F>(&mut self, var: F) -> Result<()>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        /*
+         * The volume goes first, so the generation below replaces the
+         * one in it.
+         */
+        if let Some(v) = var("CRUCIBLE_VOLUME") {
+            let request = serde_json::from_str(&v)
+                .map_err(|e| anyhow!("CRUCIBLE_VOLUME: {}", e))?;
+            self.volume = Some(request);
+        }
+
+        let up = &mut self.upstairs;
+        if let Some(v) = var("CRUCIBLE_TARGET") {
+            up.target = v
+                .split(',')
+                .filter(|t| !t.trim().is_empty())
+                .map(|t| parse_env("CRUCIBLE_TARGET", t))
+                .collect::<Result<_>>()?;
+        }
+        if let Some(v) = var("CRUCIBLE_KEY") {
+            up.key = Some(v);
+        }
+        if let Some(v) = var("CRUCIBLE_GEN") {
+            up.gen = parse_env("CRUCIBLE_GEN", &v)?;
+            if let Some(volume) = &mut self.volume {
+                volume.gen = up.gen;
+            }
+        }
+        if let Some(v) = var("CRUCIBLE_CONTROL") {
+            up.control = Some(parse_env("CRUCIBLE_CONTROL", &v)?);
+        }
+        if let Some(v) = var("CRUCIBLE_READ_ONLY") {
+            up.read_only = parse_env("CRUCIBLE_READ_ONLY", &v)?;
+        }
+
+        let ds = &mut self.downstairs;
+        if let Some(v) = var("CRUCIBLE_DS_ADDRESS") {
+            ds.address = Some(parse_env("CRUCIBLE_DS_ADDRESS", &v)?);
+        }
+        if let Some(v) = var("CRUCIBLE_DS_PORT") {
+            ds.port = Some(parse_env("CRUCIBLE_DS_PORT", &v)?);
+        }
+        if let Some(v) = var("CRUCIBLE_DS_DATA") {
+            ds.data = Some(PathBuf::from(v));
+        }
+        if let Some(v) = var("CRUCIBLE_DS_UUID") {
+            ds.uuid = Some(parse_env("CRUCIBLE_DS_UUID", &v)?);
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Catch what we can before anything is started.
+     */
+    pub fn validate(&self) -> Result<()> {
+        if let Some(volume) = &self.volume {
+            if !self.upstairs.target.is_empty() {
+                bail!("give either upstairs targets or a volume, not both");
+            }
+            volume.validate()?;
+        }
+        if !self.upstairs.target.is_empty() {
+            self.upstairs.crucible_opts()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl UpstairsConfig {
+    pub fn policy(&self) -> Result<ReplicationPolicy> {
+        let majority = ReplicationPolicy::majority(self.target.len())?;
+        ReplicationPolicy::new(
+            majority.replicas(),
+            self.write_quorum.unwrap_or_else(|| majority.write_quorum()),
+            self.flush_quorum.unwrap_or_else(|| majority.flush_quorum()),
+        )
+    }
+
+    pub fn job_timeout(&self) -> Result<JobTimeout> {
+        let default = JobTimeout::default();
+        JobTimeout::new(
+            self.job_timeout_secs
+                .map(Duration::from_secs)
+                .unwrap_or_else(|| default.timeout()),
+            self.job_max_misses.unwrap_or_else(|| default.max_misses()),
+        )
+    }
+
+    /*
+     * The options to start an upstairs with.  The key is checked here,
+     * as CrucibleOpts::key_bytes() panics on a bad one.
+     */
+    pub fn crucible_opts(&self) -> Result<CrucibleOpts> {
+        if self.target.is_empty() {
+            bail!("must specify at least one upstairs target");
+        }
+        if let Some(key) = &self.key {
+            let decoded = base64::decode(key)
+                .map_err(|e| anyhow!("could not base64 decode key: {}", e))?;
+            if decoded.len() != 32 {
+                bail!("key length must be 32 bytes, not {}", decoded.len());
+            }
+        }
+
+        Ok(CrucibleOpts {
+            target: self.target.clone(),
+            lossy: self.lossy,
+            key: self.key.clone(),
+            legacy_xts: self.legacy_xts,
+            control: self.control,
+            policy: Some(self.policy()?),
+            read_only: self.read_only,
+            job_timeout: Some(self.job_timeout()?),
+            uuid_mismatch: self.uuid_mismatch,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    const CONFIG: &str = r#"
+        [upstairs]
+        target = ["10.0.0.1:3801", "10.0.0.2:3801", "10.0.0.3:3801"]
+        key = "9YGqFSwBHCX/IbjstbI1WuUPKOfwrwNAJSFzUN2w4iU="
+        gen = 3
+        control = "127.0.0.1:7777"
+        write_quorum = 3
+        job_timeout_secs = 10
+        uuid_mismatch = "reactivate"
+
+        [downstairs]
+        port = 3801
+        data = "/var/crucible/3801"
+        uuid = "9b2e3a44-8bf5-4f2a-a4a8-8ab4d0c6a6d0"
+    "#;
+
+    const VOLUME: &str = r#"
+        [volume]
+        block_size = 512
+        gen = 2
+
+        [[volume.sub_volumes]]
+        target = ["10.0.0.1:3801", "10.0.0.2:3801", "10.0.0.3:3801"]
+
+        [[volume.sub_volumes]]
+        target = ["10.0.0.4:3801", "10.0.0.5:3801", "10.0.0.6:3801"]
+        key = "abc"
+
+        [volume.read_only_parent]
+        target = ["10.0.0.7:3801"]
+    "#;
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> + 'static {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    #[test]
+    fn upstairs_from_toml() {
+        let config = Config::from_toml(CONFIG).unwrap();
+        config.validate().unwrap();
+        assert!(config.volume.is_none());
+
+        let opts = config.upstairs.crucible_opts().unwrap();
+        assert_eq!(opts.target.len(), 3);
+        assert_eq!(opts.control, Some("127.0.0.1:7777".parse().unwrap()));
+        assert_eq!(opts.key_bytes().unwrap().len(), 32);
+        assert!(!opts.read_only);
+
+        let policy = opts.policy();
+        assert_eq!(policy.replicas(), 3);
+        assert_eq!(policy.write_quorum(), 3);
+        assert_eq!(policy.flush_quorum(), 2);
+
+        let timeout = opts.job_timeout();
+        assert_eq!(timeout.timeout(), Duration::from_secs(10));
+        assert_eq!(timeout.max_misses(), JobTimeout::default().max_misses());
+        assert_eq!(opts.uuid_mismatch(), UuidMismatchPolicy::Reactivate);
+
+        assert_eq!(config.upstairs.gen, 3);
+        assert_eq!(config.downstairs.port, Some(3801));
+        assert_eq!(config.downstairs.address, None);
+        assert!(config.downstairs.uuid.is_some());
+    }
+
+    #[test]
+    fn volume_from_toml() {
+        let config = Config::from_toml(VOLUME).unwrap();
+        config.validate().unwrap();
+
+        let volume = config.volume.unwrap();
+        assert_eq!(volume.gen, 2);
+        assert_eq!(volume.sub_volumes.len(), 2);
+        assert_eq!(volume.sub_volumes[1].key, Some("abc".to_string()));
+        assert_eq!(volume.read_only_parent.unwrap().target.len(), 1);
+        assert!(config.upstairs.target.is_empty());
+    }
+
+    #[test]
+    fn empty_is_default() {
+        let config = Config::from_toml("").unwrap();
+        assert_eq!(config, Config::default());
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn unknown_keys_are_refused() {
+        assert!(Config::from_toml("[upstairs]\ntargets = []\n").is_err());
+        assert!(Config::from_toml("[pantry]\n").is_err());
+    }
+
+    #[test]
+    fn env_overrides_file() {
+        let mut config = Config::from_toml(CONFIG).unwrap();
+        config
+            .apply_env(env(&[
+                ("CRUCIBLE_TARGET", "127.0.0.1:3810, 127.0.0.1:3811"),
+                ("CRUCIBLE_GEN", "7"),
+                ("CRUCIBLE_READ_ONLY", "true"),
+                ("CRUCIBLE_DS_PORT", "3810"),
+                ("CRUCIBLE_DS_DATA", "/tmp/ds"),
+            ]))
+            .unwrap();
+
+        let up = &config.upstairs;
+        assert_eq!(
+            up.target,
+            vec![
+                "127.0.0.1:3810".parse::<SocketAddrV4>().unwrap(),
+                "127.0.0.1:3811".parse().unwrap(),
+            ]
+        );
+        assert_eq!(up.gen, 7);
+        assert!(up.read_only);
+        /*
+         * Not overridden, so still what the file said.
+         */
+        assert_eq!(up.job_timeout_secs, Some(10));
+        assert_eq!(config.downstairs.port, Some(3810));
+        assert_eq!(config.downstairs.data, Some(PathBuf::from("/tmp/ds")));
+
+        /*
+         * Two targets can't take the write quorum of three from the file.
+         */
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn env_volume_and_gen() {
+        let mut config = Config::default();
+        config
+            .apply_env(env(&[
+                (
+                    "CRUCIBLE_VOLUME",
+                    r#"{"block_size": 4096, "gen": 1, "sub_volumes":
+                    [{"target": ["127.0.0.1:3801"]}]}"#,
+                ),
+                ("CRUCIBLE_GEN", "9"),
+            ]))
+            .unwrap();
+        config.validate().unwrap();
+
+        let volume = config.volume.unwrap();
+        assert_eq!(volume.block_size, 4096);
+        assert_eq!(volume.gen, 9);
+    }
+
+    #[test]
+    fn bad_env_names_the_variable() {
+        let mut config = Config::default();
+        let e = config
+            .apply_env(env(&[("CRUCIBLE_DS_PORT", "99999")]))
+            .unwrap_err();
+        assert!(e.to_string().contains("CRUCIBLE_DS_PORT"), "{}", e);
+
+        let e = config
+            .apply_env(env(&[("CRUCIBLE_TARGET", "10.0.0.1")]))
+            .unwrap_err();
+        assert!(e.to_string().contains("CRUCIBLE_TARGET"), "{}", e);
+    }
+
+    #[test]
+    fn invalid_configs() {
+        let mut config = Config::from_toml(CONFIG).unwrap();
+        config.upstairs.key = Some("c2hvcnQ=".to_string());
+        assert!(config.validate().is_err());
+
+        let mut config = Config::from_toml(CONFIG).unwrap();
+        config.upstairs.job_max_misses = Some(0);
+        assert!(config.validate().is_err());
+
+        let mut config = Config::from_toml(VOLUME).unwrap();
+        config.upstairs.target = vec!["127.0.0.1:3801".parse().unwrap()];
+        assert!(config.validate().is_err());
+
+        let mut config = Config::from_toml(VOLUME).unwrap();
+        config.volume.as_mut().unwrap().block_size = 1000;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn load_reads_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("crucible.toml");
+        fs::write(&path, CONFIG).unwrap();
+
+        let config = Config::load(Some(&path)).unwrap();
+        assert_eq!(
+            config.downstairs.data.as_deref(),
+            Some(Path::new("/var/crucible/3801"))
+        );
+
+        assert!(Config::load(Some(&dir.path().join("missing.toml"))).is_err());
+    }
+}
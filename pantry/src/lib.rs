@@ -0,0 +1,493 @@
+// Copyright 2021 Oxide Computer Company
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use schemars::JsonSchema;
+use serde::Serialize;
+use tokio::runtime::Handle;
+use uuid::Uuid;
+
+use crucible::*;
+
+pub mod server;
+
+/*
+ * An image is written to the volume this much at a time.  A bulk read or
+ * write can move at most MAX_BULK_BYTES.
+ */
+const IMPORT_CHUNK: usize = 1024 * 1024;
+pub const MAX_BULK_BYTES: usize = 1024 * 1024;
+
+/*
+ * Nothing but the pantry uses a volume it has attached, so a scrub
+ * doesn't have to leave room for a guest.
+ */
+const SCRUB_CHUNK: u64 = 2048;
+const SCRUB_PAUSE: Duration = Duration::from_millis(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum JobKind {
+    Import,
+    Scrub,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Running,
+    Done,
+    Failed(String),
+}
+
+/*
+ * Work on an attached volume that runs in the background, and how far
+ * it has got.
+ */
+#[derive(Debug)]
+struct Job {
+    volume: String,
+    kind: JobKind,
+    bytes_done: AtomicU64,
+    /*
+     * Zero until known.  An import only knows it if the server sends a
+     * Content-Length.
+     */
+    bytes_total: AtomicU64,
+    state: Mutex<JobState>,
+}
+
+impl Job {
+    fn running(&self) -> bool {
+        *self.state.lock().unwrap() == JobState::Running
+    }
+
+    fn finish(&self, result: Result<()>) {
+        let state = match result {
+            Ok(()) => JobState::Done,
+            Err(e) => JobState::Failed(e.to_string()),
+        };
+        println!("{:?} of {}: {:?}", self.kind, self.volume, state);
+        *self.state.lock().unwrap() = state;
+    }
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct JobStatus {
+    pub id: Uuid,
+    pub volume: String,
+    pub kind: JobKind,
+    pub state: JobState,
+    pub bytes_done: u64,
+    pub bytes_total: u64,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct VolumeStatus {
+    pub block_size: u64,
+    pub total_size: u64,
+    pub read_only: bool,
+    /*
+     * Jobs on this volume that have not finished.
+     */
+    pub running_jobs: Vec<Uuid>,
+}
+
+/*
+ * The pantry attaches volumes for whoever is moving data in or out of
+ * them, so that doesn't have to happen in a hypervisor with a guest
+ * running.  Each volume is attached under a name the caller picks.  An
+ * image can be imported from a URL, the parent of a volume scrubbed into
+ * it, and any part of a volume read or written directly.  Imports and
+ * scrubs run as jobs in the background, and the caller polls them to see
+ * how far along they are.
+ *
+ * XXX A volume that is detached is flushed and forgotten, but its
+ * upstairs tasks keep running until the pantry exits, as there is not
+ * yet a way to stop them.
+ */
+pub struct Pantry {
+    volumes: Mutex<HashMap<String, Arc<Volume>>>,
+    jobs: Mutex<HashMap<Uuid, Arc<Job>>>,
+    client: reqwest::Client,
+}
+
+impl Default for Pantry {
+    fn default() -> Self {
+        Pantry::new()
+    }
+}
+
+/*
+ * The data written past the end of an image is zero, up to the end of the
+ * block it ends in.
+ */
+fn pad_to_block(mut data: BytesMut, block_size: u64) -> Bytes {
+    let bs = block_size as usize;
+    let len = (data.len() + bs - 1) / bs * bs;
+    data.resize(len, 0);
+    data.freeze()
+}
+
+fn check_url(url: &str) -> Result<()> {
+    let url = reqwest::Url::parse(url)?;
+    match url.scheme() {
+        "http" | "https" => Ok(()),
+        scheme => bail!("can't import from a {} URL", scheme),
+    }
+}
+
+async fn write_at(volume: &Volume, offset: u64, data: Bytes) -> Result<()> {
+    let offset = Block::from_byte_offset(offset, volume.block_size())?;
+    volume.write(offset, data).await?;
+    Ok(())
+}
+
+/*
+ * Copy an image from a URL to the start of a volume, then flush it.
+ */
+async fn import(
+    client: reqwest::Client,
+    volume: Arc<Volume>,
+    url: String,
+    job: Arc<Job>,
+) -> Result<()> {
+    let mut response = client.get(&url).send().await?.error_for_status()?;
+
+    let size = volume.total_size();
+    if let Some(len) = response.content_length() {
+        if len > size {
+            bail!(
+                "image of {} bytes is bigger than the volume of {}",
+                len,
+                size
+            );
+        }
+        job.bytes_total.store(len, Ordering::SeqCst);
+    }
+
+    let mut buf = BytesMut::with_capacity(IMPORT_CHUNK);
+    let mut offset = 0;
+    let mut image_len = 0;
+    while let Some(bytes) = response.chunk().await? {
+        image_len += bytes.len() as u64;
+        if image_len > size {
+            bail!("image is bigger than the volume of {} bytes", size);
+        }
+        buf.extend_from_slice(&bytes);
+
+        while buf.len() >= IMPORT_CHUNK {
+            let chunk = buf.split_to(IMPORT_CHUNK).freeze();
+            write_at(&volume, offset, chunk).await?;
+            offset += IMPORT_CHUNK as u64;
+            job.bytes_done.store(offset, Ordering::SeqCst);
+        }
+    }
+    if !buf.is_empty() {
+        write_at(&volume, offset, pad_to_block(buf, volume.block_size()))
+            .await?;
+    }
+    volume.flush().await?;
+
+    job.bytes_done.store(image_len, Ordering::SeqCst);
+    job.bytes_total.store(image_len, Ordering::SeqCst);
+    Ok(())
+}
+
+impl Pantry {
+    pub fn new() -> Pantry {
+        Pantry {
+            volumes: Mutex::new(HashMap::new()),
+            jobs: Mutex::new(HashMap::new()),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    fn volume(&self, name: &str) -> Result<Arc<Volume>> {
+        self.volumes
+            .lock()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| anyhow!("no volume {} is attached", name))
+    }
+
+    fn running_jobs(&self, name: &str) -> Vec<Uuid> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, job)| job.volume == name && job.running())
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /*
+     * Build the volume a request describes and keep it under the given
+     * name.  This waits for every upstairs in it to go active.
+     */
+    pub async fn attach(
+        &self,
+        name: String,
+        request: VolumeConstructionRequest,
+    ) -> Result<()> {
+        request.validate()?;
+        if self.volumes.lock().unwrap().contains_key(&name) {
+            bail!("volume {} is already attached", name);
+        }
+
+        let handle = Handle::current();
+        let volume = tokio::task::spawn_blocking(move || {
+            Volume::construct(&request, &handle)
+        })
+        .await??;
+
+        let mut volumes = self.volumes.lock().unwrap();
+        if volumes.contains_key(&name) {
+            bail!("volume {} is already attached", name);
+        }
+        println!(
+            "Attached {} with {} blocks of {}",
+            name,
+            volume.total_blocks(),
+            volume.block_size()
+        );
+        volumes.insert(name, Arc::new(volume));
+
+        Ok(())
+    }
+
+    pub fn volume_status(&self, name: &str) -> Result<VolumeStatus> {
+        let volume = self.volume(name)?;
+        Ok(VolumeStatus {
+            block_size: volume.block_size(),
+            total_size: volume.total_size(),
+            read_only: volume.read_only(),
+            running_jobs: self.running_jobs(name),
+        })
+    }
+
+    /*
+     * Flush a volume and forget it.  Not while a job is still using it.
+     */
+    pub async fn detach(&self, name: &str) -> Result<()> {
+        let volume = {
+            let mut volumes = self.volumes.lock().unwrap();
+            if !volumes.contains_key(name) {
+                bail!("no volume {} is attached", name);
+            }
+            let running = self.running_jobs(name);
+            if !running.is_empty() {
+                bail!("volume {} has jobs running: {:?}", name, running);
+            }
+            volumes.remove(name).unwrap()
+        };
+
+        volume.flush().await?;
+        println!("Detached {}", name);
+        Ok(())
+    }
+
+    fn start_job(&self, name: &str, kind: JobKind) -> (Uuid, Arc<Job>) {
+        let id = Uuid::new_v4();
+        let job = Arc::new(Job {
+            volume: name.to_string(),
+            kind,
+            bytes_done: AtomicU64::new(0),
+            bytes_total: AtomicU64::new(0),
+            state: Mutex::new(JobState::Running),
+        });
+        self.jobs.lock().unwrap().insert(id, job.clone());
+        (id, job)
+    }
+
+    /*
+     * Start copying the image at url to the start of a volume.  An image
+     * that doesn't end on a block is padded with zeros.
+     */
+    pub fn import_from_url(&self, name: &str, url: String) -> Result<Uuid> {
+        check_url(&url)?;
+        let volume = self.volume(name)?;
+        if volume.read_only() {
+            bail!("volume {} is read only", name);
+        }
+
+        let (id, job) = self.start_job(name, JobKind::Import);
+        println!("Import {} to {} as job {}", url, name, id);
+        let client = self.client.clone();
+        tokio::spawn(async move {
+            let result = import(client, volume, url, job.clone()).await;
+            job.finish(result);
+        });
+
+        Ok(id)
+    }
+
+    /*
+     * Start copying the read only parent of a volume into it, so the
+     * parent is no longer needed.
+     */
+    pub fn scrub(&self, name: &str) -> Result<Uuid> {
+        let volume = self.volume(name)?;
+        let parent_size = match volume.read_only_parent() {
+            Some(parent) => {
+                let range = parent.lba_range();
+                (range.end - range.start) * volume.block_size()
+            }
+            None => bail!("volume {} has no read only parent", name),
+        };
+        if volume.read_only() {
+            bail!("volume {} is read only", name);
+        }
+
+        let (id, job) = self.start_job(name, JobKind::Scrub);
+        job.bytes_total.store(parent_size, Ordering::SeqCst);
+        println!("Scrub {} as job {}", name, id);
+
+        let mut scrub = volume.start_scrub(SCRUB_CHUNK, SCRUB_PAUSE);
+        tokio::spawn(async move {
+            /*
+             * Scrub progress is kept by the volume, so check on it while
+             * the scrub runs.
+             */
+            let result = loop {
+                tokio::select! {
+                    result = &mut scrub => break result,
+                    _ = tokio::time::sleep(Duration::from_secs(1)) => {
+                        let done = volume.scrub_point() * volume.block_size();
+                        job.bytes_done.store(done, Ordering::SeqCst);
+                    }
+                }
+            };
+            let result = match result {
+                Ok(Ok(())) => volume.flush().await.map_err(|e| e.into()),
+                Ok(Err(e)) => Err(e.into()),
+                Err(e) => Err(e.into()),
+            };
+            job.bytes_done.store(
+                volume.scrub_point() * volume.block_size(),
+                Ordering::SeqCst,
+            );
+            job.finish(result);
+        });
+
+        Ok(id)
+    }
+
+    pub fn job(&self, id: Uuid) -> Result<JobStatus> {
+        let job = self
+            .jobs
+            .lock()
+            .unwrap()
+            .get(&id)
+            .cloned()
+            .ok_or_else(|| anyhow!("no job {}", id))?;
+
+        let state = job.state.lock().unwrap().clone();
+        Ok(JobStatus {
+            id,
+            volume: job.volume.clone(),
+            kind: job.kind,
+            state,
+            bytes_done: job.bytes_done.load(Ordering::SeqCst),
+            bytes_total: job.bytes_total.load(Ordering::SeqCst),
+        })
+    }
+
+    /*
+     * Read or write part of a volume directly.  The offset and size must
+     * be whole blocks.
+     */
+    pub async fn bulk_read(
+        &self,
+        name: &str,
+        offset: u64,
+        size: usize,
+    ) -> Result<Vec<u8>> {
+        if size > MAX_BULK_BYTES {
+            bail!("can read at most {} bytes at once", MAX_BULK_BYTES);
+        }
+        let volume = self.volume(name)?;
+
+        let offset = Block::from_byte_offset(offset, volume.block_size())?;
+        let data = Buffer::new(size);
+        volume.read(offset, data.clone()).await?;
+
+        let data = data.as_vec().clone();
+        Ok(data)
+    }
+
+    pub async fn bulk_write(
+        &self,
+        name: &str,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> Result<()> {
+        if data.len() > MAX_BULK_BYTES {
+            bail!("can write at most {} bytes at once", MAX_BULK_BYTES);
+        }
+        let volume = self.volume(name)?;
+
+        write_at(&volume, offset, Bytes::from(data)).await?;
+        volume.flush().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn image_tail_is_padded() {
+        let data = BytesMut::from(&[1u8; 700][..]);
+        let padded = pad_to_block(data, 512);
+        assert_eq!(padded.len(), 1024);
+        assert!(padded[..700].iter().all(|b| *b == 1));
+        assert!(padded[700..].iter().all(|b| *b == 0));
+
+        let data = BytesMut::from(&[1u8; 512][..]);
+        assert_eq!(pad_to_block(data, 512).len(), 512);
+    }
+
+    #[test]
+    fn only_http_urls() {
+        check_url("http://example.com/disk.img").unwrap();
+        check_url("https://example.com/disk.img").unwrap();
+        assert!(check_url("file:///etc/passwd").is_err());
+        assert!(check_url("not a url").is_err());
+    }
+
+    #[tokio::test]
+    async fn unknown_volume_or_job() {
+        let pantry = Pantry::new();
+        assert!(pantry.volume_status("nope").is_err());
+        assert!(pantry.detach("nope").await.is_err());
+        assert!(pantry
+            .import_from_url("nope", "http://example.com/x".to_string())
+            .is_err());
+        assert!(pantry.scrub("nope").is_err());
+        assert!(pantry.bulk_read("nope", 0, 512).await.is_err());
+        assert!(pantry.job(Uuid::new_v4()).is_err());
+    }
+
+    #[tokio::test]
+    async fn bulk_limits() {
+        let pantry = Pantry::new();
+        assert!(pantry
+            .bulk_read("nope", 0, MAX_BULK_BYTES + 1)
+            .await
+            .unwrap_err()
+            .to_string()
+            .contains("at most"));
+        assert!(pantry
+            .bulk_write("nope", 0, vec![0; MAX_BULK_BYTES + 1])
+            .await
+            .unwrap_err()
+            .to_string()
+            .contains("at most"));
+    }
+}
@@ -0,0 +1,32 @@
+// Copyright 2021 Oxide Computer Company
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::Result;
+use structopt::StructOpt;
+
+use crucible_pantry::{server, Pantry};
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "attach volumes to move data in and out of them")]
+struct Opt {
+    #[structopt(short, long, default_value = "127.0.0.1:17000")]
+    listen: SocketAddr,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let opt = Opt::from_args();
+
+    /*
+     * If any of our async tasks in our runtime panic, then we should
+     * exit the program right away.
+     */
+    let default_panic = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default_panic(info);
+        std::process::exit(1);
+    }));
+
+    server::start(Arc::new(Pantry::new()), opt.listen).await
+}
@@ -0,0 +1,276 @@
+// Copyright 2021 Oxide Computer Company
+use super::*;
+
+use dropshot::endpoint;
+use dropshot::ApiDescription;
+use dropshot::ConfigDropshot;
+use dropshot::ConfigLogging;
+use dropshot::ConfigLoggingLevel;
+use dropshot::HttpError;
+use dropshot::HttpResponseDeleted;
+use dropshot::HttpResponseOk;
+use dropshot::HttpResponseUpdatedNoContent;
+use dropshot::HttpServerStarter;
+use dropshot::Path;
+use dropshot::RequestContext;
+use dropshot::TypedBody;
+use serde::Deserialize;
+use std::net::SocketAddr;
+
+/*
+ * A bulk write of MAX_BULK_BYTES, in base64, with room to spare.
+ */
+const REQUEST_BODY_MAX_BYTES: usize = MAX_BULK_BYTES * 2;
+
+pub fn api() -> ApiDescription<Arc<Pantry>> {
+    let mut api = ApiDescription::new();
+    api.register(volume_attach).unwrap();
+    api.register(volume_status).unwrap();
+    api.register(volume_detach).unwrap();
+    api.register(volume_import_from_url).unwrap();
+    api.register(volume_scrub).unwrap();
+    api.register(volume_bulk_read).unwrap();
+    api.register(volume_bulk_write).unwrap();
+    api.register(job_status).unwrap();
+    api
+}
+
+pub async fn start(pantry: Arc<Pantry>, addr: SocketAddr) -> Result<()> {
+    let config_dropshot = ConfigDropshot {
+        bind_address: addr,
+        request_body_max_bytes: REQUEST_BODY_MAX_BYTES,
+        ..Default::default()
+    };
+
+    let log = ConfigLogging::StderrTerminal {
+        level: ConfigLoggingLevel::Info,
+    }
+    .to_logger("pantry")
+    .map_err(|e| anyhow!("failed to create logger: {}", e))?;
+
+    let server = HttpServerStarter::new(&config_dropshot, api(), pantry, &log)
+        .map_err(|e| anyhow!("failed to create pantry server: {}", e))?
+        .start();
+    println!("Pantry listening on {}", addr);
+
+    server
+        .await
+        .map_err(|e| anyhow!("pantry server failed: {}", e))
+}
+
+fn bad_request(e: anyhow::Error) -> HttpError {
+    HttpError::for_bad_request(None, e.to_string())
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct VolumePath {
+    name: String,
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct JobPath {
+    id: Uuid,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+struct JobStarted {
+    job_id: Uuid,
+}
+
+/*
+ * Attach the volume in the body under this name.
+ */
+#[endpoint {
+    method = POST,
+    path = "/volume/{name}",
+}]
+async fn volume_attach(
+    rqctx: Arc<RequestContext<Arc<Pantry>>>,
+    path: Path<VolumePath>,
+    body: TypedBody<VolumeConstructionRequest>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    let pantry = rqctx.context();
+    let name = path.into_inner().name;
+
+    pantry
+        .attach(name, body.into_inner())
+        .await
+        .map_err(bad_request)?;
+
+    Ok(HttpResponseUpdatedNoContent())
+}
+
+#[endpoint {
+    method = GET,
+    path = "/volume/{name}",
+}]
+async fn volume_status(
+    rqctx: Arc<RequestContext<Arc<Pantry>>>,
+    path: Path<VolumePath>,
+) -> Result<HttpResponseOk<VolumeStatus>, HttpError> {
+    let pantry = rqctx.context();
+    let name = path.into_inner().name;
+
+    let status = pantry
+        .volume_status(&name)
+        .map_err(|e| HttpError::for_not_found(None, e.to_string()))?;
+
+    Ok(HttpResponseOk(status))
+}
+
+#[endpoint {
+    method = DELETE,
+    path = "/volume/{name}",
+}]
+async fn volume_detach(
+    rqctx: Arc<RequestContext<Arc<Pantry>>>,
+    path: Path<VolumePath>,
+) -> Result<HttpResponseDeleted, HttpError> {
+    let pantry = rqctx.context();
+    let name = path.into_inner().name;
+
+    pantry.detach(&name).await.map_err(bad_request)?;
+
+    Ok(HttpResponseDeleted())
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct ImportFromUrl {
+    url: String,
+}
+
+/*
+ * Start copying an image at a URL into the volume.  Poll the job this
+ * returns to see when it is done.
+ */
+#[endpoint {
+    method = POST,
+    path = "/volume/{name}/import_from_url",
+}]
+async fn volume_import_from_url(
+    rqctx: Arc<RequestContext<Arc<Pantry>>>,
+    path: Path<VolumePath>,
+    body: TypedBody<ImportFromUrl>,
+) -> Result<HttpResponseOk<JobStarted>, HttpError> {
+    let pantry = rqctx.context();
+    let name = path.into_inner().name;
+
+    let job_id = pantry
+        .import_from_url(&name, body.into_inner().url)
+        .map_err(bad_request)?;
+
+    Ok(HttpResponseOk(JobStarted { job_id }))
+}
+
+/*
+ * Start copying the volume's read only parent into it.
+ */
+#[endpoint {
+    method = POST,
+    path = "/volume/{name}/scrub",
+}]
+async fn volume_scrub(
+    rqctx: Arc<RequestContext<Arc<Pantry>>>,
+    path: Path<VolumePath>,
+) -> Result<HttpResponseOk<JobStarted>, HttpError> {
+    let pantry = rqctx.context();
+    let name = path.into_inner().name;
+
+    let job_id = pantry.scrub(&name).map_err(bad_request)?;
+
+    Ok(HttpResponseOk(JobStarted { job_id }))
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct BulkRead {
+    offset: u64,
+    size: usize,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct BulkData {
+    base64_encoded_data: String,
+}
+
+/*
+ * Export part of a volume, up to MAX_BULK_BYTES at a time.
+ */
+#[endpoint {
+    method = POST,
+    path = "/volume/{name}/bulk_read",
+}]
+async fn volume_bulk_read(
+    rqctx: Arc<RequestContext<Arc<Pantry>>>,
+    path: Path<VolumePath>,
+    body: TypedBody<BulkRead>,
+) -> Result<HttpResponseOk<BulkData>, HttpError> {
+    let pantry = rqctx.context();
+    let name = path.into_inner().name;
+    let req = body.into_inner();
+
+    let data = pantry
+        .bulk_read(&name, req.offset, req.size)
+        .await
+        .map_err(bad_request)?;
+
+    Ok(HttpResponseOk(BulkData {
+        base64_encoded_data: base64::encode(data),
+    }))
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct BulkWrite {
+    offset: u64,
+    base64_encoded_data: String,
+}
+
+#[endpoint {
+    method = POST,
+    path = "/volume/{name}/bulk_write",
+}]
+async fn volume_bulk_write(
+    rqctx: Arc<RequestContext<Arc<Pantry>>>,
+    path: Path<VolumePath>,
+    body: TypedBody<BulkWrite>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    let pantry = rqctx.context();
+    let name = path.into_inner().name;
+    let req = body.into_inner();
+
+    let data = base64::decode(&req.base64_encoded_data)
+        .map_err(|e| HttpError::for_bad_request(None, e.to_string()))?;
+    pantry
+        .bulk_write(&name, req.offset, data)
+        .await
+        .map_err(bad_request)?;
+
+    Ok(HttpResponseUpdatedNoContent())
+}
+
+#[endpoint {
+    method = GET,
+    path = "/job/{id}",
+}]
+async fn job_status(
+    rqctx: Arc<RequestContext<Arc<Pantry>>>,
+    path: Path<JobPath>,
+) -> Result<HttpResponseOk<JobStatus>, HttpError> {
+    let pantry = rqctx.context();
+    let id = path.into_inner().id;
+
+    let status = pantry
+        .job(id)
+        .map_err(|e| HttpError::for_not_found(None, e.to_string()))?;
+
+    Ok(HttpResponseOk(status))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn api_registers() {
+        api();
+    }
+}
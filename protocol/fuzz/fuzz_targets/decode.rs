@@ -0,0 +1,23 @@
+// Copyright 2021 Oxide Computer Company
+#![no_main]
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crucible_protocol::{CrucibleDecoder, CrucibleEncoder};
+
+/*
+ * Whatever a peer sends, decoding it must not panic.  Any message that
+ * does decode has to come back the same after another encode and decode.
+ */
+fuzz_target!(|data: &[u8]| {
+    let mut decoder = CrucibleDecoder::new();
+    let mut src = BytesMut::from(data);
+
+    while let Ok(Some(m)) = decoder.decode(&mut src) {
+        let mut buf = BytesMut::new();
+        CrucibleEncoder::new().encode(&m, &mut buf).unwrap();
+        let again = CrucibleDecoder::new().decode(&mut buf).unwrap();
+        assert_eq!(again, Some(m));
+    }
+});
@@ -0,0 +1,48 @@
+// Copyright 2021 Oxide Computer Company
+#![no_main]
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use tokio_util::codec::Decoder;
+
+use crucible_protocol::{CrucibleDecoder, Message};
+
+/*
+ * Feed data to the decoder chunk bytes at a time, as it would arrive off
+ * a socket.  Return the messages decoded, and if decoding failed.
+ */
+fn decode_in_chunks(data: &[u8], chunk: usize) -> (Vec<Message>, bool) {
+    let mut decoder = CrucibleDecoder::new();
+    let mut src = BytesMut::new();
+    let mut messages = Vec::new();
+
+    for piece in data.chunks(chunk) {
+        src.extend_from_slice(piece);
+        loop {
+            match decoder.decode(&mut src) {
+                Ok(Some(m)) => messages.push(m),
+                Ok(None) => break,
+                Err(_) => return (messages, true),
+            }
+        }
+    }
+
+    (messages, false)
+}
+
+/*
+ * However the bytes are split up, the decoder has to see the same
+ * messages, and fail at the same place, as it does with all of them at
+ * once.  The first byte picks the chunk size.
+ */
+fuzz_target!(|data: &[u8]| {
+    if data.is_empty() {
+        return;
+    }
+    let chunk = data[0] as usize + 1;
+    let data = &data[1..];
+
+    assert_eq!(
+        decode_in_chunks(data, chunk),
+        decode_in_chunks(data, data.len().max(1))
+    );
+});
@@ -1,23 +1,52 @@
 // Copyright 2021 Oxide Computer Company
 use anyhow::bail;
-use bytes::{Buf, BufMut, BytesMut};
+use bincode::Options;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use serde::{Deserialize, Serialize};
 use tokio_util::codec::{Decoder, Encoder};
 use uuid::Uuid;
 
 const MAX_FRM_LEN: usize = 100 * 1024 * 1024; // 100M
 
+/*
+ * The version sent in HereIAm.  Bump this with any change to Message or
+ * to what is in it.
+ *
+ * 2: RuokSeq/ImokSeq, and the extent limit in Flush.
+ * 3: Block checksums in ExtentData.
+ * 4: The generation in PromoteToActive, and Attach, which says if the
+ *    upstairs is read only.
+ */
+pub const CRUCIBLE_MESSAGE_VERSION: u32 = 4;
+
 use crucible_common::{Block, CrucibleError, RegionDefinition};
 
+/*
+ * The data in a Write, an ExtentData and so a Message is Bytes, except
+ * while a message is decoded, when it is borrowed from the frame.  See
+ * CrucibleDecoder.
+ */
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
-pub struct Write {
+pub struct Write<D = Bytes> {
     pub eid: u64,
     pub offset: Block,
-    pub data: bytes::Bytes,
+    pub data: D,
     pub nonce: Option<Vec<u8>>,
     pub tag: Option<Vec<u8>>,
 }
 
+impl<D> Write<D> {
+    fn map_data<E>(self, f: &impl Fn(D) -> E) -> Write<E> {
+        Write {
+            eid: self.eid,
+            offset: self.offset,
+            data: f(self.data),
+            nonce: self.nonce,
+            tag: self.tag,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct ReadRequest {
     pub eid: u64,
@@ -37,16 +66,14 @@ pub struct ReadResponse {
 }
 
 impl ReadResponse {
+    /*
+     * A response with no data yet, but room for all the blocks asked for.
+     * The data is read straight into that room, so it is never filled in
+     * first just to be written over.
+     */
     pub fn from_request(request: &ReadRequest, bs: usize) -> ReadResponse {
-        /*
-         * XXX Some thought will need to be given to where the read
-         * data buffer is created, both on this side and the remote.
-         * Also, we (I) need to figure out how to read data into an
-         * uninitialized buffer. Until then, we have this workaround.
-         */
         let sz = request.num_blocks as usize * bs;
-        let mut data = BytesMut::with_capacity(sz);
-        data.resize(sz, 1);
+        let data = BytesMut::with_capacity(sz);
 
         ReadResponse {
             eid: request.eid,
@@ -73,23 +100,76 @@ impl ReadResponse {
     }
 }
 
+/*
+ * The full contents of a single extent, used to copy an extent from one
+ * downstairs to another during repair.  There is one entry in contexts
+ * for each block in the extent, holding the nonce and tag for that block
+ * if it has one, and the same for checksums, so the receiver can tell
+ * if the data was damaged on the way.
+ */
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ExtentData<D = Bytes> {
+    pub eid: u64,
+    pub data: D,
+    pub contexts: Vec<Option<(Vec<u8>, Vec<u8>)>>,
+    pub checksums: Vec<Option<u32>>,
+    pub gen_number: u64,
+    pub flush_number: u64,
+}
+
+impl<D> ExtentData<D> {
+    fn map_data<E>(self, f: &impl Fn(D) -> E) -> ExtentData<E> {
+        ExtentData {
+            eid: self.eid,
+            data: f(self.data),
+            contexts: self.contexts,
+            checksums: self.checksums,
+            gen_number: self.gen_number,
+            flush_number: self.flush_number,
+        }
+    }
+}
+
+/*
+ * Sent along with a flush to ask each downstairs to take a snapshot of
+ * its region once that flush is done.
+ */
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct SnapshotDetails {
+    pub snapshot_name: String,
+}
+
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
-pub enum Message {
+pub enum Message<D = Bytes> {
     /*
      * Initial negotiation
+     * HereIAm: version, Uuid
+     * YesItsMe: version
+     *
+     * These two must keep their place in this enum and what is in them,
+     * so a peer of any version can read them.  A downstairs that doesn't
+     * speak the version an upstairs asked for answers with VersionMismatch
+     * instead.
      */
     HereIAm(u32, Uuid),
     YesItsMe(u32),
 
     /*
-     * Forcefully tell this downstairs to promote us (an Upstairs) to
-     * active.
+     * Sent once the version is agreed.
+     * Attach: read only
+     */
+    Attach(bool),
+
+    /*
+     * Ask this downstairs to promote us (an Upstairs) to active, with our
+     * generation number.  The highest generation wins, and the old
+     * Upstairs is kicked out.
      *
-     * Kick out the old Upstairs.
+     * YouAreNoLongerActive: UUID and generation of the active Upstairs
      */
-    PromoteToActive(Uuid),
+    PromoteToActive(Uuid, u64),
     YouAreNowActive(Uuid),
-    YouAreNoLongerActive(Uuid), // UUID of new active Upstairs
+    YouAreNoLongerActive(Uuid, u64),
 
     /*
      * If downstairs sees a UUID that doesn't match what was negotiated, it
@@ -99,9 +179,17 @@ pub enum Message {
 
     /*
      * Ping related
+     * RuokSeq: sequence number, timestamp
+     * ImokSeq: the sequence number and timestamp of the RuokSeq
+     *
+     * The timestamp is the sender's, in microseconds from a point of its
+     * choosing.  It is only sent back, so the sender can work out the
+     * round trip without keeping each ping it has sent.
      */
     Ruok,
     Imok,
+    RuokSeq(u64, u64),
+    ImokSeq(u64, u64),
 
     /*
      * Metadata exchange
@@ -117,10 +205,24 @@ pub enum Message {
      * Write: Uuid, job id, dependencies, [Write]
      * WriteAck: Uuid, job id, result
      */
-    Write(Uuid, u64, Vec<u64>, Vec<Write>),
+    Write(Uuid, u64, Vec<u64>, Vec<Write<D>>),
     WriteAck(Uuid, u64, Result<(), CrucibleError>),
 
-    Flush(Uuid, u64, Vec<u64>, u64, u64),
+    /*
+     * Flush: Uuid, job id, dependencies, flush number, gen number,
+     *        snapshot to take after the flush, only flush extents below
+     *        this one (set while a live repair is under way)
+     * FlushAck: Uuid, job id, result
+     */
+    Flush(
+        Uuid,
+        u64,
+        Vec<u64>,
+        u64,
+        u64,
+        Option<SnapshotDetails>,
+        Option<u64>,
+    ),
     FlushAck(Uuid, u64, Result<(), CrucibleError>),
 
     /*
@@ -130,9 +232,85 @@ pub enum Message {
     ReadRequest(Uuid, u64, Vec<u64>, Vec<ReadRequest>),
     ReadResponse(Uuid, u64, Result<Vec<ReadResponse>, CrucibleError>),
 
+    /*
+     * Extent repair
+     * ExtentRepairRead: Uuid, job id, dependencies, extent id
+     * ExtentRepairData: Uuid, job id, Result<ExtentData>
+     * ExtentRepairWrite: Uuid, job id, dependencies, ExtentData
+     * ExtentRepairAck: Uuid, job id, result
+     */
+    ExtentRepairRead(Uuid, u64, Vec<u64>, u64),
+    ExtentRepairData(Uuid, u64, Result<ExtentData<D>, CrucibleError>),
+    ExtentRepairWrite(Uuid, u64, Vec<u64>, ExtentData<D>),
+    ExtentRepairAck(Uuid, u64, Result<(), CrucibleError>),
+
+    /*
+     * VersionMismatch: the version this downstairs speaks
+     */
+    VersionMismatch(u32),
+
     Unknown(u32, BytesMut),
 }
 
+impl<D> Message<D> {
+    /*
+     * The same message, with f applied to the data of each Write or
+     * ExtentData in it.
+     */
+    fn map_data<E>(self, f: impl Fn(D) -> E) -> Message<E> {
+        use Message::*;
+
+        match self {
+            HereIAm(v, u) => HereIAm(v, u),
+            YesItsMe(v) => YesItsMe(v),
+            Attach(ro) => Attach(ro),
+            PromoteToActive(u, g) => PromoteToActive(u, g),
+            YouAreNowActive(u) => YouAreNowActive(u),
+            YouAreNoLongerActive(u, g) => YouAreNoLongerActive(u, g),
+            UuidMismatch(u) => UuidMismatch(u),
+            Ruok => Ruok,
+            Imok => Imok,
+            RuokSeq(seq, ts) => RuokSeq(seq, ts),
+            ImokSeq(seq, ts) => ImokSeq(seq, ts),
+            RegionInfoPlease => RegionInfoPlease,
+            RegionInfo(def) => RegionInfo(def),
+            ExtentVersionsPlease => ExtentVersionsPlease,
+            LastFlush(n) => LastFlush(n),
+            LastFlushAck(n) => LastFlushAck(n),
+            ExtentVersions(gen, flush, dirty) => {
+                ExtentVersions(gen, flush, dirty)
+            }
+            Write(u, id, deps, writes) => Write(
+                u,
+                id,
+                deps,
+                writes.into_iter().map(|w| w.map_data(&f)).collect(),
+            ),
+            WriteAck(u, id, res) => WriteAck(u, id, res),
+            Flush(u, id, deps, flush, gen, snapshot, limit) => {
+                Flush(u, id, deps, flush, gen, snapshot, limit)
+            }
+            FlushAck(u, id, res) => FlushAck(u, id, res),
+            ReadRequest(u, id, deps, requests) => {
+                ReadRequest(u, id, deps, requests)
+            }
+            ReadResponse(u, id, res) => ReadResponse(u, id, res),
+            ExtentRepairRead(u, id, deps, eid) => {
+                ExtentRepairRead(u, id, deps, eid)
+            }
+            ExtentRepairData(u, id, res) => {
+                ExtentRepairData(u, id, res.map(|e| e.map_data(&f)))
+            }
+            ExtentRepairWrite(u, id, deps, extent) => {
+                ExtentRepairWrite(u, id, deps, extent.map_data(&f))
+            }
+            ExtentRepairAck(u, id, res) => ExtentRepairAck(u, id, res),
+            VersionMismatch(v) => VersionMismatch(v),
+            Unknown(v, buf) => Unknown(v, buf),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct CrucibleEncoder {}
 
@@ -190,11 +368,19 @@ impl Encoder<&Message> for CrucibleEncoder {
     }
 }
 
-pub struct CrucibleDecoder {}
+/*
+ * Decodes frames of a u32 length prefix, counting itself, followed by one
+ * Message.  Once a prefix has been read it is consumed and the length of
+ * the rest of the frame is kept, so a frame arriving in pieces is not
+ * looked over again on every read.
+ */
+pub struct CrucibleDecoder {
+    frame_len: Option<usize>,
+}
 
 impl CrucibleDecoder {
     pub fn new() -> Self {
-        CrucibleDecoder {}
+        CrucibleDecoder { frame_len: None }
     }
 }
 
@@ -212,37 +398,67 @@ impl Decoder for CrucibleDecoder {
         &mut self,
         src: &mut BytesMut,
     ) -> Result<Option<Self::Item>, Self::Error> {
-        if src.len() < 4 {
-            /*
-             * Wait for the u32 length prefix.
-             */
-            return Ok(None);
-        }
-
-        /*
-         * Get the length prefix from the frame.
-         */
-        let mut length_bytes = [0u8; 4];
-        length_bytes.copy_from_slice(&src[0..4]);
-        let len = u32::from_le_bytes(length_bytes) as usize;
-
-        if len > MAX_FRM_LEN {
-            bail!("frame is {} bytes, more than maximum {}", len, MAX_FRM_LEN);
-        }
+        let body_len = match self.frame_len {
+            Some(body_len) => body_len,
+            None => {
+                if src.len() < 4 {
+                    /*
+                     * Wait for the u32 length prefix.
+                     */
+                    return Ok(None);
+                }
+
+                let len = src.get_u32_le() as usize;
+                if len > MAX_FRM_LEN {
+                    bail!(
+                        "frame is {} bytes, more than maximum {}",
+                        len,
+                        MAX_FRM_LEN
+                    );
+                }
+                if len < 4 {
+                    bail!("frame is {} bytes, shorter than its length", len);
+                }
+
+                self.frame_len = Some(len - 4);
+                len - 4
+            }
+        };
 
-        if src.len() < len {
+        if src.len() < body_len {
             /*
              * Wait for an entire frame.  Expand the buffer to fit.
              */
-            src.reserve(len);
+            src.reserve(body_len - src.len());
             return Ok(None);
         }
 
-        src.advance(4);
-
-        let message = bincode::deserialize_from(src.reader());
-
-        Ok(Some(message?))
+        /*
+         * The message has to be exactly the rest of the frame.  Decoding
+         * is limited to those bytes, so a length inside the message can't
+         * make us read, or allocate for, more than the frame holds.
+         *
+         * The frame is split off rather than copied.  The message is
+         * decoded with the data in a Write or ExtentData borrowed from
+         * the frame, and that is then sliced out of it, so it is never
+         * copied.  The data in a ReadResponse is a BytesMut the upstairs
+         * decrypts in place, so that is still copied out.
+         */
+        let frame = src.split_to(body_len).freeze();
+        self.frame_len = None;
+
+        let message: Message<&[u8]> = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .with_limit(body_len as u64)
+            .deserialize(&frame)?;
+
+        Ok(Some(message.map_data(|data| {
+            if data.is_empty() {
+                Bytes::new()
+            } else {
+                frame.slice_ref(data)
+            }
+        })))
     }
 }
 
@@ -272,6 +488,27 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn rt_attach() -> Result<()> {
+        let input = Message::Attach(true);
+        assert_eq!(input, round_trip(&input)?);
+        Ok(())
+    }
+
+    #[test]
+    fn rt_promote_to_active() -> Result<()> {
+        let input = Message::PromoteToActive(Uuid::new_v4(), 3);
+        assert_eq!(input, round_trip(&input)?);
+        Ok(())
+    }
+
+    #[test]
+    fn rt_you_are_no_longer_active() -> Result<()> {
+        let input = Message::YouAreNoLongerActive(Uuid::new_v4(), 4);
+        assert_eq!(input, round_trip(&input)?);
+        Ok(())
+    }
+
     #[test]
     fn rt_yes_its_me() -> Result<()> {
         let input = Message::YesItsMe(20000);
@@ -279,6 +516,30 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn rt_version_mismatch() -> Result<()> {
+        let input = Message::VersionMismatch(CRUCIBLE_MESSAGE_VERSION);
+        assert_eq!(input, round_trip(&input)?);
+        Ok(())
+    }
+
+    #[test]
+    fn negotiation_keeps_its_place() -> Result<()> {
+        /*
+         * A peer of another version must still read these the same way.
+         */
+        let uuid = Uuid::new_v4();
+        let here: Message = Message::HereIAm(9, uuid);
+        let here = bincode::serialize(&here)?;
+        assert_eq!(here[0..4], 0u32.to_le_bytes());
+        assert_eq!(here[4..8], 9u32.to_le_bytes());
+        assert_eq!(here[8..], bincode::serialize(&uuid)?[..]);
+        let yes: Message = Message::YesItsMe(9);
+        let yes = bincode::serialize(&yes)?;
+        assert_eq!(yes, [1, 0, 0, 0, 9, 0, 0, 0]);
+        Ok(())
+    }
+
     #[test]
     fn rt_ruok() -> Result<()> {
         let input = Message::Ruok;
@@ -293,6 +554,20 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn rt_ruok_seq() -> Result<()> {
+        let input = Message::RuokSeq(7, u64::MAX);
+        assert_eq!(input, round_trip(&input)?);
+        Ok(())
+    }
+
+    #[test]
+    fn rt_imok_seq() -> Result<()> {
+        let input = Message::ImokSeq(7, 123_456);
+        assert_eq!(input, round_trip(&input)?);
+        Ok(())
+    }
+
     #[test]
     fn rt_evp() -> Result<()> {
         let input = Message::ExtentVersionsPlease;
@@ -318,6 +593,209 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn rt_extent_repair_read() -> Result<()> {
+        let input = Message::ExtentRepairRead(Uuid::new_v4(), 1001, vec![], 4);
+        assert_eq!(input, round_trip(&input)?);
+        Ok(())
+    }
+
+    #[test]
+    fn rt_extent_repair_write() -> Result<()> {
+        let input = Message::ExtentRepairWrite(
+            Uuid::new_v4(),
+            1002,
+            vec![1000, 1001],
+            ExtentData {
+                eid: 4,
+                data: bytes::Bytes::from(vec![7; 1024]),
+                contexts: vec![None, Some((vec![1, 2, 3], vec![4, 5, 6]))],
+                checksums: vec![Some(0x1234_5678), None],
+                gen_number: 2,
+                flush_number: 9,
+            },
+        );
+        assert_eq!(input, round_trip(&input)?);
+        Ok(())
+    }
+
+    #[test]
+    fn rt_flush() -> Result<()> {
+        let input =
+            Message::Flush(Uuid::new_v4(), 1003, vec![1002], 7, 2, None, None);
+        assert_eq!(input, round_trip(&input)?);
+        Ok(())
+    }
+
+    #[test]
+    fn rt_flush_snapshot() -> Result<()> {
+        let input = Message::Flush(
+            Uuid::new_v4(),
+            1003,
+            vec![1002],
+            7,
+            2,
+            Some(SnapshotDetails {
+                snapshot_name: "before-upgrade".to_string(),
+            }),
+            None,
+        );
+        assert_eq!(input, round_trip(&input)?);
+        Ok(())
+    }
+
+    #[test]
+    fn short_frame_fails() {
+        let mut decoder = CrucibleDecoder::new();
+        let mut buffer = BytesMut::new();
+        buffer.put_u32_le(3);
+        buffer.put_u32_le(0);
+
+        assert!(decoder.decode(&mut buffer).is_err());
+    }
+
+    #[test]
+    fn frame_longer_than_message_fails() -> Result<()> {
+        let mut encoder = CrucibleEncoder::new();
+        let mut decoder = CrucibleDecoder::new();
+        let mut buffer = BytesMut::new();
+        encoder.encode(Message::Ruok, &mut buffer)?;
+
+        /*
+         * One more byte in the frame than the message uses.
+         */
+        let len = buffer.len() as u32 + 1;
+        buffer[0..4].copy_from_slice(&len.to_le_bytes());
+        buffer.put_u8(0);
+
+        assert!(decoder.decode(&mut buffer).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn huge_vector_length_fails() -> Result<()> {
+        let mut encoder = CrucibleEncoder::new();
+        let mut decoder = CrucibleDecoder::new();
+        let mut buffer = BytesMut::new();
+        encoder.encode(
+            Message::ExtentVersions(vec![1], vec![], vec![]),
+            &mut buffer,
+        )?;
+
+        /*
+         * After the frame length and the message type comes the length of
+         * the first vector.  Claim far more than the frame could hold.
+         */
+        buffer[8..16].copy_from_slice(&(u64::MAX / 2).to_le_bytes());
+
+        assert!(decoder.decode(&mut buffer).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn back_to_back_frames() -> Result<()> {
+        let mut encoder = CrucibleEncoder::new();
+        let mut decoder = CrucibleDecoder::new();
+        let mut buffer = BytesMut::new();
+        encoder.encode(Message::Ruok, &mut buffer)?;
+        encoder.encode(Message::LastFlush(7), &mut buffer)?;
+
+        assert_eq!(decoder.decode(&mut buffer)?, Some(Message::Ruok));
+        assert_eq!(decoder.decode(&mut buffer)?, Some(Message::LastFlush(7)));
+        assert_eq!(decoder.decode(&mut buffer)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn frame_in_pieces() -> Result<()> {
+        let mut encoder = CrucibleEncoder::new();
+        let mut decoder = CrucibleDecoder::new();
+        let mut whole = BytesMut::new();
+        encoder.encode(Message::LastFlush(7), &mut whole)?;
+
+        /*
+         * The prefix and part of the message.  The prefix is taken once
+         * and not looked for again when the rest shows up.
+         */
+        let mut buffer = whole.split_to(6);
+        assert_eq!(decoder.decode(&mut buffer)?, None);
+        assert_eq!(buffer.len(), 2);
+
+        buffer.extend_from_slice(&whole);
+        assert_eq!(decoder.decode(&mut buffer)?, Some(Message::LastFlush(7)));
+        assert!(buffer.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn write_data_is_not_copied() -> Result<()> {
+        let mut encoder = CrucibleEncoder::new();
+        let mut decoder = CrucibleDecoder::new();
+        let mut buffer = BytesMut::new();
+        let input = Message::Write(
+            Uuid::new_v4(),
+            1,
+            vec![],
+            vec![Write {
+                eid: 0,
+                offset: Block::new_512(1),
+                data: bytes::Bytes::from(vec![9; 512]),
+                nonce: None,
+                tag: None,
+            }],
+        );
+        encoder.encode(input.clone(), &mut buffer)?;
+
+        let start = buffer.as_ptr() as usize;
+        let end = start + buffer.len();
+
+        let output = decoder.decode(&mut buffer)?.unwrap();
+        assert_eq!(output, input);
+        match output {
+            Message::Write(_, _, _, writes) => {
+                let p = writes[0].data.as_ptr() as usize;
+                assert!(p >= start && p + writes[0].data.len() <= end);
+            }
+            m => panic!("decoded {:?}", m),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn extent_data_is_not_copied() -> Result<()> {
+        let mut encoder = CrucibleEncoder::new();
+        let mut decoder = CrucibleDecoder::new();
+        let mut buffer = BytesMut::new();
+        let input = Message::ExtentRepairWrite(
+            Uuid::new_v4(),
+            1,
+            vec![],
+            ExtentData {
+                eid: 3,
+                data: bytes::Bytes::from(vec![7; 1024]),
+                contexts: vec![None, None],
+                checksums: vec![None, None],
+                gen_number: 1,
+                flush_number: 2,
+            },
+        );
+        encoder.encode(input.clone(), &mut buffer)?;
+
+        let start = buffer.as_ptr() as usize;
+        let end = start + buffer.len();
+
+        let output = decoder.decode(&mut buffer)?.unwrap();
+        assert_eq!(output, input);
+        match output {
+            Message::ExtentRepairWrite(_, _, _, extent) => {
+                let p = extent.data.as_ptr() as usize;
+                assert!(p >= start && p + extent.data.len() <= end);
+            }
+            m => panic!("decoded {:?}", m),
+        }
+        Ok(())
+    }
+
     #[test]
     fn correctly_detect_truncated_message() -> Result<()> {
         let mut encoder = CrucibleEncoder::new();
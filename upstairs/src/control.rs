@@ -0,0 +1,195 @@
+// Copyright 2021 Oxide Computer Company
+use super::*;
+
+use dropshot::endpoint;
+use dropshot::ApiDescription;
+use dropshot::ConfigDropshot;
+use dropshot::ConfigLogging;
+use dropshot::ConfigLoggingLevel;
+use dropshot::HttpError;
+use dropshot::HttpResponseOk;
+use dropshot::HttpResponseUpdatedNoContent;
+use dropshot::HttpServerStarter;
+use dropshot::Path;
+use dropshot::RequestContext;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::net::SocketAddr;
+
+/*
+ * The control server is an optional HTTP server for operators.  It shows
+ * the state of the upstairs and each downstairs, and has a few actions
+ * to poke at a running upstairs.
+ */
+pub async fn start(up: &Arc<Upstairs>, addr: SocketAddr) -> Result<()> {
+    let config_dropshot = ConfigDropshot {
+        bind_address: addr,
+        request_body_max_bytes: 1024,
+        ..Default::default()
+    };
+
+    let log = ConfigLogging::StderrTerminal {
+        level: ConfigLoggingLevel::Info,
+    }
+    .to_logger("upstairs-control")
+    .map_err(|e| anyhow!("failed to create logger: {}", e))?;
+
+    let mut api = ApiDescription::new();
+    api.register(upstairs_info).unwrap();
+    api.register(upstairs_deactivate).unwrap();
+    api.register(downstairs_repair).unwrap();
+
+    let server =
+        HttpServerStarter::new(&config_dropshot, api, up.clone(), &log)
+            .map_err(|e| anyhow!("failed to create control server: {}", e))?
+            .start();
+    println!("Control server listening on {}", addr);
+
+    server
+        .await
+        .map_err(|e| anyhow!("control server failed: {}", e))
+}
+
+/**
+ * The state of the upstairs and the work for each downstairs.  Vecs are
+ * index by client ID.
+ */
+#[derive(Debug, Serialize, JsonSchema)]
+struct UpstairsInfo {
+    uuid: Uuid,
+    active: bool,
+    ds_state: Vec<DsState>,
+    /*
+     * Jobs not yet sent to each downstairs.
+     */
+    ds_new_jobs: Vec<usize>,
+    /*
+     * Jobs sent to each downstairs that have not been answered.
+     */
+    ds_in_progress_jobs: Vec<usize>,
+    ds_last_flush: Vec<u64>,
+    ds_skipped_jobs: Vec<usize>,
+    /*
+     * Ping round trip times and misses for each downstairs.
+     */
+    ds_health: Vec<HealthStats>,
+    /*
+     * What we do when a downstairs answers with UuidMismatch, and how
+     * many times each has.
+     */
+    uuid_mismatch_policy: UuidMismatchPolicy,
+    ds_uuid_mismatches: Vec<u64>,
+    /*
+     * All jobs on the downstairs active list.
+     */
+    ds_active_jobs: usize,
+    /*
+     * Guest IOs not yet completed.
+     */
+    guest_active_jobs: usize,
+    /*
+     * Guest IO waiting on completion, and the most we let in before
+     * the guest has to wait.
+     */
+    guest_queue: QueueDepth,
+    guest_queue_limit: QueueDepth,
+    /*
+     * Write-back data acked to the guest but not yet done.
+     */
+    guest_dirty_bytes: usize,
+    /*
+     * The downstairs under live repair and the extent it is on.
+     */
+    live_repair: Option<(u8, u64)>,
+    counters: IOCounters,
+}
+
+#[endpoint {
+    method = GET,
+    path = "/upstairs/info",
+}]
+async fn upstairs_info(
+    rqctx: Arc<RequestContext<Arc<Upstairs>>>,
+) -> Result<HttpResponseOk<UpstairsInfo>, HttpError> {
+    let up = rqctx.context();
+
+    let guest_active_jobs = up.up_work_active() as usize;
+    let guest_dirty_bytes = up.guest.dirty_bytes();
+    let active = up.is_active();
+    let limits = up.guest.queue_limits();
+    let guest_queue_limit = QueueDepth {
+        jobs: limits.max_jobs(),
+        bytes: limits.max_bytes(),
+    };
+    let ds = up.downstairs.lock().unwrap();
+
+    Ok(HttpResponseOk(UpstairsInfo {
+        uuid: up.uuid,
+        active,
+        ds_state: ds.ds_state.clone(),
+        ds_new_jobs: (0..3).map(|cid| ds.new_work(cid).len()).collect(),
+        ds_in_progress_jobs: (0..3).map(|cid| ds.submitted_work(cid)).collect(),
+        ds_last_flush: ds.ds_last_flush.clone(),
+        ds_skipped_jobs: ds.ds_skipped_jobs.iter().map(|s| s.len()).collect(),
+        ds_health: ds.ds_health.iter().map(|h| h.stats()).collect(),
+        uuid_mismatch_policy: ds.uuid_mismatch,
+        ds_uuid_mismatches: ds.ds_uuid_mismatches.clone(),
+        ds_active_jobs: ds.active.len(),
+        guest_active_jobs,
+        guest_queue: up.guest.queue_depth(),
+        guest_queue_limit,
+        guest_dirty_bytes,
+        live_repair: ds.live_repair.map(|lr| (lr.dest, lr.next_eid)),
+        counters: ds.counters.clone(),
+    }))
+}
+
+/*
+ * Stop taking IO.  The downstairs will see this on the next IO we send
+ * and drop back to New.
+ */
+#[endpoint {
+    method = POST,
+    path = "/upstairs/deactivate",
+}]
+async fn upstairs_deactivate(
+    rqctx: Arc<RequestContext<Arc<Upstairs>>>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    let up = rqctx.context();
+
+    if !up.is_active() {
+        return Err(HttpError::for_bad_request(
+            None,
+            "upstairs is not active".to_string(),
+        ));
+    }
+    up.set_inactive();
+
+    Ok(HttpResponseUpdatedNoContent())
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct ClientPath {
+    cid: u8,
+}
+
+/*
+ * Take a downstairs out of service and have it come back through live
+ * repair.
+ */
+#[endpoint {
+    method = POST,
+    path = "/downstairs/{cid}/repair",
+}]
+async fn downstairs_repair(
+    rqctx: Arc<RequestContext<Arc<Upstairs>>>,
+    path: Path<ClientPath>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    let up = rqctx.context();
+    let cid = path.into_inner().cid;
+
+    up.ds_fault(cid)
+        .map_err(|e| HttpError::for_bad_request(None, e.to_string()))?;
+
+    Ok(HttpResponseUpdatedNoContent())
+}
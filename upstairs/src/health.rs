@@ -0,0 +1,259 @@
+// Copyright 2021 Oxide Computer Company
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use schemars::JsonSchema;
+use serde::Serialize;
+use tokio::time::Instant;
+
+/*
+ * How many of the most recent round trip times the stats are taken over.
+ */
+const RTT_WINDOW: usize = 32;
+
+/*
+ * Pings in a row that can go unanswered before an Active downstairs is
+ * faulted.  We ping every ten seconds, so this is well inside the time
+ * we wait before giving up on the connection altogether.
+ */
+pub(crate) const MAX_MISSED_PINGS: u32 = 3;
+
+/*
+ * The health of our connection to one downstairs, from the pings we send
+ * it.  Each ping carries a sequence number and the time it was sent,
+ * which the downstairs sends back, so a reply is enough to work out the
+ * round trip.  A ping still unanswered when the next one goes out is
+ * counted as missed.
+ */
+#[derive(Debug, Clone)]
+pub(crate) struct ConnectionHealth {
+    epoch: Instant,
+    next_seq: u64,
+    outstanding: Option<u64>,
+    pings_sent: u64,
+    pings_answered: u64,
+    pings_missed: u64,
+    consecutive_missed: u32,
+    rtt: VecDeque<Duration>,
+}
+
+/*
+ * What we report for a connection.  Round trip times are over the last
+ * RTT_WINDOW replies, and are absent until there has been one.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Serialize, JsonSchema)]
+pub struct HealthStats {
+    pub pings_sent: u64,
+    pub pings_answered: u64,
+    pub pings_missed: u64,
+    pub consecutive_missed: u32,
+    pub rtt_last_us: Option<u64>,
+    pub rtt_min_us: Option<u64>,
+    pub rtt_mean_us: Option<u64>,
+    pub rtt_max_us: Option<u64>,
+}
+
+impl ConnectionHealth {
+    pub(crate) fn new() -> ConnectionHealth {
+        ConnectionHealth {
+            epoch: Instant::now(),
+            next_seq: 1,
+            outstanding: None,
+            pings_sent: 0,
+            pings_answered: 0,
+            pings_missed: 0,
+            consecutive_missed: 0,
+            rtt: VecDeque::with_capacity(RTT_WINDOW),
+        }
+    }
+
+    /*
+     * A new connection was made.  A ping sent on the old one will never
+     * be answered, but that was the old connection's problem.
+     */
+    pub(crate) fn reconnect(&mut self) {
+        self.outstanding = None;
+        self.consecutive_missed = 0;
+    }
+
+    /*
+     * Record a ping going out now, and return the sequence number and
+     * timestamp to send with it.
+     */
+    pub(crate) fn ping(&mut self, now: Instant) -> (u64, u64) {
+        if self.outstanding.is_some() {
+            self.pings_missed += 1;
+            self.consecutive_missed += 1;
+        }
+
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.outstanding = Some(seq);
+        self.pings_sent += 1;
+
+        let ts = now.saturating_duration_since(self.epoch).as_micros();
+        (seq, ts.min(u64::MAX as u128) as u64)
+    }
+
+    /*
+     * A reply to the ping with this sequence number and timestamp came
+     * in now.  A late reply to a ping already counted as missed still
+     * tells us the round trip, but does not clear the misses.
+     */
+    pub(crate) fn pong(&mut self, seq: u64, ts: u64, now: Instant) {
+        let sent = match self.epoch.checked_add(Duration::from_micros(ts)) {
+            Some(sent) if seq < self.next_seq && sent <= now => sent,
+            _ => {
+                /*
+                 * Not something we sent.
+                 */
+                return;
+            }
+        };
+
+        if self.outstanding == Some(seq) {
+            self.outstanding = None;
+            self.consecutive_missed = 0;
+            self.pings_answered += 1;
+        }
+
+        if self.rtt.len() == RTT_WINDOW {
+            self.rtt.pop_front();
+        }
+        self.rtt.push_back(now - sent);
+    }
+
+    pub(crate) fn consecutive_missed(&self) -> u32 {
+        self.consecutive_missed
+    }
+
+    pub(crate) fn stats(&self) -> HealthStats {
+        let us = |d: &Duration| d.as_micros() as u64;
+        let mean = if self.rtt.is_empty() {
+            None
+        } else {
+            Some(self.rtt.iter().map(us).sum::<u64>() / self.rtt.len() as u64)
+        };
+
+        HealthStats {
+            pings_sent: self.pings_sent,
+            pings_answered: self.pings_answered,
+            pings_missed: self.pings_missed,
+            consecutive_missed: self.consecutive_missed,
+            rtt_last_us: self.rtt.back().map(us),
+            rtt_min_us: self.rtt.iter().map(us).min(),
+            rtt_mean_us: mean,
+            rtt_max_us: self.rtt.iter().map(us).max(),
+        }
+    }
+}
+
+impl Default for ConnectionHealth {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let mut h = ConnectionHealth::new();
+        let t = h.epoch;
+
+        let (seq, ts) = h.ping(t);
+        h.pong(seq, ts, t + Duration::from_micros(300));
+        let (seq, ts) = h.ping(t + Duration::from_secs(10));
+        h.pong(
+            seq,
+            ts,
+            t + Duration::from_secs(10) + Duration::from_micros(100),
+        );
+
+        let s = h.stats();
+        assert_eq!(s.pings_sent, 2);
+        assert_eq!(s.pings_answered, 2);
+        assert_eq!(s.pings_missed, 0);
+        assert_eq!(s.rtt_last_us, Some(100));
+        assert_eq!(s.rtt_min_us, Some(100));
+        assert_eq!(s.rtt_mean_us, Some(200));
+        assert_eq!(s.rtt_max_us, Some(300));
+    }
+
+    #[test]
+    fn no_replies() {
+        let h = ConnectionHealth::new();
+        let s = h.stats();
+        assert_eq!(s, HealthStats::default());
+    }
+
+    #[test]
+    fn missed_pings() {
+        let mut h = ConnectionHealth::new();
+        let t = h.epoch;
+
+        let (seq, ts) = h.ping(t);
+        h.ping(t);
+        h.ping(t);
+        assert_eq!(h.consecutive_missed(), 2);
+
+        /*
+         * A late reply gives a round trip, but the ping it answers was
+         * already counted as missed.
+         */
+        h.pong(seq, ts, t + Duration::from_millis(5));
+        assert_eq!(h.consecutive_missed(), 2);
+        assert_eq!(h.stats().rtt_last_us, Some(5000));
+
+        let (seq, ts) = h.ping(t);
+        assert_eq!(h.consecutive_missed(), 3);
+        h.pong(seq, ts, t);
+        assert_eq!(h.consecutive_missed(), 0);
+
+        let s = h.stats();
+        assert_eq!(s.pings_sent, 4);
+        assert_eq!(s.pings_answered, 1);
+        assert_eq!(s.pings_missed, 3);
+    }
+
+    #[test]
+    fn reconnect_forgets_outstanding() {
+        let mut h = ConnectionHealth::new();
+        let t = h.epoch;
+
+        h.ping(t);
+        h.ping(t);
+        h.reconnect();
+        h.ping(t);
+        assert_eq!(h.consecutive_missed(), 0);
+        assert_eq!(h.stats().pings_missed, 1);
+    }
+
+    #[test]
+    fn bogus_replies_ignored() {
+        let mut h = ConnectionHealth::new();
+        let t = h.epoch;
+
+        let (seq, ts) = h.ping(t);
+        h.pong(seq + 1, ts, t);
+        h.pong(seq, ts + 1_000_000, t);
+        h.pong(seq, u64::MAX, t);
+        assert_eq!(h.stats().pings_answered, 0);
+        assert_eq!(h.stats().rtt_last_us, None);
+    }
+
+    #[test]
+    fn window_is_bounded() {
+        let mut h = ConnectionHealth::new();
+        let t = h.epoch;
+
+        for i in 0..(RTT_WINDOW as u64 + 10) {
+            let (seq, ts) = h.ping(t);
+            h.pong(seq, ts, t + Duration::from_micros(i));
+        }
+        assert_eq!(h.rtt.len(), RTT_WINDOW);
+        assert_eq!(h.stats().rtt_min_us, Some(10));
+    }
+}
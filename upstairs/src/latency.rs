@@ -0,0 +1,230 @@
+// Copyright 2021 Oxide Computer Company
+use std::time::Duration;
+
+use schemars::JsonSchema;
+use serde::Serialize;
+
+/*
+ * Values below this many microseconds each get a bucket of their own.
+ * Above it, every power of two is split into this many buckets, so a
+ * value is off by at most an eighth.
+ */
+const SUB_BITS: u32 = 3;
+const SUB_BUCKETS: u64 = 1 << SUB_BITS;
+
+fn bucket(us: u64) -> usize {
+    if us < SUB_BUCKETS {
+        return us as usize;
+    }
+    let shift = 63 - us.leading_zeros() - SUB_BITS;
+    let sub = (us >> shift) - SUB_BUCKETS;
+    ((shift as u64 + 1) * SUB_BUCKETS + sub) as usize
+}
+
+/*
+ * The smallest value that goes in bucket i.
+ */
+fn bucket_low(i: usize) -> u64 {
+    let i = i as u64;
+    if i < SUB_BUCKETS {
+        return i;
+    }
+    let shift = i / SUB_BUCKETS - 1;
+    (SUB_BUCKETS + i % SUB_BUCKETS) << shift
+}
+
+/*
+ * A histogram of latencies, in microseconds.
+ */
+#[derive(Debug, Clone, Default, Serialize, JsonSchema)]
+pub struct LatencyHistogram {
+    count: u64,
+    sum_us: u64,
+    max_us: u64,
+    buckets: Vec<u64>,
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, latency: Duration) {
+        let us = latency.as_micros().min(u64::MAX as u128) as u64;
+        let b = bucket(us);
+        if self.buckets.len() <= b {
+            self.buckets.resize(b + 1, 0);
+        }
+        self.buckets[b] += 1;
+        self.count += 1;
+        self.sum_us = self.sum_us.saturating_add(us);
+        self.max_us = self.max_us.max(us);
+    }
+
+    pub fn merge(&mut self, other: &LatencyHistogram) {
+        if self.buckets.len() < other.buckets.len() {
+            self.buckets.resize(other.buckets.len(), 0);
+        }
+        for (b, n) in other.buckets.iter().enumerate() {
+            self.buckets[b] += n;
+        }
+        self.count += other.count;
+        self.sum_us = self.sum_us.saturating_add(other.sum_us);
+        self.max_us = self.max_us.max(other.max_us);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn max(&self) -> Duration {
+        Duration::from_micros(self.max_us)
+    }
+
+    pub fn sum(&self) -> Duration {
+        Duration::from_micros(self.sum_us)
+    }
+
+    pub fn mean(&self) -> Duration {
+        if self.count == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_micros(self.sum_us / self.count)
+    }
+
+    /*
+     * The latency that pct percent of those recorded were at or under.
+     * This is the top of the bucket it falls in, or the largest value
+     * recorded if that is smaller.
+     */
+    pub fn percentile(&self, pct: f64) -> Duration {
+        if self.count == 0 {
+            return Duration::ZERO;
+        }
+
+        let rank = ((pct / 100.0) * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (b, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                /*
+                 * Past the last bucket this wraps to u64::MAX, which the
+                 * max then caps.
+                 */
+                let top = bucket_low(b + 1).wrapping_sub(1);
+                return Duration::from_micros(top.min(self.max_us));
+            }
+        }
+        self.max()
+    }
+}
+
+/*
+ * Where the time for guest IO of one kind went:
+ *
+ * - submit: from the guest sending it to the upstairs building the
+ *   downstairs jobs for it, which is mostly time waiting for room in
+ *   the guest queue.
+ * - downstairs: from then until enough downstairs have answered for the
+ *   guest to be told.
+ *
+ * Only IO from the guest is counted, not flushes the upstairs sends on
+ * its own.
+ */
+#[derive(Debug, Clone, Default, Serialize, JsonSchema)]
+pub struct PhaseLatency {
+    pub submit: LatencyHistogram,
+    pub downstairs: LatencyHistogram,
+}
+
+#[derive(Debug, Clone, Default, Serialize, JsonSchema)]
+pub struct IOLatency {
+    pub read: PhaseLatency,
+    pub write: PhaseLatency,
+    pub flush: PhaseLatency,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum GuestIOKind {
+    Read,
+    Write,
+    Flush,
+}
+
+impl IOLatency {
+    pub(crate) fn record(
+        &mut self,
+        kind: GuestIOKind,
+        submit: Duration,
+        downstairs: Duration,
+    ) {
+        let phase = match kind {
+            GuestIOKind::Read => &mut self.read,
+            GuestIOKind::Write => &mut self.write,
+            GuestIOKind::Flush => &mut self.flush,
+        };
+        phase.submit.record(submit);
+        phase.downstairs.record(downstairs);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn buckets_cover_every_value() {
+        for us in 0..100_000u64 {
+            let b = bucket(us);
+            assert!(bucket_low(b) <= us, "{} in bucket {}", us, b);
+            assert!(us < bucket_low(b + 1), "{} in bucket {}", us, b);
+        }
+        assert_eq!(bucket_low(bucket(u64::MAX)), 15 << 60);
+    }
+
+    #[test]
+    fn small_values_are_exact() {
+        let mut h = LatencyHistogram::default();
+        for us in 1..=4 {
+            h.record(Duration::from_micros(us));
+        }
+
+        assert_eq!(h.count(), 4);
+        assert_eq!(h.percentile(50.0), Duration::from_micros(2));
+        assert_eq!(h.percentile(100.0), Duration::from_micros(4));
+        assert_eq!(h.max(), Duration::from_micros(4));
+        assert_eq!(h.mean(), Duration::from_micros(2));
+    }
+
+    #[test]
+    fn percentiles() {
+        let mut h = LatencyHistogram::default();
+        for us in 1..=1000 {
+            h.record(Duration::from_micros(us));
+        }
+
+        for (pct, want) in [(50.0, 500), (95.0, 950), (99.0, 990)] {
+            let got = h.percentile(pct).as_micros() as u64;
+            assert!(got >= want, "p{} {} < {}", pct, got, want);
+            assert!(got <= want + want / 8, "p{} {} > {}", pct, got, want);
+        }
+        assert_eq!(h.percentile(100.0), Duration::from_micros(1000));
+    }
+
+    #[test]
+    fn empty() {
+        let h = LatencyHistogram::default();
+        assert_eq!(h.percentile(99.0), Duration::ZERO);
+        assert_eq!(h.mean(), Duration::ZERO);
+    }
+
+    #[test]
+    fn merge() {
+        let mut a = LatencyHistogram::default();
+        let mut b = LatencyHistogram::default();
+        a.record(Duration::from_micros(10));
+        b.record(Duration::from_millis(10));
+        b.record(Duration::from_millis(20));
+
+        a.merge(&b);
+        assert_eq!(a.count(), 3);
+        assert_eq!(a.max(), Duration::from_millis(20));
+        assert_eq!(a.percentile(1.0), Duration::from_micros(10));
+    }
+}
@@ -3,16 +3,17 @@
 #![allow(clippy::mutex_atomic)]
 
 use std::clone::Clone;
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt;
 use std::fmt::{Debug, Formatter};
 use std::io::{Read, Result as IOResult, Seek, SeekFrom, Write};
-use std::net::SocketAddrV4;
+use std::net::{SocketAddr, SocketAddrV4};
 use std::sync::mpsc as std_mpsc;
-use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::time::Duration;
 
 pub use crucible_common::*;
+pub use crucible_protocol::SnapshotDetails;
 use crucible_protocol::*;
 
 use anyhow::{anyhow, bail, Result};
@@ -20,7 +21,8 @@ pub use bytes::{Bytes, BytesMut};
 use futures::{SinkExt, StreamExt};
 use rand::prelude::*;
 use ringbuffer::{AllocRingBuffer, RingBufferExt, RingBufferWrite};
-use serde::Serialize;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use tokio::net::tcp::WriteHalf;
 use tokio::net::{TcpSocket, TcpStream};
 use tokio::sync::{mpsc, watch, Notify};
@@ -32,12 +34,27 @@ use uuid::Uuid;
 
 use aes::cipher::generic_array::GenericArray;
 use aes::{Aes128, NewBlockCipher};
+use aes_gcm_siv::aead::{AeadInPlace, NewAead};
+use aes_gcm_siv::{Aes256GcmSiv, Key, Nonce, Tag};
 use xts_mode::{get_tweak_default, Xts128};
 
+mod control;
+mod health;
+mod latency;
 mod pseudo_file;
 mod test;
+mod volume;
 
+pub use health::HealthStats;
+use health::{ConnectionHealth, MAX_MISSED_PINGS};
+use latency::GuestIOKind;
+pub use latency::{IOLatency, LatencyHistogram, PhaseLatency};
 pub use pseudo_file::CruciblePseudoFile;
+use pseudo_file::IOSpan;
+pub use volume::{
+    ReadOnlyParent, RegionRequest, SubVolume, UrlImage, Volume,
+    VolumeConstructionRequest,
+};
 
 #[usdt::provider]
 mod cdt {
@@ -49,6 +66,14 @@ mod cdt {
     fn gw_read_end(_: u64) {}
     fn gw_write_end(_: u64) {}
     fn gw_flush_end(_: u64) {}
+    fn ds_read_io_start(_: u64, _: u8) {}
+    fn ds_write_io_start(_: u64, _: u8) {}
+    fn ds_flush_io_start(_: u64, _: u8) {}
+    fn ds_repair_io_start(_: u64, _: u8) {}
+    fn ds_read_io_done(_: u64, _: u8) {}
+    fn ds_write_io_done(_: u64, _: u8) {}
+    fn ds_flush_io_done(_: u64, _: u8) {}
+    fn ds_repair_io_done(_: u64, _: u8) {}
 }
 
 #[derive(Debug, Clone)]
@@ -56,12 +81,201 @@ pub struct CrucibleOpts {
     pub target: Vec<SocketAddrV4>,
     pub lossy: bool,
     pub key: Option<String>,
+    /*
+     * The volume has blocks written before blocks had an encryption
+     * version.  Blocks with no nonce or tag are then read as AES-XTS, or
+     * as zeros if they were never written.  Those blocks can't be
+     * authenticated, so without this they are a DecryptionError.
+     */
+    pub legacy_xts: bool,
+    /*
+     * If set, start the control server on this address.
+     */
+    pub control: Option<SocketAddr>,
+    /*
+     * If not set, there are three downstairs and writes and flushes are
+     * acked when two of them finish.
+     */
+    pub policy: Option<ReplicationPolicy>,
+    /*
+     * Attach without ever writing or flushing.  Other upstairs can be
+     * attached to the same downstairs at the same time.
+     */
+    pub read_only: bool,
+    /*
+     * If not set, a downstairs is faulted once jobs sent to it have been
+     * outstanding past 30 seconds at three checks in a row.
+     */
+    pub job_timeout: Option<JobTimeout>,
+    /*
+     * If not set, a downstairs that answers with UuidMismatch is faulted.
+     */
+    pub uuid_mismatch: Option<UuidMismatchPolicy>,
+}
+
+/*
+ * How many downstairs hold a copy of the region, and how many of them have
+ * to finish a write or a flush before it is acked back to the guest.  A
+ * read is acked as soon as one downstairs returns data.
+ */
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ReplicationPolicy {
+    replicas: usize,
+    write_quorum: usize,
+    flush_quorum: usize,
+}
+
+impl ReplicationPolicy {
+    pub fn new(
+        replicas: usize,
+        write_quorum: usize,
+        flush_quorum: usize,
+    ) -> Result<ReplicationPolicy> {
+        if replicas == 0 || replicas > 3 {
+            bail!("replicas must be from 1 to 3, not {}", replicas);
+        }
+        if write_quorum == 0 || write_quorum > replicas {
+            bail!(
+                "write quorum {} is not valid for {} replicas",
+                write_quorum,
+                replicas
+            );
+        }
+        if flush_quorum == 0 || flush_quorum > replicas {
+            bail!(
+                "flush quorum {} is not valid for {} replicas",
+                flush_quorum,
+                replicas
+            );
+        }
+
+        Ok(ReplicationPolicy {
+            replicas,
+            write_quorum,
+            flush_quorum,
+        })
+    }
+
+    /**
+     * Ack writes and flushes once a majority of the replicas have them.
+     * With one replica, this is the single downstairs mode used for
+     * development.
+     */
+    pub fn majority(replicas: usize) -> Result<ReplicationPolicy> {
+        let quorum = replicas / 2 + 1;
+        ReplicationPolicy::new(replicas, quorum, quorum)
+    }
+
+    pub fn replicas(&self) -> usize {
+        self.replicas
+    }
+
+    pub fn write_quorum(&self) -> usize {
+        self.write_quorum
+    }
+
+    pub fn flush_quorum(&self) -> usize {
+        self.flush_quorum
+    }
+}
+
+impl Default for ReplicationPolicy {
+    fn default() -> Self {
+        ReplicationPolicy::majority(3).unwrap()
+    }
+}
+
+/*
+ * How long a downstairs has to answer a job, and how many times in a row
+ * it can be found with an overdue job before we fault it.  Its unfinished
+ * jobs then complete from the other replicas, and it comes back through
+ * live repair when it reconnects.
+ */
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct JobTimeout {
+    timeout: Duration,
+    max_misses: u32,
+}
+
+impl JobTimeout {
+    pub fn new(timeout: Duration, max_misses: u32) -> Result<JobTimeout> {
+        if timeout == Duration::from_secs(0) {
+            bail!("job timeout must be greater than zero");
+        }
+        if max_misses == 0 {
+            bail!("max misses must be greater than zero");
+        }
+
+        Ok(JobTimeout {
+            timeout,
+            max_misses,
+        })
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn max_misses(&self) -> u32 {
+        self.max_misses
+    }
+}
+
+impl Default for JobTimeout {
+    fn default() -> Self {
+        JobTimeout::new(Duration::from_secs(30), 3).unwrap()
+    }
+}
+
+/*
+ * What to do when a downstairs answers with UuidMismatch, which it does
+ * when work we sent carries a UUID other than the one our connection to
+ * it was opened with.
+ *
+ * Fault takes that downstairs out of service, and it comes back through
+ * live repair when it reconnects.  Renegotiate drops the connection and
+ * connects again, which introduces us with our UUID, and anything it had
+ * not finished is replayed.  Both leave the other downstairs alone, and a
+ * downstairs that can't be faulted is renegotiated instead.
+ *
+ * Reactivate fails every guest IO not yet acked and starts activation
+ * over with every downstairs, as if the guest had just asked for it.
+ */
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum UuidMismatchPolicy {
+    Fault,
+    Renegotiate,
+    Reactivate,
+}
+
+impl Default for UuidMismatchPolicy {
+    fn default() -> Self {
+        UuidMismatchPolicy::Fault
+    }
+}
+
+impl std::str::FromStr for UuidMismatchPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "fault" => Ok(UuidMismatchPolicy::Fault),
+            "renegotiate" => Ok(UuidMismatchPolicy::Renegotiate),
+            "reactivate" => Ok(UuidMismatchPolicy::Reactivate),
+            _ => bail!(
+                "unknown UUID mismatch policy {:?}, use fault, \
+                renegotiate or reactivate",
+                s
+            ),
+        }
+    }
 }
 
 impl CrucibleOpts {
     pub fn key_bytes(&self) -> Option<Vec<u8>> {
         if let Some(key) = &self.key {
-            // For xts, key size must be 32 bytes
+            // For AES-256-GCM-SIV, key size must be 32 bytes
             let decoded_key =
                 base64::decode(key).expect("could not base64 decode key!");
 
@@ -74,6 +288,18 @@ impl CrucibleOpts {
             None
         }
     }
+
+    pub fn policy(&self) -> ReplicationPolicy {
+        self.policy.unwrap_or_default()
+    }
+
+    pub fn job_timeout(&self) -> JobTimeout {
+        self.job_timeout.unwrap_or_default()
+    }
+
+    pub fn uuid_mismatch(&self) -> UuidMismatchPolicy {
+        self.uuid_mismatch.unwrap_or_default()
+    }
 }
 
 pub fn deadline_secs(secs: u64) -> Instant {
@@ -99,6 +325,24 @@ async fn process_message(
         Message::ReadResponse(uuid, ds_id, responses) => {
             (*uuid, *ds_id, responses.clone())
         }
+        Message::ExtentRepairData(uuid, ds_id, result) => {
+            return process_repair_message(
+                u,
+                *uuid,
+                *ds_id,
+                up_coms.client_id,
+                result.clone().map(Some),
+            );
+        }
+        Message::ExtentRepairAck(uuid, ds_id, result) => {
+            return process_repair_message(
+                u,
+                *uuid,
+                *ds_id,
+                up_coms.client_id,
+                result.clone().map(|_| None),
+            );
+        }
         /*
          * For this case, we will (TODO) want to log an error to someone, but
          * I don't think there is anything else we can do.
@@ -118,12 +362,34 @@ async fn process_message(
     }
 
     if u.complete(ds_id, up_coms.client_id, result)? {
-        up_coms.ds_done_tx.send(ds_id).await?;
+        up_coms.ds_done_tx.send(DsDone::Job(ds_id)).await?;
     }
 
     Ok(())
 }
 
+/*
+ * Repair jobs have no guest waiting on them, so they don't go through the
+ * ds_done path.
+ */
+fn process_repair_message(
+    u: &Arc<Upstairs>,
+    uuid: Uuid,
+    ds_id: u64,
+    client_id: u8,
+    result: Result<Option<ExtentData>, CrucibleError>,
+) -> Result<()> {
+    if u.uuid != uuid {
+        println!(
+            "[{}] u.uuid {:?} != repair job {} uuid {:?}!",
+            client_id, u.uuid, ds_id, uuid
+        );
+        return Err(CrucibleError::UuidMismatch.into());
+    }
+
+    u.repair_complete(ds_id, client_id, result)
+}
+
 /*
  * Convert a virtual block offset and length into a Vec of tuples:
  *
@@ -221,6 +487,7 @@ pub fn extent_from_offset(
  */
 fn process_downstairs(
     target: &SocketAddrV4,
+    client_id: u8,
     u: &Arc<Upstairs>,
     gens: Vec<u64>,
     versions: Vec<u64>,
@@ -236,6 +503,19 @@ fn process_downstairs(
         println!("{}  dirty: {:?}", target, dirty);
     }
 
+    /*
+     * Keep everything this downstairs told us, reconciliation compares
+     * all three once they are here.
+     */
+    u.downstairs.lock().unwrap().region_metadata.insert(
+        client_id,
+        RegionMetadata {
+            generation: gens,
+            flush_numbers: versions.clone(),
+            dirty,
+        },
+    );
+
     let mut fi = u.flush_info.lock().unwrap();
     if fi.flush_numbers.is_empty() {
         /*
@@ -277,8 +557,7 @@ fn process_downstairs(
                 "{} MISMATCH expected: {:?} != new: {:?}",
                 target, fi.flush_numbers, versions
             );
-            // XXX Recovery process should start here
-            println!("{} Ignoring this downstairs version info", target);
+            println!("{} Reconciliation will repair this", target);
         }
     }
 
@@ -319,8 +598,23 @@ async fn io_send(
      * This XXX is for coming back here and making a better job of
      * flow control.
      */
-    let mut new_work = u.downstairs.lock().unwrap().new_work(client_id);
-    if !u.is_active() {
+    let (mut new_work, my_state) = {
+        let ds = u.downstairs.lock().unwrap();
+        (ds.new_work(client_id), ds.ds_state[client_id as usize])
+    };
+    if my_state == DsState::Failed {
+        /*
+         * This downstairs was faulted while connected.  Drop the
+         * connection, it will come back through live repair.
+         */
+        bail!("[{}] {} io_send while Failed", client_id, u.uuid);
+    }
+    let verifying = my_state == DsState::Verifying;
+    /*
+     * Reconciliation sends its repair work before the upstairs is
+     * active, that is the only work allowed then.
+     */
+    if !u.is_active() && !verifying {
         /*
          * If we are not active, then some other upstairs
          * has taken over control from us.  Kick this
@@ -373,6 +667,7 @@ async fn io_send(
                 dependencies,
                 writes,
             } => {
+                cdt::ds_write_io_start!(|| (*new_id, client_id));
                 fw.send(Message::Write(
                     u.uuid,
                     *new_id,
@@ -385,13 +680,18 @@ async fn io_send(
                 dependencies,
                 flush_number,
                 gen_number,
+                snapshot_details,
+                extent_limit,
             } => {
+                cdt::ds_flush_io_start!(|| (*new_id, client_id));
                 fw.send(Message::Flush(
                     u.uuid,
                     *new_id,
                     dependencies.clone(),
                     flush_number,
                     gen_number,
+                    snapshot_details.clone(),
+                    extent_limit,
                 ))
                 .await?
             }
@@ -399,6 +699,7 @@ async fn io_send(
                 dependencies,
                 requests,
             } => {
+                cdt::ds_read_io_start!(|| (*new_id, client_id));
                 fw.send(Message::ReadRequest(
                     u.uuid,
                     *new_id,
@@ -407,6 +708,30 @@ async fn io_send(
                 ))
                 .await?
             }
+            IOop::ExtentRepairRead { dependencies, eid } => {
+                cdt::ds_repair_io_start!(|| (*new_id, client_id));
+                fw.send(Message::ExtentRepairRead(
+                    u.uuid,
+                    *new_id,
+                    dependencies.clone(),
+                    eid,
+                ))
+                .await?
+            }
+            IOop::ExtentRepairWrite {
+                dependencies,
+                eid: _,
+                extent,
+            } => {
+                cdt::ds_repair_io_start!(|| (*new_id, client_id));
+                fw.send(Message::ExtentRepairWrite(
+                    u.uuid,
+                    *new_id,
+                    dependencies.clone(),
+                    extent.unwrap(),
+                ))
+                .await?
+            }
         }
     }
     Ok(false)
@@ -457,13 +782,15 @@ async fn proc(
         if my_state == DsState::Offline {
             ds.re_new(up_coms.client_id);
         }
+        ds.ds_health[up_coms.client_id as usize].reconnect();
     }
 
     let mut self_promotion = false;
     /*
      * As the "client", we must begin the negotiation.
      */
-    fw.send(Message::HereIAm(1, up.uuid)).await?;
+    fw.send(Message::HereIAm(CRUCIBLE_MESSAGE_VERSION, up.uuid))
+        .await?;
 
     /*
      * Used to track where we are in the current negotiation.
@@ -486,8 +813,9 @@ async fn proc(
      * negotiated variable on the left:
      *
      *          Upstairs             Downstairs
-     * 0:          HereIAm(v)  --->
+     * 0:    HereIAm(v, uuid)  --->
      *                         <---  YesItsMe(v)
+     *       Attach(ro)      --->
      *
      * At this point, a downstairs will wait for a "PromoteToActive" message
      * to be sent to it.  If this is a new upstairs that has not yet
@@ -497,7 +825,7 @@ async fn proc(
      * upstairs is_active() and, if the upstairs is active, we send the
      * downstairs the message ourselves.
      *
-     * 1: PromoteToActive(uuid)--->
+     * 1: PromoteToActive(uuid, gen)--->
      *                         <---  YouAreNowActive(uuid)
      *
      * 2:    RegionInfoPlease  --->
@@ -509,8 +837,7 @@ async fn proc(
      *    For "New" or "Disconnected" it means this downstairs never was
      *    "Active" and we have to go through the full compare of this
      *    downstairs with other downstairs and make sure they are
-     *    consistent (This code still TBW).  The New/Disconnected steps
-     *    continue here:
+     *    consistent.  The New/Disconnected steps continue here:
      *
      *          Upstairs             Downstairs
      * 4: ExtentVersionsPlease --->
@@ -519,7 +846,9 @@ async fn proc(
      *    Now with the extent info, Upstairs calls process_downstairs() and
      *    if no problems, sends connected=true to the up_listen() task,
      *    we set the downstairs to DsState::WaitQuorum and we exit the
-     *    while loop.
+     *    while loop.  Once all three are in WaitQuorum, up_listen() runs
+     *    reconciliation to repair any extents that don't match before the
+     *    upstairs goes active.
      *
      *    For the "Offline" state, the downstairs was connected and verified
      *    and after that point the connection was lost.  To handle this
@@ -547,7 +876,8 @@ async fn proc(
                 bail!("timed out during negotiation");
             }
             _ = sleep_until(ping_interval) => {
-                fw.send(Message::Ruok).await?;
+                let (seq, ts) = up.ds_ping(up_coms.client_id);
+                fw.send(Message::RuokSeq(seq, ts)).await?;
                 ping_interval = deadline_secs(5);
             }
             r = up_coms.ds_active_rx.changed(),
@@ -555,9 +885,9 @@ async fn proc(
             {
                 /*
                  * The activating guest sends us the generation number.
-                 * TODO: Update the promote to active message to send
-                 * the generation number along with the UUID for the
-                 * downstairs to validate.
+                 * It is already stored on the upstairs, and goes to the
+                 * downstairs with the promote so it can refuse us if
+                 * an upstairs with a higher generation has taken over.
                  */
                 match r {
                     Ok(_) => {
@@ -584,7 +914,9 @@ async fn proc(
                     up_coms.client_id
                 );
                 self_promotion = true;
-                fw.send(Message::PromoteToActive(up.uuid)).await?;
+                fw.send(
+                    Message::PromoteToActive(up.uuid, up.get_generation())
+                ).await?;
             }
             f = fr.next() => {
                 // When the downstairs responds, push the deadlines
@@ -601,23 +933,37 @@ async fn proc(
                         return Ok(())
                     }
                     Some(Message::Imok) => {}
+                    Some(Message::ImokSeq(seq, ts)) => {
+                        up.ds_pong(up_coms.client_id, seq, ts);
+                    }
+                    Some(Message::VersionMismatch(version)) => {
+                        up.ds_transition(
+                            up_coms.client_id,
+                            DsState::BadVersion
+                        );
+                        bail!(
+                            "downstairs speaks version {}, we speak {}",
+                            version,
+                            CRUCIBLE_MESSAGE_VERSION
+                        );
+                    }
                     Some(Message::YesItsMe(version)) => {
                         if negotiated != 0 {
                             bail!("Got version already!");
                         }
 
-                        /*
-                         * XXX Valid version to compare with should come
-                         * from main task. In the future we will also have
-                         * to handle a version mismatch.
-                         */
-                        if version != 1 {
+                        if version != CRUCIBLE_MESSAGE_VERSION {
                             up.ds_transition(
                                 up_coms.client_id,
                                 DsState::BadVersion
                             );
-                            bail!("expected version 1, got {}", version);
+                            bail!(
+                                "expected version {}, got {}",
+                                CRUCIBLE_MESSAGE_VERSION,
+                                version
+                            );
                         }
+                        fw.send(Message::Attach(up.read_only)).await?;
                         negotiated = 1;
                         /*
                          * We only set is_active after all three downstairs
@@ -636,7 +982,10 @@ async fn proc(
                                 up_coms.client_id
                             );
                             self_promotion = true;
-                            fw.send(Message::PromoteToActive(up.uuid)).await?;
+                            fw.send(Message::PromoteToActive(
+                                up.uuid,
+                                up.get_generation(),
+                            )).await?;
                         } else {
                             /*
                              * Transition this Downstairs to WaitActive
@@ -667,9 +1016,10 @@ async fn proc(
                                     up_coms.ds_active_rx.borrow_and_update();
                                 }
                                 self_promotion = true;
-                                fw.send(
-                                    Message::PromoteToActive(up.uuid)
-                                ).await?;
+                                fw.send(Message::PromoteToActive(
+                                    up.uuid,
+                                    up.get_generation(),
+                                )).await?;
                             }
                         }
                     }
@@ -694,9 +1044,22 @@ async fn proc(
                         fw.send(Message::RegionInfoPlease).await?;
 
                     }
-                    Some(Message::YouAreNoLongerActive(new_active_uuid)) => {
+                    Some(Message::YouAreNoLongerActive(
+                        new_active_uuid,
+                        new_gen,
+                    )) => {
                         if up.uuid != new_active_uuid {
-                            up.set_inactive();
+                            up.ds_superseded(
+                                up_coms.client_id,
+                                new_active_uuid,
+                                new_gen,
+                            );
+                            bail!(
+                                "[{}] {} gen {} is active",
+                                up_coms.client_id,
+                                new_active_uuid,
+                                new_gen,
+                            );
                         }
                     }
                     Some(Message::RegionInfo(region_def)) => {
@@ -735,6 +1098,38 @@ async fn proc(
                             negotiated = 4;
                             fw.send(Message::ExtentVersionsPlease).await?;
 
+                        } else if up.is_active() && up.read_only {
+                            /*
+                             * Live repair has to write to this
+                             * downstairs, which we can't do.
+                             */
+                            bail!(
+                                "[{}] read only, can't repair from {:?}",
+                                up_coms.client_id,
+                                my_state,
+                            );
+
+                        } else if up.is_active() && matches!(
+                            my_state,
+                            DsState::New
+                                | DsState::Disconnected
+                                | DsState::Failed
+                        ) {
+                            /*
+                             * This downstairs failed (or was replaced) and
+                             * has come back while the other two are taking
+                             * IO.  It joins under live repair, and the last
+                             * flush we send it covers all the work it
+                             * missed.
+                             */
+                            let lf = up.ds_live_repair_join(
+                                up_coms.client_id
+                            )?;
+                            println!("[{}] live repair join, last flush {}",
+                                up_coms.client_id, lf);
+                            negotiated = 3;
+                            fw.send(Message::LastFlush(lf)).await?;
+
                         } else {
                             /*
                              * TODO: This is the case where a downstairs
@@ -761,18 +1156,38 @@ async fn proc(
                             let state = &up.downstairs.lock().unwrap().ds_state;
                             state[up_coms.client_id as usize]
                         };
-                        assert_eq!(my_state, DsState::Offline);
                         println!("[{}] replied this last flush ID: {}",
                             up_coms.client_id,
                             last_flush,
                         );
-                        // Assert now, but this should eventually be an
-                        // error and move the downstairs to failed. XXX
-                        assert_eq!(
-                            up.last_flush_id(up_coms.client_id), last_flush
-                        );
-                        up.ds_transition(
-                            up_coms.client_id, DsState::Replay);
+                        if my_state == DsState::LiveRepair {
+                            println!("[{}] ready for live repair",
+                                up_coms.client_id);
+                        } else {
+                            assert_eq!(my_state, DsState::Offline);
+                            let expected =
+                                up.last_flush_id(up_coms.client_id);
+                            if expected != last_flush {
+                                /*
+                                 * We don't have what this downstairs
+                                 * needs to catch up, so replay won't
+                                 * work.  It will come back through live
+                                 * repair.
+                                 */
+                                up.downstairs
+                                    .lock()
+                                    .unwrap()
+                                    .ds_replay_abandon(up_coms.client_id);
+                                bail!(
+                                    "[{}] last flush {} != expected {}",
+                                    up_coms.client_id,
+                                    last_flush,
+                                    expected
+                                );
+                            }
+                            up.ds_transition(
+                                up_coms.client_id, DsState::Replay);
+                        }
 
                         *connected = true;
                         negotiated = 5;
@@ -796,7 +1211,14 @@ async fn proc(
                          * downstairs, and make the decision on which data is
                          * correct once we have everything.
                          */
-                        process_downstairs(target, up, gen, flush, dirty)?;
+                        process_downstairs(
+                            target,
+                            up_coms.client_id,
+                            up,
+                            gen,
+                            flush,
+                            dirty
+                        )?;
 
                         negotiated = 5;
                         up.ds_transition(
@@ -808,23 +1230,17 @@ async fn proc(
                     }
                     Some(Message::UuidMismatch(expected_uuid)) => {
                         /*
-                         * XXX If our downstairs
-                         * is returning a different UUID then we have, is that
-                         * a cause to disable ourselves, or just drop this work
-                         * on the ground?  Can this happen in a case where
-                         * we (the upstairs) should continue to accept work?
+                         * This downstairs is not Active yet, so it can't
+                         * be faulted, and we connect again unless the
+                         * policy is to start activation over.
                          */
-                        println!(
-                            "[{}] {} received UuidMismatch, expecting {:?}!",
-                            up_coms.client_id, up.uuid, expected_uuid
-                        );
-                        up.set_inactive();
-                        up.ds_transition(
-                            up_coms.client_id, DsState::New
+                        let policy = up.ds_uuid_mismatch(
+                            up_coms.client_id, expected_uuid
                         );
                         bail!(
-                            "[{}] {} received UuidMismatch, expecting {:?}!",
-                            up_coms.client_id, up.uuid, expected_uuid
+                            "[{}] {} received UuidMismatch, expecting {:?}, \
+                            {:?}",
+                            up_coms.client_id, up.uuid, expected_uuid, policy
                         );
                     }
                     Some(m) => {
@@ -912,14 +1328,16 @@ async fn cmd_loop(
     let mut more_work = up.ds_replay_active(up_coms.client_id);
 
     /*
-     * To keep things alive, initiate a ping any time we have been idle for
-     * 10 seconds.
+     * Ping every 10 seconds, busy or not.  This keeps things alive when
+     * idle, and keeps the round trip times for this downstairs current.
      *
      * XXX figure out what deadlines make sense here
      */
     let mut more_work_interval = deadline_secs(1);
     let mut ping_interval = deadline_secs(10);
     let mut timeout_deadline = deadline_secs(50);
+    let job_timeout = up.job_timeout();
+    let mut job_deadline = Instant::now() + job_timeout;
 
     let (tx, mut rx) = mpsc::channel::<Message>(100);
 
@@ -949,35 +1367,57 @@ async fn cmd_loop(
              */
             biased;
             f = fr.next() => {
-                // When the downstairs responds, push the deadlines
+                // When the downstairs responds, push the deadline
                 timeout_deadline = deadline_secs(50);
-                ping_interval = deadline_secs(10);
 
                 match f.transpose()? {
                     None => {
                         println!("[{}] None response", up_coms.client_id);
                         return Ok(())
                     },
-                    Some(Message::YouAreNoLongerActive(new_active_uuid)) => {
+                    Some(Message::ImokSeq(seq, ts)) => {
+                        up.ds_pong(up_coms.client_id, seq, ts);
+                    }
+                    Some(Message::YouAreNoLongerActive(
+                        new_active_uuid,
+                        new_gen,
+                    )) => {
                         if up.uuid != new_active_uuid {
-                            up.set_inactive();
+                            up.ds_superseded(
+                                up_coms.client_id,
+                                new_active_uuid,
+                                new_gen,
+                            );
+                            bail!(
+                                "[{}] {} gen {} is active",
+                                up_coms.client_id,
+                                new_active_uuid,
+                                new_gen,
+                            );
                         }
                     }
                     Some(Message::UuidMismatch(expected_uuid)) => {
                         /*
-                         * For now, when we get the wrong UUID back from
-                         * the downstairs, take ourselves out.
-                         * XXX Can we handle the case of a corrupted
-                         * UUID?
                          * XXX Can a bad downstairs sending us a bad
                          * UUID be used as a denial of service?
                          */
-                        up.set_inactive();
-                        up.ds_transition(up_coms.client_id, DsState::New);
-                        bail!(
-                            "[{}] received UuidMismatch, expecting {:?}!",
+                        let policy = up.ds_uuid_mismatch(
                             up_coms.client_id, expected_uuid
                         );
+                        if policy == UuidMismatchPolicy::Fault {
+                            /*
+                             * As for missed job deadlines, there may now
+                             * be work ready to ack.
+                             */
+                            let _ = up_coms
+                                .ds_done_tx
+                                .send(DsDone::CheckAcks)
+                                .await;
+                        }
+                        bail!(
+                            "[{}] received UuidMismatch, expecting {:?}, {:?}",
+                            up_coms.client_id, expected_uuid, policy
+                        );
                     }
                     Some(m) => {
                         tx.send(m).await?;
@@ -991,6 +1431,12 @@ async fn cmd_loop(
                  * that there is no new work but we won't know until we
                  * check.
                  */
+                if up.ds_state(up_coms.client_id) == DsState::New {
+                    /*
+                     * Another client task started activation over.
+                     */
+                    bail!("[{}] reactivating", up_coms.client_id);
+                }
                 let more =
                     io_send(up, &mut fw, up_coms.client_id, lossy).await?;
 
@@ -1033,12 +1479,36 @@ async fn cmd_loop(
                     up_coms.client_id);
                 return Ok(());
             }
+            _ = sleep_until(job_deadline) => {
+                if up.ds_deadline_check(up_coms.client_id) {
+                    /*
+                     * The jobs this downstairs was sitting on are now
+                     * skipped, some may be ready to ack.
+                     */
+                    let _ =
+                        up_coms.ds_done_tx.send(DsDone::CheckAcks).await;
+                    bail!(
+                        "[{}] missed too many job deadlines, faulted",
+                        up_coms.client_id
+                    );
+                }
+                job_deadline = Instant::now() + job_timeout;
+            }
             _ = sleep_until(ping_interval) => {
-                /*
-                 * To keep things alive, initiate a ping any time we have
-                 * been idle for (TBD) seconds.
-                 */
-                fw.send(Message::Ruok).await?;
+                let (seq, ts) = up.ds_ping(up_coms.client_id);
+                if up.ds_ping_check(up_coms.client_id) {
+                    /*
+                     * As for missed job deadlines, there may now be
+                     * work ready to ack.
+                     */
+                    let _ =
+                        up_coms.ds_done_tx.send(DsDone::CheckAcks).await;
+                    bail!(
+                        "[{}] missed too many pings, faulted",
+                        up_coms.client_id
+                    );
+                }
+                fw.send(Message::RuokSeq(seq, ts)).await?;
 
                 if lossy {
                     /*
@@ -1079,9 +1549,10 @@ struct UpComs {
     ds_status_tx: mpsc::Sender<Condition>,
     /**
      * This channel is used to transmit that an IO request sent by the
-     * upstairs to all required downstairs has completed.
+     * upstairs to all required downstairs has completed, or that
+     * jobs may be ready to ack for some other reason.
      */
-    ds_done_tx: mpsc::Sender<u64>,
+    ds_done_tx: mpsc::Sender<DsDone>,
     /**
      * This channel is used to notify the proc task that it's time to
      * promote this downstairs to active.
@@ -1089,6 +1560,22 @@ struct UpComs {
     ds_active_rx: watch::Receiver<u64>,
 }
 
+/*
+ * What a downstairs client task tells up_ds_listen.
+ */
+#[derive(Debug)]
+enum DsDone {
+    /*
+     * Enough downstairs have answered this job.
+     */
+    Job(u64),
+    /*
+     * A downstairs was faulted and its jobs skipped, so go look for
+     * work that is now ready to ack.
+     */
+    CheckAcks,
+}
+
 /*
  * This task is responsible for the connection to a specific downstairs
  * instance.
@@ -1191,6 +1678,12 @@ async fn looper(
     }
 }
 
+/*
+ * The most jobs we will hold on to for a downstairs that is Offline.
+ * XXX This should be based on memory use, not a job count.
+ */
+const MAX_REPLAY_JOBS: usize = 10000;
+
 /*
  * The structure that tracks information about the three downstairs
  * connections as well as the work that each is doing.
@@ -1212,45 +1705,221 @@ struct Downstairs {
      * The last flush ID that this downstairs has acked.
      */
     ds_last_flush: Vec<u64>,
+    /*
+     * Jobs that were skipped for a downstairs because it was gone too
+     * long for us to hold them for replay.  That downstairs no longer
+     * matches the others and has to come back through live repair.
+     */
+    ds_skipped_jobs: Vec<Vec<u64>>,
     downstairs_errors: HashMap<u8, u64>, // client id -> errors
     active: HashMap<u64, DownstairsIO>,
     next_id: u64,
     completed: AllocRingBuffer<u64>,
+    /*
+     * Progress of a live repair, if one is running.
+     */
+    live_repair: Option<LiveRepair>,
+    /*
+     * The extent versions each downstairs reported when it connected,
+     * index by client ID.
+     */
+    region_metadata: HashMap<u8, RegionMetadata>,
+    counters: IOCounters,
+    policy: ReplicationPolicy,
+    job_timeout: JobTimeout,
+    /*
+     * When each job still in progress was sent, index by client ID.
+     * Entries for jobs that have since finished are dropped the next
+     * time we check deadlines.
+     */
+    ds_sent: Vec<HashMap<u64, Instant>>,
+    /*
+     * Deadline checks in a row that found an overdue job, index by
+     * client ID.
+     */
+    ds_deadline_misses: Vec<u32>,
+    /*
+     * Ping round trips and misses, index by client ID.
+     */
+    ds_health: Vec<ConnectionHealth>,
+    uuid_mismatch: UuidMismatchPolicy,
+    /*
+     * UuidMismatch answers from each downstairs, index by client ID.
+     */
+    ds_uuid_mismatches: Vec<u64>,
 }
 
 /*
- * These counts describe the various states that a Downstairs IO can
- * be in.
+ * Running totals of the IO this upstairs has done.  Guest IO is counted
+ * when it is acked back to the guest, errors and replays are counted per
+ * downstairs.
  */
-#[derive(Debug, Default)]
-pub struct WorkCounts {
-    active: u64,  // New or in flight to downstairs.
-    error: u64,   // This IO had an error.
-    skipped: u64, // Skipped
-    done: u64,    // This IO has completed
-}
-
-impl WorkCounts {
-    fn completed_ok(&self) -> u64 {
-        self.done
-    }
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+struct IOCounters {
+    read_ops: u64,
+    read_bytes: u64,
+    write_ops: u64,
+    write_bytes: u64,
+    flush_ops: u64,
+    /*
+     * IOs that returned an error, index by client ID.
+     */
+    ds_errors: Vec<u64>,
+    /*
+     * IOs sent again after a downstairs reconnected, index by client ID.
+     */
+    ds_replays: Vec<u64>,
 }
 
-impl Default for Downstairs {
-    fn default() -> Self {
-        Self {
-            ds_uuid: HashMap::new(),
-            ds_state: vec![DsState::New; 3],
-            ds_last_flush: vec![0; 3],
-            downstairs_errors: HashMap::new(),
-            active: HashMap::new(),
-            completed: AllocRingBuffer::with_capacity(2048),
-            next_id: 1000,
+impl IOCounters {
+    fn new() -> IOCounters {
+        IOCounters {
+            read_ops: 0,
+            read_bytes: 0,
+            write_ops: 0,
+            write_bytes: 0,
+            flush_ops: 0,
+            ds_errors: vec![0; 3],
+            ds_replays: vec![0; 3],
         }
     }
 }
 
-impl Downstairs {
+/*
+ * Fire the dtrace probe for a downstairs finishing this job.
+ */
+fn cdt_ds_work_done(work: &IOop, ds_id: u64, client_id: u8) {
+    match work {
+        IOop::Read {
+            dependencies: _,
+            requests: _,
+        } => {
+            cdt::ds_read_io_done!(|| (ds_id, client_id));
+        }
+        IOop::Write {
+            dependencies: _,
+            writes: _,
+        } => {
+            cdt::ds_write_io_done!(|| (ds_id, client_id));
+        }
+        IOop::Flush {
+            dependencies: _,
+            flush_number: _,
+            gen_number: _,
+            snapshot_details: _,
+            extent_limit: _,
+        } => {
+            cdt::ds_flush_io_done!(|| (ds_id, client_id));
+        }
+        IOop::ExtentRepairRead {
+            dependencies: _,
+            eid: _,
+        }
+        | IOop::ExtentRepairWrite {
+            dependencies: _,
+            eid: _,
+            extent: _,
+        } => {
+            cdt::ds_repair_io_done!(|| (ds_id, client_id));
+        }
+    }
+}
+
+/*
+ * The generation number, flush number, and dirty bit for every extent on
+ * one downstairs.
+ */
+#[derive(Debug, Clone, Default)]
+struct RegionMetadata {
+    generation: Vec<u64>,
+    flush_numbers: Vec<u64>,
+    dirty: Vec<bool>,
+}
+
+impl RegionMetadata {
+    /*
+     * Higher generation wins, then higher flush number.  Between two
+     * extents with the same numbers, one that is not dirty is a better
+     * copy than one that is.
+     */
+    fn version(&self, eid: usize) -> (u64, u64, bool) {
+        (
+            self.generation[eid],
+            self.flush_numbers[eid],
+            !self.dirty[eid],
+        )
+    }
+}
+
+/*
+ * Tracks the live repair of one downstairs.  Extents are repaired in
+ * order, one at a time.
+ */
+#[derive(Debug, Copy, Clone)]
+struct LiveRepair {
+    /*
+     * The client ID of the downstairs under repair.
+     */
+    dest: u8,
+    /*
+     * The next extent that needs repair.
+     */
+    next_eid: u64,
+    /*
+     * The repair read and repair write job IDs for the extent currently
+     * being repaired.
+     */
+    pending: Option<(u64, u64)>,
+    /*
+     * Set when a repair job fails, the repair will be abandoned.
+     */
+    failed: bool,
+}
+
+/*
+ * These counts describe the various states that a Downstairs IO can
+ * be in.
+ */
+#[derive(Debug, Default)]
+pub struct WorkCounts {
+    active: u64,  // New or in flight to downstairs.
+    error: u64,   // This IO had an error.
+    skipped: u64, // Skipped
+    done: u64,    // This IO has completed
+}
+
+impl WorkCounts {
+    fn completed_ok(&self) -> u64 {
+        self.done
+    }
+}
+
+impl Default for Downstairs {
+    fn default() -> Self {
+        Self {
+            ds_uuid: HashMap::new(),
+            ds_state: vec![DsState::New; 3],
+            ds_last_flush: vec![0; 3],
+            ds_skipped_jobs: vec![Vec::new(); 3],
+            downstairs_errors: HashMap::new(),
+            active: HashMap::new(),
+            completed: AllocRingBuffer::with_capacity(2048),
+            next_id: 1000,
+            live_repair: None,
+            region_metadata: HashMap::new(),
+            counters: IOCounters::new(),
+            policy: ReplicationPolicy::default(),
+            job_timeout: JobTimeout::default(),
+            ds_sent: vec![HashMap::new(); 3],
+            ds_deadline_misses: vec![0; 3],
+            ds_health: vec![ConnectionHealth::new(); 3],
+            uuid_mismatch: UuidMismatchPolicy::default(),
+            ds_uuid_mismatches: vec![0; 3],
+        }
+    }
+}
+
+impl Downstairs {
     /**
      * Assign a new downstairs ID.
      */
@@ -1270,19 +1939,92 @@ impl Downstairs {
      * errors, as for all we know it's a new downstairs.
      */
     fn in_progress(&mut self, ds_id: u64, client_id: u8) -> Option<IOop> {
+        let live_repair =
+            self.ds_state[client_id as usize] == DsState::LiveRepair;
+
+        /*
+         * A downstairs under live repair must not stamp the current flush
+         * number on extents the repair has not copied yet.  Everything
+         * below next_eid is done, and the extent being repaired is done
+         * too if its repair write is ahead of this job.
+         */
+        let extent_limit = match &self.live_repair {
+            Some(lr) if live_repair && lr.dest == client_id => {
+                Some(match lr.pending {
+                    Some((_, write_id)) if write_id < ds_id => lr.next_eid + 1,
+                    _ => lr.next_eid,
+                })
+            }
+            _ => None,
+        };
         let job = self.active.get_mut(&ds_id).unwrap();
 
-        let newstate = match &self.downstairs_errors.get(&client_id) {
-            Some(_) => IOState::Skipped,
-            None => IOState::InProgress,
+        /*
+         * A repair write can't be sent until the data from the source
+         * downstairs has arrived.  Leave it as New and we will come back
+         * for it.
+         */
+        if let IOop::ExtentRepairWrite {
+            dependencies: _,
+            eid: _,
+            extent: None,
+        } = &job.work
+        {
+            return None;
+        }
+
+        /*
+         * A downstairs under live repair does not yet have valid data,
+         * so it takes no part in reads.
+         */
+        let is_read = matches!(
+            job.work,
+            IOop::Read {
+                dependencies: _,
+                requests: _,
+            }
+        );
+        let newstate = if self.downstairs_errors.get(&client_id).is_some()
+            || (live_repair && is_read)
+        {
+            IOState::Skipped
+        } else {
+            IOState::InProgress
         };
 
         let oldstate = job.state.insert(client_id, newstate.clone());
         assert_eq!(oldstate, Some(IOState::New));
+        if newstate == IOState::InProgress {
+            self.ds_sent[client_id as usize].insert(ds_id, Instant::now());
+        }
 
         match newstate {
             IOState::Skipped => None,
-            IOState::InProgress => Some(job.work.clone()),
+            IOState::InProgress => {
+                let mut work = job.work.clone();
+
+                /*
+                 * This downstairs will never see a job that was skipped
+                 * for it, so it must not wait on one.
+                 */
+                let active = &self.active;
+                work.deps_mut().retain(|dep| {
+                    !matches!(
+                        active.get(dep).and_then(|j| j.state.get(&client_id)),
+                        Some(IOState::Skipped)
+                    )
+                });
+
+                if let IOop::Flush {
+                    extent_limit: limit,
+                    ..
+                } = &mut work
+                {
+                    *limit = extent_limit;
+                }
+
+                Some(work)
+            }
             _ => panic!("bad state in in_progress!"),
         }
     }
@@ -1328,6 +2070,16 @@ impl Downstairs {
                 continue;
             }
 
+            /*
+             * Repair jobs only ever go to one downstairs, the others
+             * were skipped when the job was created and stay that way.
+             */
+            if job.work.is_repair()
+                && Some(&IOState::Skipped) == job.state.get(&client_id)
+            {
+                continue;
+            }
+
             /*
              * If the job is InProgress or New, then we can just go back
              * to New and no extra work is required.
@@ -1353,11 +2105,12 @@ impl Downstairs {
                         }
                     } else {
                         /*
-                         * For a write or flush, if we have 3 completed,
-                         * then we can leave this job as AckReady, if not,
-                         * then we have to undo the AckReady.
+                         * For a write or flush, if every replica
+                         * completed, then we can leave this job as
+                         * AckReady, if not, then we have to undo the
+                         * AckReady.
                          */
-                        if jobs_completed_ok < 3 {
+                        if jobs_completed_ok < self.policy.replicas() as u64 {
                             println!("Remove AckReady for W/F {}", ds_id);
                             job.ack_status = AckStatus::NotAcked;
                         }
@@ -1365,6 +2118,7 @@ impl Downstairs {
                 }
             }
             job.state.insert(client_id, IOState::New);
+            self.counters.ds_replays[client_id as usize] += 1;
         }
     }
 
@@ -1410,11 +2164,426 @@ impl Downstairs {
         ackable
     }
 
+    /*
+     * Any client past the number of replicas will never connect.
+     */
+    fn set_policy(&mut self, policy: ReplicationPolicy) {
+        self.policy = policy;
+        for cid in policy.replicas()..3 {
+            self.ds_state[cid] = DsState::Disabled;
+        }
+    }
+
+    /*
+     * Called once every job timeout.  Returns true when this downstairs
+     * has had an overdue job at max_misses checks in a row.
+     */
+    fn deadline_missed(&mut self, client_id: u8) -> bool {
+        let now = Instant::now();
+        let timeout = self.job_timeout.timeout();
+        let active = &self.active;
+        let sent = &mut self.ds_sent[client_id as usize];
+
+        sent.retain(|ds_id, _| {
+            matches!(
+                active.get(ds_id).and_then(|job| job.state.get(&client_id)),
+                Some(IOState::InProgress)
+            )
+        });
+
+        /*
+         * A flush that takes a snapshot may copy the whole region, which
+         * takes far longer than any other job, and the jobs sent after it
+         * wait for it.  Nothing is late while one is in progress, and
+         * the clock starts again once it is done.
+         */
+        let snapshot = sent.keys().any(|ds_id| {
+            matches!(
+                active.get(ds_id).map(|job| &job.work),
+                Some(IOop::Flush {
+                    snapshot_details: Some(_),
+                    ..
+                })
+            )
+        });
+        let late = if snapshot {
+            0
+        } else {
+            sent.values()
+                .filter(|start| now.duration_since(**start) > timeout)
+                .count()
+        };
+
+        let misses = &mut self.ds_deadline_misses[client_id as usize];
+        if late == 0 {
+            *misses = 0;
+            return false;
+        }
+        *misses += 1;
+        println!(
+            "[{}] {} jobs past the {:?} deadline, miss {} of {}",
+            client_id,
+            late,
+            timeout,
+            misses,
+            self.job_timeout.max_misses()
+        );
+
+        *misses >= self.job_timeout.max_misses()
+    }
+
+    /*
+     * After a downstairs is faulted, the jobs it held up may now have an
+     * answer from every downstairs.  Make those ready to ack, and retire
+     * any flush that was only waiting on it.  Returns true if there is
+     * work to ack back to the guest.
+     */
+    fn finish_skipped(&mut self) -> bool {
+        let mut ackable = false;
+        let mut acked_flushes = Vec::new();
+
+        let replicas = self.policy.replicas();
+        for job in self.active.values_mut() {
+            if !job.answered(replicas) {
+                continue;
+            }
+            match job.ack_status {
+                AckStatus::NotAcked => {
+                    job.ack_status = AckStatus::AckReady;
+                    ackable = true;
+                }
+                AckStatus::AckReady => {
+                    ackable = true;
+                }
+                AckStatus::Acked => {
+                    acked_flushes.push(job.ds_id);
+                }
+            }
+        }
+
+        /*
+         * Retiring a flush takes every job before it along, so wait until
+         * those are all finished and acked.  Go from the newest flush down,
+         * once one retires the older ones are gone with it.
+         */
+        acked_flushes.sort_unstable();
+        for ds_id in acked_flushes.into_iter().rev() {
+            let ready = self
+                .active
+                .values()
+                .filter(|job| job.ds_id <= ds_id)
+                .all(|job| {
+                    job.ack_status == AckStatus::Acked && job.answered(replicas)
+                });
+            if ready && self.active.contains_key(&ds_id) {
+                self.retire_check(ds_id);
+            }
+        }
+
+        ackable
+    }
+
+    /*
+     * The client IDs of the downstairs that hold a replica.
+     */
+    fn replicas(&self) -> std::ops::Range<u8> {
+        0..self.policy.replicas() as u8
+    }
+
     /**
      * Enqueue a new downstairs request.
      */
-    fn enqueue(&mut self, io: DownstairsIO) {
+    fn enqueue(&mut self, mut io: DownstairsIO) {
+        /*
+         * A job will never go to a client that isn't a replica, but it
+         * still has to look finished there so it can retire.
+         */
+        for cid in self.policy.replicas() as u8..3 {
+            io.state.insert(cid, IOState::Skipped);
+        }
         self.active.insert(io.ds_id, io);
+
+        /*
+         * Nothing retires while a downstairs is Offline, so everything
+         * since its last flush is still here for replay.  Don't let that
+         * grow forever.
+         */
+        if self.active.len() > MAX_REPLAY_JOBS {
+            for cid in 0..3 {
+                if self.ds_state[cid as usize] == DsState::Offline {
+                    self.ds_replay_abandon(cid);
+                }
+            }
+        }
+    }
+
+    /**
+     * Mark every job this downstairs has not finished as Skipped for it,
+     * and return the sorted IDs of those jobs.
+     */
+    fn skip_unfinished(&mut self, client_id: u8) -> Vec<u64> {
+        let mut skipped = Vec::new();
+        for job in self.active.values_mut() {
+            let state = job.state.get_mut(&client_id).unwrap();
+            if *state == IOState::New || *state == IOState::InProgress {
+                *state = IOState::Skipped;
+                skipped.push(job.ds_id);
+            }
+        }
+        skipped.sort_unstable();
+        skipped
+    }
+
+    /**
+     * Give up on replaying work to this downstairs.  Every job it has not
+     * finished is skipped for it, so flushes can retire without it, and
+     * it is marked Failed.  When it comes back, it goes through live
+     * repair instead of replay.
+     */
+    fn ds_replay_abandon(&mut self, client_id: u8) {
+        let skipped = self.skip_unfinished(client_id);
+
+        println!(
+            "[{}] Abandon replay, {} jobs skipped, transition from {:?} \
+            to Failed",
+            client_id,
+            skipped.len(),
+            self.ds_state[client_id as usize],
+        );
+        self.ds_skipped_jobs[client_id as usize].extend(skipped);
+        self.ds_state[client_id as usize] = DsState::Failed;
+    }
+
+    /**
+     * Enqueue the two jobs that copy extent eid from the source downstairs
+     * to the dest downstairs, and return their IDs.
+     *
+     * Every job already on the queue is a dependency, and any job enqueued
+     * later will depend on these.  Both IDs are taken at the same time so
+     * no guest write can land between the read on the source and the
+     * write on the dest.
+     */
+    fn enqueue_repair(&mut self, eid: u64, source: u8, dest: u8) -> (u64, u64) {
+        let mut deps = self.active.keys().cloned().collect::<Vec<u64>>();
+        deps.sort_unstable();
+
+        let read_id = self.next_id();
+        let write_id = self.next_id();
+
+        let mut read_state = HashMap::new();
+        let mut write_state = HashMap::new();
+        for cid in 0..3 {
+            read_state.insert(
+                cid,
+                if cid == source {
+                    IOState::New
+                } else {
+                    IOState::Skipped
+                },
+            );
+            write_state.insert(
+                cid,
+                if cid == dest {
+                    IOState::New
+                } else {
+                    IOState::Skipped
+                },
+            );
+        }
+
+        /*
+         * There is no guest waiting on these, so they start out acked.
+         */
+        self.enqueue(DownstairsIO {
+            ds_id: read_id,
+            guest_id: 0,
+            work: IOop::ExtentRepairRead {
+                dependencies: deps.clone(),
+                eid,
+            },
+            state: read_state,
+            ack_status: AckStatus::Acked,
+            data: None,
+            repair_write: Some(write_id),
+        });
+        self.enqueue(DownstairsIO {
+            ds_id: write_id,
+            guest_id: 0,
+            work: IOop::ExtentRepairWrite {
+                dependencies: deps,
+                eid,
+                extent: None,
+            },
+            state: write_state,
+            ack_status: AckStatus::Acked,
+            data: None,
+            repair_write: None,
+        });
+
+        (read_id, write_id)
+    }
+
+    /**
+     * A repair job has finished on the downstairs it was sent to.  When
+     * the read from the source finishes, the extent data is handed to the
+     * matching repair write so it can go to the dest.
+     */
+    fn repair_complete(
+        &mut self,
+        ds_id: u64,
+        client_id: u8,
+        result: Result<Option<ExtentData>, CrucibleError>,
+    ) -> Result<()> {
+        /*
+         * A reconciliation that was abandoned clears out its jobs, but
+         * the downstairs may still answer for them.
+         */
+        let job = match self.active.get_mut(&ds_id) {
+            Some(job) => job,
+            None => {
+                println!(
+                    "[{}] repair job {} is no longer active",
+                    client_id, ds_id
+                );
+                return Ok(());
+            }
+        };
+        let is_read = matches!(
+            job.work,
+            IOop::ExtentRepairRead {
+                dependencies: _,
+                eid: _,
+            }
+        );
+
+        let newstate = match &result {
+            Ok(_) => IOState::Done,
+            Err(e) => IOState::Error(e.clone()),
+        };
+        let oldstate = match job.state.get(&client_id) {
+            Some(state) => state.clone(),
+            None => bail!(
+                "[{}] repair job {} has no state for this downstairs",
+                client_id,
+                ds_id
+            ),
+        };
+        if oldstate != IOState::InProgress {
+            bail!(
+                "[{}] repair job {} completed while not InProgress: {}",
+                client_id,
+                ds_id,
+                oldstate
+            );
+        }
+        job.state.insert(client_id, newstate);
+        cdt_ds_work_done(&job.work, ds_id, client_id);
+
+        if !is_read {
+            return Ok(());
+        }
+
+        /*
+         * The dest is the one downstairs the write is still waiting on.
+         * If the write has already gone away there is no one to give the
+         * data to.
+         */
+        let write_id = match job.repair_write {
+            Some(write_id) => write_id,
+            None => bail!(
+                "[{}] repair read {} has no repair write",
+                client_id,
+                ds_id
+            ),
+        };
+        let write_job = match self.active.get_mut(&write_id) {
+            Some(write_job) => write_job,
+            None => {
+                println!(
+                    "[{}] repair write {} for read {} is no longer active",
+                    client_id, write_id, ds_id
+                );
+                return Ok(());
+            }
+        };
+        let dest = match write_job
+            .state
+            .iter()
+            .find(|(_, state)| **state == IOState::New)
+        {
+            Some((dest, _)) => *dest,
+            None => return Ok(()),
+        };
+
+        match result {
+            Ok(data) => {
+                if let IOop::ExtentRepairWrite {
+                    dependencies: _,
+                    eid: _,
+                    extent,
+                } = &mut write_job.work
+                {
+                    *extent = data;
+                }
+            }
+            Err(e) => {
+                println!(
+                    "[{}] repair read {} failed: {:?}",
+                    client_id, ds_id, e
+                );
+                write_job.state.insert(dest, IOState::Skipped);
+                if let Some(lr) = &mut self.live_repair {
+                    if lr.dest == dest {
+                        lr.failed = true;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Compare the extent versions from all three downstairs, and return
+     * the extents that don't match.  Each entry is the extent, the client
+     * with the best copy of it, and the clients that need that copy.
+     *
+     * If any copy of an extent is dirty, we can't know the others match
+     * it even with the same flush number, so every other copy is repaired.
+     */
+    fn reconcile_list(&self) -> Vec<(u64, u8, Vec<u8>)> {
+        let meta: Vec<&RegionMetadata> = self
+            .replicas()
+            .map(|cid| self.region_metadata.get(&cid).unwrap())
+            .collect();
+
+        let mut list = Vec::new();
+        for eid in 0..meta[0].flush_numbers.len() {
+            let versions: Vec<(u64, u64, bool)> =
+                meta.iter().map(|m| m.version(eid)).collect();
+
+            let mut source = 0;
+            for cid in 1..meta.len() {
+                if versions[cid] > versions[source] {
+                    source = cid;
+                }
+            }
+
+            let any_dirty = versions.iter().any(|v| !v.2);
+            let dests: Vec<u8> = (0..meta.len())
+                .filter(|cid| {
+                    *cid != source
+                        && (any_dirty || versions[*cid] != versions[source])
+                })
+                .map(|cid| cid as u8)
+                .collect();
+
+            if !dests.is_empty() {
+                list.push((eid as u64, source as u8, dests));
+            }
+        }
+
+        list
     }
 
     /**
@@ -1454,8 +2623,10 @@ impl Downstairs {
          * the Guest
          *
          * Not ok:
-         * - 2+ errors for Write/Flush
-         * - 3+ errors for Reads
+         * - more errors than the replicas left over after a quorum for
+         *   Write/Flush
+         * - no replica returned data for Reads, which is errors from
+         *   all of them or errors from some and the rest skipped
          *
          * TODO: this doesn't tell the Guest what the error(s) were?
          * TODO: Add retries here as well.
@@ -1468,29 +2639,41 @@ impl Downstairs {
             .ok_or_else(|| anyhow!("reqid {} is not active", ds_id))?;
 
         /*
-         * XXX: this code assumes that 3 downstairs is the max that we'll
-         * ever support.
+         * A write or flush has failed once too many replicas have errors
+         * for the rest to make a quorum.
          */
+        let replicas = self.policy.replicas() as u64;
         let bad_job = match &job.work {
             IOop::Read {
                 dependencies: _dependencies,
                 requests: _,
-            } => wc.error == 3,
+            } => wc.done == 0,
             IOop::Write {
                 dependencies: _dependencies,
                 writes: _,
-            } => wc.error >= 2,
+            } => wc.error > replicas - self.policy.write_quorum() as u64,
             IOop::Flush {
                 dependencies: _dependencies,
                 flush_number: _flush_number,
                 gen_number: _gen_number,
-            } => wc.error >= 2,
+                snapshot_details: _,
+                extent_limit: _,
+            } => wc.error > replicas - self.policy.flush_quorum() as u64,
+            IOop::ExtentRepairRead {
+                dependencies: _,
+                eid: _,
+            }
+            | IOop::ExtentRepairWrite {
+                dependencies: _,
+                eid: _,
+                extent: _,
+            } => wc.error > 0,
         };
 
         if bad_job {
             Err(CrucibleError::IoError(format!(
-                "{} out of 3 downstairs returned an error",
-                wc.error
+                "{} out of {} downstairs returned an error",
+                wc.error, replicas
             )))
         } else {
             Ok(())
@@ -1499,35 +2682,58 @@ impl Downstairs {
 
     /*
      * This function just does the match on IOop type and updates the dtrace
-     * probe for that operation finishing.
+     * probe and the IO counters for that operation finishing.
      */
-    fn cdt_gw_work_done(&self, ds_id: u64, gw_id: u64) {
+    fn cdt_gw_work_done(&mut self, ds_id: u64, gw_id: u64) {
         let job = self
             .active
             .get(&ds_id)
             .ok_or_else(|| anyhow!("reqid {} is not active", ds_id))
             .unwrap();
+        let counters = &mut self.counters;
 
         match &job.work {
             IOop::Read {
                 dependencies: _,
-                requests: _,
+                requests,
             } => {
                 cdt::gw_read_end!(|| (gw_id));
+                counters.read_ops += 1;
+                counters.read_bytes += requests
+                    .iter()
+                    .map(|r| {
+                        r.num_blocks * r.offset.block_size_in_bytes() as u64
+                    })
+                    .sum::<u64>();
             }
             IOop::Write {
                 dependencies: _,
-                writes: _,
+                writes,
             } => {
                 cdt::gw_write_end!(|| (gw_id));
+                counters.write_ops += 1;
+                counters.write_bytes +=
+                    writes.iter().map(|w| w.data.len() as u64).sum::<u64>();
             }
             IOop::Flush {
                 dependencies: _,
                 flush_number: _,
                 gen_number: _,
+                snapshot_details: _,
+                extent_limit: _,
             } => {
                 cdt::gw_flush_end!(|| (gw_id));
+                counters.flush_ops += 1;
             }
+            IOop::ExtentRepairRead {
+                dependencies: _,
+                eid: _,
+            }
+            | IOop::ExtentRepairWrite {
+                dependencies: _,
+                eid: _,
+                extent: _,
+            } => {}
         }
     }
 
@@ -1573,10 +2779,19 @@ impl Downstairs {
             IOState::Done
         };
 
-        let oldstate = job.state.insert(client_id, newstate.clone()).unwrap();
         /*
-         * Verify the job was InProgress
+         * Verify the job was InProgress.  An answer for a job that is not
+         * (one skipped when this downstairs was faulted, or a duplicate)
+         * is an error, and the job state is not touched.
          */
+        let oldstate = match job.state.get(&client_id) {
+            Some(state) => state.clone(),
+            None => bail!(
+                "[{}] job {} has no state for this downstairs",
+                client_id,
+                ds_id
+            ),
+        };
         if oldstate != IOState::InProgress {
             bail!(
                 "[{}] job completed while not InProgress: {}",
@@ -1584,8 +2799,28 @@ impl Downstairs {
                 oldstate
             );
         }
+        job.state.insert(client_id, newstate.clone());
+        cdt_ds_work_done(&job.work, ds_id, client_id);
+
+        /*
+         * The jobs that waited behind a snapshot get the full timeout
+         * from now, see deadline_missed.
+         */
+        if matches!(
+            job.work,
+            IOop::Flush {
+                snapshot_details: Some(_),
+                ..
+            }
+        ) {
+            let now = Instant::now();
+            self.ds_sent[client_id as usize]
+                .values_mut()
+                .for_each(|sent| *sent = now);
+        }
 
         if matches!(newstate, IOState::Error(_)) {
+            self.counters.ds_errors[client_id as usize] += 1;
             // Mark this downstairs as bad if this was a write or flush
             // XXX: reconcilation, retries?
             // XXX: Errors should be reported to nexus
@@ -1597,7 +2832,9 @@ impl Downstairs {
                 } | IOop::Flush {
                     dependencies: _,
                     flush_number: _,
-                    gen_number: _
+                    gen_number: _,
+                    snapshot_details: _,
+                    extent_limit: _
                 }
             ) {
                 let errors: u64 = match self.downstairs_errors.get(&client_id) {
@@ -1618,6 +2855,8 @@ impl Downstairs {
                 dependencies: _dependencies,
                 flush_number: _flush_number,
                 gen_number: _gen_number,
+                snapshot_details: _,
+                extent_limit: _,
             } = &job.work
             {
                 self.ds_last_flush[client_id as usize] = ds_id;
@@ -1651,7 +2890,7 @@ impl Downstairs {
                     writes: _,
                 } => {
                     assert!(read_data.is_empty());
-                    if jobs_completed_ok == 2 {
+                    if jobs_completed_ok == self.policy.write_quorum() as u64 {
                         notify_guest = true;
                         job.ack_status = AckStatus::AckReady;
                     }
@@ -1660,14 +2899,31 @@ impl Downstairs {
                     dependencies: _dependencies,
                     flush_number: _flush_number,
                     gen_number: _gen_number,
+                    snapshot_details: _,
+                    extent_limit: _,
                 } => {
                     assert!(read_data.is_empty());
-                    if jobs_completed_ok == 2 {
+                    if jobs_completed_ok == self.policy.flush_quorum() as u64 {
                         notify_guest = true;
                         job.ack_status = AckStatus::AckReady;
                     }
                     self.ds_last_flush[client_id as usize] = ds_id;
                 }
+                /*
+                 * Repair jobs are created already acked and complete
+                 * through repair_complete instead.
+                 */
+                IOop::ExtentRepairRead {
+                    dependencies: _,
+                    eid: _,
+                }
+                | IOop::ExtentRepairWrite {
+                    dependencies: _,
+                    eid: _,
+                    extent: _,
+                } => {
+                    panic!("repair job {} completed as guest IO", ds_id);
+                }
             }
         }
         /*
@@ -1752,6 +3008,8 @@ impl Downstairs {
                 dependencies: _dependencies,
                 flush_number: _flush_number,
                 gen_number: _gen_number,
+                snapshot_details: _,
+                extent_limit: _,
             } => Ok(true),
             _ => Ok(false),
         }
@@ -1776,46 +3034,90 @@ impl Downstairs {
     }
 }
 
-/// Implement XTS encryption
-/// See: https://en.wikipedia.org/wiki/Disk_encryption_theory#XEX-based_\
-/// tweaked-codebook_mode_with_ciphertext_stealing_(XTS)
+/*
+ * The size in bytes of the nonce and tag stored with each encrypted block.
+ * The nonce stored is one byte longer: it starts with the encryption
+ * version the block was written with.
+ */
+pub const NONCE_SIZE: usize = 12;
+pub const TAG_SIZE: usize = 16;
+
+/*
+ * The on disk encryption formats, by the version byte at the start of a
+ * block's stored nonce:
+ *
+ * 0: AES-128-XTS over 512 byte sectors, tweaked by the offset in the
+ *    extent.  Nothing is stored with the block, so these blocks have no
+ *    nonce at all and are not authenticated.  They are only ever read,
+ *    and only for a volume that says it has them.
+ * 1: AES-256-GCM-SIV with a random nonce, and the extent and offset of
+ *    the block as associated data.
+ *
+ * GCM-SIV derives a new key from each nonce, and a repeated nonce only
+ * shows that the same block was written twice, so random nonces are safe
+ * for as many writes as a volume will see.
+ */
+pub const ENCRYPTION_VERSION: u8 = 1;
+
+/*
+ * Encryption of a volume's blocks under the volume's key.  Each block is
+ * encrypted on its own with a new nonce, and the nonce and tag are sent to
+ * the downstairs with it, which keeps them with the block.  The extent and
+ * offset of the block are authenticated along with it, so a block that
+ * turns up somewhere else won't decrypt.
+ */
 pub struct EncryptionContext {
+    cipher: Aes256GcmSiv,
     xts: Xts128<Aes128>,
     key: Vec<u8>,
-    block_size: usize,
+    legacy_xts: bool,
 }
 
 impl Debug for EncryptionContext {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
-        f.debug_struct("EncryptionContext")
-            .field("block_size", &self.block_size)
-            .finish()
+        f.debug_struct("EncryptionContext").finish()
     }
 }
 
 impl Clone for EncryptionContext {
     fn clone(&self) -> Self {
-        EncryptionContext::new(self.key.clone(), self.block_size)
+        EncryptionContext::new(self.key.clone(), self.legacy_xts)
     }
 
     fn clone_from(&mut self, source: &Self) {
-        *self = EncryptionContext::new(source.key.clone(), source.block_size);
+        *self = EncryptionContext::new(source.key.clone(), source.legacy_xts);
     }
 }
 
+/*
+ * Where a block is, as the associated data for its encryption.
+ */
+fn block_aad(eid: u64, offset: u64) -> [u8; 16] {
+    let mut aad = [0u8; 16];
+    aad[..8].copy_from_slice(&eid.to_le_bytes());
+    aad[8..].copy_from_slice(&offset.to_le_bytes());
+    aad
+}
+
 impl EncryptionContext {
-    pub fn new(key: Vec<u8>, block_size: usize) -> EncryptionContext {
+    /*
+     * With legacy_xts, blocks with no nonce or tag are read as they were
+     * before blocks had a version, see CrucibleOpts.
+     */
+    pub fn new(key: Vec<u8>, legacy_xts: bool) -> EncryptionContext {
         assert!(key.len() == 32);
 
+        let cipher = Aes256GcmSiv::new(Key::from_slice(&key));
+
         let cipher_1 = Aes128::new(GenericArray::from_slice(&key[..16]));
         let cipher_2 = Aes128::new(GenericArray::from_slice(&key[16..]));
-
         let xts = Xts128::<Aes128>::new(cipher_1, cipher_2);
 
         EncryptionContext {
+            cipher,
             xts,
             key,
-            block_size,
+            legacy_xts,
         }
     }
 
@@ -1823,26 +3125,114 @@ impl EncryptionContext {
         &self.key
     }
 
-    pub fn block_size(&self) -> usize {
-        self.block_size
-    }
+    /*
+     * Encrypt the block at offset in extent eid, and return the nonce
+     * (with the version in front) and tag to store with it.
+     */
+    pub fn encrypt_in_place(
+        &self,
+        data: &mut [u8],
+        eid: u64,
+        offset: u64,
+    ) -> (Vec<u8>, Vec<u8>) {
+        let mut nonce = vec![0u8; NONCE_SIZE + 1];
+        nonce[0] = ENCRYPTION_VERSION;
+        thread_rng().fill_bytes(&mut nonce[1..]);
 
-    pub fn encrypt_in_place(&self, data: &mut [u8], sector_index: u128) {
-        self.xts.encrypt_area(
-            data,
-            self.block_size,
-            sector_index,
-            get_tweak_default,
-        );
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(
+                Nonce::from_slice(&nonce[1..]),
+                &block_aad(eid, offset),
+                data,
+            )
+            .expect("block too large to encrypt");
+
+        (nonce, tag.to_vec())
     }
 
-    pub fn decrypt_in_place(&self, data: &mut [u8], sector_index: u128) {
-        self.xts.decrypt_area(
-            data,
-            self.block_size,
-            sector_index,
-            get_tweak_default,
-        );
+    /*
+     * Check and decrypt the block at offset in extent eid.  On a volume
+     * with legacy_xts, a block that has never been written has no nonce
+     * or tag, and reads as zeros, and a block with data but no nonce or
+     * tag was written before blocks were versioned, and is decrypted as
+     * it was written.  Anywhere else, a block with no nonce or tag could
+     * be one a downstairs has changed, and is an error.
+     */
+    pub fn decrypt_in_place(
+        &self,
+        data: &mut [u8],
+        eid: u64,
+        offset: u64,
+        nonce: Option<&[u8]>,
+        tag: Option<&[u8]>,
+    ) -> Result<(), CrucibleError> {
+        let (nonce, tag) = match (nonce, tag) {
+            (Some(nonce), Some(tag))
+                if nonce.len() == NONCE_SIZE + 1
+                    && nonce[0] == ENCRYPTION_VERSION
+                    && tag.len() == TAG_SIZE =>
+            {
+                (&nonce[1..], tag)
+            }
+            (None, None) if !self.legacy_xts => {
+                crucible_bail!(
+                    DecryptionError,
+                    "extent {} block {} has no nonce or tag",
+                    eid,
+                    offset
+                );
+            }
+            (None, None) if data.iter().all(|b| *b == 0) => return Ok(()),
+            (None, None) if data.len() % 512 == 0 => {
+                self.xts.decrypt_area(
+                    data,
+                    512,
+                    offset as u128,
+                    get_tweak_default,
+                );
+                return Ok(());
+            }
+            (Some(nonce), Some(_))
+                if nonce.first() != Some(&ENCRYPTION_VERSION) =>
+            {
+                crucible_bail!(
+                    DecryptionError,
+                    "extent {} block {} has unknown encryption version {:?}",
+                    eid,
+                    offset,
+                    nonce.first()
+                );
+            }
+            _ => {
+                crucible_bail!(
+                    DecryptionError,
+                    "extent {} block {} has a bad nonce or tag",
+                    eid,
+                    offset
+                );
+            }
+        };
+
+        if self
+            .cipher
+            .decrypt_in_place_detached(
+                Nonce::from_slice(nonce),
+                &block_aad(eid, offset),
+                data,
+                Tag::from_slice(tag),
+            )
+            .is_err()
+        {
+            crucible_bail!(
+                DecryptionError,
+                "extent {} block {} failed authentication",
+                eid,
+                offset
+            );
+        }
+
+        Ok(())
     }
 }
 
@@ -1941,6 +3331,13 @@ pub struct Upstairs {
      * queue was not a flush.
      */
     need_flush: Mutex<bool>,
+
+    /*
+     * This upstairs never sends a write or a flush to the downstairs.
+     * Guest writes fail, and guest flushes are acked without going
+     * anywhere.
+     */
+    read_only: bool,
 }
 
 impl Upstairs {
@@ -1949,6 +3346,12 @@ impl Upstairs {
             target: vec![],
             lossy: false,
             key: None,
+            legacy_xts: false,
+            control: None,
+            policy: None,
+            read_only: false,
+            job_timeout: None,
+            uuid_mismatch: None,
         };
         Self::new(
             &opts,
@@ -1963,38 +3366,32 @@ impl Upstairs {
         guest: Arc<Guest>,
     ) -> Arc<Upstairs> {
         /*
-         * XXX Make sure we have three and only three downstairs
+         * There must be one target for each replica.
          */
+        let policy = opt.policy();
         #[cfg(not(test))]
-        assert_eq!(opt.target.len(), 3);
+        assert_eq!(opt.target.len(), policy.replicas());
+        let mut downstairs = Downstairs::default();
+        downstairs.set_policy(policy);
+        downstairs.job_timeout = opt.job_timeout();
+        downstairs.uuid_mismatch = opt.uuid_mismatch();
 
         // create an encryption context if a key is supplied.
-        let encryption_context = opt.key_bytes().map(|key| {
-            Arc::new(EncryptionContext::new(
-                key,
-                /*
-                 * XXX: It would be good to do BlockOp::QueryBlockSize here,
-                 * but this creates a deadlock. Upstairs::new runs before
-                 * up_ds_listen in up_main, and up_ds_listen needs to run
-                 * to answer BlockOp::QueryBlockSize.
-                 *
-                 * At this point ddef is the default, the downstairs haven't
-                 * reported in.
-                 */
-                512,
-            ))
-        });
+        let encryption_context = opt
+            .key_bytes()
+            .map(|key| Arc::new(EncryptionContext::new(key, opt.legacy_xts)));
 
         Arc::new(Upstairs {
             active: Mutex::new(Active::default()),
             uuid: Uuid::new_v4(),      // XXX get from Nexus?
             generation: Mutex::new(0), // XXX Also get from Nexus?
             guest,
-            downstairs: Mutex::new(Downstairs::default()),
+            downstairs: Mutex::new(downstairs),
             flush_info: Mutex::new(FlushInfo::new()),
             ddef: Mutex::new(def),
             encryption_context,
             need_flush: Mutex::new(false),
+            read_only: opt.read_only,
         })
     }
 
@@ -2034,6 +3431,54 @@ impl Upstairs {
         self.active.lock().unwrap().active
     }
 
+    /*
+     * A downstairs has told us an upstairs with a higher generation has
+     * taken over.  There is no flushing what we have, the downstairs
+     * belong to the new upstairs now and would refuse it.  Go inactive
+     * and fail every guest IO that has not been acked, so the guest
+     * hears about it now instead of waiting on IO that will never finish.
+     */
+    fn ds_superseded(&self, client_id: u8, new_uuid: Uuid, new_gen: u64) {
+        println!(
+            "[{}] {} gen {} replaced by {} gen {}",
+            client_id,
+            self.uuid,
+            self.get_generation(),
+            new_uuid,
+            new_gen
+        );
+        self.set_inactive();
+        self.fail_unacked();
+    }
+
+    /*
+     * Fail every guest IO that has not been acked, because the
+     * downstairs won't be finishing it for us.
+     */
+    fn fail_unacked(&self) {
+        let mut gw = self.guest.guest_work.lock().unwrap();
+        let mut ds = self.downstairs.lock().unwrap();
+        let mut pending = ds
+            .active
+            .values()
+            .filter(|job| job.ack_status != AckStatus::Acked)
+            .map(|job| job.ds_id)
+            .collect::<Vec<u64>>();
+        pending.sort_unstable();
+
+        for ds_id in pending {
+            let job = ds.active.get_mut(&ds_id).unwrap();
+            job.ack_status = AckStatus::Acked;
+            let gw_id = job.guest_id;
+            gw.ds_complete(
+                gw_id,
+                ds_id,
+                None,
+                Err(CrucibleError::UpstairsInactive),
+            );
+        }
+    }
+
     /*
      * The guest has requested this upstairs go active.
      */
@@ -2090,7 +3535,7 @@ impl Upstairs {
         *flush = true;
     }
     fn flush_needed(&self) -> bool {
-        if !self.is_active() {
+        if !self.is_active() || self.read_only {
             return false;
         }
         *self.need_flush.lock().unwrap()
@@ -2100,17 +3545,46 @@ impl Upstairs {
     pub fn submit_flush(
         &self,
         sender: Option<std_mpsc::Sender<Result<(), CrucibleError>>>,
+        permit: Option<QueuePermit>,
+        snapshot_details: Option<SnapshotDetails>,
     ) -> Result<(), CrucibleError> {
         if !self.is_active() {
             crucible_bail!(UpstairsInactive);
         }
 
+        /*
+         * Nothing has been written, so there is nothing to flush.  A
+         * reader can't ask the downstairs to take a snapshot, as the
+         * flush that would carry the request is never sent.
+         */
+        if self.read_only && snapshot_details.is_some() {
+            crucible_bail!(SnapshotFailed, "read only upstairs");
+        }
+        if self.read_only {
+            if let Some(sender) = sender {
+                let _ = sender.send(Ok(()));
+            }
+            return Ok(());
+        }
+
         /*
          * Lock first the guest_work struct where this new job will go,
          * then lock the downstairs struct. Once we have both we can proceed
          * to build our flush command.
          */
         let mut gw = self.guest.guest_work.lock().unwrap();
+
+        /*
+         * A write we acked early has failed, so this guest flush can't
+         * promise that everything before it is on disk.  Early acked
+         * writes that are still going are waited on below.
+         */
+        if sender.is_some() {
+            if let Some(e) = gw.write_back_error.take() {
+                return Err(e);
+            }
+        }
+
         let mut downstairs = self.downstairs.lock().unwrap();
         self.set_flush_clear();
 
@@ -2148,13 +3622,23 @@ impl Upstairs {
             next_flush,
             gw_id,
             self.get_generation(),
+            snapshot_details,
         );
 
         let mut sub = HashMap::new();
         sub.insert(next_id, 0);
 
-        let new_gtos =
-            GtoS::new(sub, Vec::new(), None, HashMap::new(), sender, None);
+        let mut new_gtos =
+            GtoS::new(sub, Vec::new(), None, HashMap::new(), sender, permit);
+        new_gtos.kind = GuestIOKind::Flush;
+
+        /*
+         * A guest flush covers the writes we acked early, so it is not
+         * done until they are, and it fails if any of them do.
+         */
+        if new_gtos.sender.is_some() {
+            new_gtos.write_back_wait = gw.dirty_writes.clone();
+        }
         gw.active.insert(gw_id, new_gtos);
         cdt::gw_flush_start!(|| (gw_id));
 
@@ -2175,10 +3659,14 @@ impl Upstairs {
         offset: Block,
         data: Bytes,
         sender: std_mpsc::Sender<Result<(), CrucibleError>>,
+        permit: Option<QueuePermit>,
     ) -> Result<(), CrucibleError> {
         if !self.is_active() {
             crucible_bail!(UpstairsInactive);
         }
+        if self.read_only {
+            crucible_bail!(ModifyingReadOnlyRegion);
+        }
 
         /*
          * Get the next ID for the guest work struct we will make at the
@@ -2195,6 +3683,7 @@ impl Upstairs {
          * and length may span two extents, and eventually XXX, two regions.
          */
         let ddef = self.ddef.lock().unwrap();
+        ddef.validate_io(offset, data.len())?;
         let nwo = extent_from_offset(
             *ddef,
             offset,
@@ -2231,23 +3720,29 @@ impl Upstairs {
             let byte_len: usize =
                 num_blocks.value as usize * ddef.block_size() as usize;
 
-            let sub_data = if let Some(context) = &self.encryption_context {
-                // Encrypt here
+            /*
+             * With encryption on, each write here is a single block, see
+             * extent_from_offset.
+             */
+            let (sub_data, nonce, tag) = if let Some(context) =
+                &self.encryption_context
+            {
                 let mut mut_data =
                     data.slice(cur_offset..(cur_offset + byte_len)).to_vec();
-                context.encrypt_in_place(&mut mut_data[..], bo.value as u128);
-                Bytes::copy_from_slice(&mut_data)
+                let (nonce, tag) =
+                    context.encrypt_in_place(&mut mut_data[..], eid, bo.value);
+                (Bytes::from(mut_data), Some(nonce), Some(tag))
             } else {
                 // Unencrypted
-                data.slice(cur_offset..(cur_offset + byte_len))
+                (data.slice(cur_offset..(cur_offset + byte_len)), None, None)
             };
 
             writes.push(crucible_protocol::Write {
                 eid,
                 offset: bo,
                 data: sub_data,
-                nonce: None,
-                tag: None,
+                nonce,
+                tag,
             });
 
             cur_offset += byte_len;
@@ -2258,17 +3753,29 @@ impl Upstairs {
         sub.insert(next_id, 0); // XXX does value here matter?
         new_ds_work.push(wr);
 
+        /*
+         * With write-back on, tell the guest this write is done now if
+         * there is room for more dirty data.  Later reads depend on this
+         * write, so they still see it.  The next guest flush waits for
+         * it, and returns the error if it fails.
+         */
+        let (sender, dirty) = if gw.write_back_room(data.len()) {
+            let _ = sender.send(Ok(()));
+            gw.dirty_bytes += data.len();
+            (None, data.len())
+        } else {
+            (Some(sender), 0)
+        };
+
         /*
          * New work created, add to the guest_work HM
          */
-        let new_gtos = GtoS::new(
-            sub,
-            Vec::new(),
-            None,
-            HashMap::new(),
-            Some(sender),
-            None,
-        );
+        let mut new_gtos =
+            GtoS::new(sub, Vec::new(), None, HashMap::new(), sender, permit);
+        new_gtos.dirty = dirty;
+        if dirty > 0 {
+            gw.dirty_writes.insert(gw_id);
+        }
         {
             gw.active.insert(gw_id, new_gtos);
         }
@@ -2293,6 +3800,7 @@ impl Upstairs {
         offset: Block,
         data: Buffer,
         sender: std_mpsc::Sender<Result<(), CrucibleError>>,
+        permit: Option<QueuePermit>,
     ) -> Result<(), CrucibleError> {
         if !self.is_active() {
             crucible_bail!(UpstairsInactive);
@@ -2312,6 +3820,7 @@ impl Upstairs {
          * and length may span many extents, and eventually, TODO, regions.
          */
         let ddef = self.ddef.lock().unwrap();
+        ddef.validate_io(offset, data.len())?;
         let nwo = extent_from_offset(
             *ddef,
             offset,
@@ -2368,7 +3877,7 @@ impl Upstairs {
             Some(data),
             HashMap::new(),
             Some(sender),
-            self.encryption_context.clone(),
+            permit,
         );
         {
             gw.active.insert(gw_id, new_gtos);
@@ -2444,52 +3953,382 @@ impl Upstairs {
             new_state
         );
 
-        let old_state = ds.ds_state[client_id as usize];
+        let old_state = ds.ds_state[client_id as usize];
+
+        /*
+         * Check that this is a valid transition
+         */
+        match new_state {
+            DsState::WaitActive => {
+                if old_state == DsState::Offline {
+                    if self.is_active() {
+                        panic!(
+                            "[{}] {} Bad state change when active {:?} -> {:?}",
+                            client_id, self.uuid, old_state, new_state,
+                        );
+                    }
+                } else if old_state != DsState::New
+                    && old_state != DsState::Failed
+                    && old_state != DsState::Disconnected
+                {
+                    panic!(
+                        "[{}] {} {} Negotiation failed, {:?} -> {:?}",
+                        client_id,
+                        self.uuid,
+                        self.is_active(),
+                        old_state,
+                        new_state,
+                    );
+                }
+            }
+            DsState::WaitQuorum => {
+                assert_eq!(old_state, DsState::WaitActive);
+            }
+            DsState::Replay => {
+                assert_eq!(old_state, DsState::Offline);
+            }
+            _ => (),
+        }
+
+        if old_state != new_state {
+            println!(
+                "[{}] Transition from {:?} to {:?}",
+                client_id, ds.ds_state[client_id as usize], new_state,
+            );
+            ds.ds_state[client_id as usize] = new_state;
+        } else {
+            panic!("[{}] transition to same state: {:?}", client_id, new_state);
+        }
+    }
+
+    /*
+     * Take an Active downstairs out of service.  Everything it has not
+     * finished is skipped, and it is marked Failed.  Its connection is
+     * dropped the next time it looks for work, and when it reconnects it
+     * comes back through live repair.
+     */
+    fn ds_fault(&self, client_id: u8) -> Result<()> {
+        if client_id >= 3 {
+            bail!("invalid client {}", client_id);
+        }
+        if !self.is_active() {
+            bail!("upstairs is not active");
+        }
+
+        let mut ds = self.downstairs.lock().unwrap();
+        if ds.live_repair.is_some() {
+            bail!("live repair already in progress");
+        }
+        let active = ds
+            .ds_state
+            .iter()
+            .filter(|state| **state == DsState::Active)
+            .count();
+        if ds.ds_state[client_id as usize] != DsState::Active
+            || active < ds.policy.replicas()
+            || active < 2
+        {
+            bail!(
+                "[{}] can't fault, downstairs state is {:?}",
+                client_id,
+                ds.ds_state
+            );
+        }
+
+        let skipped = ds.skip_unfinished(client_id);
+        println!(
+            "[{}] Fault, {} jobs skipped, transition from Active to Failed",
+            client_id,
+            skipped.len()
+        );
+        ds.ds_state[client_id as usize] = DsState::Failed;
+
+        Ok(())
+    }
+
+    /*
+     * Called from the client task once every job timeout.  If this
+     * downstairs has kept jobs waiting past their deadline too many times
+     * in a row, fault it, and return true so the caller drops the
+     * connection and lets the guest know there is work to ack.
+     */
+    fn ds_deadline_check(&self, client_id: u8) -> bool {
+        {
+            let mut ds = self.downstairs.lock().unwrap();
+            if ds.ds_state[client_id as usize] != DsState::Active {
+                ds.ds_deadline_misses[client_id as usize] = 0;
+                return false;
+            }
+            if !ds.deadline_missed(client_id) {
+                return false;
+            }
+        }
+
+        if let Err(e) = self.ds_fault(client_id) {
+            println!("[{}] Missed deadlines, but {}", client_id, e);
+            return false;
+        }
+
+        let mut ds = self.downstairs.lock().unwrap();
+        ds.ds_deadline_misses[client_id as usize] = 0;
+        ds.finish_skipped();
+        true
+    }
+
+    fn job_timeout(&self) -> Duration {
+        self.downstairs.lock().unwrap().job_timeout.timeout()
+    }
+
+    /*
+     * Record a ping to this downstairs, and return the sequence number
+     * and timestamp to send with it.
+     */
+    fn ds_ping(&self, client_id: u8) -> (u64, u64) {
+        let mut ds = self.downstairs.lock().unwrap();
+        ds.ds_health[client_id as usize].ping(Instant::now())
+    }
+
+    fn ds_pong(&self, client_id: u8, seq: u64, ts: u64) {
+        let mut ds = self.downstairs.lock().unwrap();
+        ds.ds_health[client_id as usize].pong(seq, ts, Instant::now());
+    }
+
+    /*
+     * Called from the client task each time it pings.  An Active
+     * downstairs that has let too many pings in a row go unanswered is
+     * faulted the same way as one that misses job deadlines, and we
+     * return true so the caller drops the connection.
+     */
+    fn ds_ping_check(&self, client_id: u8) -> bool {
+        {
+            let ds = self.downstairs.lock().unwrap();
+            let missed = ds.ds_health[client_id as usize].consecutive_missed();
+            if ds.ds_state[client_id as usize] != DsState::Active
+                || missed < MAX_MISSED_PINGS
+            {
+                return false;
+            }
+        }
+
+        if let Err(e) = self.ds_fault(client_id) {
+            println!("[{}] Missed pings, but {}", client_id, e);
+            return false;
+        }
+
+        self.downstairs.lock().unwrap().finish_skipped();
+        true
+    }
+
+    /*
+     * A downstairs answered with UuidMismatch.  Do what the policy says,
+     * and return the policy that was followed, as the client task drops
+     * the connection either way.  If it was Fault, there may now be work
+     * ready to ack.
+     */
+    fn ds_uuid_mismatch(
+        &self,
+        client_id: u8,
+        expected: Uuid,
+    ) -> UuidMismatchPolicy {
+        let policy = {
+            let mut ds = self.downstairs.lock().unwrap();
+            ds.ds_uuid_mismatches[client_id as usize] += 1;
+            ds.uuid_mismatch
+        };
+        println!(
+            "[{}] {} received UuidMismatch, expecting {:?}, policy {:?}",
+            client_id, self.uuid, expected, policy
+        );
+
+        match policy {
+            UuidMismatchPolicy::Fault => {
+                if let Err(e) = self.ds_fault(client_id) {
+                    println!("[{}] Can't fault, renegotiate: {}", client_id, e);
+                    return UuidMismatchPolicy::Renegotiate;
+                }
+                self.downstairs.lock().unwrap().finish_skipped();
+            }
+            UuidMismatchPolicy::Renegotiate => {}
+            UuidMismatchPolicy::Reactivate => self.ds_reactivate(),
+        }
+
+        policy
+    }
+
+    /*
+     * Start activation over.  We go inactive with the request to go
+     * active still set, fail what the guest is waiting on, and send
+     * every downstairs back to New.  Each client task drops its
+     * connection when it sees that, and when they have all connected
+     * again they are reconciled and we go active, the same as the first
+     * time.
+     */
+    fn ds_reactivate(&self) {
+        {
+            let mut active = self.active.lock().unwrap();
+            active.active = false;
+            active.active_request = true;
+        }
+        println!("{} reactivating", self.uuid);
+        self.fail_unacked();
+
+        let mut ds = self.downstairs.lock().unwrap();
+        ds.active.clear();
+        ds.live_repair = None;
+        ds.region_metadata.clear();
+        ds.ds_skipped_jobs.iter_mut().for_each(|s| s.clear());
+        ds.ds_sent.iter_mut().for_each(|s| s.clear());
+        ds.ds_state.iter_mut().for_each(|ds_state| {
+            if *ds_state != DsState::Disabled {
+                *ds_state = DsState::New;
+            }
+        });
+    }
+
+    /*
+     * We went inactive to start activation over, and are waiting for the
+     * downstairs to connect again.
+     */
+    fn is_reactivating(&self) -> bool {
+        let active = self.active.lock().unwrap();
+        !active.active && active.active_request
+    }
+
+    /*
+     * A downstairs that was not Offline has connected while we are active.
+     * We can't trust anything it has, so it joins under live repair.  All
+     * the work it has not finished is skipped for it, and the returned ID
+     * is sent as its last flush so it treats every job up to this point
+     * as already done.
+     */
+    fn ds_live_repair_join(&self, client_id: u8) -> Result<u64> {
+        let mut ds = self.downstairs.lock().unwrap();
+        if let Some(lr) = &ds.live_repair {
+            bail!(
+                "[{}] live repair of {} already in progress",
+                client_id,
+                lr.dest
+            );
+        }
+
+        for job in ds.active.values_mut() {
+            let state = job.state.get_mut(&client_id).unwrap();
+            if *state == IOState::New || *state == IOState::InProgress {
+                *state = IOState::Skipped;
+            }
+        }
+        ds.downstairs_errors.remove(&client_id);
+        ds.ds_skipped_jobs[client_id as usize].clear();
+
+        println!(
+            "[{}] Transition from {:?} to LiveRepair",
+            client_id, ds.ds_state[client_id as usize]
+        );
+        ds.ds_state[client_id as usize] = DsState::LiveRepair;
+        ds.live_repair = Some(LiveRepair {
+            dest: client_id,
+            next_eid: 0,
+            pending: None,
+            failed: false,
+        });
+
+        Ok(ds.next_id - 1)
+    }
 
-        /*
-         * Check that this is a valid transition
-         */
-        match new_state {
-            DsState::WaitActive => {
-                if old_state == DsState::Offline {
-                    if self.is_active() {
-                        panic!(
-                            "[{}] {} Bad state change when active {:?} -> {:?}",
-                            client_id, self.uuid, old_state, new_state,
-                        );
+    /*
+     * Move a live repair forward.  If the extent being repaired is done,
+     * start on the next one.  When all extents are repaired, the
+     * downstairs goes Active.
+     *
+     * Returns true if there may be new work for the downstairs.
+     */
+    fn live_repair_step(&self) -> bool {
+        let mut ds = self.downstairs.lock().unwrap();
+        let mut lr = match ds.live_repair {
+            Some(lr) => lr,
+            None => return false,
+        };
+        let dest = lr.dest as usize;
+
+        if ds.ds_state[dest] != DsState::LiveRepair || lr.failed {
+            println!(
+                "[{}] live repair stopped at extent {}",
+                lr.dest, lr.next_eid
+            );
+            if let Some((_, write_id)) = lr.pending {
+                if let Some(job) = ds.active.get_mut(&write_id) {
+                    if job.state.get(&lr.dest) == Some(&IOState::New) {
+                        job.state.insert(lr.dest, IOState::Skipped);
                     }
-                } else if old_state != DsState::New
-                    && old_state != DsState::Failed
-                    && old_state != DsState::Disconnected
-                {
-                    panic!(
-                        "[{}] {} {} Negotiation failed, {:?} -> {:?}",
-                        client_id,
-                        self.uuid,
-                        self.is_active(),
-                        old_state,
-                        new_state,
-                    );
                 }
             }
-            DsState::WaitQuorum => {
-                assert_eq!(old_state, DsState::WaitActive);
+            if ds.ds_state[dest] == DsState::LiveRepair {
+                ds.ds_state[dest] = DsState::Failed;
             }
-            DsState::Replay => {
-                assert_eq!(old_state, DsState::Offline);
+            ds.live_repair = None;
+            return false;
+        }
+
+        if let Some((_, write_id)) = lr.pending {
+            /*
+             * If the write is no longer on the active list, it was retired
+             * after it finished everywhere.
+             */
+            match ds.active.get(&write_id).map(|j| j.state.get(&lr.dest)) {
+                None | Some(Some(IOState::Done)) => {
+                    lr.pending = None;
+                    lr.next_eid += 1;
+                }
+                Some(Some(IOState::Error(_))) => {
+                    lr.failed = true;
+                    ds.live_repair = Some(lr);
+                    return false;
+                }
+                _ => {
+                    /*
+                     * Still waiting.  The read data may have arrived, so
+                     * let the downstairs tasks look for work.
+                     */
+                    return true;
+                }
             }
-            _ => (),
         }
 
-        if old_state != new_state {
-            println!(
-                "[{}] Transition from {:?} to {:?}",
-                client_id, ds.ds_state[client_id as usize], new_state,
-            );
-            ds.ds_state[client_id as usize] = new_state;
-        } else {
-            panic!("[{}] transition to same state: {:?}", client_id, new_state);
+        if lr.next_eid == self.ddef.lock().unwrap().extent_count() as u64 {
+            println!("[{}] live repair done, Transition to Active", lr.dest);
+            ds.ds_state[dest] = DsState::Active;
+            ds.live_repair = None;
+            return false;
         }
+
+        /*
+         * Copy from the first healthy downstairs.
+         */
+        let source = match (0..3).find(|cid| {
+            *cid != lr.dest && ds.ds_state[*cid as usize] == DsState::Active
+        }) {
+            Some(source) => source,
+            None => {
+                println!("[{}] live repair has no source", lr.dest);
+                ds.live_repair = Some(lr);
+                return false;
+            }
+        };
+
+        lr.pending = Some(ds.enqueue_repair(lr.next_eid, source, lr.dest));
+        ds.live_repair = Some(lr);
+
+        true
+    }
+
+    fn repair_complete(
+        &self,
+        ds_id: u64,
+        client_id: u8,
+        result: Result<Option<ExtentData>, CrucibleError>,
+    ) -> Result<()> {
+        let mut ds = self.downstairs.lock().unwrap();
+        ds.repair_complete(ds_id, client_id, result)
     }
 
     fn ds_state(&self, client_id: u8) -> DsState {
@@ -2502,12 +4341,16 @@ impl Upstairs {
      * different downstairs.  If all are in the proper state, then
      * move forward and start the process.
      *
-     * Return false if we are not ready, or if things failed.
-     * If we failed, then we will update the DsState for what failed.
+     * Return false if we are not ready, or if reconciliation is still
+     * running.  Return true once all downstairs match and we are active.
      */
     fn ds_reconciliation(&self) -> bool {
         let mut ds = self.downstairs.lock().unwrap();
 
+        if ds.ds_state.iter().any(|dst| *dst == DsState::Verifying) {
+            return self.ds_reconciliation_check(&mut ds);
+        }
+
         /*
          * Make sure all downstairs are in the correct state before we
          * proceed.
@@ -2515,18 +4358,15 @@ impl Upstairs {
         let not_ready = ds
             .ds_state
             .iter()
-            .filter(|dst| **dst != DsState::WaitQuorum)
+            .filter(|dst| {
+                **dst != DsState::WaitQuorum && **dst != DsState::Disabled
+            })
             .count();
         if not_ready > 0 {
             println!("Waiting for {} more clients to be ready", not_ready);
             return false;
         }
 
-        /*
-         * This is where the actual code would take place.
-         */
-        println!("Fake reconciliation has finished!!!");
-
         /*
          * XXX TODO:
          * To make things work on a disconnected then reconnected
@@ -2536,14 +4376,122 @@ impl Upstairs {
          */
         assert_eq!(ds.active.len(), 0);
 
+        let list = ds.reconcile_list();
+        if list.is_empty() {
+            println!("All extents match, no reconciliation needed");
+            self.ds_reconciliation_done(&mut ds);
+            return true;
+        }
+
+        /*
+         * We can't repair anything, and reads could return different data
+         * depending on which downstairs answers first.  A read-write
+         * upstairs has to attach and reconcile these first.
+         */
+        if self.read_only {
+            println!(
+                "Read only upstairs found {} extents that don't match, \
+                not going active",
+                list.len()
+            );
+            return false;
+        }
+
+        /*
+         * Copy the best version of each extent that doesn't match to the
+         * downstairs that need it.  The repair jobs depend on each other,
+         * so they run one at a time.
+         */
+        println!("Reconciliation needs to repair {} extents", list.len());
+        for (eid, source, dests) in list {
+            for dest in dests {
+                println!("  extent {} from [{}] to [{}]", eid, source, dest);
+                ds.enqueue_repair(eid, source, dest);
+            }
+        }
+        ds.ds_state.iter_mut().for_each(|ds_state| {
+            if *ds_state != DsState::Disabled {
+                println!("Transition from {:?} to Verifying", *ds_state);
+                *ds_state = DsState::Verifying;
+            }
+        });
+
+        false
+    }
+
+    /*
+     * Reconciliation is running, see if it has finished or failed.  If a
+     * downstairs went away or a repair failed, the repair jobs are thrown
+     * away and the downstairs that are still here go back to WaitQuorum.
+     * Reconciliation starts over when all replicas are ready again.
+     */
+    fn ds_reconciliation_check(&self, ds: &mut MutexGuard<Downstairs>) -> bool {
+        let missing = ds
+            .ds_state
+            .iter()
+            .any(|dst| *dst != DsState::Verifying && *dst != DsState::Disabled);
+        let failed = ds.active.values().any(|job| {
+            job.state
+                .values()
+                .any(|state| matches!(state, IOState::Error(_)))
+        });
+
+        if missing || failed {
+            println!(
+                "Reconciliation stopped, missing:{} failed:{}",
+                missing, failed
+            );
+            ds.active.clear();
+            ds.ds_state.iter_mut().for_each(|ds_state| {
+                if *ds_state == DsState::Verifying {
+                    *ds_state = DsState::WaitQuorum;
+                }
+            });
+            return false;
+        }
+
+        let done = ds.active.values().all(|job| {
+            job.state
+                .values()
+                .all(|state| matches!(state, IOState::Done | IOState::Skipped))
+        });
+        if !done {
+            return false;
+        }
+
+        println!("Reconciliation has finished");
+        ds.active.clear();
+        self.ds_reconciliation_done(ds);
+        true
+    }
+
+    /*
+     * All the downstairs match, move them all to Active and start
+     * accepting IO.
+     */
+    fn ds_reconciliation_done(&self, ds: &mut MutexGuard<Downstairs>) {
         /*
-         * Now that we have completed reconciliation, we move all
-         * the downstairs to the next state.
+         * The next flush must be higher than any flush number already on
+         * any downstairs.
          */
+        let max_flush = ds
+            .region_metadata
+            .values()
+            .flat_map(|m| m.flush_numbers.iter())
+            .max()
+            .cloned()
+            .unwrap_or(0);
+        let mut fi = self.flush_info.lock().unwrap();
+        if fi.next_flush <= max_flush {
+            fi.next_flush = max_flush + 1;
+        }
+        drop(fi);
+
         ds.ds_state.iter_mut().for_each(|ds_state| {
-            println!("Transition from {:?} to Active", *ds_state);
-            assert_eq!(*ds_state, DsState::WaitQuorum);
-            *ds_state = DsState::Active;
+            if *ds_state != DsState::Disabled {
+                println!("Transition from {:?} to Active", *ds_state);
+                *ds_state = DsState::Active;
+            }
         });
 
         /*
@@ -2551,7 +4499,6 @@ impl Upstairs {
          * allow incoming IO
          */
         self.set_active();
-        true
     }
 
     /**
@@ -2625,6 +4572,7 @@ impl Upstairs {
         client_ddef: RegionDefinition,
     ) -> Result<()> {
         println!("[{}] Got region def {:?}", client_id, client_ddef);
+        client_ddef.validate()?;
 
         /*
          * XXX Eventually we will be provided UUIDs when the upstairs
@@ -2633,8 +4581,23 @@ impl Upstairs {
          * For now, we take whatever connects to us first.
          */
         let mut ds = self.downstairs.lock().unwrap();
-        if let Some(uuid) = ds.ds_uuid.get(&client_id) {
-            if *uuid != client_ddef.uuid() {
+        if let Some(uuid) = ds.ds_uuid.get(&client_id).cloned() {
+            if uuid != client_ddef.uuid()
+                && self.is_active()
+                && ds.ds_state[client_id as usize] != DsState::Offline
+            {
+                /*
+                 * A replacement downstairs, it will get everything from
+                 * live repair.
+                 */
+                println!(
+                    "Replacing client:{} uuid:{} with {}",
+                    client_id,
+                    uuid,
+                    client_ddef.uuid()
+                );
+                ds.ds_uuid.insert(client_id, client_ddef.uuid());
+            } else if uuid != client_ddef.uuid() {
                 panic!(
                     "New client:{} uuid:{}  does not match existing {}",
                     client_id,
@@ -2695,6 +4658,27 @@ impl Upstairs {
         client_id: u8,
         read_data: Result<Vec<ReadResponse>, CrucibleError>,
     ) -> Result<bool> {
+        /*
+         * Check and decrypt read data as it arrives, before taking the
+         * lock.  Data that fails is an error from this downstairs only, so
+         * the read is answered by another one if it can be.
+         */
+        let read_data = match (read_data, &self.encryption_context) {
+            (Ok(mut responses), Some(context)) => {
+                match decrypt_responses(context, &mut responses) {
+                    Ok(()) => Ok(responses),
+                    Err(e) => {
+                        println!(
+                            "[{}] {} job {} read failed to decrypt: {}",
+                            client_id, self.uuid, ds_id, e
+                        );
+                        Err(e)
+                    }
+                }
+            }
+            (read_data, _) => read_data,
+        };
+
         let mut work = self.downstairs.lock().unwrap();
 
         if !self.is_active() {
@@ -2714,7 +4698,7 @@ impl Upstairs {
          * we already received, because it may never come back.
          */
         let ds_state = work.ds_state[client_id as usize];
-        if ds_state != DsState::Active {
+        if ds_state != DsState::Active && ds_state != DsState::LiveRepair {
             println!(
                 "[{}] {} WARNING finish job {} when downstairs state:{:?}",
                 client_id, self.uuid, ds_id, ds_state
@@ -2749,7 +4733,9 @@ impl Upstairs {
                     } | IOop::Flush {
                         dependencies: _,
                         flush_number: _,
-                        gen_number: _
+                        gen_number: _,
+                        snapshot_details: _,
+                        extent_limit: _
                     }
                 ) {
                     self.ds_transition(client_id, DsState::Failed);
@@ -2761,6 +4747,25 @@ impl Upstairs {
     }
 }
 
+/*
+ * Check and decrypt, in place, the blocks a downstairs returned for a read.
+ */
+fn decrypt_responses(
+    context: &EncryptionContext,
+    responses: &mut [ReadResponse],
+) -> Result<(), CrucibleError> {
+    for response in responses.iter_mut() {
+        context.decrypt_in_place(
+            &mut response.data[..],
+            response.eid,
+            response.offset.value,
+            response.nonce.as_deref(),
+            response.tag.as_deref(),
+        )?;
+    }
+    Ok(())
+}
+
 #[derive(Debug)]
 struct FlushInfo {
     flush_numbers: Vec<u64>,
@@ -2796,7 +4801,7 @@ impl FlushInfo {
  * XXX This very much still under development. Most of these are place
  * holders and the final set of states will change.
  */
-#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, JsonSchema)]
 enum DsState {
     /*
      * New connection
@@ -2824,9 +4829,15 @@ enum DsState {
      */
     Disconnected,
     /*
-     * Comparing downstairs for consistency.
+     * Reconciliation is copying extents between downstairs to make them
+     * consistent.
+     */
+    Verifying,
+    /*
+     * This client is not one of the replicas in the replication policy,
+     * and will never connect.
      */
-    _Verifying,
+    Disabled,
     /*
      * Failed when attempting to make consistent.
      */
@@ -2856,6 +4867,12 @@ enum DsState {
      * sending it all the I/O it missed when it was unavailable.
      */
     Replay,
+    /*
+     * This downstairs came back after failing (or is a replacement) while
+     * the upstairs was active.  It receives writes and flushes, and its
+     * extents are being copied from a healthy downstairs one at a time.
+     */
+    LiveRepair,
     /*
      * Another Upstairs has connected and is now active.
      */
@@ -2888,6 +4905,11 @@ struct DownstairsIO {
      * If the operation is a Read, this holds the resulting buffer
      */
     data: Option<Vec<ReadResponse>>,
+    /*
+     * For an extent repair read, the repair write that is waiting on the
+     * data it returns.
+     */
+    repair_write: Option<u64>,
 }
 
 impl DownstairsIO {
@@ -2905,6 +4927,19 @@ impl DownstairsIO {
 
         wc
     }
+
+    /*
+     * Every downstairs that holds a replica has answered this job, or
+     * won't be asked to.
+     */
+    fn answered(&self, replicas: usize) -> bool {
+        (0..replicas as u8).all(|cid| {
+            matches!(
+                self.state.get(&cid),
+                Some(IOState::Done | IOState::Skipped | IOState::Error(_))
+            )
+        })
+    }
 }
 
 /*
@@ -2924,6 +4959,29 @@ pub enum IOop {
         dependencies: Vec<u64>, // Jobs that must finish before this
         flush_number: u64,
         gen_number: u64,
+        /*
+         * Have each downstairs snapshot its region once this is done.
+         */
+        snapshot_details: Option<SnapshotDetails>,
+        /*
+         * Only flush extents below this one.  Set per downstairs when the
+         * flush is sent, never when it is created.
+         */
+        extent_limit: Option<u64>,
+    },
+    /*
+     * Copy a whole extent from one downstairs to another.  The read is
+     * sent to the source only, and the write (once the read data has
+     * arrived) is sent to the destination only.
+     */
+    ExtentRepairRead {
+        dependencies: Vec<u64>, // Jobs that must finish before this
+        eid: u64,
+    },
+    ExtentRepairWrite {
+        dependencies: Vec<u64>, // Jobs that must finish before this
+        eid: u64,
+        extent: Option<ExtentData>,
     },
 }
 
@@ -2938,13 +4996,67 @@ impl IOop {
                 dependencies,
                 flush_number: _flush_number,
                 gen_number: _,
+                snapshot_details: _,
+                extent_limit: _,
+            } => dependencies,
+            IOop::Read {
+                dependencies,
+                requests: _,
+            } => dependencies,
+            IOop::ExtentRepairRead {
+                dependencies,
+                eid: _,
+            } => dependencies,
+            IOop::ExtentRepairWrite {
+                dependencies,
+                eid: _,
+                extent: _,
+            } => dependencies,
+        }
+    }
+
+    fn deps_mut(&mut self) -> &mut Vec<u64> {
+        match self {
+            IOop::Write {
+                dependencies,
+                writes: _,
+            } => dependencies,
+            IOop::Flush {
+                dependencies,
+                flush_number: _flush_number,
+                gen_number: _,
+                snapshot_details: _,
+                extent_limit: _,
             } => dependencies,
             IOop::Read {
                 dependencies,
                 requests: _,
             } => dependencies,
+            IOop::ExtentRepairRead {
+                dependencies,
+                eid: _,
+            } => dependencies,
+            IOop::ExtentRepairWrite {
+                dependencies,
+                eid: _,
+                extent: _,
+            } => dependencies,
         }
     }
+
+    pub fn is_repair(&self) -> bool {
+        matches!(
+            self,
+            IOop::ExtentRepairRead {
+                dependencies: _,
+                eid: _,
+            } | IOop::ExtentRepairWrite {
+                dependencies: _,
+                eid: _,
+                extent: _,
+            }
+        )
+    }
 }
 
 /*
@@ -3199,13 +5311,14 @@ fn test_buffer_len_over_block_size() {
 enum BlockOp {
     Read { offset: Block, data: Buffer },
     Write { offset: Block, data: Bytes },
-    Flush,
+    Flush { snapshot: Option<SnapshotDetails> },
     GoActive { gen: u64 },
     // Query ops
     QueryBlockSize { data: Arc<Mutex<u64>> },
     QueryTotalSize { data: Arc<Mutex<u64>> },
     QueryUpstairsActive { data: Arc<Mutex<bool>> },
     QueryUpstairsUuid { data: Arc<Mutex<Uuid>> },
+    QueryUpstairsReadOnly { data: Arc<Mutex<bool>> },
     // Begin testing options.
     QueryExtentSize { data: Arc<Mutex<Block>> },
     QueryWorkQueue { data: Arc<Mutex<usize>> },
@@ -3264,10 +5377,38 @@ struct GtoS {
     sender: Option<std_mpsc::Sender<Result<(), CrucibleError>>>,
 
     /*
-     * Optional encryption context - Some if the corresponding Upstairs is
-     * Some.
+     * The room this IO takes up in the guest queue.  It is given back
+     * when this job completes and is dropped.  None for IO the Upstairs
+     * issues itself.
      */
-    encryption_context: Option<Arc<EncryptionContext>>,
+    permit: Option<QueuePermit>,
+
+    /*
+     * For a write that was acked to the guest before it finished, the
+     * bytes it counts against the write-back limit.
+     */
+    dirty: usize,
+
+    /*
+     * For a guest flush, the early acked writes that came before it and
+     * have not finished yet.  The flush is not acked until they have,
+     * and it returns the first error from any of them.
+     */
+    write_back_wait: HashSet<u64>,
+    write_back_error: Option<CrucibleError>,
+
+    /*
+     * The result of a flush whose downstairs jobs are done, while it
+     * waits on write_back_wait.
+     */
+    held_result: Option<Result<(), CrucibleError>>,
+
+    /*
+     * What kind of guest IO this is, and when the upstairs built the
+     * downstairs jobs for it, for the IO latency stats.
+     */
+    kind: GuestIOKind,
+    started: Instant,
 }
 
 impl GtoS {
@@ -3277,15 +5418,30 @@ impl GtoS {
         guest_buffer: Option<Buffer>,
         downstairs_buffer: HashMap<u64, Vec<ReadResponse>>,
         sender: Option<std_mpsc::Sender<Result<(), CrucibleError>>>,
-        encryption_context: Option<Arc<EncryptionContext>>,
+        permit: Option<QueuePermit>,
     ) -> GtoS {
+        /*
+         * A flush is set as one by submit_flush.
+         */
+        let kind = if guest_buffer.is_some() {
+            GuestIOKind::Read
+        } else {
+            GuestIOKind::Write
+        };
+
         GtoS {
             submitted,
             completed,
             guest_buffer,
             downstairs_buffer,
             sender,
-            encryption_context,
+            permit,
+            dirty: 0,
+            write_back_wait: HashSet::new(),
+            write_back_error: None,
+            held_result: None,
+            kind,
+            started: Instant::now(),
         }
     }
 
@@ -3305,24 +5461,14 @@ impl GtoS {
                 let responses = self.downstairs_buffer.remove(ds_id).unwrap();
 
                 for response in responses {
-                    let mut ds_vec = response.data.to_vec();
-
-                    // if there's an encryption context, decrypt the
-                    // downstairs buffer.
-                    if let Some(context) = &self.encryption_context {
-                        context.decrypt_in_place(
-                            &mut ds_vec[..],
-                            response.offset.value as u128,
-                        );
-                    }
-
-                    // Copy over into guest memory.
+                    // Copy over into guest memory.  Encrypted data was
+                    // checked and decrypted as it arrived.
                     {
                         let _ignored =
                             span!(Level::TRACE, "copy to guest buffer")
                                 .entered();
                         let mut vec = guest_buffer.as_vec();
-                        for i in &ds_vec {
+                        for i in &response.data[..] {
                             vec[offset] = *i;
                             offset += 1;
                         }
@@ -3378,9 +5524,103 @@ struct GuestWork {
     active: HashMap<u64, GtoS>,
     next_gw_id: u64,
     completed: AllocRingBuffer<u64>,
+
+    /*
+     * If set, write-back is on and this is the most write data we will
+     * ack to the guest before it is done on the downstairs.
+     */
+    write_back: Option<usize>,
+    dirty_bytes: usize,
+
+    /*
+     * The early acked writes that are not done yet, by gw_id.
+     */
+    dirty_writes: HashSet<u64>,
+
+    /*
+     * The first error from a write that was acked early and finished
+     * with no guest flush waiting on it, for the next guest flush to
+     * report.
+     */
+    write_back_error: Option<CrucibleError>,
+
+    latency: IOLatency,
 }
 
-impl GuestWork {
+impl GuestWork {
+    fn write_back_room(&self, len: usize) -> bool {
+        match self.write_back {
+            Some(max_dirty) => self.dirty_bytes + len <= max_dirty,
+            None => false,
+        }
+    }
+
+    /*
+     * An early acked write has finished.  Hand its result to the guest
+     * flushes waiting on it, and ack any flush that was only waiting on
+     * this write.
+     */
+    fn write_back_done(
+        &mut self,
+        gw_id: u64,
+        dirty: usize,
+        result: &Result<(), CrucibleError>,
+    ) {
+        self.dirty_bytes -= dirty;
+        self.dirty_writes.remove(&gw_id);
+        if let Err(e) = result {
+            println!("Write-back write {} failed: {:?}", gw_id, e);
+        }
+
+        let mut reported = false;
+        let mut ready = Vec::new();
+        for (flush_id, job) in self.active.iter_mut() {
+            if !job.write_back_wait.remove(&gw_id) {
+                continue;
+            }
+            if let Err(e) = result {
+                reported = true;
+                if job.write_back_error.is_none() {
+                    job.write_back_error = Some(e.clone());
+                }
+            }
+            if job.write_back_wait.is_empty() && job.held_result.is_some() {
+                ready.push(*flush_id);
+            }
+        }
+
+        if let Err(e) = result {
+            if !reported && self.write_back_error.is_none() {
+                self.write_back_error = Some(e.clone());
+            }
+        }
+
+        for flush_id in ready {
+            let job = self.active.get_mut(&flush_id).unwrap();
+            let result = job.held_result.take().unwrap();
+            self.finish(flush_id, result);
+        }
+    }
+
+    /*
+     * Send the final result for a guest job, with the error from any
+     * early acked write it waited on, and move it to completed.
+     */
+    fn finish(&mut self, gw_id: u64, result: Result<(), CrucibleError>) {
+        let gtos_job = self.active.get_mut(&gw_id).unwrap();
+        let result = match gtos_job.write_back_error.take() {
+            Some(e) if result.is_ok() => Err(e),
+            _ => result,
+        };
+
+        let dirty = gtos_job.dirty;
+        gtos_job.notify(result.clone());
+        self.complete(gw_id);
+        if dirty > 0 {
+            self.write_back_done(gw_id, dirty, &result);
+        }
+    }
+
     fn next_gw_id(&mut self) -> u64 {
         let id = self.next_gw_id;
         self.next_gw_id += 1;
@@ -3477,8 +5717,23 @@ impl GuestWork {
                     gtos_job.transfer();
                 }
 
-                gtos_job.notify(result);
-                self.complete(gw_id);
+                /*
+                 * IO the upstairs sent on its own has no permit, and
+                 * is not counted.
+                 */
+                if let Some(permit) = &gtos_job.permit {
+                    self.latency.record(
+                        gtos_job.kind,
+                        gtos_job.started.duration_since(permit.issued),
+                        gtos_job.started.elapsed(),
+                    );
+                }
+
+                if !gtos_job.write_back_wait.is_empty() {
+                    gtos_job.held_result = Some(result);
+                    return;
+                }
+                self.finish(gw_id, result);
             }
         } else {
             /*
@@ -3499,6 +5754,7 @@ impl GuestWork {
 pub struct BlockReq {
     op: BlockOp,
     send: std_mpsc::Sender<Result<(), CrucibleError>>,
+    permit: Option<QueuePermit>,
 }
 
 impl BlockReq {
@@ -3508,8 +5764,138 @@ impl BlockReq {
     fn new(
         op: BlockOp,
         send: std_mpsc::Sender<Result<(), CrucibleError>>,
+        permit: Option<QueuePermit>,
     ) -> BlockReq {
-        Self { op, send }
+        Self { op, send, permit }
+    }
+}
+
+/**
+ * Limits on guest IO that has been submitted but not yet completed.
+ * Once either limit is reached, new reads, writes and flushes from the
+ * guest wait until enough earlier IO finishes.  This keeps a slow
+ * downstairs from letting the guest pile up IO in our memory.
+ */
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct QueueLimits {
+    max_jobs: usize,
+    max_bytes: usize,
+}
+
+impl QueueLimits {
+    pub fn new(max_jobs: usize, max_bytes: usize) -> Result<QueueLimits> {
+        if max_jobs == 0 {
+            bail!("max_jobs must be at least 1");
+        }
+        if max_bytes == 0 {
+            bail!("max_bytes must be at least 1");
+        }
+
+        Ok(QueueLimits {
+            max_jobs,
+            max_bytes,
+        })
+    }
+
+    pub fn max_jobs(&self) -> usize {
+        self.max_jobs
+    }
+
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+}
+
+impl Default for QueueLimits {
+    fn default() -> Self {
+        QueueLimits {
+            max_jobs: 1024,
+            max_bytes: 1024 * 1024 * 1024,
+        }
+    }
+}
+
+/*
+ * Guest IO in flight right now.
+ */
+#[derive(Debug, Default, Copy, Clone, PartialEq, Serialize, JsonSchema)]
+pub struct QueueDepth {
+    pub jobs: usize,
+    pub bytes: usize,
+}
+
+#[derive(Debug)]
+struct GuestQueue {
+    limits: QueueLimits,
+    depth: Mutex<QueueDepth>,
+    room: Notify,
+}
+
+impl GuestQueue {
+    fn new(limits: QueueLimits) -> GuestQueue {
+        GuestQueue {
+            limits,
+            depth: Mutex::new(QueueDepth::default()),
+            room: Notify::new(),
+        }
+    }
+
+    /*
+     * Take room for one IO of the given size if there is enough.  An IO
+     * bigger than max_bytes is let in when the queue is empty, otherwise
+     * it could never be sent.
+     */
+    fn try_admit(self: &Arc<Self>, bytes: usize) -> Option<QueuePermit> {
+        let mut depth = self.depth.lock().unwrap();
+        if depth.jobs > 0
+            && (depth.jobs >= self.limits.max_jobs
+                || depth.bytes + bytes > self.limits.max_bytes)
+        {
+            return None;
+        }
+
+        depth.jobs += 1;
+        depth.bytes += bytes;
+        Some(QueuePermit {
+            queue: self.clone(),
+            bytes,
+            issued: Instant::now(),
+        })
+    }
+
+    async fn admit(self: &Arc<Self>, bytes: usize) -> QueuePermit {
+        loop {
+            /*
+             * Get in line for a wakeup before we look, so a permit
+             * dropped between the look and the wait is not missed.
+             */
+            let room = self.room.notified();
+            if let Some(permit) = self.try_admit(bytes) {
+                return permit;
+            }
+            room.await;
+        }
+    }
+}
+
+/*
+ * The room one guest IO takes up in the GuestQueue.  Dropping it gives
+ * the room back and wakes anyone waiting for it.
+ */
+#[derive(Debug)]
+pub struct QueuePermit {
+    queue: Arc<GuestQueue>,
+    bytes: usize,
+    issued: Instant,
+}
+
+impl Drop for QueuePermit {
+    fn drop(&mut self) {
+        let mut depth = self.queue.depth.lock().unwrap();
+        depth.jobs -= 1;
+        depth.bytes -= self.bytes;
+        drop(depth);
+        self.queue.room.notify_waiters();
     }
 }
 
@@ -3535,6 +5921,23 @@ impl BlockReqWaiter {
         }
     }
 
+    /*
+     * Async version of block_wait for callers running inside a tokio
+     * runtime. The notifier is a std channel, so wait for it on the
+     * blocking pool rather than stalling an executor thread.  Outside of
+     * a runtime (the pseudo file polls this with block_on from a plain
+     * thread) there is no blocking pool, and blocking is fine.
+     */
+    pub async fn wait(mut self) -> Result<(), CrucibleError> {
+        if tokio::runtime::Handle::try_current().is_err() {
+            return self.block_wait();
+        }
+        match tokio::task::spawn_blocking(move || self.block_wait()).await {
+            Ok(v) => v,
+            Err(e) => crucible_bail!(GenericError, "{:?}", e),
+        }
+    }
+
     pub fn try_wait(&mut self) -> Option<Result<(), CrucibleError>> {
         match self.recv.try_recv() {
             Ok(v) => Some(v),
@@ -3593,6 +5996,12 @@ pub struct Guest {
     reqs: Mutex<VecDeque<BlockReq>>,
     notify: Notify,
 
+    /*
+     * Reads, writes and flushes from the guest that have not completed,
+     * and how many of them we let in before the guest has to wait.
+     */
+    queue: Arc<GuestQueue>,
+
     /*
      * When the crucible listening task has noticed a new IO request, it
      * will pull it from the reqs queue and create an GuestWork struct
@@ -3606,13 +6015,40 @@ pub struct Guest {
      * required downstairs operations are completed.
      */
     guest_work: Mutex<GuestWork>,
+
+    /*
+     * Held for writing by a read-modify-write from its reads until its
+     * write is submitted, and for reading by all other IO while it is
+     * submitted.  See write_bytes.
+     */
+    rmw_lock: tokio::sync::RwLock<()>,
+}
+
+/*
+ * Wait on a future from one of the sync Guest calls.  On a thread of a
+ * multi threaded runtime, block_in_place moves the runtime's other tasks
+ * off this thread first, so the upstairs tasks the future waits on can
+ * still run.  A current thread runtime can't do that, and block_in_place
+ * panics there rather than hang.  Off the runtime, nothing else needs
+ * this thread.
+ */
+fn block_on<F: std::future::Future>(f: F) -> F::Output {
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) => tokio::task::block_in_place(move || handle.block_on(f)),
+        Err(_) => futures::executor::block_on(f),
+    }
 }
 
 /*
  * These methods are how to add or checking for new work on the Guest struct
  */
+
 impl Guest {
     pub fn new() -> Guest {
+        Guest::with_limits(QueueLimits::default())
+    }
+
+    pub fn with_limits(limits: QueueLimits) -> Guest {
         Guest {
             active: Mutex::new(false),
             /*
@@ -3620,6 +6056,7 @@ impl Guest {
              */
             reqs: Mutex::new(VecDeque::new()),
             notify: Notify::new(),
+            queue: Arc::new(GuestQueue::new(limits)),
             /*
              * The active hashmap is for in-flight I/O operations
              * that we have taken off the incoming queue, but we have not
@@ -3631,7 +6068,13 @@ impl Guest {
                 active: HashMap::new(), // GtoS
                 next_gw_id: 1,
                 completed: AllocRingBuffer::with_capacity(2048),
+                write_back: None,
+                dirty_bytes: 0,
+                dirty_writes: HashSet::new(),
+                write_back_error: None,
+                latency: IOLatency::default(),
             }),
+            rmw_lock: tokio::sync::RwLock::new(()),
         }
     }
 
@@ -3639,14 +6082,66 @@ impl Guest {
      * This is used to submit a new BlockOp IO request to Crucible.
      */
     fn send(&self, op: BlockOp) -> BlockReqWaiter {
+        self.send_permit(op, None)
+    }
+
+    fn send_permit(
+        &self,
+        op: BlockOp,
+        permit: Option<QueuePermit>,
+    ) -> BlockReqWaiter {
         let (send, recv) = std_mpsc::channel();
 
-        self.reqs.lock().unwrap().push_back(BlockReq::new(op, send));
+        self.reqs
+            .lock()
+            .unwrap()
+            .push_back(BlockReq::new(op, send, permit));
         self.notify.notify_one();
 
         BlockReqWaiter::new(recv)
     }
 
+    /*
+     * Guest IO that has not yet completed, for status reporting.
+     */
+    pub fn queue_depth(&self) -> QueueDepth {
+        *self.queue.depth.lock().unwrap()
+    }
+
+    pub fn queue_limits(&self) -> QueueLimits {
+        self.queue.limits
+    }
+
+    /*
+     * Turn write-back on (or off with None).  Writes are acked as soon as
+     * the upstairs has them, until max_dirty_bytes of writes are waiting
+     * on the downstairs; after that they are acked when done as usual.
+     * A guest flush still waits for every write before it, and returns
+     * an error if any write acked early has failed.
+     */
+    pub fn set_write_back(&self, max_dirty_bytes: Option<usize>) {
+        self.guest_work.lock().unwrap().write_back = max_dirty_bytes;
+    }
+
+    /*
+     * Bytes of writes acked to the guest that are not yet done.
+     */
+    pub fn dirty_bytes(&self) -> usize {
+        self.guest_work.lock().unwrap().dirty_bytes
+    }
+
+    /*
+     * Where the time went for guest IO completed since this upstairs
+     * started, or since the last reset.  See PhaseLatency.
+     */
+    pub fn io_latency(&self) -> IOLatency {
+        self.guest_work.lock().unwrap().latency.clone()
+    }
+
+    pub fn reset_io_latency(&self) {
+        self.guest_work.lock().unwrap().latency = IOLatency::default();
+    }
+
     /*
      * A crucible task will listen for new work using this.
      */
@@ -3664,39 +6159,49 @@ impl Guest {
         offset: u64,
     ) -> Result<Block, CrucibleError> {
         let bs = self.query_block_size()?;
-
-        if (offset % bs) != 0 {
-            crucible_bail!(OffsetUnaligned);
-        }
-
-        Ok(Block::new(offset / bs, bs.trailing_zeros()))
+        Block::from_byte_offset(offset, bs)
     }
 
     /*
      * `read` and `write` accept a block offset, and data must be a
      * multiple of block size.
+     *
+     * If the guest queue is full these block until there is room.  The
+     * `_async` versions wait for room without blocking the thread.  See
+     * block_on() for calling these from a runtime thread.
      */
     pub fn read(
         &self,
         offset: Block,
         data: Buffer,
     ) -> Result<BlockReqWaiter, CrucibleError> {
-        if !self.is_active() {
-            return Err(CrucibleError::UpstairsInactive);
-        }
+        block_on(self.read_async(offset, data))
+    }
 
-        let bs = self.query_block_size()?;
+    pub async fn read_async(
+        &self,
+        offset: Block,
+        data: Buffer,
+    ) -> Result<BlockReqWaiter, CrucibleError> {
+        let _rmw = self.rmw_lock.read().await;
+        self.submit_read(offset, data).await
+    }
 
-        if (data.len() % bs as usize) != 0 {
-            crucible_bail!(DataLenUnaligned);
+    async fn submit_read(
+        &self,
+        offset: Block,
+        data: Buffer,
+    ) -> Result<BlockReqWaiter, CrucibleError> {
+        if !self.is_active() {
+            return Err(CrucibleError::UpstairsInactive);
         }
 
-        if offset.block_size_in_bytes() as u64 != bs {
-            crucible_bail!(BlockSizeMismatch);
-        }
+        let bs = self.query_block_size_async().await?;
+        offset.check_io(data.len(), bs)?;
 
+        let permit = self.queue.admit(data.len()).await;
         let rio = BlockOp::Read { offset, data };
-        Ok(self.send(rio))
+        Ok(self.send_permit(rio, Some(permit)))
     }
 
     pub fn write(
@@ -3704,22 +6209,33 @@ impl Guest {
         offset: Block,
         data: Bytes,
     ) -> Result<BlockReqWaiter, CrucibleError> {
-        if !self.is_active() {
-            return Err(CrucibleError::UpstairsInactive);
-        }
+        block_on(self.write_async(offset, data))
+    }
 
-        let bs = self.query_block_size()?;
+    pub async fn write_async(
+        &self,
+        offset: Block,
+        data: Bytes,
+    ) -> Result<BlockReqWaiter, CrucibleError> {
+        let _rmw = self.rmw_lock.read().await;
+        self.submit_write(offset, data).await
+    }
 
-        if (data.len() % bs as usize) != 0 {
-            crucible_bail!(DataLenUnaligned);
+    async fn submit_write(
+        &self,
+        offset: Block,
+        data: Bytes,
+    ) -> Result<BlockReqWaiter, CrucibleError> {
+        if !self.is_active() {
+            return Err(CrucibleError::UpstairsInactive);
         }
 
-        if offset.block_size_in_bytes() as u64 != bs {
-            crucible_bail!(BlockSizeMismatch);
-        }
+        let bs = self.query_block_size_async().await?;
+        offset.check_io(data.len(), bs)?;
 
+        let permit = self.queue.admit(data.len()).await;
         let wio = BlockOp::Write { offset, data };
-        Ok(self.send(wio))
+        Ok(self.send_permit(wio, Some(permit)))
     }
 
     /*
@@ -3750,12 +6266,107 @@ impl Guest {
         self.write(self.byte_offset_to_block(offset)?, data)
     }
 
+    /*
+     * `read_bytes` and `write_bytes` take any byte offset and length.
+     * IO that is not on block boundaries is done in whole blocks.  A read
+     * reads every block it touches and copies out the bytes asked for.  A
+     * write reads every block it touches, puts the new bytes in place,
+     * and writes all the blocks back.
+     *
+     * Each job depends on the IO submitted before it, and the rmw lock
+     * keeps anything else from being submitted between the reads and the
+     * write.  So every block is written whole, with either what was there
+     * or the new bytes, and IO sent after write_bytes sees the write.
+     */
+    pub async fn read_bytes(
+        &self,
+        offset: u64,
+        data: Buffer,
+    ) -> Result<(), CrucibleError> {
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let bs = self.query_block_size_async().await?;
+        let span = IOSpan::new(offset, data.len() as u64, bs);
+        if span.is_block_regular() {
+            let first = Block::new(offset / bs, bs.trailing_zeros());
+            return self.read_async(first, data).await?.wait().await;
+        }
+
+        let waiter = {
+            let _rmw = self.rmw_lock.read().await;
+            span.read_affected_blocks_from_guest(self).await?
+        };
+        waiter.wait().await?;
+
+        span.read_from_blocks_into_buffer(&mut data.as_vec());
+        Ok(())
+    }
+
+    pub async fn write_bytes(
+        &self,
+        offset: u64,
+        data: Bytes,
+    ) -> Result<(), CrucibleError> {
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let bs = self.query_block_size_async().await?;
+        let span = IOSpan::new(offset, data.len() as u64, bs);
+        if span.is_block_regular() {
+            let first = Block::new(offset / bs, bs.trailing_zeros());
+            return self.write_async(first, data).await?.wait().await;
+        }
+
+        let waiter = {
+            let _rmw = self.rmw_lock.write().await;
+
+            span.read_affected_blocks_from_guest(self)
+                .await?
+                .wait()
+                .await?;
+            span.write_from_buffer_into_blocks(&data);
+            span.write_affected_blocks_to_guest(self).await?
+        };
+        waiter.wait().await
+    }
+
     pub fn flush(&self) -> Result<BlockReqWaiter, CrucibleError> {
+        block_on(self.flush_async())
+    }
+
+    pub async fn flush_async(&self) -> Result<BlockReqWaiter, CrucibleError> {
+        self.flush_snapshot_async(None).await
+    }
+
+    /*
+     * Flush, then have every downstairs take a snapshot of its region
+     * with the given name.  The snapshot holds everything written before
+     * the flush, and nothing written after it.
+     */
+    pub fn snapshot(
+        &self,
+        snapshot_details: SnapshotDetails,
+    ) -> Result<BlockReqWaiter, CrucibleError> {
+        block_on(self.flush_snapshot_async(Some(snapshot_details)))
+    }
+
+    async fn flush_snapshot_async(
+        &self,
+        snapshot_details: Option<SnapshotDetails>,
+    ) -> Result<BlockReqWaiter, CrucibleError> {
         if !self.is_active() {
             return Err(CrucibleError::UpstairsInactive);
         }
 
-        Ok(self.send(BlockOp::Flush))
+        let _rmw = self.rmw_lock.read().await;
+        let permit = self.queue.admit(0).await;
+        let fio = BlockOp::Flush {
+            snapshot: snapshot_details,
+        };
+        Ok(self.send_permit(fio, Some(permit)))
     }
 
     pub fn set_active(&self) {
@@ -3801,6 +6412,13 @@ impl Guest {
         return Ok(*data.lock().map_err(|_| CrucibleError::DataLockError)?);
     }
 
+    pub fn query_read_only(&self) -> Result<bool, CrucibleError> {
+        let data = Arc::new(Mutex::new(false));
+        let ro_query = BlockOp::QueryUpstairsReadOnly { data: data.clone() };
+        self.send(ro_query).block_wait()?;
+        return Ok(*data.lock().map_err(|_| CrucibleError::DataLockError)?);
+    }
+
     pub fn query_block_size(&self) -> Result<u64, CrucibleError> {
         if !self.is_active() {
             return Err(CrucibleError::UpstairsInactive);
@@ -3812,6 +6430,22 @@ impl Guest {
         return Ok(*data.lock().map_err(|_| CrucibleError::DataLockError)?);
     }
 
+    /*
+     * For the async IO paths, which can't block a runtime thread while
+     * the upstairs answers.
+     */
+    async fn query_block_size_async(&self) -> Result<u64, CrucibleError> {
+        if !self.is_active() {
+            return Err(CrucibleError::UpstairsInactive);
+        }
+
+        let data = Arc::new(Mutex::new(0));
+        let size_query = BlockOp::QueryBlockSize { data: data.clone() };
+        self.send(size_query).wait().await?;
+        let bs = *data.lock().map_err(|_| CrucibleError::DataLockError)?;
+        Ok(bs)
+    }
+
     pub fn query_total_size(&self) -> Result<u64, CrucibleError> {
         if !self.is_active() {
             return Err(CrucibleError::UpstairsInactive);
@@ -3954,16 +6588,19 @@ fn send_active(t: &[Target], gen: u64) {
  * complete any buffer transfers (reads) and then notify the guest that
  * their work has been completed.
  */
-async fn up_ds_listen(up: &Arc<Upstairs>, mut ds_done_rx: mpsc::Receiver<u64>) {
+async fn up_ds_listen(
+    up: &Arc<Upstairs>,
+    mut ds_done_rx: mpsc::Receiver<DsDone>,
+) {
     /*
      * Accept _any_ ds_done message, but work on the whole list of ackable
      * work.
      */
-    while let Some(ds_id) = ds_done_rx.recv().await {
+    while let Some(ds_done) = ds_done_rx.recv().await {
         if !up.is_active() {
             println!(
-                "up_ds_listen: ignoring ds_id:{}  Upstairs is not active",
-                ds_id
+                "up_ds_listen: ignoring {:?}  Upstairs is not active",
+                ds_done
             );
             continue;
         }
@@ -3991,7 +6628,7 @@ async fn up_ds_listen(up: &Arc<Upstairs>, mut ds_done_rx: mpsc::Receiver<u64>) {
             if !up.is_active() {
                 println!(
                     "up_ds_listen ignoring ds_id:{}  Upstairs not active",
-                    ds_id
+                    ds_id_done
                 );
                 continue;
             }
@@ -4046,7 +6683,7 @@ async fn process_new_io(
      */
     match req.op {
         /*
-         * These three options can be handled by this task directly,
+         * These options can be handled by this task directly,
          * and don't require the upstairs to be fully online.
          */
         BlockOp::GoActive { gen } => {
@@ -4069,12 +6706,18 @@ async fn process_new_io(
             *data.lock().unwrap() = up.uuid;
             let _ = req.send.send(Ok(()));
         }
+        BlockOp::QueryUpstairsReadOnly { data } => {
+            *data.lock().unwrap() = up.read_only;
+            let _ = req.send.send(Ok(()));
+        }
         /*
          * These options are only functional once the upstairs is
          * active and should not be accepted if we are not active.
          */
         BlockOp::Read { offset, data } => {
-            if let Err(e) = up.submit_read(offset, data, req.send.clone()) {
+            if let Err(e) =
+                up.submit_read(offset, data, req.send.clone(), req.permit)
+            {
                 let _ = req.send.send(Err(e));
                 return;
             }
@@ -4082,15 +6725,19 @@ async fn process_new_io(
             *lastcast += 1;
         }
         BlockOp::Write { offset, data } => {
-            if let Err(e) = up.submit_write(offset, data, req.send.clone()) {
+            if let Err(e) =
+                up.submit_write(offset, data, req.send.clone(), req.permit)
+            {
                 let _ = req.send.send(Err(e));
                 return;
             }
             send_work(dst, *lastcast);
             *lastcast += 1;
         }
-        BlockOp::Flush => {
-            if let Err(e) = up.submit_flush(Some(req.send.clone())) {
+        BlockOp::Flush { snapshot } => {
+            if let Err(e) =
+                up.submit_flush(Some(req.send.clone()), req.permit, snapshot)
+            {
                 let _ = req.send.send(Err(e));
                 return;
             }
@@ -4186,6 +6833,7 @@ async fn up_listen(
 ) {
     println!("Wait for all three downstairs to come online");
     let mut lastcast = 1;
+    let mut reconcile_check = deadline_secs(1);
 
     stat_update(up, "start");
     loop {
@@ -4213,6 +6861,22 @@ async fn up_listen(
                         println!("#### ? #### DISCONNECTED due to None! ####");
                     }
                 }
+                _ = sleep_until(reconcile_check) => {
+                    /*
+                     * If a reconciliation is running, see if it is done
+                     * and let the downstairs tasks look for repair work.
+                     */
+                    if up.ds_state_copy().contains(&DsState::Verifying) {
+                        if up.ds_reconciliation() {
+                            break;
+                        }
+                        send_work(&dst, lastcast);
+                        lastcast += 1;
+                    }
+                    reconcile_check = Instant::now()
+                        .checked_add(Duration::from_millis(100))
+                        .unwrap();
+                }
                 req = up.guest.recv() => {
                     /*
                      * There are a few commands we will accept before we
@@ -4242,6 +6906,7 @@ async fn up_listen(
          */
         let mut flush_check = deadline_secs(5);
         let mut show_work_interval = deadline_secs(5);
+        let mut repair_check = deadline_secs(1);
 
         loop {
             tokio::select! {
@@ -4259,6 +6924,18 @@ async fn up_listen(
                         } else {
                             println!("[{}] online {}", c.client_id, c.target);
                         }
+
+                        /*
+                         * A client task started activation over.  Have
+                         * the others look for work, so they see it and
+                         * drop their connections, then wait for them all
+                         * to come back as we did at the start.
+                         */
+                        if up.is_reactivating() {
+                            send_work(&dst, lastcast);
+                            lastcast += 1;
+                            break;
+                        }
                     } else {
                         /*
                          * A None here means all senders were dropped, which
@@ -4299,6 +6976,18 @@ async fn up_listen(
                 req = up.guest.recv() => {
                     process_new_io(up, &dst, req, &mut lastcast).await;
                 }
+                _ = sleep_until(repair_check) => {
+                    /*
+                     * Keep any live repair moving along.
+                     */
+                    if up.live_repair_step() {
+                        send_work(&dst, lastcast);
+                        lastcast += 1;
+                    }
+                    repair_check = Instant::now()
+                        .checked_add(Duration::from_millis(100))
+                        .unwrap();
+                }
                 _ = sleep_until(flush_check) => {
                     /*
                      * This must fire every "flush_check" seconds to make sure
@@ -4308,7 +6997,7 @@ async fn up_listen(
                     if up.flush_needed() {
                         println!("Need a flush");
 
-                        if let Err(e) = up.submit_flush(None) {
+                        if let Err(e) = up.submit_flush(None, None, None) {
                             println!("flush send failed:{:?}", e);
                             // XXX What to do here?
                         } else {
@@ -4352,6 +7041,15 @@ pub async fn up_main(opt: CrucibleOpts, guest: Arc<Guest>) -> Result<()> {
      */
     let up = Upstairs::new(&opt, RegionDefinition::default(), guest);
 
+    if let Some(control) = opt.control {
+        let upc = Arc::clone(&up);
+        tokio::spawn(async move {
+            if let Err(e) = control::start(&upc, control).await {
+                eprintln!("Control server failed: {:?}", e);
+            }
+        });
+    }
+
     /*
      * Use this channel to receive updates on target status from each task
      * we create to connect to a downstairs.
@@ -4459,6 +7157,7 @@ fn create_write_eob(
         state,
         ack_status: AckStatus::NotAcked,
         data: None,
+        repair_write: None,
     }
 }
 
@@ -4490,6 +7189,7 @@ fn create_read_eob(
         state,
         ack_status: AckStatus::NotAcked,
         data: None,
+        repair_write: None,
     }
 }
 
@@ -4502,11 +7202,14 @@ fn create_flush(
     flush_number: u64,
     guest_id: u64,
     gen_number: u64,
+    snapshot_details: Option<SnapshotDetails>,
 ) -> DownstairsIO {
     let flush = IOop::Flush {
         dependencies,
         flush_number,
         gen_number,
+        snapshot_details,
+        extent_limit: None,
     };
 
     let mut state = HashMap::new();
@@ -4520,6 +7223,7 @@ fn create_flush(
         state,
         ack_status: AckStatus::NotAcked,
         data: None,
+        repair_write: None,
     }
 }
 
@@ -4530,6 +7234,7 @@ fn create_flush(
 fn show_all_work(up: &Arc<Upstairs>) -> WQCounts {
     let mut iosc: IOStateCount = IOStateCount::new();
     let up_count = up.guest.guest_work.lock().unwrap().active.len();
+    let dirty = up.guest.dirty_bytes();
 
     let work = up.downstairs.lock().unwrap();
     let mut kvec: Vec<u64> = work.active.keys().cloned().collect::<Vec<u64>>();
@@ -4543,6 +7248,16 @@ fn show_all_work(up: &Arc<Upstairs>) -> WQCounts {
         up_count,
         kvec.len(),
     );
+    let depth = up.guest.queue_depth();
+    let limits = up.guest.queue_limits();
+    println!(
+        " Guest queue jobs:{}/{} bytes:{}/{} dirty:{}",
+        depth.jobs,
+        limits.max_jobs(),
+        depth.bytes,
+        limits.max_bytes(),
+        dirty,
+    );
     if kvec.is_empty() {
         if up_count != 0 {
             show_guest_work(&up.guest);
@@ -4590,10 +7305,27 @@ fn show_all_work(up: &Arc<Upstairs>) -> WQCounts {
                     dependencies: _dependencies,
                     flush_number: _flush_number,
                     gen_number: _gen_number,
+                    snapshot_details: _,
+                    extent_limit: _,
                 } => {
                     let job_type = "Flush".to_string();
                     (job_type, 0)
                 }
+                IOop::ExtentRepairRead {
+                    dependencies: _dependencies,
+                    eid: _,
+                } => {
+                    let job_type = "RepRd".to_string();
+                    (job_type, 0)
+                }
+                IOop::ExtentRepairWrite {
+                    dependencies: _dependencies,
+                    eid: _,
+                    extent: _,
+                } => {
+                    let job_type = "RepWr".to_string();
+                    (job_type, 0)
+                }
             };
 
             print!(
@@ -4624,6 +7356,11 @@ fn show_all_work(up: &Arc<Upstairs>) -> WQCounts {
             print!("{} ", lf);
         }
         println!();
+        print!("Skipped for replay: ");
+        for skipped in work.ds_skipped_jobs.iter() {
+            print!("{} ", skipped.len());
+        }
+        println!();
     }
 
     let done = work.completed.to_vec();
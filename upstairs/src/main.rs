@@ -1,5 +1,5 @@
 // Copyright 2021 Oxide Computer Company
-use std::net::SocketAddrV4;
+use std::net::{SocketAddr, SocketAddrV4};
 use std::sync::Arc;
 
 use anyhow::{bail, Result};
@@ -23,6 +23,12 @@ pub struct Opt {
 
     #[structopt(short, long, default_value = "0")]
     gen: u64,
+
+    /*
+     * Start the upstairs control server on this address.
+     */
+    #[structopt(long)]
+    control: Option<SocketAddr>,
 }
 
 pub fn opts() -> Result<Opt> {
@@ -105,6 +111,12 @@ mod tests {
             target: opt.target,
             lossy: false,
             key: opt.key,
+            legacy_xts: false,
+            control: opt.control,
+            policy: None,
+            read_only: false,
+            job_timeout: None,
+            uuid_mismatch: None,
         };
 
         if let Some(key) = crucible_opts.key_bytes() {
@@ -117,10 +129,17 @@ mod tests {
 
 fn main() -> Result<()> {
     let opt = opts()?;
+    let policy = ReplicationPolicy::majority(opt.target.len())?;
     let crucible_opts = CrucibleOpts {
         target: opt.target,
         lossy: false,
         key: opt.key,
+        legacy_xts: false,
+        control: opt.control,
+        policy: Some(policy),
+        read_only: false,
+        job_timeout: None,
+        uuid_mismatch: None,
     };
 
     let runtime = Builder::new_multi_thread()
@@ -7,7 +7,8 @@ use super::*;
  * - the offset is block aligned, and
  * - the size is a multiple of block size
  *
- * If either of these is not true, then perform some fix up here.
+ * If either of these is not true, then perform some fix up here.  See
+ * Guest::read_bytes and Guest::write_bytes.
  */
 #[derive(Debug)]
 pub struct IOSpan {
@@ -65,56 +66,58 @@ impl IOSpan {
         &self.buffer
     }
 
+    fn first_block(&self) -> Block {
+        Block::new(
+            self.affected_block_numbers[0],
+            self.block_size.trailing_zeros(),
+        )
+    }
+
+    /*
+     * These submit straight to the guest, without taking its rmw lock.
+     * The caller holds that.
+     */
     #[instrument]
-    pub fn read_affected_blocks_from_guest(
-        &mut self,
+    pub async fn read_affected_blocks_from_guest(
+        &self,
         guest: &Guest,
     ) -> Result<BlockReqWaiter, CrucibleError> {
-        guest.read(
-            Block::new(
-                self.affected_block_numbers[0],
-                self.block_size.trailing_zeros(),
-            ),
-            self.buffer.clone(),
-        )
+        guest
+            .submit_read(self.first_block(), self.buffer.clone())
+            .await
     }
 
     #[instrument]
-    pub fn write_affected_blocks_to_guest(
+    pub async fn write_affected_blocks_to_guest(
         &self,
         guest: &Guest,
     ) -> Result<BlockReqWaiter, CrucibleError> {
         let bytes = Bytes::from(self.buffer.as_vec().clone());
-        guest.write(
-            Block::new(
-                self.affected_block_numbers[0],
-                self.block_size.trailing_zeros(),
-            ),
-            bytes,
-        )
+        guest.submit_write(self.first_block(), bytes).await
     }
 
     #[instrument]
     pub fn read_from_blocks_into_buffer(&self, data: &mut [u8]) {
         assert_eq!(data.len(), self.sz as usize);
 
-        for (i, item) in data.iter_mut().enumerate() {
-            *item = self.buffer.as_vec()[self.phase as usize + i];
-        }
+        let phase = self.phase as usize;
+        data.copy_from_slice(&self.buffer.as_vec()[phase..phase + data.len()]);
     }
 
     #[instrument]
     pub fn write_from_buffer_into_blocks(&self, data: &[u8]) {
         assert_eq!(data.len(), self.sz as usize);
 
-        for (i, item) in data.iter().enumerate() {
-            self.buffer.as_vec()[self.phase as usize + i] = *item;
-        }
+        let phase = self.phase as usize;
+        self.buffer.as_vec()[phase..phase + data.len()].copy_from_slice(data);
     }
 }
 
 /*
  * Wrap a Crucible guest and implement Read + Write + Seek traits.
+ *
+ * The block size it gives is the sector size it was made with, or else
+ * the block size of the regions.  Any offset and length work either way.
  */
 pub struct CruciblePseudoFile {
     active: bool,
@@ -122,7 +125,8 @@ pub struct CruciblePseudoFile {
     offset: u64,
     sz: u64,
     block_size: u64,
-    rmw_lock: RwLock<bool>,
+    physical_block_size: u64,
+    sector_size: Option<u64>,
     upstairs_uuid: Uuid,
 }
 
@@ -134,15 +138,43 @@ impl CruciblePseudoFile {
             offset: 0,
             sz: 0,
             block_size: 0,
-            rmw_lock: RwLock::new(false),
+            physical_block_size: 0,
+            sector_size: None,
             upstairs_uuid: Uuid::default(),
         })
     }
 
+    /*
+     * Present sectors of sector_size bytes, for a guest that insists on
+     * them, over regions made with larger blocks (512 byte sectors on 4K
+     * blocks, say).  Sectors that don't fill their blocks are a
+     * read-modify-write in the guest's read_bytes and write_bytes.
+     */
+    pub fn with_sector_size(
+        guest: Arc<Guest>,
+        sector_size: u64,
+    ) -> Result<Self, CrucibleError> {
+        if sector_size < 512 || !sector_size.is_power_of_two() {
+            crucible_bail!(
+                InvalidBlockSize,
+                "sector size {} is not a power of two from 512",
+                sector_size
+            );
+        }
+
+        let mut cpf = CruciblePseudoFile::from_guest(guest)?;
+        cpf.sector_size = Some(sector_size);
+        Ok(cpf)
+    }
+
     pub fn block_size(&self) -> u64 {
         self.block_size
     }
 
+    pub fn physical_block_size(&self) -> u64 {
+        self.physical_block_size
+    }
+
     pub fn sz(&self) -> u64 {
         self.sz
     }
@@ -151,7 +183,19 @@ impl CruciblePseudoFile {
         self.guest.activate(gen)?;
 
         self.sz = self.guest.query_total_size()? as u64;
-        self.block_size = self.guest.query_block_size()? as u64;
+        self.physical_block_size = self.guest.query_block_size()? as u64;
+        self.block_size = match self.sector_size {
+            Some(sector_size) if sector_size > self.physical_block_size => {
+                crucible_bail!(
+                    InvalidBlockSize,
+                    "sector size {} is larger than the block size {}",
+                    sector_size,
+                    self.physical_block_size
+                );
+            }
+            Some(sector_size) => sector_size,
+            None => self.physical_block_size,
+        };
         self.upstairs_uuid = self.guest.query_upstairs_uuid()?;
 
         self.active = true;
@@ -211,7 +255,6 @@ impl Write for CruciblePseudoFile {
 
 impl Seek for CruciblePseudoFile {
     fn seek(&mut self, pos: SeekFrom) -> IOResult<u64> {
-        // TODO: let guard = self.rmw_lock.write().unwrap() ?
         // TODO: does not check against block device size
 
         let mut offset: i64 = self.offset as i64;
@@ -245,16 +288,14 @@ impl Seek for CruciblePseudoFile {
 }
 
 impl CruciblePseudoFile {
+    /*
+     * The guest does any read-modify-write, and keeps it in order with
+     * all other IO.
+     */
     fn _read(&mut self, buf: &mut [u8]) -> Result<usize, CrucibleError> {
-        let _guard = self.rmw_lock.read().unwrap();
-
-        let mut span =
-            IOSpan::new(self.offset, buf.len() as u64, self.block_size);
-
-        let mut waiter = span.read_affected_blocks_from_guest(&self.guest)?;
-        waiter.block_wait()?;
-
-        span.read_from_blocks_into_buffer(buf);
+        let data = Buffer::new(buf.len());
+        block_on(self.guest.read_bytes(self.offset, data.clone()))?;
+        buf.copy_from_slice(&data.as_vec());
 
         // TODO: for block devices, we can't increment offset past the
         // device size but we're supposed to be pretending to be a proper
@@ -265,42 +306,8 @@ impl CruciblePseudoFile {
     }
 
     fn _write(&mut self, buf: &[u8]) -> Result<usize, CrucibleError> {
-        let mut span =
-            IOSpan::new(self.offset, buf.len() as u64, self.block_size);
-
-        /*
-         * Crucible's dependency system will properly resolve requests in
-         * the order they are received but if the request is not block
-         * aligned and block sized we need to do read-modify-write (RMW)]
-         * here. Use a reader-writer lock, and grab the write portion of
-         * the lock when doing RMW to cause all other operations (which
-         * only grab the read portion
-         * of the lock) to pause. Otherwise all operations can use the
-         * read portion of this lock and Crucible will sort it out.
-         */
-        if !span.is_block_regular() {
-            let _guard = self.rmw_lock.write().unwrap();
-
-            let mut waiter =
-                span.read_affected_blocks_from_guest(&self.guest)?;
-            waiter.block_wait()?;
-
-            span.write_from_buffer_into_blocks(buf);
-
-            let mut waiter =
-                span.write_affected_blocks_to_guest(&self.guest)?;
-            waiter.block_wait()?;
-        } else {
-            let _guard = self.rmw_lock.read().unwrap();
-
-            let offset = Block::new(
-                self.offset / self.block_size,
-                self.block_size.trailing_zeros(),
-            );
-            let bytes = BytesMut::from(buf);
-            let mut waiter = self.guest.write(offset, bytes.freeze())?;
-            waiter.block_wait()?;
-        }
+        let data = Bytes::copy_from_slice(buf);
+        block_on(self.guest.write_bytes(self.offset, data))?;
 
         // TODO: can't increment offset past the device size
         self.offset += buf.len() as u64;
@@ -309,8 +316,6 @@ impl CruciblePseudoFile {
     }
 
     fn _flush(&mut self) -> Result<(), CrucibleError> {
-        let _guard = self.rmw_lock.write().unwrap();
-
         let mut waiter = self.guest.flush()?;
         waiter.block_wait()?;
 
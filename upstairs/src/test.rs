@@ -166,11 +166,190 @@ mod test {
         }
     }
 
+    /*
+     * Answer IO sent to a guest from memory, one request at a time in the
+     * order sent, as the upstairs keeps it in order.
+     */
+    fn serve_from_memory(
+        guest: Arc<Guest>,
+        block_size: u64,
+        blocks: u64,
+    ) -> Arc<Mutex<Vec<u8>>> {
+        guest.set_active();
+        let disk = Arc::new(Mutex::new(vec![0u8; (block_size * blocks) as _]));
+
+        let d = disk.clone();
+        tokio::spawn(async move {
+            loop {
+                let req = guest.recv().await;
+                let result = match &req.op {
+                    BlockOp::Read { offset, data } => {
+                        let at = offset.byte_value() as usize;
+                        let mut data = data.as_vec();
+                        let len = data.len();
+                        assert_eq!(len as u64 % block_size, 0);
+                        data.copy_from_slice(&d.lock().unwrap()[at..at + len]);
+                        Ok(())
+                    }
+                    BlockOp::Write { offset, data } => {
+                        let at = offset.byte_value() as usize;
+                        assert_eq!(data.len() as u64 % block_size, 0);
+                        d.lock().unwrap()[at..at + data.len()]
+                            .copy_from_slice(data);
+                        Ok(())
+                    }
+                    BlockOp::Flush { .. } => Ok(()),
+                    BlockOp::GoActive { .. } => Ok(()),
+                    BlockOp::QueryUpstairsActive { data } => {
+                        *data.lock().unwrap() = true;
+                        Ok(())
+                    }
+                    BlockOp::QueryUpstairsUuid { .. } => Ok(()),
+                    BlockOp::QueryTotalSize { data } => {
+                        *data.lock().unwrap() = block_size * blocks;
+                        Ok(())
+                    }
+                    BlockOp::QueryBlockSize { data } => {
+                        *data.lock().unwrap() = block_size;
+                        Ok(())
+                    }
+                    op => panic!("unexpected {:?}", op),
+                };
+                let _ = req.send.send(result);
+                tokio::task::yield_now().await;
+            }
+        });
+
+        disk
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn write_bytes_overlapping_unaligned() {
+        let guest = Arc::new(Guest::new());
+        let disk = serve_from_memory(guest.clone(), 512, 16);
+
+        /*
+         * Each write shares blocks with the writes either side of it, and
+         * overlaps them by 100 bytes.  A read-modify-write that lets
+         * another in between its read and its write puts back what it
+         * read, and loses the other write.
+         */
+        let writes: Vec<_> = (0..16u8)
+            .map(|i| {
+                let guest = guest.clone();
+                tokio::spawn(async move {
+                    let data = Bytes::from(vec![i + 1; 400]);
+                    guest.write_bytes(7 + i as u64 * 300, data).await
+                })
+            })
+            .collect();
+        for w in writes {
+            w.await.unwrap().unwrap();
+        }
+
+        let data = Buffer::new(15 * 300 + 400);
+        guest.read_bytes(7, data.clone()).await.unwrap();
+        let data = data.as_vec();
+        assert_eq!(data[..], disk.lock().unwrap()[7..7 + data.len()]);
+
+        for i in 0..16 {
+            let at = i * 300;
+            let me = i as u8 + 1;
+
+            // What only this write covers is all there.
+            let only = if i == 0 { 0 } else { at + 100 };
+            let end = if i == 15 { at + 400 } else { at + 300 };
+            assert!(data[only..end].iter().all(|b| *b == me));
+
+            // And the next write either came before it or after it.
+            if i < 15 {
+                let shared = &data[at + 300..at + 400];
+                assert!(
+                    shared.iter().all(|b| *b == me)
+                        || shared.iter().all(|b| *b == me + 1)
+                );
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn pseudo_file_512_sectors_on_4k_blocks() {
+        let guest = Arc::new(Guest::new());
+        let disk = serve_from_memory(guest.clone(), 4096, 4);
+
+        /*
+         * The pseudo file blocks, as nbd_server does, so it goes on a
+         * thread of its own.  The guest only ever sees whole blocks.
+         */
+        let (data, expected) = tokio::task::spawn_blocking(move || {
+            let mut cpf =
+                CruciblePseudoFile::with_sector_size(guest, 512).unwrap();
+            cpf.activate(1).unwrap();
+            assert_eq!(cpf.block_size(), 512);
+            assert_eq!(cpf.physical_block_size(), 4096);
+            assert_eq!(cpf.sz(), 4 * 4096);
+
+            /*
+             * Sectors inside one block, sectors that cross a block
+             * boundary, and a whole block written over one that was
+             * partly written.
+             */
+            let mut expected = vec![0u8; cpf.sz() as usize];
+            for (sector, count, fill) in
+                [(1, 1, 1u8), (7, 3, 2), (9, 1, 3), (15, 2, 4), (16, 8, 5)]
+            {
+                let start = sector * 512;
+                let end = start + count * 512;
+                cpf.seek(SeekFrom::Start(start as u64)).unwrap();
+                cpf.write_all(&vec![fill; end - start]).unwrap();
+                expected[start..end].fill(fill);
+            }
+            cpf.flush().unwrap();
+
+            let mut data = vec![0u8; expected.len()];
+            cpf.seek(SeekFrom::Start(0)).unwrap();
+            cpf.read_exact(&mut data).unwrap();
+            (data, expected)
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(data, expected);
+        assert_eq!(*disk.lock().unwrap(), expected);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn pseudo_file_sector_size_checked() {
+        let guest = Arc::new(Guest::new());
+        serve_from_memory(guest.clone(), 512, 4);
+
+        assert!(
+            CruciblePseudoFile::with_sector_size(guest.clone(), 256).is_err()
+        );
+        assert!(
+            CruciblePseudoFile::with_sector_size(guest.clone(), 1000).is_err()
+        );
+
+        // A sector can't be larger than the block it is in.
+        let result = tokio::task::spawn_blocking(move || {
+            CruciblePseudoFile::with_sector_size(guest, 4096)
+                .unwrap()
+                .activate(1)
+        })
+        .await
+        .unwrap();
+        assert!(matches!(result, Err(CrucibleError::InvalidBlockSize(_))));
+    }
+
     /*
      * Beware, if you change these defaults, then you will have to change
      * all the hard coded tests below that use make_upstairs().
      */
     fn make_upstairs() -> Arc<Upstairs> {
+        make_upstairs_with(false)
+    }
+
+    fn make_upstairs_with(read_only: bool) -> Arc<Upstairs> {
         let mut def = RegionDefinition::default();
         def.set_block_size(512);
         def.set_extent_size(Block::new_512(100));
@@ -180,6 +359,12 @@ mod test {
             target: vec![],
             lossy: false,
             key: None,
+            legacy_xts: false,
+            control: None,
+            policy: None,
+            read_only,
+            job_timeout: None,
+            uuid_mismatch: None,
         };
 
         Upstairs::new(&opts, def, Arc::new(Guest::new()))
@@ -342,17 +527,28 @@ mod test {
         let key_bytes =
             base64::decode("ClENKTXD2bCyXSHnKXY7GGnk+NvQKbwpatjWP2fJzk0=")
                 .unwrap();
-        let context = EncryptionContext::new(Vec::<u8>::from(key_bytes), 512);
+        let context = EncryptionContext::new(Vec::<u8>::from(key_bytes), false);
 
         let mut block = [0u8; 512];
         thread_rng().fill(&mut block[..]);
 
         let orig_block = block.clone();
 
-        context.encrypt_in_place(&mut block[..], 0);
+        let (nonce, tag) = context.encrypt_in_place(&mut block[..], 1, 0);
         assert_ne!(block, orig_block);
+        assert_eq!(nonce.len(), NONCE_SIZE + 1);
+        assert_eq!(nonce[0], ENCRYPTION_VERSION);
+        assert_eq!(tag.len(), TAG_SIZE);
 
-        context.decrypt_in_place(&mut block[..], 0);
+        context
+            .decrypt_in_place(
+                &mut block[..],
+                1,
+                0,
+                Some(&nonce[..]),
+                Some(&tag[..]),
+            )
+            .unwrap();
         assert_eq!(block, orig_block);
     }
 
@@ -363,7 +559,7 @@ mod test {
         let key_bytes =
             base64::decode("EVrH+ABhMP0MLfxynCalDq1vWCCWCWFfsSsJoJeDCx8=")
                 .unwrap();
-        let context = EncryptionContext::new(Vec::<u8>::from(key_bytes), 512);
+        let context = EncryptionContext::new(Vec::<u8>::from(key_bytes), false);
 
         let mut block = [0u8; 512];
         thread_rng().fill(&mut block[..]);
@@ -371,11 +567,142 @@ mod test {
         let orig_block = block.clone();
 
         // The wrong block index shouldn't work.
-        context.encrypt_in_place(&mut block[..], 0);
+        let (nonce, tag) = context.encrypt_in_place(&mut block[..], 0, 0);
+        assert_ne!(block, orig_block);
+
+        let res = context.decrypt_in_place(
+            &mut block[..],
+            0,
+            1,
+            Some(&nonce[..]),
+            Some(&tag[..]),
+        );
+        assert!(matches!(res, Err(CrucibleError::DecryptionError(_))));
+
+        // Nor should the right block in another extent.
+        let res = context.decrypt_in_place(
+            &mut block[..],
+            1,
+            0,
+            Some(&nonce[..]),
+            Some(&tag[..]),
+        );
+        assert!(matches!(res, Err(CrucibleError::DecryptionError(_))));
         assert_ne!(block, orig_block);
+    }
+
+    #[test]
+    pub fn test_upstairs_encryption_context_tampered() {
+        use rand::{thread_rng, Rng};
+
+        let key_bytes =
+            base64::decode("ClENKTXD2bCyXSHnKXY7GGnk+NvQKbwpatjWP2fJzk0=")
+                .unwrap();
+        let context = EncryptionContext::new(Vec::<u8>::from(key_bytes), false);
+
+        let mut block = [0u8; 512];
+        thread_rng().fill(&mut block[..]);
+        let (nonce, mut tag) = context.encrypt_in_place(&mut block[..], 2, 3);
+
+        let mut changed = block;
+        changed[100] ^= 1;
+        let res = context.decrypt_in_place(
+            &mut changed[..],
+            2,
+            3,
+            Some(&nonce[..]),
+            Some(&tag[..]),
+        );
+        assert!(matches!(res, Err(CrucibleError::DecryptionError(_))));
+
+        tag[0] ^= 1;
+        let res = context.decrypt_in_place(
+            &mut block[..],
+            2,
+            3,
+            Some(&nonce[..]),
+            Some(&tag[..]),
+        );
+        assert!(matches!(res, Err(CrucibleError::DecryptionError(_))));
+
+        // A short tag is refused, not a panic.
+        let res = context.decrypt_in_place(
+            &mut block[..],
+            2,
+            3,
+            Some(&nonce[..]),
+            Some(&tag[..4]),
+        );
+        assert!(matches!(res, Err(CrucibleError::DecryptionError(_))));
+
+        // So is a version we don't know.
+        tag[0] ^= 1;
+        let mut nonce = nonce;
+        nonce[0] = ENCRYPTION_VERSION + 1;
+        let res = context.decrypt_in_place(
+            &mut block[..],
+            2,
+            3,
+            Some(&nonce[..]),
+            Some(&tag[..]),
+        );
+        assert!(matches!(res, Err(CrucibleError::DecryptionError(_))));
+    }
+
+    #[test]
+    pub fn test_upstairs_encryption_context_unwritten() {
+        let key_bytes =
+            base64::decode("ClENKTXD2bCyXSHnKXY7GGnk+NvQKbwpatjWP2fJzk0=")
+                .unwrap();
+        let context = EncryptionContext::new(key_bytes.clone(), false);
+
+        // A block with no context can't be told from one a downstairs
+        // has wiped, unless the volume says it has blocks like that.
+        let mut block = [0u8; 512];
+        let res = context.decrypt_in_place(&mut block[..], 0, 0, None, None);
+        assert!(matches!(res, Err(CrucibleError::DecryptionError(_))));
+
+        // There, a block never written reads as zeros.
+        let context = EncryptionContext::new(key_bytes, true);
+        context
+            .decrypt_in_place(&mut block[..], 0, 0, None, None)
+            .unwrap();
+        assert_eq!(block, [0u8; 512]);
+    }
+
+    #[test]
+    pub fn test_upstairs_encryption_context_legacy_xts() {
+        use aes::cipher::generic_array::GenericArray;
+        use aes::{Aes128, NewBlockCipher};
+        use rand::{thread_rng, Rng};
+        use xts_mode::{get_tweak_default, Xts128};
+
+        let key_bytes =
+            base64::decode("ClENKTXD2bCyXSHnKXY7GGnk+NvQKbwpatjWP2fJzk0=")
+                .unwrap();
+        let context = EncryptionContext::new(key_bytes.clone(), true);
 
-        context.decrypt_in_place(&mut block[..], 1);
+        // A block written before blocks had a version, as that was done.
+        let xts = Xts128::<Aes128>::new(
+            Aes128::new(GenericArray::from_slice(&key_bytes[..16])),
+            Aes128::new(GenericArray::from_slice(&key_bytes[16..])),
+        );
+        let mut block = [0u8; 4096];
+        thread_rng().fill(&mut block[..]);
+        let orig_block = block;
+        xts.encrypt_area(&mut block[..], 512, 7, get_tweak_default);
         assert_ne!(block, orig_block);
+
+        // Only read on a volume that says it has them.
+        let strict = EncryptionContext::new(key_bytes.clone(), false);
+        let mut copy = block;
+        let res = strict.decrypt_in_place(&mut copy[..], 0, 7, None, None);
+        assert!(matches!(res, Err(CrucibleError::DecryptionError(_))));
+
+        context
+            .decrypt_in_place(&mut block[..], 0, 7, None, None)
+            .unwrap();
+        assert_eq!(block, orig_block);
     }
 
     #[test]
@@ -386,7 +713,7 @@ mod test {
 
         let next_id = work.next_id();
 
-        let op = create_flush(next_id, vec![], 10, 0, 0);
+        let op = create_flush(next_id, vec![], 10, 0, 0, None);
 
         work.enqueue(op);
 
@@ -419,7 +746,7 @@ mod test {
 
         let next_id = work.next_id();
 
-        let op = create_flush(next_id, vec![], 10, 0, 0);
+        let op = create_flush(next_id, vec![], 10, 0, 0, None);
 
         work.enqueue(op);
 
@@ -460,7 +787,7 @@ mod test {
 
         let next_id = work.next_id();
 
-        let op = create_flush(next_id, vec![], 10, 0, 0);
+        let op = create_flush(next_id, vec![], 10, 0, 0, None);
 
         work.enqueue(op);
 
@@ -792,6 +1119,139 @@ mod test {
         }
     }
 
+    #[test]
+    fn work_read_decrypt_failure_uses_another_downstairs() {
+        use rand::{thread_rng, Rng};
+
+        let opts = CrucibleOpts {
+            target: vec![],
+            lossy: false,
+            key: Some("ClENKTXD2bCyXSHnKXY7GGnk+NvQKbwpatjWP2fJzk0=".into()),
+            legacy_xts: false,
+            control: None,
+            policy: None,
+            read_only: false,
+            job_timeout: None,
+            uuid_mismatch: None,
+        };
+        let upstairs = Upstairs::new(
+            &opts,
+            RegionDefinition::default(),
+            Arc::new(Guest::new()),
+        );
+        upstairs.set_active();
+
+        let request = ReadRequest {
+            eid: 1,
+            offset: Block::new_512(7),
+            num_blocks: 1,
+        };
+
+        let mut block = vec![0u8; 512];
+        thread_rng().fill(&mut block[..]);
+        let orig_block = block.clone();
+        let (nonce, tag) = upstairs
+            .encryption_context
+            .as_ref()
+            .unwrap()
+            .encrypt_in_place(&mut block[..], 1, 7);
+
+        let next_id = {
+            let mut work = upstairs.downstairs.lock().unwrap();
+            let next_id = work.next_id();
+            let op =
+                create_read_eob(next_id, vec![], 10, vec![request.clone()]);
+            work.enqueue(op);
+            work.in_progress(next_id, 0);
+            work.in_progress(next_id, 1);
+            work.in_progress(next_id, 2);
+            next_id
+        };
+
+        let response = |data: &[u8]| {
+            let mut response =
+                ReadResponse::from_request_with_data(&request, data);
+            response.nonce = Some(nonce.clone());
+            response.tag = Some(tag.clone());
+            Ok(vec![response])
+        };
+
+        // A block changed on one downstairs is an error from that one.
+        let mut changed = block.clone();
+        changed[10] ^= 1;
+        assert_eq!(
+            upstairs.complete(next_id, 0, response(&changed)).unwrap(),
+            false
+        );
+
+        // And the read is answered by the next.
+        assert_eq!(
+            upstairs.complete(next_id, 1, response(&block)).unwrap(),
+            true
+        );
+
+        let work = upstairs.downstairs.lock().unwrap();
+        let job = work.active.get(&next_id).unwrap();
+        assert!(matches!(
+            job.state.get(&0),
+            Some(IOState::Error(CrucibleError::DecryptionError(_)))
+        ));
+        assert_eq!(job.ack_status, AckStatus::AckReady);
+        assert_eq!(&job.data.as_ref().unwrap()[0].data[..], &orig_block[..]);
+    }
+
+    #[test]
+    fn work_read_errors_and_skipped_is_an_error() {
+        /*
+         * One downstairs is skipped (faulted, or the target of a live
+         * repair) and the other two return errors.  Nobody has the data,
+         * so the read has to fail rather than be acked with none.
+         */
+        let upstairs = Upstairs::default();
+        upstairs.set_active();
+        let mut work = upstairs.downstairs.lock().unwrap();
+
+        let next_id = work.next_id();
+        let request = ReadRequest {
+            eid: 0,
+            offset: Block::new_512(7),
+            num_blocks: 1,
+        };
+        let op = create_read_eob(next_id, vec![], 10, vec![request]);
+        work.enqueue(op);
+
+        assert_eq!(work.skip_unfinished(2), vec![next_id]);
+        assert!(work.in_progress(next_id, 0).is_some());
+        assert!(work.in_progress(next_id, 1).is_some());
+
+        assert_eq!(
+            work.complete(
+                next_id,
+                0,
+                &Err(CrucibleError::GenericError(format!("bad")))
+            )
+            .unwrap(),
+            false
+        );
+        assert_eq!(
+            work.complete(
+                next_id,
+                1,
+                &Err(CrucibleError::GenericError(format!("bad")))
+            )
+            .unwrap(),
+            true
+        );
+
+        let job = work.active.get(&next_id).unwrap();
+        assert_eq!(job.ack_status, AckStatus::AckReady);
+        assert!(job.data.is_none());
+        assert!(matches!(
+            work.result(next_id),
+            Err(CrucibleError::IoError(_))
+        ));
+    }
+
     #[test]
     fn work_assert_ok_transfer_of_read_after_downstairs_write_errors() {
         let upstairs = Upstairs::default();
@@ -1068,7 +1528,7 @@ mod test {
         // A flush is required to move work to completed
         // Create the flush then send it to all downstairs.
         let next_id = work.next_id();
-        let op = create_flush(next_id, vec![], 10, 0, 0);
+        let op = create_flush(next_id, vec![], 10, 0, 0, None);
 
         work.enqueue(op);
 
@@ -1159,7 +1619,7 @@ mod test {
 
         // Create the flush, put on the work queue
         let flush_id = work.next_id();
-        let op = create_flush(flush_id, vec![], 10, 0, 0);
+        let op = create_flush(flush_id, vec![], 10, 0, 0, None);
         work.enqueue(op);
 
         // Simulate sending the flush to downstairs 0 and 1
@@ -1251,7 +1711,7 @@ mod test {
 
         // Create the flush IO
         let next_id = work.next_id();
-        let op = create_flush(next_id, vec![], 10, 0, 0);
+        let op = create_flush(next_id, vec![], 10, 0, 0, None);
         work.enqueue(op);
 
         // Submit the flush to all three downstairs.
@@ -1339,7 +1799,7 @@ mod test {
 
         // Create and enqueue the flush.
         let flush_id = work.next_id();
-        let op = create_flush(flush_id, vec![], 10, 0, 0);
+        let op = create_flush(flush_id, vec![], 10, 0, 0, None);
         work.enqueue(op);
 
         // Send the flush to two downstairs.
@@ -1653,4 +2113,1286 @@ mod test {
         assert_eq!(work.complete(id1, 0, &Ok(vec![])).unwrap(), false);
         assert_eq!(work.complete(id1, 2, &Ok(vec![])).unwrap(), false);
     }
+
+    /*
+     * Set up an active upstairs with downstairs 2 joining under live
+     * repair.
+     */
+    fn live_repair_upstairs() -> Arc<Upstairs> {
+        let up = make_upstairs();
+        up.set_active();
+        {
+            let mut ds = up.downstairs.lock().unwrap();
+            ds.ds_state[0] = DsState::Active;
+            ds.ds_state[1] = DsState::Active;
+            ds.ds_state[2] = DsState::Failed;
+        }
+        up.ds_live_repair_join(2).unwrap();
+        up
+    }
+
+    fn repair_extent(eid: u64) -> ExtentData {
+        ExtentData {
+            eid,
+            data: Bytes::from(vec![1u8; 512]),
+            contexts: vec![None],
+            checksums: vec![None],
+            gen_number: 1,
+            flush_number: 2,
+        }
+    }
+
+    #[test]
+    fn live_repair_join_skips_old_work() {
+        let up = make_upstairs();
+        up.set_active();
+        let mut ds = up.downstairs.lock().unwrap();
+        ds.ds_state[0] = DsState::Active;
+        ds.ds_state[1] = DsState::Active;
+        ds.ds_state[2] = DsState::Failed;
+
+        let id1 = ds.next_id();
+        let op = create_flush(id1, vec![], 10, 0, 0, None);
+        ds.enqueue(op);
+        drop(ds);
+
+        let lf = up.ds_live_repair_join(2).unwrap();
+        assert_eq!(lf, id1);
+
+        let ds = up.downstairs.lock().unwrap();
+        assert_eq!(ds.ds_state[2], DsState::LiveRepair);
+        let job = ds.active.get(&id1).unwrap();
+        assert_eq!(job.state.get(&0), Some(&IOState::New));
+        assert_eq!(job.state.get(&2), Some(&IOState::Skipped));
+        drop(ds);
+
+        // Only one repair at a time.
+        assert!(up.ds_live_repair_join(1).is_err());
+    }
+
+    #[test]
+    fn live_repair_read_feeds_write() {
+        let up = live_repair_upstairs();
+        assert!(up.live_repair_step());
+
+        let mut ds = up.downstairs.lock().unwrap();
+        let (read_id, write_id) = ds.live_repair.unwrap().pending.unwrap();
+
+        // The read goes to the first healthy downstairs only.
+        let read = ds.active.get(&read_id).unwrap();
+        assert_eq!(read.state.get(&0), Some(&IOState::New));
+        assert_eq!(read.state.get(&1), Some(&IOState::Skipped));
+        assert_eq!(read.state.get(&2), Some(&IOState::Skipped));
+
+        // The write can't go until the read data is here.
+        assert!(ds.in_progress(write_id, 2).is_none());
+        assert!(ds.in_progress(read_id, 0).is_some());
+        ds.repair_complete(read_id, 0, Ok(Some(repair_extent(0))))
+            .unwrap();
+
+        // Now the write carries the data, and doesn't wait on a read that
+        // downstairs 2 never saw.
+        match ds.in_progress(write_id, 2).unwrap() {
+            IOop::ExtentRepairWrite {
+                dependencies,
+                eid,
+                extent,
+            } => {
+                assert!(!dependencies.contains(&read_id));
+                assert_eq!(eid, 0);
+                assert_eq!(extent, Some(repair_extent(0)));
+            }
+            x => panic!("unexpected work {:?}", x),
+        }
+        ds.repair_complete(write_id, 2, Ok(None)).unwrap();
+        drop(ds);
+
+        // The next step moves on to the next extent.
+        assert!(up.live_repair_step());
+        let ds = up.downstairs.lock().unwrap();
+        let lr = ds.live_repair.unwrap();
+        assert_eq!(lr.next_eid, 1);
+        assert_ne!(lr.pending, Some((read_id, write_id)));
+    }
+
+    #[test]
+    fn live_repair_read_after_write_is_gone() {
+        let up = live_repair_upstairs();
+        assert!(up.live_repair_step());
+
+        let mut ds = up.downstairs.lock().unwrap();
+        let (read_id, write_id) = ds.live_repair.unwrap().pending.unwrap();
+        assert_eq!(
+            ds.active.get(&read_id).unwrap().repair_write,
+            Some(write_id)
+        );
+
+        assert!(ds.in_progress(read_id, 0).is_some());
+        ds.active.remove(&write_id);
+        ds.repair_complete(read_id, 0, Ok(Some(repair_extent(0))))
+            .unwrap();
+        assert_eq!(
+            ds.active.get(&read_id).unwrap().state.get(&0),
+            Some(&IOState::Done)
+        );
+    }
+
+    #[test]
+    fn live_repair_read_error_fails_dest() {
+        let up = live_repair_upstairs();
+        assert!(up.live_repair_step());
+
+        let mut ds = up.downstairs.lock().unwrap();
+        let (read_id, write_id) = ds.live_repair.unwrap().pending.unwrap();
+        assert!(ds.in_progress(read_id, 0).is_some());
+        ds.repair_complete(
+            read_id,
+            0,
+            Err(CrucibleError::GenericError("bad".to_string())),
+        )
+        .unwrap();
+
+        // The write will never be sent.
+        let write = ds.active.get(&write_id).unwrap();
+        assert_eq!(write.state.get(&2), Some(&IOState::Skipped));
+        drop(ds);
+
+        assert!(!up.live_repair_step());
+        let ds = up.downstairs.lock().unwrap();
+        assert!(ds.live_repair.is_none());
+        assert_eq!(ds.ds_state[2], DsState::Failed);
+    }
+
+    #[test]
+    fn live_repair_reads_skip_dest() {
+        let up = live_repair_upstairs();
+        let mut ds = up.downstairs.lock().unwrap();
+
+        let next_id = ds.next_id();
+        let request = ReadRequest {
+            eid: 0,
+            offset: Block::new_512(7),
+            num_blocks: 2,
+        };
+        let op = create_read_eob(next_id, vec![], 10, vec![request]);
+        ds.enqueue(op);
+
+        assert!(ds.in_progress(next_id, 0).is_some());
+        assert!(ds.in_progress(next_id, 2).is_none());
+        let job = ds.active.get(&next_id).unwrap();
+        assert_eq!(job.state.get(&2), Some(&IOState::Skipped));
+    }
+
+    fn flush_limit(work: IOop) -> Option<u64> {
+        match work {
+            IOop::Flush { extent_limit, .. } => extent_limit,
+            x => panic!("unexpected work {:?}", x),
+        }
+    }
+
+    #[test]
+    fn live_repair_flush_stops_at_repair_point() {
+        let up = live_repair_upstairs();
+        let mut ds = up.downstairs.lock().unwrap();
+
+        // Nothing has been repaired yet, so the dest flushes nothing.
+        let id1 = ds.next_id();
+        ds.enqueue(create_flush(id1, vec![], 10, 0, 0, None));
+        assert_eq!(flush_limit(ds.in_progress(id1, 0).unwrap()), None);
+        assert_eq!(flush_limit(ds.in_progress(id1, 2).unwrap()), Some(0));
+        drop(ds);
+
+        // A flush behind the repair write for extent 0 covers extent 0.
+        assert!(up.live_repair_step());
+        let mut ds = up.downstairs.lock().unwrap();
+        let id2 = ds.next_id();
+        ds.enqueue(create_flush(id2, vec![], 11, 0, 0, None));
+        assert_eq!(flush_limit(ds.in_progress(id2, 1).unwrap()), None);
+        assert_eq!(flush_limit(ds.in_progress(id2, 2).unwrap()), Some(1));
+    }
+
+    #[test]
+    fn complete_without_state_is_an_error() {
+        let mut ds = Downstairs::default();
+        let id1 = ds.next_id();
+        ds.enqueue(create_flush(id1, vec![], 10, 0, 0, None));
+        ds.active.get_mut(&id1).unwrap().state.remove(&2);
+        assert!(ds.complete(id1, 2, &Ok(vec![])).is_err());
+        assert!(ds.repair_complete(id1, 2, Ok(None)).is_err());
+    }
+
+    fn region_metadata(
+        generation: Vec<u64>,
+        flush_numbers: Vec<u64>,
+        dirty: Vec<bool>,
+    ) -> RegionMetadata {
+        RegionMetadata {
+            generation,
+            flush_numbers,
+            dirty,
+        }
+    }
+
+    #[test]
+    fn reconcile_list_all_match() {
+        let mut ds = Downstairs::default();
+        for cid in 0..3 {
+            ds.region_metadata.insert(
+                cid,
+                region_metadata(vec![1, 1], vec![3, 4], vec![false, false]),
+            );
+        }
+        assert!(ds.reconcile_list().is_empty());
+    }
+
+    #[test]
+    fn reconcile_list_flush_mismatch() {
+        let mut ds = Downstairs::default();
+        ds.region_metadata.insert(
+            0,
+            region_metadata(vec![1, 1], vec![3, 4], vec![false, false]),
+        );
+        ds.region_metadata.insert(
+            1,
+            region_metadata(vec![1, 1], vec![3, 5], vec![false, false]),
+        );
+        ds.region_metadata.insert(
+            2,
+            region_metadata(vec![1, 1], vec![2, 4], vec![false, false]),
+        );
+
+        assert_eq!(
+            ds.reconcile_list(),
+            vec![(0, 0, vec![2]), (1, 1, vec![0, 2])]
+        );
+    }
+
+    #[test]
+    fn reconcile_list_gen_wins_over_flush() {
+        let mut ds = Downstairs::default();
+        ds.region_metadata
+            .insert(0, region_metadata(vec![1], vec![9], vec![false]));
+        ds.region_metadata
+            .insert(1, region_metadata(vec![2], vec![1], vec![false]));
+        ds.region_metadata
+            .insert(2, region_metadata(vec![1], vec![9], vec![false]));
+
+        assert_eq!(ds.reconcile_list(), vec![(0, 1, vec![0, 2])]);
+    }
+
+    #[test]
+    fn reconcile_list_dirty() {
+        let mut ds = Downstairs::default();
+        ds.region_metadata
+            .insert(0, region_metadata(vec![1], vec![3], vec![true]));
+        ds.region_metadata
+            .insert(1, region_metadata(vec![1], vec![3], vec![false]));
+        ds.region_metadata
+            .insert(2, region_metadata(vec![1], vec![3], vec![true]));
+
+        // The clean copy is used, and both dirty ones are repaired.
+        assert_eq!(ds.reconcile_list(), vec![(0, 1, vec![0, 2])]);
+
+        // All dirty with the same numbers, the first one is picked.
+        ds.region_metadata
+            .insert(1, region_metadata(vec![1], vec![3], vec![true]));
+        assert_eq!(ds.reconcile_list(), vec![(0, 0, vec![1, 2])]);
+    }
+
+    /*
+     * Set up an upstairs with all three downstairs waiting for quorum, and
+     * extent 1 on downstairs 2 behind the others.
+     */
+    fn reconcile_upstairs() -> Arc<Upstairs> {
+        reconcile_upstairs_with(false)
+    }
+
+    fn reconcile_upstairs_with(read_only: bool) -> Arc<Upstairs> {
+        let up = make_upstairs_with(read_only);
+        let mut ds = up.downstairs.lock().unwrap();
+        for cid in 0..3 {
+            ds.ds_state[cid as usize] = DsState::WaitQuorum;
+            ds.region_metadata.insert(
+                cid,
+                region_metadata(vec![1, 1], vec![3, 3], vec![false, false]),
+            );
+        }
+        ds.region_metadata.get_mut(&2).unwrap().flush_numbers[1] = 2;
+        drop(ds);
+        up
+    }
+
+    #[test]
+    fn reconcile_repairs_before_active() {
+        let up = reconcile_upstairs();
+
+        assert!(!up.ds_reconciliation());
+        assert!(!up.is_active());
+
+        let mut ds = up.downstairs.lock().unwrap();
+        assert_eq!(ds.ds_state, vec![DsState::Verifying; 3]);
+        assert_eq!(ds.active.len(), 2);
+        let read_id = *ds.active.keys().min().unwrap();
+        let write_id = read_id + 1;
+
+        assert!(ds.in_progress(read_id, 0).is_some());
+        ds.repair_complete(read_id, 0, Ok(Some(repair_extent(1))))
+            .unwrap();
+        assert!(ds.in_progress(write_id, 2).is_some());
+        drop(ds);
+
+        // Not done until the write is done.
+        assert!(!up.ds_reconciliation());
+
+        up.downstairs
+            .lock()
+            .unwrap()
+            .repair_complete(write_id, 2, Ok(None))
+            .unwrap();
+        assert!(up.ds_reconciliation());
+        assert!(up.is_active());
+
+        let ds = up.downstairs.lock().unwrap();
+        assert_eq!(ds.ds_state, vec![DsState::Active; 3]);
+        assert!(ds.active.is_empty());
+        drop(ds);
+
+        assert_eq!(up.flush_info.lock().unwrap().next_flush, 4);
+    }
+
+    #[test]
+    fn reconcile_read_only_does_not_repair() {
+        let up = reconcile_upstairs_with(true);
+
+        assert!(!up.ds_reconciliation());
+        assert!(!up.is_active());
+
+        let ds = up.downstairs.lock().unwrap();
+        assert_eq!(ds.ds_state, vec![DsState::WaitQuorum; 3]);
+        assert!(ds.active.is_empty());
+    }
+
+    #[test]
+    fn read_only_refuses_write() {
+        let up = make_upstairs_with(true);
+        up.set_active();
+
+        let (send, recv) = std_mpsc::channel();
+        let res = up.submit_write(
+            Block::new_512(0),
+            Bytes::from(vec![1; 512]),
+            send,
+            None,
+        );
+        assert_eq!(res, Err(CrucibleError::ModifyingReadOnlyRegion));
+        assert!(recv.try_recv().is_err());
+        assert!(up.downstairs.lock().unwrap().active.is_empty());
+    }
+
+    #[test]
+    fn read_only_flush_acks_without_work() {
+        let up = make_upstairs_with(true);
+        up.set_active();
+        up.set_flush_need();
+        assert!(!up.flush_needed());
+
+        let (send, recv) = std_mpsc::channel();
+        up.submit_flush(Some(send), None, None).unwrap();
+        assert_eq!(recv.try_recv().unwrap(), Ok(()));
+        assert!(up.downstairs.lock().unwrap().active.is_empty());
+        assert!(up.guest.guest_work.lock().unwrap().active.is_empty());
+    }
+
+    #[test]
+    fn read_only_refuses_snapshot() {
+        let up = make_upstairs_with(true);
+        up.set_active();
+
+        let (send, recv) = std_mpsc::channel();
+        let snapshot = SnapshotDetails {
+            snapshot_name: "snap".to_string(),
+        };
+        assert!(matches!(
+            up.submit_flush(Some(send), None, Some(snapshot)),
+            Err(CrucibleError::SnapshotFailed(_))
+        ));
+        assert!(recv.try_recv().is_err());
+    }
+
+    #[test]
+    fn snapshot_goes_out_with_flush() {
+        let up = make_upstairs();
+        up.set_active();
+
+        let (send, _recv) = std_mpsc::channel();
+        let snapshot = SnapshotDetails {
+            snapshot_name: "snap".to_string(),
+        };
+        up.submit_flush(Some(send), None, Some(snapshot.clone()))
+            .unwrap();
+
+        let ds = up.downstairs.lock().unwrap();
+        assert_eq!(ds.active.len(), 1);
+        match &ds.active.values().next().unwrap().work {
+            IOop::Flush {
+                snapshot_details, ..
+            } => assert_eq!(snapshot_details, &Some(snapshot)),
+            w => panic!("expected a flush, got {:?}", w),
+        }
+    }
+
+    #[test]
+    fn reconcile_restarts_on_missing_downstairs() {
+        let up = reconcile_upstairs();
+        assert!(!up.ds_reconciliation());
+
+        up.downstairs.lock().unwrap().ds_state[1] = DsState::Disconnected;
+        assert!(!up.ds_reconciliation());
+
+        let ds = up.downstairs.lock().unwrap();
+        assert!(ds.active.is_empty());
+        assert_eq!(
+            ds.ds_state,
+            vec![
+                DsState::WaitQuorum,
+                DsState::Disconnected,
+                DsState::WaitQuorum
+            ]
+        );
+        drop(ds);
+        assert!(!up.is_active());
+    }
+
+    #[test]
+    fn replay_abandon_skips_unfinished_work() {
+        let upstairs = Upstairs::default();
+        upstairs.set_active();
+        let mut work = upstairs.downstairs.lock().unwrap();
+        work.ds_state[0] = DsState::Active;
+        work.ds_state[1] = DsState::Offline;
+        work.ds_state[2] = DsState::Active;
+
+        let id1 = work.next_id();
+        let op = create_flush(id1, vec![], 10, 0, 0, None);
+        work.enqueue(op);
+        assert!(work.in_progress(id1, 1).is_some());
+
+        let id2 = work.next_id();
+        let op = create_flush(id2, vec![id1], 11, 0, 0, None);
+        work.enqueue(op);
+
+        work.ds_replay_abandon(1);
+
+        assert_eq!(work.ds_state[1], DsState::Failed);
+        assert_eq!(work.ds_skipped_jobs[1], vec![id1, id2]);
+        for id in [id1, id2].iter() {
+            let job = work.active.get(id).unwrap();
+            assert_eq!(job.state.get(&1), Some(&IOState::Skipped));
+            assert_eq!(job.state.get(&0), Some(&IOState::New));
+        }
+        assert!(work.ds_skipped_jobs[0].is_empty());
+    }
+
+    #[test]
+    fn replay_abandon_when_too_many_jobs() {
+        let upstairs = Upstairs::default();
+        upstairs.set_active();
+        let mut work = upstairs.downstairs.lock().unwrap();
+        work.ds_state[0] = DsState::Active;
+        work.ds_state[1] = DsState::Active;
+        work.ds_state[2] = DsState::Offline;
+
+        for _ in 0..MAX_REPLAY_JOBS {
+            let id = work.next_id();
+            let op = create_flush(id, vec![], 10, 0, 0, None);
+            work.enqueue(op);
+        }
+        assert_eq!(work.ds_state[2], DsState::Offline);
+
+        let id = work.next_id();
+        let op = create_flush(id, vec![], 10, 0, 0, None);
+        work.enqueue(op);
+        assert_eq!(work.ds_state[2], DsState::Failed);
+        assert_eq!(work.ds_skipped_jobs[2].len(), MAX_REPLAY_JOBS + 1);
+        assert_eq!(work.ds_state[0], DsState::Active);
+    }
+
+    #[test]
+    fn live_repair_join_clears_skipped_jobs() {
+        let up = make_upstairs();
+        up.set_active();
+        let mut ds = up.downstairs.lock().unwrap();
+        ds.ds_state[0] = DsState::Active;
+        ds.ds_state[1] = DsState::Active;
+        ds.ds_state[2] = DsState::Offline;
+        let id1 = ds.next_id();
+        let op = create_flush(id1, vec![], 10, 0, 0, None);
+        ds.enqueue(op);
+        ds.ds_replay_abandon(2);
+        drop(ds);
+
+        up.ds_live_repair_join(2).unwrap();
+        assert!(up.downstairs.lock().unwrap().ds_skipped_jobs[2].is_empty());
+    }
+
+    #[test]
+    fn fault_downstairs_for_repair() {
+        let up = make_upstairs();
+        up.set_active();
+        let mut ds = up.downstairs.lock().unwrap();
+        ds.ds_state = vec![DsState::Active; 3];
+        let id1 = ds.next_id();
+        let op = create_flush(id1, vec![], 10, 0, 0, None);
+        ds.enqueue(op);
+        assert!(ds.in_progress(id1, 1).is_some());
+        drop(ds);
+
+        up.ds_fault(1).unwrap();
+
+        let mut ds = up.downstairs.lock().unwrap();
+        assert_eq!(ds.ds_state[1], DsState::Failed);
+        let job = ds.active.get(&id1).unwrap();
+        assert_eq!(job.state.get(&1), Some(&IOState::Skipped));
+
+        // A late answer from the faulted downstairs is ignored.
+        assert!(ds.complete(id1, 1, &Ok(vec![])).is_err());
+        let job = ds.active.get(&id1).unwrap();
+        assert_eq!(job.state.get(&1), Some(&IOState::Skipped));
+        drop(ds);
+
+        // Only one downstairs can be out at a time.
+        assert!(up.ds_fault(0).is_err());
+        assert!(up.ds_fault(3).is_err());
+    }
+
+    #[test]
+    fn complete_not_in_progress_keeps_state() {
+        let mut ds = Downstairs::default();
+        let id1 = ds.next_id();
+        ds.enqueue(create_flush(id1, vec![], 10, 0, 0, None));
+
+        // Never sent, so there is nothing to complete.
+        assert!(ds.complete(id1, 0, &Ok(vec![])).is_err());
+        let job = ds.active.get(&id1).unwrap();
+        assert_eq!(job.state.get(&0), Some(&IOState::New));
+
+        // A second answer does not replace the first.
+        assert!(ds.in_progress(id1, 0).is_some());
+        ds.complete(id1, 0, &Ok(vec![])).unwrap();
+        let e = CrucibleError::GenericError("late".to_string());
+        assert!(ds.complete(id1, 0, &Err(e.clone())).is_err());
+        let job = ds.active.get(&id1).unwrap();
+        assert_eq!(job.state.get(&0), Some(&IOState::Done));
+
+        // Same for a repair job.
+        assert!(ds.repair_complete(id1, 1, Err(e)).is_err());
+        let job = ds.active.get(&id1).unwrap();
+        assert_eq!(job.state.get(&1), Some(&IOState::New));
+    }
+
+    #[test]
+    fn fault_downstairs_not_active() {
+        let up = make_upstairs();
+        up.downstairs.lock().unwrap().ds_state = vec![DsState::Active; 3];
+        assert!(up.ds_fault(0).is_err());
+    }
+
+    #[test]
+    fn uuid_mismatch_policy_from_str() {
+        assert_eq!(
+            "renegotiate".parse::<UuidMismatchPolicy>().unwrap(),
+            UuidMismatchPolicy::Renegotiate
+        );
+        assert!("ignore".parse::<UuidMismatchPolicy>().is_err());
+        assert_eq!(UuidMismatchPolicy::default(), UuidMismatchPolicy::Fault);
+    }
+
+    #[test]
+    fn uuid_mismatch_fault() {
+        let up = make_upstairs();
+        up.set_active();
+        up.downstairs.lock().unwrap().ds_state = vec![DsState::Active; 3];
+
+        let policy = up.ds_uuid_mismatch(2, Uuid::new_v4());
+        assert_eq!(policy, UuidMismatchPolicy::Fault);
+        assert_eq!(up.ds_state(2), DsState::Failed);
+        assert!(up.is_active());
+
+        // With one already out, the next can only reconnect.
+        let policy = up.ds_uuid_mismatch(0, Uuid::new_v4());
+        assert_eq!(policy, UuidMismatchPolicy::Renegotiate);
+        assert_eq!(up.ds_state(0), DsState::Active);
+        assert_eq!(up.downstairs.lock().unwrap().ds_uuid_mismatches, [1, 0, 1]);
+    }
+
+    #[test]
+    fn uuid_mismatch_reactivate() {
+        let up = make_upstairs();
+        up.set_active();
+        let mut ds = up.downstairs.lock().unwrap();
+        ds.ds_state = vec![DsState::Active; 3];
+        ds.uuid_mismatch = UuidMismatchPolicy::Reactivate;
+        let id1 = ds.next_id();
+        let op = create_flush(id1, vec![], 10, 0, 0, None);
+        ds.enqueue(op);
+        drop(ds);
+
+        let policy = up.ds_uuid_mismatch(1, Uuid::new_v4());
+        assert_eq!(policy, UuidMismatchPolicy::Reactivate);
+        assert!(!up.is_active());
+        assert!(up.is_active_requested());
+        assert!(up.is_reactivating());
+
+        let ds = up.downstairs.lock().unwrap();
+        assert_eq!(ds.ds_state, vec![DsState::New; 3]);
+        assert!(ds.active.is_empty());
+    }
+
+    #[test]
+    fn job_timeout_validation() {
+        assert!(JobTimeout::new(Duration::from_secs(0), 3).is_err());
+        assert!(JobTimeout::new(Duration::from_secs(1), 0).is_err());
+        let jt = JobTimeout::new(Duration::from_secs(5), 2).unwrap();
+        assert_eq!(jt.timeout(), Duration::from_secs(5));
+        assert_eq!(jt.max_misses(), 2);
+    }
+
+    #[test]
+    fn deadline_misses_reset_on_answer() {
+        let upstairs = Upstairs::default();
+        let mut ds = upstairs.downstairs.lock().unwrap();
+        ds.job_timeout = JobTimeout::new(Duration::from_millis(1), 2).unwrap();
+
+        let id1 = ds.next_id();
+        let op = create_flush(id1, vec![], 10, 0, 0, None);
+        ds.enqueue(op);
+        assert!(ds.in_progress(id1, 0).is_some());
+        std::thread::sleep(Duration::from_millis(5));
+
+        // One late check is not enough to fault.
+        assert!(!ds.deadline_missed(0));
+        assert_eq!(ds.ds_deadline_misses[0], 1);
+        assert!(!ds.deadline_missed(1));
+
+        // Once the job is answered, the count starts over.
+        ds.complete(id1, 0, &Ok(vec![])).unwrap();
+        assert!(!ds.deadline_missed(0));
+        assert_eq!(ds.ds_deadline_misses[0], 0);
+        assert!(ds.ds_sent[0].is_empty());
+    }
+
+    #[test]
+    fn snapshot_flush_has_no_deadline() {
+        let upstairs = Upstairs::default();
+        let mut ds = upstairs.downstairs.lock().unwrap();
+        ds.job_timeout = JobTimeout::new(Duration::from_millis(1), 2).unwrap();
+
+        let id1 = ds.next_id();
+        let snapshot = SnapshotDetails {
+            snapshot_name: "snap".to_string(),
+        };
+        let op = create_flush(id1, vec![], 10, 0, 0, Some(snapshot));
+        ds.enqueue(op);
+        let id2 = ds.next_id();
+        let op = create_flush(id2, vec![id1], 11, 1, 0, None);
+        ds.enqueue(op);
+        assert!(ds.in_progress(id1, 0).is_some());
+        assert!(ds.in_progress(id2, 0).is_some());
+        std::thread::sleep(Duration::from_millis(5));
+
+        // Neither the snapshot nor what waits on it is late.
+        assert!(!ds.deadline_missed(0));
+        assert!(!ds.deadline_missed(0));
+        assert_eq!(ds.ds_deadline_misses[0], 0);
+
+        // Once the snapshot is done, the next job gets its full time.
+        let done = Instant::now();
+        ds.complete(id1, 0, &Ok(vec![])).unwrap();
+        assert!(*ds.ds_sent[0].get(&id2).unwrap() >= done);
+    }
+
+    #[test]
+    fn deadline_faults_and_finishes_jobs() {
+        let up = make_upstairs();
+        up.set_active();
+        let mut ds = up.downstairs.lock().unwrap();
+        ds.ds_state = vec![DsState::Active; 3];
+        ds.set_policy(ReplicationPolicy::new(3, 3, 3).unwrap());
+        ds.job_timeout = JobTimeout::new(Duration::from_millis(1), 2).unwrap();
+
+        let id1 = ds.next_id();
+        let op = create_write_eob(
+            id1,
+            vec![],
+            10,
+            vec![crucible_protocol::Write {
+                eid: 0,
+                offset: Block::new_512(7),
+                data: Bytes::from(vec![1]),
+                nonce: None,
+                tag: None,
+            }],
+        );
+        ds.enqueue(op);
+        for cid in 0..3 {
+            assert!(ds.in_progress(id1, cid).is_some());
+        }
+        ds.complete(id1, 0, &Ok(vec![])).unwrap();
+        ds.complete(id1, 1, &Ok(vec![])).unwrap();
+        assert_eq!(ds.ackable_work().len(), 0);
+        drop(ds);
+        std::thread::sleep(Duration::from_millis(5));
+
+        assert!(!up.ds_deadline_check(2));
+        assert!(up.ds_deadline_check(2));
+
+        /*
+         * The hung downstairs is out, and the write can be acked from
+         * the other two.
+         */
+        let mut ds = up.downstairs.lock().unwrap();
+        assert_eq!(ds.ds_state[2], DsState::Failed);
+        let job = ds.active.get(&id1).unwrap();
+        assert_eq!(job.state.get(&2), Some(&IOState::Skipped));
+        assert_eq!(job.ack_status, AckStatus::AckReady);
+        assert!(ds.result(id1).is_ok());
+        assert_eq!(ds.ds_deadline_misses[2], 0);
+        drop(ds);
+
+        // A faulted downstairs is not checked again.
+        assert!(!up.ds_deadline_check(2));
+    }
+
+    #[test]
+    fn io_counters_guest_work() {
+        let upstairs = Upstairs::default();
+        upstairs.set_active();
+        let mut work = upstairs.downstairs.lock().unwrap();
+
+        let id1 = work.next_id();
+        let op = create_write_eob(
+            id1,
+            vec![],
+            10,
+            vec![crucible_protocol::Write {
+                eid: 0,
+                offset: Block::new_512(7),
+                data: Bytes::from(vec![1; 512]),
+                nonce: None,
+                tag: None,
+            }],
+        );
+        work.enqueue(op);
+
+        let id2 = work.next_id();
+        let op = create_read_eob(
+            id2,
+            vec![],
+            11,
+            vec![ReadRequest {
+                eid: 0,
+                offset: Block::new_512(7),
+                num_blocks: 2,
+            }],
+        );
+        work.enqueue(op);
+
+        let id3 = work.next_id();
+        let op = create_flush(id3, vec![], 10, 12, 0, None);
+        work.enqueue(op);
+
+        work.cdt_gw_work_done(id1, 10);
+        work.cdt_gw_work_done(id2, 11);
+        work.cdt_gw_work_done(id3, 12);
+
+        assert_eq!(work.counters.write_ops, 1);
+        assert_eq!(work.counters.write_bytes, 512);
+        assert_eq!(work.counters.read_ops, 1);
+        assert_eq!(work.counters.read_bytes, 1024);
+        assert_eq!(work.counters.flush_ops, 1);
+    }
+
+    #[test]
+    fn io_counters_errors_and_replays() {
+        let upstairs = Upstairs::default();
+        upstairs.set_active();
+        let mut work = upstairs.downstairs.lock().unwrap();
+
+        let id1 = work.next_id();
+        let op = create_flush(id1, vec![], 10, 0, 0, None);
+        work.enqueue(op);
+        for cid in 0..3 {
+            assert!(work.in_progress(id1, cid).is_some());
+        }
+
+        let err = Err(CrucibleError::GenericError("bad".to_string()));
+        work.complete(id1, 2, &err).unwrap();
+        assert_eq!(work.counters.ds_errors, vec![0, 0, 1]);
+
+        work.re_new(0);
+        assert_eq!(work.counters.ds_replays, vec![1, 0, 0]);
+    }
+
+    #[test]
+    fn replication_policy_validation() {
+        assert!(ReplicationPolicy::new(0, 1, 1).is_err());
+        assert!(ReplicationPolicy::new(4, 2, 2).is_err());
+        assert!(ReplicationPolicy::new(3, 0, 2).is_err());
+        assert!(ReplicationPolicy::new(3, 2, 4).is_err());
+        assert!(ReplicationPolicy::new(2, 3, 1).is_err());
+        assert!(ReplicationPolicy::new(3, 3, 1).is_ok());
+
+        let p = ReplicationPolicy::majority(1).unwrap();
+        assert_eq!(p.write_quorum(), 1);
+        assert_eq!(p.flush_quorum(), 1);
+        let p = ReplicationPolicy::majority(2).unwrap();
+        assert_eq!(p.write_quorum(), 2);
+        let p = ReplicationPolicy::majority(3).unwrap();
+        assert_eq!(p.write_quorum(), 2);
+        assert_eq!(ReplicationPolicy::default(), p);
+    }
+
+    #[test]
+    fn single_replica_write_acks_and_retires() {
+        let upstairs = Upstairs::default();
+        upstairs.set_active();
+        let mut work = upstairs.downstairs.lock().unwrap();
+        work.set_policy(ReplicationPolicy::majority(1).unwrap());
+        assert_eq!(work.ds_state[1], DsState::Disabled);
+        assert_eq!(work.ds_state[2], DsState::Disabled);
+
+        let id1 = work.next_id();
+        let op = create_write_eob(
+            id1,
+            vec![],
+            10,
+            vec![crucible_protocol::Write {
+                eid: 0,
+                offset: Block::new_512(7),
+                data: Bytes::from(vec![1]),
+                nonce: None,
+                tag: None,
+            }],
+        );
+        work.enqueue(op);
+
+        let job = work.active.get(&id1).unwrap();
+        assert_eq!(job.state.get(&1), Some(&IOState::Skipped));
+        assert_eq!(job.state.get(&2), Some(&IOState::Skipped));
+
+        assert!(work.in_progress(id1, 0).is_some());
+        assert_eq!(work.complete(id1, 0, &Ok(vec![])).unwrap(), true);
+        assert_eq!(work.ackable_work().len(), 1);
+        work.ack(id1);
+
+        let id2 = work.next_id();
+        let op = create_flush(id2, vec![id1], 11, 0, 0, None);
+        work.enqueue(op);
+        assert!(work.in_progress(id2, 0).is_some());
+        assert_eq!(work.complete(id2, 0, &Ok(vec![])).unwrap(), true);
+        work.ack(id2);
+        work.retire_check(id2);
+        assert_eq!(work.completed.len(), 2);
+        assert!(work.active.is_empty());
+    }
+
+    #[test]
+    fn all_replica_quorum_write() {
+        let upstairs = Upstairs::default();
+        upstairs.set_active();
+        let mut work = upstairs.downstairs.lock().unwrap();
+        work.set_policy(ReplicationPolicy::new(3, 3, 3).unwrap());
+
+        let id1 = work.next_id();
+        let op = create_write_eob(
+            id1,
+            vec![],
+            10,
+            vec![crucible_protocol::Write {
+                eid: 0,
+                offset: Block::new_512(7),
+                data: Bytes::from(vec![1]),
+                nonce: None,
+                tag: None,
+            }],
+        );
+        work.enqueue(op);
+        for cid in 0..3 {
+            assert!(work.in_progress(id1, cid).is_some());
+        }
+
+        assert_eq!(work.complete(id1, 0, &Ok(vec![])).unwrap(), false);
+        assert_eq!(work.complete(id1, 1, &Ok(vec![])).unwrap(), false);
+        assert_eq!(work.ackable_work().len(), 0);
+        assert!(work.result(id1).is_ok());
+
+        /*
+         * With every replica needed for a quorum, one error fails the
+         * write.
+         */
+        let err = Err(CrucibleError::GenericError("bad".to_string()));
+        assert_eq!(work.complete(id1, 2, &err).unwrap(), true);
+        assert!(work.result(id1).is_err());
+    }
+
+    #[test]
+    fn queue_limits_validation() {
+        assert!(QueueLimits::new(0, 100).is_err());
+        assert!(QueueLimits::new(10, 0).is_err());
+        let limits = QueueLimits::new(10, 100).unwrap();
+        assert_eq!(limits.max_jobs(), 10);
+        assert_eq!(limits.max_bytes(), 100);
+    }
+
+    #[test]
+    fn guest_queue_job_limit() {
+        let queue =
+            Arc::new(GuestQueue::new(QueueLimits::new(2, 100).unwrap()));
+
+        let p1 = queue.try_admit(10).unwrap();
+        let _p2 = queue.try_admit(10).unwrap();
+        assert!(queue.try_admit(0).is_none());
+        assert_eq!(
+            *queue.depth.lock().unwrap(),
+            QueueDepth { jobs: 2, bytes: 20 }
+        );
+
+        drop(p1);
+        assert_eq!(
+            *queue.depth.lock().unwrap(),
+            QueueDepth { jobs: 1, bytes: 10 }
+        );
+        assert!(queue.try_admit(0).is_some());
+    }
+
+    #[test]
+    fn guest_queue_byte_limit() {
+        let queue =
+            Arc::new(GuestQueue::new(QueueLimits::new(10, 100).unwrap()));
+
+        let p1 = queue.try_admit(60).unwrap();
+        assert!(queue.try_admit(50).is_none());
+        let p2 = queue.try_admit(40).unwrap();
+        drop(p1);
+        drop(p2);
+
+        /*
+         * An IO bigger than the whole limit still goes when nothing else
+         * is in flight.
+         */
+        let big = queue.try_admit(500).unwrap();
+        assert!(queue.try_admit(1).is_none());
+        drop(big);
+        assert_eq!(*queue.depth.lock().unwrap(), QueueDepth::default());
+    }
+
+    #[tokio::test]
+    async fn guest_queue_waits_for_room() {
+        let queue =
+            Arc::new(GuestQueue::new(QueueLimits::new(1, 100).unwrap()));
+        let p1 = queue.admit(10).await;
+
+        let q = queue.clone();
+        let mut waiter = tokio::spawn(async move { q.admit(10).await.bytes });
+        let wait = Duration::from_millis(10);
+        assert!(tokio::time::timeout(wait, &mut waiter).await.is_err());
+
+        drop(p1);
+        assert_eq!(waiter.await.unwrap(), 10);
+    }
+
+    #[test]
+    fn guest_write_holds_queue_until_complete() {
+        let guest = Guest::with_limits(QueueLimits::new(4, 4096).unwrap());
+        let permit = guest.queue.try_admit(512).unwrap();
+        let _waiter = guest.send_permit(
+            BlockOp::Write {
+                offset: Block::new_512(0),
+                data: Bytes::from(vec![0; 512]),
+            },
+            Some(permit),
+        );
+        assert_eq!(
+            guest.queue_depth(),
+            QueueDepth {
+                jobs: 1,
+                bytes: 512
+            }
+        );
+
+        /*
+         * The permit moves with the request, and is only given back when
+         * the request (and later its GtoS) goes away.
+         */
+        let req = guest.reqs.lock().unwrap().pop_front().unwrap();
+        assert_eq!(guest.queue_depth().jobs, 1);
+        drop(req);
+        assert_eq!(guest.queue_depth(), QueueDepth::default());
+    }
+
+    /*
+     * Send a one block write through the upstairs, and return the result
+     * channel with the guest and downstairs job IDs.
+     */
+    fn write_back_write(
+        up: &Arc<Upstairs>,
+    ) -> (std_mpsc::Receiver<Result<(), CrucibleError>>, u64, u64) {
+        let (send, recv) = std_mpsc::channel();
+        up.submit_write(
+            Block::new_512(0),
+            Bytes::from(vec![1; 512]),
+            send,
+            None,
+        )
+        .unwrap();
+        let ds = up.downstairs.lock().unwrap();
+        let ds_id = *ds.active.keys().max().unwrap();
+        let gw_id = ds.active.get(&ds_id).unwrap().guest_id;
+        (recv, gw_id, ds_id)
+    }
+
+    #[test]
+    fn superseded_fails_pending_io() {
+        let up = make_upstairs();
+        up.set_active();
+        up.set_generation(2);
+
+        let (recv, _, ds_id) = write_back_write(&up);
+        assert!(recv.try_recv().is_err());
+
+        up.ds_superseded(0, Uuid::new_v4(), 3);
+        assert!(!up.is_active());
+        assert_eq!(
+            recv.try_recv().unwrap(),
+            Err(CrucibleError::UpstairsInactive)
+        );
+
+        let ds = up.downstairs.lock().unwrap();
+        let job = ds.active.get(&ds_id).unwrap();
+        assert_eq!(job.ack_status, AckStatus::Acked);
+        drop(ds);
+        assert!(up.guest.guest_work.lock().unwrap().active.is_empty());
+
+        // Nothing new is taken once we are inactive.
+        let (send, _recv) = std_mpsc::channel();
+        assert!(up
+            .submit_write(
+                Block::new_512(0),
+                Bytes::from(vec![1; 512]),
+                send,
+                None
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn write_back_acks_early() {
+        let up = make_upstairs();
+        up.set_active();
+        up.guest.set_write_back(Some(4096));
+
+        let (recv, gw_id, ds_id) = write_back_write(&up);
+        assert_eq!(recv.try_recv().unwrap(), Ok(()));
+        assert_eq!(up.guest.dirty_bytes(), 512);
+
+        up.guest.guest_work.lock().unwrap().ds_complete(
+            gw_id,
+            ds_id,
+            None,
+            Ok(()),
+        );
+        assert_eq!(up.guest.dirty_bytes(), 0);
+        assert!(recv.try_recv().is_err());
+    }
+
+    #[test]
+    fn guest_io_latency_is_counted() {
+        let up = make_upstairs();
+        up.set_active();
+
+        /*
+         * A write from the guest has a permit and is counted, a flush the
+         * upstairs sends on its own does not.
+         */
+        let permit = up.guest.queue.try_admit(512).unwrap();
+        let (send, _recv) = std_mpsc::channel();
+        up.submit_write(
+            Block::new_512(0),
+            Bytes::from(vec![1; 512]),
+            send,
+            Some(permit),
+        )
+        .unwrap();
+        up.submit_flush(None, None, None).unwrap();
+
+        let jobs: Vec<(u64, u64)> = up
+            .downstairs
+            .lock()
+            .unwrap()
+            .active
+            .values()
+            .map(|job| (job.guest_id, job.ds_id))
+            .collect();
+        let mut gw = up.guest.guest_work.lock().unwrap();
+        for (gw_id, ds_id) in jobs {
+            gw.ds_complete(gw_id, ds_id, None, Ok(()));
+        }
+        drop(gw);
+
+        let latency = up.guest.io_latency();
+        assert_eq!(latency.write.submit.count(), 1);
+        assert_eq!(latency.write.downstairs.count(), 1);
+        assert_eq!(latency.flush.downstairs.count(), 0);
+
+        up.guest.reset_io_latency();
+        assert_eq!(up.guest.io_latency().write.submit.count(), 0);
+    }
+
+    #[test]
+    fn write_back_over_limit_is_write_through() {
+        let up = make_upstairs();
+        up.set_active();
+        up.guest.set_write_back(Some(512));
+
+        let (recv1, _, _) = write_back_write(&up);
+        assert_eq!(recv1.try_recv().unwrap(), Ok(()));
+
+        let (recv2, gw_id, ds_id) = write_back_write(&up);
+        assert!(recv2.try_recv().is_err());
+        assert_eq!(up.guest.dirty_bytes(), 512);
+
+        up.guest.guest_work.lock().unwrap().ds_complete(
+            gw_id,
+            ds_id,
+            None,
+            Ok(()),
+        );
+        assert_eq!(recv2.try_recv().unwrap(), Ok(()));
+        assert_eq!(up.guest.dirty_bytes(), 512);
+    }
+
+    #[test]
+    fn write_back_error_fails_next_flush() {
+        let up = make_upstairs();
+        up.set_active();
+        up.guest.set_write_back(Some(4096));
+
+        let (_recv, gw_id, ds_id) = write_back_write(&up);
+        let err = CrucibleError::GenericError("bad".to_string());
+        up.guest.guest_work.lock().unwrap().ds_complete(
+            gw_id,
+            ds_id,
+            None,
+            Err(err.clone()),
+        );
+        assert_eq!(up.guest.dirty_bytes(), 0);
+
+        let (send, _recv) = std_mpsc::channel();
+        assert_eq!(up.submit_flush(Some(send), None, None), Err(err));
+
+        // The error is only reported once.
+        let (send, _recv) = std_mpsc::channel();
+        assert!(up.submit_flush(Some(send), None, None).is_ok());
+    }
+
+    /*
+     * Submit a guest flush and return its result channel with the guest
+     * and downstairs job IDs.
+     */
+    fn write_back_flush(
+        up: &Arc<Upstairs>,
+    ) -> (std_mpsc::Receiver<Result<(), CrucibleError>>, u64, u64) {
+        let (send, recv) = std_mpsc::channel();
+        up.submit_flush(Some(send), None, None).unwrap();
+        let ds = up.downstairs.lock().unwrap();
+        let ds_id = *ds.active.keys().max().unwrap();
+        let gw_id = ds.active.get(&ds_id).unwrap().guest_id;
+        (recv, gw_id, ds_id)
+    }
+
+    #[test]
+    fn write_back_flush_waits_for_writes() {
+        let up = make_upstairs();
+        up.set_active();
+        up.guest.set_write_back(Some(4096));
+
+        let (_wrecv, w_gw, w_ds) = write_back_write(&up);
+        let (frecv, f_gw, f_ds) = write_back_flush(&up);
+
+        // The flush is done on the downstairs, but the write is not.
+        up.guest.guest_work.lock().unwrap().ds_complete(
+            f_gw,
+            f_ds,
+            None,
+            Ok(()),
+        );
+        assert!(frecv.try_recv().is_err());
+
+        up.guest.guest_work.lock().unwrap().ds_complete(
+            w_gw,
+            w_ds,
+            None,
+            Ok(()),
+        );
+        assert_eq!(frecv.try_recv().unwrap(), Ok(()));
+        assert!(up.guest.guest_work.lock().unwrap().active.is_empty());
+    }
+
+    #[test]
+    fn write_back_flush_returns_write_error() {
+        let up = make_upstairs();
+        up.set_active();
+        up.guest.set_write_back(Some(4096));
+
+        let (_wrecv, w_gw, w_ds) = write_back_write(&up);
+        let (frecv, f_gw, f_ds) = write_back_flush(&up);
+
+        let err = CrucibleError::GenericError("bad".to_string());
+        let mut gw = up.guest.guest_work.lock().unwrap();
+        gw.ds_complete(w_gw, w_ds, None, Err(err.clone()));
+        assert!(frecv.try_recv().is_err());
+        gw.ds_complete(f_gw, f_ds, None, Ok(()));
+        drop(gw);
+        assert_eq!(frecv.try_recv().unwrap(), Err(err));
+
+        // The flush that waited reported it, so the next one is fine.
+        let (send, _recv) = std_mpsc::channel();
+        assert!(up.submit_flush(Some(send), None, None).is_ok());
+    }
+
+    #[test]
+    fn block_on_without_runtime() {
+        assert_eq!(block_on(async { 5 }), 5);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn block_on_from_runtime_task() {
+        /*
+         * The task that blocks has the only worker thread, so the sender
+         * only gets to run if block_on gives that thread up.
+         */
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let waiter = tokio::spawn(async move { block_on(rx).unwrap() });
+        tokio::spawn(async move { tx.send(7).unwrap() });
+        assert_eq!(waiter.await.unwrap(), 7);
+    }
+
+    #[tokio::test]
+    async fn block_req_waiter_async_wait() {
+        let (send, recv) = std_mpsc::channel();
+        let brw = BlockReqWaiter::new(recv);
+        send.send(Ok(())).unwrap();
+        assert!(brw.wait().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn block_req_waiter_async_wait_disconnected() {
+        let (send, recv) = std_mpsc::channel();
+        let brw = BlockReqWaiter::new(recv);
+        drop(send);
+        assert!(matches!(
+            brw.wait().await,
+            Err(CrucibleError::RecvDisconnected)
+        ));
+    }
 }
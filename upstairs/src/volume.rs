@@ -0,0 +1,1503 @@
+// Copyright 2021 Oxide Computer Company
+use super::*;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Range;
+
+use serde::Deserialize;
+use tokio::runtime::Handle;
+use tokio::task::JoinHandle;
+
+/*
+ * A Volume is a block device built out of one or more sub volumes, each of
+ * which is a Guest connected to its own set of downstairs.  The sub volumes
+ * are laid end to end to make up the LBA space of the Volume.
+ *
+ * A Volume may also have a read only parent.  The parent covers the LBA
+ * range starting at zero and is never written.  Until a block has been
+ * written through the Volume, reads of that block are served from the
+ * parent.  Once written, the block is "owned" by the sub volume and reads
+ * go there instead.
+ *
+ * Which blocks are owned is kept in a few blocks at the end of the last
+ * sub volume, so it survives a restart.  They are written when the Volume
+ * is flushed, after the data they describe is, so a block is never owned
+ * on disk before its data is there.  A write not yet flushed may be lost
+ * in a crash, and then the block reads from the parent again, as if the
+ * write had never happened.
+ *
+ * A scrub copies the parent into the sub volumes in the background, a
+ * chunk at a time, so reads eventually stop going to the parent at all.
+ * Only blocks that have not been written are copied.
+ *
+ * The read only parent is either a Guest, which must be attached read
+ * only, or an image read over HTTP.  A Volume made with new_read_only()
+ * is made entirely of read only sub volumes and can't be written.
+ */
+#[derive(Debug)]
+pub struct SubVolume {
+    lba_range: Range<u64>,
+    guest: Arc<Guest>,
+}
+
+impl SubVolume {
+    pub fn lba_range(&self) -> Range<u64> {
+        self.lba_range.clone()
+    }
+
+    pub fn guest(&self) -> Arc<Guest> {
+        self.guest.clone()
+    }
+}
+
+#[derive(Debug)]
+pub struct Volume {
+    block_size: u64,
+    sub_volumes: Vec<SubVolume>,
+    read_only_parent: Option<ReadOnlyParent>,
+    read_only: bool,
+
+    owned: Mutex<Ownership>,
+
+    /*
+     * Where owned is kept on disk, if there is a read only parent.
+     */
+    meta: Option<OwnershipArea>,
+
+    /*
+     * A guest write marks its blocks owned and is sent while holding
+     * this, and so does a scrub write after it checks the block is not
+     * owned.  A read looks at which blocks are owned and is sent under it
+     * too, and so is a flush along with the ownership it will save.  IO
+     * is done in the order it is sent, so a scrub can never land on top
+     * of a guest write, a read gets either a write and its ownership or
+     * neither, and ownership is never saved ahead of the write.
+     */
+    io_lock: tokio::sync::Mutex<()>,
+
+    /*
+     * Held while a flush writes out owned, so two flushes can't write it
+     * out of order.
+     */
+    meta_lock: tokio::sync::Mutex<()>,
+}
+
+/*
+ * Where the read only parent's blocks come from.
+ */
+#[derive(Debug)]
+enum ParentSource {
+    Guest(Arc<Guest>),
+    Url(UrlImage),
+}
+
+#[derive(Debug)]
+pub struct ReadOnlyParent {
+    lba_range: Range<u64>,
+    source: ParentSource,
+}
+
+impl ReadOnlyParent {
+    pub fn lba_range(&self) -> Range<u64> {
+        self.lba_range.clone()
+    }
+
+    async fn read(
+        &self,
+        offset: Block,
+        len: usize,
+    ) -> Result<Vec<u8>, CrucibleError> {
+        match &self.source {
+            ParentSource::Guest(guest) => {
+                let buf = Buffer::new(len);
+                guest.read_async(offset, buf.clone()).await?.wait().await?;
+                let data = buf.as_vec().clone();
+                Ok(data)
+            }
+            ParentSource::Url(image) => {
+                image.read(offset.byte_value(), len).await
+            }
+        }
+    }
+}
+
+/*
+ * A disk image at an http or https URL, read with range requests.  An
+ * image that does not end on a block boundary reads as zeros to the end
+ * of its last block.
+ */
+#[derive(Debug)]
+pub struct UrlImage {
+    url: String,
+    client: reqwest::Client,
+    size: u64,
+}
+
+fn check_url(url: &str) -> Result<()> {
+    let url = reqwest::Url::parse(url)?;
+    match url.scheme() {
+        "http" | "https" => Ok(()),
+        scheme => bail!("can't read a parent from a {} URL", scheme),
+    }
+}
+
+impl UrlImage {
+    /*
+     * Find the size of the image.  The server must answer a HEAD with the
+     * length, and must support range requests for reads.
+     */
+    pub async fn open(url: &str) -> Result<UrlImage> {
+        check_url(url)?;
+
+        let client = reqwest::Client::new();
+        let response = client.head(url).send().await?.error_for_status()?;
+        let size = match response
+            .headers()
+            .get(reqwest::header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok())
+        {
+            Some(size) => size,
+            None => bail!("{} did not give a content length", url),
+        };
+
+        Ok(UrlImage {
+            url: url.to_string(),
+            client,
+            size,
+        })
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    async fn read(
+        &self,
+        offset: u64,
+        len: usize,
+    ) -> Result<Vec<u8>, CrucibleError> {
+        let mut data = vec![0u8; len];
+        let end = std::cmp::min(offset + len as u64, self.size);
+        if offset >= end {
+            return Ok(data);
+        }
+
+        let range = format!("bytes={}-{}", offset, end - 1);
+        let bytes = async {
+            let response = self
+                .client
+                .get(&self.url)
+                .header(reqwest::header::RANGE, range)
+                .send()
+                .await?
+                .error_for_status()?;
+            if response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+                bail!("{} did not answer a range request", self.url);
+            }
+            Ok(response.bytes().await?)
+        }
+        .await
+        .map_err(|e: anyhow::Error| CrucibleError::IoError(e.to_string()))?;
+
+        let want = (end - offset) as usize;
+        if bytes.len() != want {
+            crucible_bail!(
+                IoError,
+                "{} returned {} bytes, not {}",
+                self.url,
+                bytes.len(),
+                want
+            );
+        }
+        data[..want].copy_from_slice(&bytes);
+        Ok(data)
+    }
+}
+
+/*
+ * One entry per block of the read only parent, set once that block has
+ * been written to a sub volume, and the blocks of the ownership area with
+ * entries that changed since it was last written.  Every parent block
+ * below the scrub point has been copied by the scrubber or written by the
+ * guest.
+ *
+ * A guest write owns its blocks before it is sent, so a scrub can't copy
+ * over it.  Blocks it newly owned are held in claimed, with a count of
+ * the writes to them still running, until one of those writes works or
+ * all of them fail and the blocks go back to the parent.
+ */
+#[derive(Debug, Default)]
+struct Ownership {
+    owned: Vec<bool>,
+    dirty: BTreeSet<u64>,
+    claimed: BTreeMap<u64, usize>,
+    scrub_point: u64,
+}
+
+/*
+ * The ownership area is a header block, then a bitmap with one bit for
+ * each block of the parent.  It is at the end of the last sub volume,
+ * past the end of the Volume's LBA space.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct OwnershipArea {
+    /*
+     * The first block of the area, relative to the last sub volume.
+     */
+    start: u64,
+    blocks: u64,
+}
+
+const OWNERSHIP_MAGIC: &[u8; 8] = b"CRUCOWN1";
+
+impl OwnershipArea {
+    fn bitmap_blocks(parent_blocks: u64, block_size: u64) -> u64 {
+        let bits_per_block = block_size * 8;
+        (parent_blocks + bits_per_block - 1) / bits_per_block
+    }
+
+    fn blocks_needed(parent_blocks: u64, block_size: u64) -> u64 {
+        1 + OwnershipArea::bitmap_blocks(parent_blocks, block_size)
+    }
+}
+
+/*
+ * The header says the area is in use, how big a parent it is for, and
+ * where a scrub picks up from.  A block of zeros is an area that has
+ * never been written.
+ */
+fn encode_header(
+    block_size: u64,
+    parent_blocks: u64,
+    scrub_point: u64,
+) -> Vec<u8> {
+    let mut header = vec![0u8; block_size as usize];
+    header[0..8].copy_from_slice(OWNERSHIP_MAGIC);
+    header[8..16].copy_from_slice(&parent_blocks.to_le_bytes());
+    header[16..24].copy_from_slice(&scrub_point.to_le_bytes());
+    header
+}
+
+fn header_u64(header: &[u8], at: usize) -> u64 {
+    let mut n = [0u8; 8];
+    n.copy_from_slice(&header[at..(at + 8)]);
+    u64::from_le_bytes(n)
+}
+
+/*
+ * Returns the scrub point, or None for an area never written.
+ */
+fn decode_header(
+    header: &[u8],
+    parent_blocks: u64,
+) -> Result<Option<u64>, CrucibleError> {
+    if header[0..8] != OWNERSHIP_MAGIC[..] {
+        if header.iter().any(|b| *b != 0) {
+            crucible_bail!(
+                GenericError,
+                "ownership area at the end of the volume is not valid"
+            );
+        }
+        return Ok(None);
+    }
+
+    let blocks = header_u64(header, 8);
+    if blocks != parent_blocks {
+        crucible_bail!(
+            GenericError,
+            "ownership area is for a parent of {} blocks, not {}",
+            blocks,
+            parent_blocks
+        );
+    }
+
+    Ok(Some(std::cmp::min(header_u64(header, 16), parent_blocks)))
+}
+
+/*
+ * The bitmap block `index`, with the bits for the parent blocks it holds.
+ */
+fn encode_bitmap_block(owned: &[bool], index: u64, block_size: u64) -> Vec<u8> {
+    let bits = (block_size * 8) as usize;
+    let mut data = vec![0u8; block_size as usize];
+    let first = index as usize * bits;
+    for (bit, o) in owned.iter().skip(first).take(bits).enumerate() {
+        if *o {
+            data[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+    data
+}
+
+fn decode_bitmap(bitmap: &[u8], parent_blocks: u64) -> Vec<bool> {
+    (0..parent_blocks as usize)
+        .map(|b| bitmap[b / 8] & (1 << (b % 8)) != 0)
+        .collect()
+}
+
+/*
+ * A serialized description of a whole Volume, for consumers that would
+ * rather hand us a single JSON document than attach each Guest themselves.
+ * For example:
+ *
+ * {
+ *     "block_size": 512,
+ *     "gen": 2,
+ *     "sub_volumes": [
+ *         { "target": ["10.0.0.1:3801", "10.0.0.2:3801", "10.0.0.3:3801"] }
+ *     ],
+ *     "read_only_parent": {
+ *         "target": ["10.0.0.4:3801", "10.0.0.5:3801", "10.0.0.6:3801"]
+ *     }
+ * }
+ *
+ * A read only parent can instead be an image at a URL, given as
+ * "read_only_parent_url".
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct VolumeConstructionRequest {
+    pub block_size: u64,
+    /*
+     * Every Guest in the Volume is activated with this generation.
+     */
+    pub gen: u64,
+    #[serde(default)]
+    pub read_only: bool,
+    pub sub_volumes: Vec<RegionRequest>,
+    #[serde(default)]
+    pub read_only_parent: Option<RegionRequest>,
+    #[serde(default)]
+    pub read_only_parent_url: Option<String>,
+}
+
+/*
+ * One set of downstairs, attached through its own Guest.  Writes and
+ * flushes are acked once a majority of the targets finish them, unless a
+ * quorum is given here.
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct RegionRequest {
+    pub target: Vec<SocketAddrV4>,
+    #[serde(default)]
+    pub key: Option<String>,
+    /*
+     * See CrucibleOpts::legacy_xts.
+     */
+    #[serde(default)]
+    pub legacy_xts: bool,
+    #[serde(default)]
+    pub write_quorum: Option<usize>,
+    #[serde(default)]
+    pub flush_quorum: Option<usize>,
+}
+
+impl VolumeConstructionRequest {
+    pub fn from_json(json: &str) -> Result<VolumeConstructionRequest> {
+        let request: VolumeConstructionRequest = serde_json::from_str(json)?;
+        request.validate()?;
+        Ok(request)
+    }
+
+    /*
+     * Catch what we can before connecting to anything.
+     */
+    pub fn validate(&self) -> Result<()> {
+        if self.block_size < 512 || !self.block_size.is_power_of_two() {
+            bail!("invalid block size {}", self.block_size);
+        }
+        if self.sub_volumes.is_empty() {
+            bail!("a volume needs at least one sub volume");
+        }
+        for region in self.sub_volumes.iter().chain(&self.read_only_parent) {
+            region.policy()?;
+        }
+        if let Some(url) = &self.read_only_parent_url {
+            if self.read_only_parent.is_some() {
+                bail!("give a read only parent or its URL, not both");
+            }
+            check_url(url)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl RegionRequest {
+    pub fn policy(&self) -> Result<ReplicationPolicy> {
+        let majority = ReplicationPolicy::majority(self.target.len())?;
+        ReplicationPolicy::new(
+            majority.replicas(),
+            self.write_quorum.unwrap_or_else(|| majority.write_quorum()),
+            self.flush_quorum.unwrap_or_else(|| majority.flush_quorum()),
+        )
+    }
+
+    /*
+     * Start an upstairs for this region on the runtime and wait for it to
+     * go active.
+     */
+    fn attach(
+        &self,
+        runtime: &Handle,
+        read_only: bool,
+        gen: u64,
+    ) -> Result<Arc<Guest>> {
+        let opts = CrucibleOpts {
+            target: self.target.clone(),
+            lossy: false,
+            key: self.key.clone(),
+            legacy_xts: self.legacy_xts,
+            control: None,
+            policy: Some(self.policy()?),
+            read_only,
+            job_timeout: None,
+            uuid_mismatch: None,
+        };
+
+        let guest = Arc::new(Guest::new());
+        runtime.spawn(up_main(opts, guest.clone()));
+        guest.activate(gen)?;
+
+        Ok(guest)
+    }
+}
+
+/*
+ * The part of an IO that lands on a single sub volume: the index of that
+ * sub volume, the starting block relative to the sub volume, the number of
+ * blocks, and the block offset into the caller's buffer.
+ */
+#[derive(Debug, PartialEq)]
+struct VolumeSpan {
+    sv: usize,
+    start: u64,
+    count: u64,
+    buf_block: u64,
+}
+
+/*
+ * Split the block range [start, start + count) across a list of end to
+ * end LBA ranges.
+ */
+fn lba_spans(
+    ranges: &[Range<u64>],
+    start: u64,
+    count: u64,
+) -> Result<Vec<VolumeSpan>, CrucibleError> {
+    let end = start + count;
+    let total = ranges.last().map(|r| r.end).unwrap_or(0);
+    if end > total {
+        crucible_bail!(OffsetInvalid);
+    }
+
+    let mut spans = Vec::new();
+    for (sv, r) in ranges.iter().enumerate() {
+        let s = std::cmp::max(start, r.start);
+        let e = std::cmp::min(end, r.end);
+        if s >= e {
+            continue;
+        }
+        spans.push(VolumeSpan {
+            sv,
+            start: s - r.start,
+            count: e - s,
+            buf_block: s - start,
+        });
+    }
+
+    Ok(spans)
+}
+
+/*
+ * Group a sorted list of blocks into runs of consecutive blocks.
+ */
+fn block_runs(blocks: &[u64]) -> Vec<Range<u64>> {
+    let mut runs: Vec<Range<u64>> = Vec::new();
+    for b in blocks {
+        match runs.last_mut() {
+            Some(run) if run.end == *b => run.end += 1,
+            _ => runs.push(*b..(*b + 1)),
+        }
+    }
+
+    runs
+}
+
+impl Volume {
+    pub fn new(block_size: u64) -> Volume {
+        Volume {
+            block_size,
+            sub_volumes: Vec::new(),
+            read_only_parent: None,
+            read_only: false,
+            owned: Mutex::new(Ownership::default()),
+            meta: None,
+            io_lock: tokio::sync::Mutex::new(()),
+            meta_lock: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    pub fn new_read_only(block_size: u64) -> Volume {
+        Volume {
+            read_only: true,
+            ..Volume::new(block_size)
+        }
+    }
+
+    /*
+     * Build and activate the Volume a request describes.  The upstairs for
+     * each region runs on the given runtime.  Activation blocks, so this
+     * must not be called from a task on that runtime.
+     */
+    pub fn construct(
+        request: &VolumeConstructionRequest,
+        runtime: &Handle,
+    ) -> Result<Volume> {
+        request.validate()?;
+
+        let mut volume = if request.read_only {
+            Volume::new_read_only(request.block_size)
+        } else {
+            Volume::new(request.block_size)
+        };
+
+        for region in &request.sub_volumes {
+            let guest =
+                region.attach(runtime, request.read_only, request.gen)?;
+            volume.add_subvolume(guest)?;
+        }
+        if let Some(region) = &request.read_only_parent {
+            let guest = region.attach(runtime, true, request.gen)?;
+            volume.add_read_only_parent(guest)?;
+        }
+        if let Some(url) = &request.read_only_parent_url {
+            let image = runtime.block_on(UrlImage::open(url))?;
+            volume.add_url_parent(image)?;
+        }
+
+        Ok(volume)
+    }
+
+    fn check_read_only(&self, guest: &Guest) -> Result<(), CrucibleError> {
+        if !guest.query_read_only()? {
+            crucible_bail!(GenericError, "guest is not attached read only");
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Query an active guest for its size, and make sure it uses the same
+     * block size as this Volume.  Returns the size in blocks.
+     */
+    fn guest_blocks(&self, guest: &Guest) -> Result<u64, CrucibleError> {
+        if guest.query_block_size()? != self.block_size {
+            crucible_bail!(BlockSizeMismatch);
+        }
+
+        Ok(guest.query_total_size()? / self.block_size)
+    }
+
+    /**
+     * Append an active Guest to the end of this Volume's LBA space.
+     */
+    pub fn add_subvolume(
+        &mut self,
+        guest: Arc<Guest>,
+    ) -> Result<(), CrucibleError> {
+        if self.read_only {
+            self.check_read_only(&guest)?;
+        }
+        if self.read_only_parent.is_some() {
+            crucible_bail!(
+                GenericError,
+                "sub volumes must be added before the read only parent"
+            );
+        }
+
+        let blocks = self.guest_blocks(&guest)?;
+        let start = self.total_blocks();
+
+        self.sub_volumes.push(SubVolume {
+            lba_range: start..(start + blocks),
+            guest,
+        });
+
+        Ok(())
+    }
+
+    /**
+     * Set an active Guest as the read only parent for this Volume.  The
+     * parent must not be larger than the Volume itself, less the blocks
+     * at the end of the last sub volume that keep track of which parent
+     * blocks have been written.  Those are read here, so this blocks, and
+     * must not be called from a task.
+     */
+    pub fn add_read_only_parent(
+        &mut self,
+        guest: Arc<Guest>,
+    ) -> Result<(), CrucibleError> {
+        self.check_read_only(&guest)?;
+        let blocks = self.guest_blocks(&guest)?;
+        self.set_read_only_parent(blocks, ParentSource::Guest(guest))
+    }
+
+    /**
+     * Set an image at a URL as the read only parent for this Volume, as
+     * add_read_only_parent() does for a Guest.
+     */
+    pub fn add_url_parent(
+        &mut self,
+        image: UrlImage,
+    ) -> Result<(), CrucibleError> {
+        let blocks = (image.size() + self.block_size - 1) / self.block_size;
+        self.set_read_only_parent(blocks, ParentSource::Url(image))
+    }
+
+    fn set_read_only_parent(
+        &mut self,
+        blocks: u64,
+        source: ParentSource,
+    ) -> Result<(), CrucibleError> {
+        if self.read_only_parent.is_some() {
+            crucible_bail!(GenericError, "read only parent already set");
+        }
+
+        let area_blocks = OwnershipArea::blocks_needed(blocks, self.block_size);
+        let last = match self.sub_volumes.last() {
+            Some(last) => last.lba_range(),
+            None => crucible_bail!(GenericError, "volume has no sub volumes"),
+        };
+        if blocks + area_blocks > last.end
+            || area_blocks > last.end - last.start
+        {
+            crucible_bail!(
+                InvalidNumberOfBlocks,
+                "parent has {} blocks, volume has room for {}",
+                blocks,
+                last.end.saturating_sub(area_blocks)
+            );
+        }
+
+        let area = OwnershipArea {
+            start: last.end - last.start - area_blocks,
+            blocks: area_blocks,
+        };
+        let (owned, scrub_point) = self.load_ownership(&area, blocks)?;
+
+        self.read_only_parent = Some(ReadOnlyParent {
+            lba_range: 0..blocks,
+            source,
+        });
+        self.meta = Some(area);
+        let mut ownership = self.owned.lock().unwrap();
+        ownership.owned = owned;
+        ownership.scrub_point = scrub_point;
+        drop(ownership);
+
+        Ok(())
+    }
+
+    fn load_ownership(
+        &self,
+        area: &OwnershipArea,
+        parent_blocks: u64,
+    ) -> Result<(Vec<bool>, u64), CrucibleError> {
+        let bs = self.block_size as usize;
+        let buf = Buffer::new(area.blocks as usize * bs);
+        let sv = self.sub_volumes.last().unwrap();
+        sv.guest
+            .read(self.block(area.start), buf.clone())?
+            .block_wait()?;
+
+        let data = buf.as_vec();
+        match decode_header(&data[0..bs], parent_blocks)? {
+            Some(scrub_point) => {
+                Ok((decode_bitmap(&data[bs..], parent_blocks), scrub_point))
+            }
+            None => Ok((vec![false; parent_blocks as usize], 0)),
+        }
+    }
+
+    /*
+     * Write out the header and the ownership blocks that changed since the
+     * last time, and flush them.  Ownership must have been taken before
+     * the flush of the data it describes.
+     */
+    async fn save_ownership(
+        &self,
+        area: &OwnershipArea,
+        scrub_point: u64,
+        blocks: &[(u64, Vec<u8>)],
+    ) -> Result<(), CrucibleError> {
+        let sv = self.sub_volumes.last().unwrap();
+        let parent_blocks = self.owned.lock().unwrap().owned.len() as u64;
+        let header = encode_header(self.block_size, parent_blocks, scrub_point);
+
+        let mut waiters = vec![
+            sv.guest
+                .write_async(self.block(area.start), Bytes::from(header))
+                .await?,
+        ];
+        for (index, data) in blocks {
+            waiters.push(
+                sv.guest
+                    .write_async(
+                        self.block(area.start + 1 + index),
+                        Bytes::from(data.clone()),
+                    )
+                    .await?,
+            );
+        }
+        for waiter in waiters {
+            waiter.wait().await?;
+        }
+
+        sv.guest.flush_async().await?.wait().await
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    /*
+     * Activate every guest in this volume with the generation number it
+     * was given.  A downstairs refuses an upstairs with a lower generation
+     * than the last one it saw, and kicks out an active upstairs when a
+     * higher one arrives.
+     */
+    pub fn activate(&self, gen: u64) -> Result<(), CrucibleError> {
+        for sub_volume in &self.sub_volumes {
+            sub_volume.guest.activate(gen)?;
+        }
+        if let Some(ReadOnlyParent {
+            source: ParentSource::Guest(guest),
+            ..
+        }) = &self.read_only_parent
+        {
+            guest.activate(gen)?;
+        }
+        Ok(())
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn scrub_point(&self) -> u64 {
+        self.owned.lock().unwrap().scrub_point
+    }
+
+    pub fn total_blocks(&self) -> u64 {
+        let end = self
+            .sub_volumes
+            .last()
+            .map(|s| s.lba_range.end)
+            .unwrap_or(0);
+        end - self.meta.map(|m| m.blocks).unwrap_or(0)
+    }
+
+    pub fn total_size(&self) -> u64 {
+        self.total_blocks() * self.block_size
+    }
+
+    pub fn sub_volumes(&self) -> &[SubVolume] {
+        &self.sub_volumes
+    }
+
+    pub fn read_only_parent(&self) -> Option<&ReadOnlyParent> {
+        self.read_only_parent.as_ref()
+    }
+
+    fn block(&self, value: u64) -> Block {
+        Block::new(value, self.block_size.trailing_zeros())
+    }
+
+    fn spans(
+        &self,
+        offset: Block,
+        len: usize,
+    ) -> Result<Vec<VolumeSpan>, CrucibleError> {
+        offset.check_io(len, self.block_size)?;
+
+        let mut ranges: Vec<Range<u64>> =
+            self.sub_volumes.iter().map(|s| s.lba_range()).collect();
+        if let Some(last) = ranges.last_mut() {
+            last.end = self.total_blocks();
+        }
+
+        lba_spans(&ranges, offset.value, len as u64 / self.block_size)
+    }
+
+    /*
+     * Return the blocks in [start, start + count) that fall inside the read
+     * only parent and have not yet been written.
+     */
+    fn unowned_blocks(&self, start: u64, count: u64) -> Vec<u64> {
+        let owned = &self.owned.lock().unwrap().owned;
+        (start..(start + count))
+            .filter(|b| (*b as usize) < owned.len() && !owned[*b as usize])
+            .collect()
+    }
+
+    fn set_owned(&self, start: u64, count: u64) {
+        let bits = self.block_size * 8;
+        let mut ownership = self.owned.lock().unwrap();
+        let Ownership { owned, dirty, .. } = &mut *ownership;
+        for b in start..(start + count) {
+            if (b as usize) < owned.len() && !owned[b as usize] {
+                owned[b as usize] = true;
+                dirty.insert(b / bits);
+            }
+        }
+    }
+
+    /*
+     * Own the blocks of a guest write that is about to be sent.
+     */
+    fn claim_owned(&self, start: u64, count: u64) {
+        let bits = self.block_size * 8;
+        let mut ownership = self.owned.lock().unwrap();
+        let Ownership {
+            owned,
+            dirty,
+            claimed,
+            ..
+        } = &mut *ownership;
+        for b in start..(start + count) {
+            if (b as usize) >= owned.len() {
+                continue;
+            }
+            if !owned[b as usize] {
+                owned[b as usize] = true;
+                dirty.insert(b / bits);
+                claimed.insert(b, 1);
+            } else if let Some(writes) = claimed.get_mut(&b) {
+                *writes += 1;
+            }
+        }
+    }
+
+    /*
+     * A guest write that claimed its blocks is done.  If it worked they
+     * stay owned.  If it failed, and no other write to them worked or is
+     * still running, they are not owned after all.
+     */
+    fn settle_owned(&self, start: u64, count: u64, ok: bool) {
+        let bits = self.block_size * 8;
+        let mut ownership = self.owned.lock().unwrap();
+        let Ownership {
+            owned,
+            dirty,
+            claimed,
+            ..
+        } = &mut *ownership;
+        for b in start..(start + count) {
+            if ok {
+                claimed.remove(&b);
+                continue;
+            }
+            if let Some(writes) = claimed.get_mut(&b) {
+                *writes -= 1;
+                if *writes == 0 {
+                    claimed.remove(&b);
+                    owned[b as usize] = false;
+                    dirty.insert(b / bits);
+                }
+            }
+        }
+    }
+
+    /*
+     * The scrub point, and the ownership blocks that changed since they
+     * were last taken, as they are now.
+     */
+    fn take_dirty_ownership(&self) -> (u64, Vec<(u64, Vec<u8>)>) {
+        let mut ownership = self.owned.lock().unwrap();
+        let dirty = std::mem::take(&mut ownership.dirty);
+        let blocks = dirty
+            .into_iter()
+            .map(|index| {
+                let data = encode_bitmap_block(
+                    &ownership.owned,
+                    index,
+                    self.block_size,
+                );
+                (index, data)
+            })
+            .collect();
+        (ownership.scrub_point, blocks)
+    }
+
+    pub async fn read(
+        &self,
+        offset: Block,
+        data: Buffer,
+    ) -> Result<(), CrucibleError> {
+        let bs = self.block_size as usize;
+        let spans = self.spans(offset, data.len())?;
+
+        let mut reads = Vec::with_capacity(spans.len());
+        let unowned = {
+            let _io = self.io_lock.lock().await;
+            let unowned = self.unowned_blocks(
+                offset.value,
+                data.len() as u64 / self.block_size,
+            );
+
+            for span in spans {
+                let sv = &self.sub_volumes[span.sv];
+                let buf = Buffer::new(span.count as usize * bs);
+                let waiter = sv
+                    .guest
+                    .read_async(self.block(span.start), buf.clone())
+                    .await?;
+                reads.push((span.buf_block as usize * bs, buf, waiter));
+            }
+
+            unowned
+        };
+
+        for (dst, buf, waiter) in reads {
+            waiter.wait().await?;
+            data.as_vec()[dst..(dst + buf.len())]
+                .copy_from_slice(&buf.as_vec());
+        }
+
+        /*
+         * Anything that was not yet written when the sub volume reads were
+         * sent is filled in from the read only parent.
+         */
+        if let Some(parent) = &self.read_only_parent {
+            for run in block_runs(&unowned) {
+                let len = (run.end - run.start) as usize * bs;
+                let buf = parent.read(self.block(run.start), len).await?;
+
+                let dst = (run.start - offset.value) as usize * bs;
+                data.as_vec()[dst..(dst + len)].copy_from_slice(&buf);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn write(
+        &self,
+        offset: Block,
+        data: Bytes,
+    ) -> Result<(), CrucibleError> {
+        if self.read_only {
+            crucible_bail!(ModifyingReadOnlyRegion);
+        }
+
+        let bs = self.block_size as usize;
+        let spans = self.spans(offset, data.len())?;
+        let count = data.len() as u64 / self.block_size;
+
+        let result = async {
+            let mut waiters = Vec::with_capacity(spans.len());
+            {
+                let _io = self.io_lock.lock().await;
+                self.claim_owned(offset.value, count);
+
+                for span in spans {
+                    let sv = &self.sub_volumes[span.sv];
+                    let src = span.buf_block as usize * bs;
+                    let len = span.count as usize * bs;
+
+                    waiters.push(
+                        sv.guest
+                            .write_async(
+                                self.block(span.start),
+                                data.slice(src..(src + len)),
+                            )
+                            .await?,
+                    );
+                }
+            }
+
+            for waiter in waiters {
+                waiter.wait().await?;
+            }
+
+            Ok(())
+        }
+        .await;
+
+        self.settle_owned(offset.value, count, result.is_ok());
+        result
+    }
+
+    /*
+     * Copy the read only parent into the sub volumes, starting from the
+     * scrub point.  The parent is read `chunk` blocks at a time, with a
+     * `pause` after each chunk so the scrub doesn't starve the guest.
+     *
+     * Only blocks that are not owned are copied, and ownership is saved,
+     * so a scrub started again after a restart never copies over what the
+     * guest has written.  Each chunk copied is flushed, which saves the
+     * scrub point along with it.
+     */
+    pub async fn scrub(
+        &self,
+        chunk: u64,
+        pause: Duration,
+    ) -> Result<(), CrucibleError> {
+        let parent = match &self.read_only_parent {
+            Some(parent) => parent,
+            None => return Ok(()),
+        };
+        if chunk == 0 {
+            crucible_bail!(InvalidNumberOfBlocks, "scrub chunk must not be 0");
+        }
+
+        let bs = self.block_size as usize;
+        let end = parent.lba_range.end;
+        let mut start = self.scrub_point();
+
+        while start < end {
+            let count = std::cmp::min(chunk, end - start);
+
+            let copy = !self.unowned_blocks(start, count).is_empty();
+            if copy {
+                let buf = Bytes::from(
+                    parent.read(self.block(start), count as usize * bs).await?,
+                );
+
+                let mut waiters = Vec::new();
+                {
+                    let _io = self.io_lock.lock().await;
+                    let unowned = self.unowned_blocks(start, count);
+
+                    for run in block_runs(&unowned) {
+                        let src = (run.start - start) as usize * bs;
+                        let len = (run.end - run.start) as usize * bs;
+
+                        for span in self.spans(self.block(run.start), len)? {
+                            let sv = &self.sub_volumes[span.sv];
+                            let src = src + span.buf_block as usize * bs;
+                            let len = span.count as usize * bs;
+                            let waiter = sv
+                                .guest
+                                .write_async(
+                                    self.block(span.start),
+                                    buf.slice(src..(src + len)),
+                                )
+                                .await?;
+                            waiters.push(waiter);
+                        }
+                    }
+                }
+
+                for waiter in waiters {
+                    waiter.wait().await?;
+                }
+                self.set_owned(start, count);
+            }
+
+            start += count;
+            self.owned.lock().unwrap().scrub_point = start;
+            if copy {
+                self.flush().await?;
+            }
+            tokio::time::sleep(pause).await;
+        }
+
+        self.flush().await
+    }
+
+    /*
+     * Run scrub() in its own task.
+     */
+    pub fn start_scrub(
+        self: &Arc<Self>,
+        chunk: u64,
+        pause: Duration,
+    ) -> JoinHandle<Result<(), CrucibleError>> {
+        let vol = self.clone();
+        tokio::spawn(async move { vol.scrub(chunk, pause).await })
+    }
+
+    /*
+     * The read only parent never changes, so only the sub volumes need
+     * to be flushed.  Then any blocks that became owned before the flush
+     * are saved as owned.
+     *
+     * Ownership is taken and the flushes are sent under io_lock, so every
+     * write to a block taken as owned was sent before the flush and is
+     * made durable by it.
+     */
+    pub async fn flush(&self) -> Result<(), CrucibleError> {
+        if self.read_only {
+            return Ok(());
+        }
+
+        let _meta = self.meta_lock.lock().await;
+        let mut scrub_point = 0;
+        let mut dirty = Vec::new();
+
+        let result = async {
+            let mut waiters = Vec::with_capacity(self.sub_volumes.len());
+            {
+                let _io = self.io_lock.lock().await;
+                if self.meta.is_some() {
+                    let (point, blocks) = self.take_dirty_ownership();
+                    scrub_point = point;
+                    dirty = blocks;
+                }
+                for sv in &self.sub_volumes {
+                    waiters.push(sv.guest.flush_async().await?);
+                }
+            }
+            for waiter in waiters {
+                waiter.wait().await?;
+            }
+            if let Some(area) = &self.meta {
+                self.save_ownership(area, scrub_point, &dirty).await?;
+            }
+            Ok(())
+        }
+        .await;
+
+        if result.is_err() {
+            /*
+             * Try these again on the next flush.
+             */
+            let mut ownership = self.owned.lock().unwrap();
+            ownership
+                .dirty
+                .extend(dirty.iter().map(|(index, _)| *index));
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const REQUEST: &str = r#"{
+        "block_size": 512,
+        "gen": 2,
+        "sub_volumes": [
+            {
+                "target": ["127.0.0.1:3801", "127.0.0.1:3802", "127.0.0.1:3803"]
+            },
+            { "target": ["127.0.0.1:3804"], "key": "abc" },
+            {
+                "target": ["127.0.0.1:3808", "127.0.0.1:3809"],
+                "write_quorum": 1
+            }
+        ],
+        "read_only_parent": {
+            "target": ["127.0.0.1:3805", "127.0.0.1:3806", "127.0.0.1:3807"]
+        }
+    }"#;
+
+    #[test]
+    fn construction_request_from_json() {
+        let request = VolumeConstructionRequest::from_json(REQUEST).unwrap();
+        assert_eq!(request.block_size, 512);
+        assert_eq!(request.gen, 2);
+        assert!(!request.read_only);
+        assert_eq!(request.sub_volumes.len(), 3);
+        assert_eq!(request.sub_volumes[0].target.len(), 3);
+        assert_eq!(request.sub_volumes[0].key, None);
+        assert_eq!(request.sub_volumes[1].key, Some("abc".to_string()));
+        assert_eq!(request.read_only_parent.as_ref().unwrap().target.len(), 3);
+
+        assert_eq!(
+            request.sub_volumes[0].policy().unwrap(),
+            ReplicationPolicy::majority(3).unwrap()
+        );
+        assert_eq!(
+            request.sub_volumes[2].policy().unwrap(),
+            ReplicationPolicy::new(2, 1, 2).unwrap()
+        );
+
+        let json = serde_json::to_string(&request).unwrap();
+        assert_eq!(
+            VolumeConstructionRequest::from_json(&json).unwrap(),
+            request
+        );
+    }
+
+    #[test]
+    fn construction_request_validation() {
+        let good = VolumeConstructionRequest::from_json(REQUEST).unwrap();
+
+        let mut request = good.clone();
+        request.block_size = 1000;
+        assert!(request.validate().is_err());
+
+        let mut request = good.clone();
+        request.sub_volumes.clear();
+        assert!(request.validate().is_err());
+
+        let mut request = good.clone();
+        request.sub_volumes[0].target.clear();
+        assert!(request.validate().is_err());
+
+        let mut request = good.clone();
+        request.sub_volumes[0].flush_quorum = Some(4);
+        assert!(request.validate().is_err());
+
+        let mut request = good.clone();
+        request.read_only_parent.as_mut().unwrap().target =
+            vec!["127.0.0.1:3805".parse().unwrap(); 4];
+        assert!(request.validate().is_err());
+
+        // A parent URL, but not as well as a parent region.
+        let mut request = good;
+        request.read_only_parent_url =
+            Some("http://127.0.0.1/disk.img".to_string());
+        assert!(request.validate().is_err());
+        request.read_only_parent = None;
+        assert!(request.validate().is_ok());
+        request.read_only_parent_url = Some("file:///etc/passwd".to_string());
+        assert!(request.validate().is_err());
+    }
+
+    /*
+     * Serve an image over HTTP for as long as the test runs.  Only HEAD,
+     * and GET with a range, are answered.
+     */
+    async fn serve_image(image: Vec<u8>) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener =
+            tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut sock, _) = listener.accept().await.unwrap();
+                let image = image.clone();
+                tokio::spawn(async move {
+                    let mut req = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    loop {
+                        while !req.ends_with(b"\r\n\r\n") {
+                            let n = sock.read(&mut buf).await.unwrap();
+                            if n == 0 {
+                                return;
+                            }
+                            req.extend_from_slice(&buf[..n]);
+                        }
+                        let text = String::from_utf8(req.split_off(0)).unwrap();
+                        let range = text.lines().find_map(|l| {
+                            l.to_lowercase().strip_prefix("range: bytes=").map(
+                                |r| {
+                                    let (a, b) = r.split_once('-').unwrap();
+                                    let a: usize = a.parse().unwrap();
+                                    let b: usize = b.parse().unwrap();
+                                    a..(b + 1)
+                                },
+                            )
+                        });
+
+                        let (head, body) = if text.starts_with("HEAD") {
+                            (
+                                format!(
+                                    "HTTP/1.1 200 OK\r\n\
+                                    content-length: {}\r\n\r\n",
+                                    image.len()
+                                ),
+                                vec![],
+                            )
+                        } else {
+                            let range = range.unwrap();
+                            (
+                                format!(
+                                    "HTTP/1.1 206 Partial Content\r\n\
+                                    content-length: {}\r\n\
+                                    content-range: bytes {}-{}/{}\r\n\r\n",
+                                    range.len(),
+                                    range.start,
+                                    range.end - 1,
+                                    image.len()
+                                ),
+                                image[range].to_vec(),
+                            )
+                        };
+                        sock.write_all(head.as_bytes()).await.unwrap();
+                        sock.write_all(&body).await.unwrap();
+                    }
+                });
+            }
+        });
+
+        format!("http://{}/disk.img", addr)
+    }
+
+    #[tokio::test]
+    async fn url_image_reads() {
+        let image: Vec<u8> = (0..1300u32).map(|i| i as u8).collect();
+        let url = serve_image(image.clone()).await;
+
+        let parent = UrlImage::open(&url).await.unwrap();
+        assert_eq!(parent.size(), 1300);
+        assert_eq!(parent.read(512, 512).await.unwrap(), image[512..1024]);
+
+        // The last block is filled out with zeros, and past it is all
+        // zeros.
+        let tail = parent.read(1024, 512).await.unwrap();
+        assert_eq!(tail[..276], image[1024..]);
+        assert!(tail[276..].iter().all(|b| *b == 0));
+        assert_eq!(parent.read(1536, 512).await.unwrap(), vec![0; 512]);
+
+        let mut vol = Volume::new(512);
+        assert!(vol.add_url_parent(parent).is_err());
+    }
+
+    fn span(sv: usize, start: u64, count: u64, buf_block: u64) -> VolumeSpan {
+        VolumeSpan {
+            sv,
+            start,
+            count,
+            buf_block,
+        }
+    }
+
+    #[test]
+    fn lba_spans_single_subvolume() {
+        let ranges = vec![0..10];
+        assert_eq!(lba_spans(&ranges, 2, 3).unwrap(), vec![span(0, 2, 3, 0)]);
+    }
+
+    #[test]
+    fn lba_spans_across_subvolumes() {
+        let ranges = vec![0..10, 10..15, 15..30];
+        assert_eq!(
+            lba_spans(&ranges, 8, 10).unwrap(),
+            vec![span(0, 8, 2, 0), span(1, 0, 5, 2), span(2, 0, 3, 7)],
+        );
+    }
+
+    #[test]
+    fn lba_spans_second_subvolume_only() {
+        let ranges = vec![0..10, 10..20];
+        assert_eq!(lba_spans(&ranges, 12, 8).unwrap(), vec![span(1, 2, 8, 0)]);
+    }
+
+    #[test]
+    fn lba_spans_past_end() {
+        let ranges = vec![0..10, 10..20];
+        assert!(lba_spans(&ranges, 15, 6).is_err());
+        assert!(lba_spans(&[], 0, 1).is_err());
+    }
+
+    #[test]
+    fn volume_ownership() {
+        let vol = Volume::new(512);
+        vol.owned.lock().unwrap().owned = vec![false; 8];
+
+        assert_eq!(vol.unowned_blocks(0, 4), vec![0, 1, 2, 3]);
+
+        vol.set_owned(1, 2);
+        assert_eq!(vol.unowned_blocks(0, 4), vec![0, 3]);
+
+        // Blocks past the end of the parent are never unowned.
+        vol.set_owned(6, 4);
+        assert_eq!(vol.unowned_blocks(4, 6), vec![4, 5]);
+    }
+
+    #[test]
+    fn ownership_dirty_blocks() {
+        let vol = Volume::new(512);
+        vol.owned.lock().unwrap().owned = vec![false; 5000];
+
+        vol.set_owned(1, 2);
+        vol.set_owned(4096, 1);
+        let (_, dirty) = vol.take_dirty_ownership();
+        assert_eq!(
+            dirty.iter().map(|(i, _)| *i).collect::<Vec<u64>>(),
+            vec![0, 1]
+        );
+        assert_eq!(dirty[0].1[0], 0b110);
+        assert_eq!(dirty[1].1[0], 0b1);
+
+        // Nothing changed since, and owning a block again changes nothing.
+        vol.set_owned(1, 1);
+        assert!(vol.take_dirty_ownership().1.is_empty());
+    }
+
+    #[test]
+    fn failed_write_gives_back_ownership() {
+        let vol = Volume::new(512);
+        vol.owned.lock().unwrap().owned = vec![false; 8];
+        vol.set_owned(0, 1);
+        vol.take_dirty_ownership();
+
+        // Block 0 was owned before, so a failed write leaves it owned.
+        vol.claim_owned(0, 2);
+        vol.settle_owned(0, 2, false);
+        assert_eq!(vol.unowned_blocks(0, 4), vec![1, 2, 3]);
+        let (_, dirty) = vol.take_dirty_ownership();
+        assert_eq!(dirty[0].1[0], 0b1);
+
+        // Two writes to block 2: the block stays owned if either works.
+        vol.claim_owned(2, 1);
+        vol.claim_owned(2, 1);
+        vol.settle_owned(2, 1, true);
+        vol.settle_owned(2, 1, false);
+        assert_eq!(vol.unowned_blocks(0, 4), vec![1, 3]);
+
+        // And goes back to the parent if both fail.
+        vol.claim_owned(3, 1);
+        vol.claim_owned(3, 1);
+        vol.settle_owned(3, 1, false);
+        assert_eq!(vol.unowned_blocks(0, 4), vec![1]);
+        vol.settle_owned(3, 1, false);
+        assert_eq!(vol.unowned_blocks(0, 4), vec![1, 3]);
+        assert!(vol.owned.lock().unwrap().claimed.is_empty());
+    }
+
+    #[test]
+    fn ownership_area_round_trip() {
+        assert_eq!(OwnershipArea::blocks_needed(1, 512), 2);
+        assert_eq!(OwnershipArea::blocks_needed(4096, 512), 2);
+        assert_eq!(OwnershipArea::blocks_needed(4097, 512), 3);
+
+        let header = encode_header(512, 5000, 1200);
+        assert_eq!(decode_header(&header, 5000), Ok(Some(1200)));
+        assert!(decode_header(&header, 4999).is_err());
+        assert_eq!(decode_header(&[0; 512], 5000), Ok(None));
+        assert!(decode_header(&[1; 512], 5000).is_err());
+
+        let mut owned = vec![false; 5000];
+        owned[0] = true;
+        owned[4095] = true;
+        owned[4999] = true;
+        let mut bitmap = encode_bitmap_block(&owned, 0, 512);
+        bitmap.extend(encode_bitmap_block(&owned, 1, 512));
+        assert_eq!(decode_bitmap(&bitmap, 5000), owned);
+    }
+
+    #[test]
+    fn block_runs_groups_consecutive() {
+        assert_eq!(block_runs(&[]), vec![]);
+        assert_eq!(block_runs(&[3]), vec![3..4]);
+        assert_eq!(block_runs(&[0, 1, 2, 5, 6, 9]), vec![0..3, 5..7, 9..10]);
+    }
+
+    #[tokio::test]
+    async fn scrub_without_parent() {
+        let vol = Volume::new(512);
+        assert!(vol.scrub(16, Duration::from_millis(0)).await.is_ok());
+        assert_eq!(vol.scrub_point(), 0);
+    }
+
+    #[tokio::test]
+    async fn read_only_volume_refuses_write() {
+        let vol = Volume::new_read_only(512);
+        assert!(vol.read_only());
+
+        let res = vol
+            .write(Block::new_512(0), Bytes::from(vec![0; 512]))
+            .await;
+        assert_eq!(res, Err(CrucibleError::ModifyingReadOnlyRegion));
+        assert!(vol.flush().await.is_ok());
+    }
+}